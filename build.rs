@@ -0,0 +1,50 @@
+//! Generates the gRPC service of the `grpc` feature
+//!
+//! The service is generated from a manual definition instead of `proto/pixeldike.proto` so that building does not
+//! require `protoc`.
+//! Its message types are defined by hand in `src/net/servers/grpc_server.rs` and both must be kept in sync with the
+//! proto file.
+
+fn main() {
+    #[cfg(feature = "grpc")]
+    generate_grpc_service();
+}
+
+#[cfg(feature = "grpc")]
+fn generate_grpc_service() {
+    use tonic_build::manual::{Builder, Method, Service};
+
+    let method = |name: &str, route_name: &str, input_type: &str, output_type: &str| {
+        Method::builder()
+            .name(name)
+            .route_name(route_name)
+            .input_type(format!("super::{}", input_type))
+            .output_type(format!("super::{}", output_type))
+            .codec_path("tonic::codec::ProstCodec")
+    };
+    let service = Service::builder()
+        .name("Canvas")
+        .package("pixeldike")
+        .method(method("get_pixel", "GetPixel", "PixelPosition", "Pixel").build())
+        .method(method("set_pixel", "SetPixel", "Pixel", "Empty").build())
+        .method(
+            method("set_pixels", "SetPixels", "Pixel", "SetPixelsResponse")
+                .client_streaming()
+                .build(),
+        )
+        .method(method("get_state", "GetState", "Empty", "State").build())
+        .method(
+            method(
+                "subscribe_updates",
+                "SubscribeUpdates",
+                "SubscribeRequest",
+                "Update",
+            )
+            .server_streaming()
+            .build(),
+        )
+        .build();
+
+    println!("cargo:rerun-if-changed=build.rs");
+    Builder::new().build_client(false).compile(&[service]);
+}
@@ -0,0 +1,619 @@
+//! Loading and validation of server configuration files
+//!
+//! A configuration file is a TOML document whose keys are the long names of the `server` commandline flags, e.g.
+//!
+//! ```toml
+//! listen = [
+//!     "tcp://0.0.0.0:1234?max-pixels-per-sec=10000&features=PXB",
+//!     "tcp://10.0.0.1:1338?allow=10.0.0.0/8&storm-protection=off",
+//!     "udp://0.0.0.0:1234",
+//! ]
+//! width = 1920
+//! height = 1080
+//! rotate = 180
+//! snapshot = "/var/lib/pixeldike/snapshot.pixmap"
+//! ```
+
+use crate::cli::ServerOpts;
+use anyhow::{anyhow, Context};
+use clap::Parser;
+use pixeldike::net::servers::{AccessControlOptions, RateLimitOptions, Reloadable};
+use pixeldike::sinks::pixmap_file;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::Path;
+use std::time::Duration;
+use url::Url;
+
+/// Helper to parse server options from arguments that were generated from a config file
+#[derive(Parser, Debug)]
+#[command(no_binary_name = true)]
+struct ConfigFileArgs {
+    #[command(flatten)]
+    server: ServerOpts,
+}
+
+/// Read the configuration file at `path` and parse it into server options
+pub(crate) fn load_server_opts(path: &Path) -> anyhow::Result<ServerOpts> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Could not read config file {}", path.display()))?;
+    let table: toml::Table = content
+        .parse()
+        .with_context(|| format!("{} is not a valid TOML document", path.display()))?;
+    let args = table_to_args(&table)?;
+    let parsed = ConfigFileArgs::try_parse_from(args).map_err(|e| anyhow!("{}", e.render()))?;
+    Ok(parsed.server)
+}
+
+/// Convert a configuration table into the equivalent list of commandline arguments
+fn table_to_args(table: &toml::Table) -> anyhow::Result<Vec<OsString>> {
+    let mut args = Vec::new();
+    for (key, value) in table {
+        if key == "config" {
+            return Err(anyhow!(
+                "Config files cannot include other config files (key `config`)"
+            ));
+        }
+        let flag = format!("--{}", key);
+        match value {
+            toml::Value::Boolean(true) => args.push(flag.into()),
+            toml::Value::Boolean(false) => {}
+            toml::Value::Array(values) => {
+                for value in values {
+                    args.push(flag.clone().into());
+                    args.push(scalar_to_arg(key, value)?);
+                }
+            }
+            value => {
+                args.push(flag.into());
+                args.push(scalar_to_arg(key, value)?);
+            }
+        }
+    }
+    Ok(args)
+}
+
+fn scalar_to_arg(key: &str, value: &toml::Value) -> anyhow::Result<OsString> {
+    match value {
+        toml::Value::String(s) => Ok(s.into()),
+        toml::Value::Integer(i) => Ok(i.to_string().into()),
+        toml::Value::Float(f) => Ok(f.to_string().into()),
+        value => Err(anyhow!(
+            "Unsupported value for key `{}`: expected a string, number or boolean but got a {}",
+            key,
+            value.type_str()
+        )),
+    }
+}
+
+/// Check server options for problems which would otherwise only be noticed during startup
+///
+/// All problems that were found are returned as human readable messages.
+pub(crate) fn validate_server_opts(opts: &ServerOpts) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    // canvas
+    if opts.width == 0 || opts.height == 0 {
+        problems.push(format!(
+            "The canvas size {}x{} is invalid; width and height must both be greater than 0",
+            opts.width, opts.height
+        ));
+    }
+    if opts.width > u16::MAX as usize || opts.height > u16::MAX as usize {
+        problems.push(format!(
+            "The canvas size {}x{} cannot be addressed by clients using the binary protocol; width and height must not exceed {}",
+            opts.width,
+            opts.height,
+            u16::MAX
+        ));
+    }
+
+    // listeners, each of which needs an address of its own
+    let mut bindings: BTreeMap<String, Vec<&Url>> = BTreeMap::new();
+    for url in &opts.listen {
+        let default_port = match url.scheme() {
+            #[cfg(feature = "tcp")]
+            "tcp" => Some(1234),
+            #[cfg(feature = "udp")]
+            "udp" => Some(1234),
+            #[cfg(feature = "ws")]
+            "ws" => Some(1235),
+            #[cfg(feature = "grpc")]
+            "grpc" => Some(1236),
+            #[cfg(feature = "tls")]
+            "tls" => Some(1237),
+            #[cfg(feature = "quic")]
+            "quic" => Some(1237),
+            #[cfg(feature = "dtls")]
+            "dtls" => Some(1238),
+            #[cfg(feature = "mqtt")]
+            "mqtt" => Some(1883),
+            "unix" => None,
+            scheme => {
+                problems.push(format!(
+                    "Listener {} uses the unsupported protocol {:?}; supported are {}",
+                    url,
+                    scheme,
+                    supported_listen_schemes().join(", ")
+                ));
+                continue;
+            }
+        };
+        if let Err(e) = crate::features_for(url) {
+            problems.push(format!("Listener {} enables invalid features: {}", url, e));
+        }
+        if let Err(e) = crate::acceptors_for(url) {
+            problems.push(format!(
+                "Listener {} specifies an invalid number of acceptors: {}",
+                url, e
+            ));
+        }
+        match crate::access_control_for(url) {
+            Err(e) => problems.push(format!(
+                "Listener {} specifies invalid access control: {}",
+                url, e
+            )),
+            Ok(access_control)
+                if access_control.is_restricted() && matches!(url.scheme(), "unix" | "grpc" | "mqtt") =>
+            {
+                problems.push(format!(
+                    "Listener {} restricts source networks which is not supported by {} listeners",
+                    url,
+                    url.scheme()
+                ))
+            }
+            Ok(_) => {}
+        }
+        if let Err(e) = crate::socket_options_for(url) {
+            problems.push(format!(
+                "Listener {} specifies invalid socket options: {}",
+                url, e
+            ));
+        }
+        if let Err(e) = crate::deflate_threshold_for(url) {
+            problems.push(format!(
+                "Listener {} specifies an invalid deflate threshold: {}",
+                url, e
+            ));
+        }
+        if let Err(e) = crate::ping_interval_for(url) {
+            problems.push(format!(
+                "Listener {} specifies an invalid ping interval: {}",
+                url, e
+            ));
+        }
+        if let Err(e) = crate::max_connections_per_ip_for(url, opts.max_connections_per_ip) {
+            problems.push(format!(
+                "Listener {} specifies an invalid connection limit: {}",
+                url, e
+            ));
+        }
+        if let Err(e) = crate::idle_timeout_for(url, opts.idle_timeout_secs) {
+            problems.push(format!(
+                "Listener {} specifies an invalid idle timeout: {}",
+                url, e
+            ));
+        }
+        if let Err(e) = crate::rate_limit_for(url) {
+            problems.push(format!("Listener {} specifies an invalid rate limit: {}", url, e));
+        }
+        match crate::client_ca_for(url) {
+            Some(_) if url.scheme() != "dtls" => problems.push(format!(
+                "Listener {} specifies a client CA which is only supported by dtls listeners",
+                url
+            )),
+            Some(path) if !path.is_file() => problems.push(format!(
+                "The client CA {} of listener {} does not exist",
+                path.display(),
+                url
+            )),
+            _ => {}
+        }
+        if let Err(e) = crate::mqtt_publishing_for(url) {
+            problems.push(format!(
+                "Listener {} specifies invalid publishing rates: {}",
+                url, e
+            ));
+        }
+        match crate::io_uring_workers_for(url) {
+            Err(e) => problems.push(format!(
+                "Listener {} specifies an invalid number of io_uring workers: {}",
+                url, e
+            )),
+            Ok(Some(_)) if !cfg!(feature = "io-uring") => problems.push(format!(
+                "Listener {} uses io_uring workers which require the io-uring feature",
+                url
+            )),
+            Ok(_) => {}
+        }
+        match default_port {
+            None => {
+                if url.path().is_empty() || url.path() == "/" {
+                    problems.push(format!(
+                        "Listener {} does not specify a socket path, e.g. unix:///run/pixeldike.sock",
+                        url
+                    ));
+                }
+                if let Err(e) = crate::socket_mode_for(url) {
+                    problems.push(format!(
+                        "Listener {} specifies an invalid socket mode: {}",
+                        url, e
+                    ));
+                }
+            }
+            Some(default_port) => match url.host_str() {
+                None => problems.push(format!(
+                    "Listener {} does not specify a host to bind to, e.g. {}://0.0.0.0:{}",
+                    url,
+                    url.scheme(),
+                    default_port
+                )),
+                Some(_) => match url.socket_addrs(|| Some(default_port)) {
+                    Err(e) => {
+                        problems.push(format!("The host of listener {} cannot be resolved: {}", url, e))
+                    }
+                    // mqtt listeners connect to a broker instead of binding to the address
+                    Ok(_) if url.scheme() == "mqtt" => {}
+                    Ok(addrs) => {
+                        let transport = match url.scheme() {
+                            "udp" | "quic" | "dtls" => "udp",
+                            _ => "tcp",
+                        };
+                        // the OS picks a distinct port for every listener which asks for port 0
+                        for addr in addrs.into_iter().filter(|addr| addr.port() != 0) {
+                            bindings
+                                .entry(format!("{}://{}", transport, addr))
+                                .or_default()
+                                .push(url);
+                        }
+                    }
+                },
+            },
+        }
+        if default_port.is_none() && !url.path().is_empty() {
+            bindings
+                .entry(format!("unix://{}", url.path()))
+                .or_default()
+                .push(url);
+        }
+    }
+    for (binding, urls) in bindings {
+        if let [first, others @ ..] = urls.as_slice() {
+            for other in others {
+                problems.push(format!(
+                    "Listeners {} and {} cannot both bind to {}",
+                    first, other, binding
+                ));
+            }
+        }
+    }
+
+    // admin interface
+    if let Some(url) = &opts.admin {
+        if let Err(e) = crate::admin_endpoints_for(url) {
+            problems.push(format!("Invalid admin url: {}", e));
+        }
+    }
+
+    // tls
+    #[cfg(any(feature = "tls", feature = "dtls"))]
+    if opts
+        .listen
+        .iter()
+        .any(|url| matches!(url.scheme(), "tls" | "quic" | "dtls"))
+    {
+        for (flag, path) in [("--tls-cert", &opts.tls_cert), ("--tls-key", &opts.tls_key)] {
+            match path {
+                None => problems.push(format!(
+                    "TLS, QUIC and DTLS listeners require {} to be given",
+                    flag
+                )),
+                Some(path) if !path.is_file() => {
+                    problems.push(format!("The {} file {} does not exist", flag, path.display()))
+                }
+                Some(_) => {}
+            }
+        }
+    }
+
+    // snapshots
+    if let Some(path) = &opts.file_opts.load_snapshot {
+        if !path.is_file() {
+            problems.push(format!(
+                "The snapshot {} which should be loaded does not exist",
+                path.display()
+            ));
+        }
+    }
+    if let Some(path) = &opts.file_opts.snapshot_file {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            if !dir.is_dir() {
+                problems.push(format!(
+                    "Snapshots cannot be written to {} because the directory {} does not exist",
+                    path.display(),
+                    dir.display()
+                ));
+            }
+        }
+        if opts.file_opts.snapshot_interval_secs == 0 {
+            problems.push("The snapshot interval must be at least 1 second".to_string());
+        }
+    }
+
+    // message templates
+    if let Some(dir) = &opts.message_templates {
+        if !dir.is_dir() {
+            problems.push(format!(
+                "The message template directory {} does not exist",
+                dir.display()
+            ));
+        } else if let Err(e) = pixeldike::MessageTemplates::load(dir) {
+            problems.push(format!(
+                "The message templates in {} cannot be loaded: {}",
+                dir.display(),
+                e
+            ));
+        }
+    }
+
+    // streaming
+    if opts.stream_opts.rtmp_dst_addr.is_some() || opts.stream_opts.rtsp_dst_addr.is_some() {
+        if opts.stream_opts.framerate == 0 {
+            problems.push("The stream framerate must be greater than 0".to_string());
+        }
+        let (width, height) = opts
+            .stream_opts
+            .transform
+            .transformed_size(opts.width, opts.height);
+        if width % 2 != 0 || height % 2 != 0 {
+            problems.push(format!(
+                "The streamed video would be {}x{} but its width and height must be divisible by 2 for yuv420p encoding",
+                width, height
+            ));
+        }
+        if !is_in_path("ffmpeg") {
+            problems.push("Streaming requires ffmpeg but it could not be found in PATH".to_string());
+        }
+    }
+
+    // framebuffer
+    if let Some(fb_device) = &opts.fb_opts.fb_device {
+        if !fb_device.exists() {
+            problems.push(format!(
+                "The framebuffer device {} does not exist",
+                fb_device.display()
+            ));
+        }
+        if opts.fb_opts.fb_framerate == 0 {
+            problems.push("The framebuffer framerate must be greater than 0".to_string());
+        }
+    }
+
+    // announcements
+    if let Some(group) = opts.announce_opts.announce_group {
+        if !group.ip().is_multicast() {
+            problems.push(format!(
+                "Announcements must be sent to a multicast group but {} is none",
+                group.ip()
+            ));
+        }
+    }
+
+    problems
+}
+
+/// The options of a running listener which are replaced when the configuration is reloaded
+#[derive(Debug, Clone)]
+pub(crate) struct ListenerReloadables {
+    /// The url with which the listener was started
+    pub url: Url,
+    /// The networks from which the listener accepts clients
+    pub access_control: Reloadable<AccessControlOptions>,
+    /// The rate limits of the connections of the listener
+    pub rate_limit: Reloadable<RateLimitOptions>,
+}
+
+/// Read the configuration file at `path` again and apply the options which can be changed while the server runs
+///
+/// These are the access control and rate limits of listeners, the snapshot interval and the log level. Listeners
+/// are matched by their url without the query, so adding or removing one requires a restart like changing any other
+/// option. Nothing is applied if the file has problems.
+pub(crate) fn reload_server_opts(
+    path: &Path,
+    current: &ServerOpts,
+    listeners: &[ListenerReloadables],
+) -> anyhow::Result<ServerOpts> {
+    let opts = load_server_opts(path)?;
+    let problems = validate_server_opts(&opts);
+    if !problems.is_empty() {
+        return Err(anyhow!("{}", problems.join("; ")));
+    }
+
+    // listeners
+    for listener in listeners {
+        let address = without_query(&listener.url);
+        let Some(url) = opts.listen.iter().find(|url| without_query(url) == address) else {
+            tracing::warn!(
+                "Listener {} is no longer configured but keeps running until the server is restarted",
+                address
+            );
+            continue;
+        };
+        let access_control = crate::access_control_for(url)?;
+        if *listener.access_control.get() != access_control {
+            tracing::info!("Changed the access control of listener {}", address);
+            listener.access_control.set(access_control);
+        }
+        let rate_limit = crate::rate_limit_for(url)?;
+        if *listener.rate_limit.get() != rate_limit {
+            tracing::info!("Changed the rate limits of listener {}", address);
+            listener.rate_limit.set(rate_limit);
+        }
+    }
+    for url in &opts.listen {
+        if !listeners
+            .iter()
+            .any(|listener| without_query(&listener.url) == without_query(url))
+        {
+            tracing::warn!("Listener {} is only started when the server is restarted", url);
+        }
+    }
+
+    // snapshots
+    if opts.file_opts.snapshot_interval_secs != current.file_opts.snapshot_interval_secs {
+        pixmap_file::set_snapshot_interval(Duration::from_secs(opts.file_opts.snapshot_interval_secs as u64));
+    }
+
+    // logging
+    if opts.log_level != current.log_level {
+        crate::set_log_level(opts.log_level);
+    }
+
+    Ok(opts)
+}
+
+/// A listener url without the query which configures the listener
+fn without_query(url: &Url) -> Url {
+    let mut url = url.clone();
+    url.set_query(None);
+    url
+}
+
+fn supported_listen_schemes() -> Vec<&'static str> {
+    [
+        ("tcp://", cfg!(feature = "tcp")),
+        ("udp://", cfg!(feature = "udp")),
+        ("ws://", cfg!(feature = "ws")),
+        ("grpc://", cfg!(feature = "grpc")),
+        ("tls://", cfg!(feature = "tls")),
+        ("quic://", cfg!(feature = "quic")),
+        ("dtls://", cfg!(feature = "dtls")),
+        ("mqtt://", cfg!(feature = "mqtt")),
+        ("unix://", true),
+    ]
+    .into_iter()
+    .filter_map(|(scheme, enabled)| enabled.then_some(scheme))
+    .collect()
+}
+
+/// Whether an executable with the given name can be found in one of the directories listed in `PATH`
+fn is_in_path(program: &str) -> bool {
+    std::env::var_os("PATH")
+        .map(|path| std::env::split_paths(&path).any(|dir| dir.join(program).is_file()))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let table: toml::Table = r#"
+            listen = ["tcp://127.0.0.1:1234", "udp://[::1]:1234", "unix:///tmp/pixeldike.sock"]
+            width = 1920
+            height = 1080
+            rotate = 180
+            flip-vertical = true
+        "#
+        .parse()
+        .unwrap();
+        let opts = ConfigFileArgs::try_parse_from(table_to_args(&table).unwrap())
+            .unwrap()
+            .server;
+        assert_eq!(opts.listen.len(), 3);
+        assert_eq!((opts.width, opts.height), (1920, 1080));
+        assert!(opts.transform_opts.flip_vertical);
+        assert!(validate_server_opts(&opts).is_empty());
+    }
+
+    #[test]
+    fn test_validate_config() {
+        let table: toml::Table = r#"
+            listen = ["ftp://127.0.0.1", "tcp:foo", "udp://127.0.0.1:1234?features=PXB,FOO", "unix:///tmp/p.sock?mode=999", "tcp://127.0.0.1:1234?acceptors=0", "ws://127.0.0.1?max-pixels-per-sec=lots", "tcp://127.0.0.1:1235?allow=10.0.0.0/33", "tcp://127.0.0.1:1236?nodelay=yes", "ws://127.0.0.1:1237?deflate=always", "ws://127.0.0.1:1238?ping-interval=0", "udp://127.0.0.1:1239?client-ca=/etc/pixeldike/clients.pem"]
+            width = 0
+            fb-device = "/this/does/not/exist"
+            admin = "tcp://0.0.0.0:1240"
+        "#
+        .parse()
+        .unwrap();
+        let opts = ConfigFileArgs::try_parse_from(table_to_args(&table).unwrap())
+            .unwrap()
+            .server;
+        assert_eq!(validate_server_opts(&opts).len(), 14);
+    }
+
+    #[test]
+    fn test_validate_listeners_of_same_protocol() {
+        let table: toml::Table = r#"
+            listen = ["tcp://127.0.0.1:1234?max-pixels-per-sec=1000&features=PXB", "tcp://127.0.0.1:1338?allow=127.0.0.0/8&storm-protection=off&max-connections-per-ip=off", "udp://127.0.0.1:1338", "tcp://127.0.0.1:0", "tcp://127.0.0.1:0"]
+        "#
+        .parse()
+        .unwrap();
+        let opts = ConfigFileArgs::try_parse_from(table_to_args(&table).unwrap())
+            .unwrap()
+            .server;
+        assert!(validate_server_opts(&opts).is_empty());
+
+        let table: toml::Table = r#"
+            listen = ["tcp://127.0.0.1:1234", "ws://127.0.0.1:1234", "unix:///tmp/p.sock", "unix:///tmp/p.sock?mode=660", "tcp://127.0.0.1:1235?idle-timeout=soon"]
+        "#
+        .parse()
+        .unwrap();
+        let opts = ConfigFileArgs::try_parse_from(table_to_args(&table).unwrap())
+            .unwrap()
+            .server;
+        assert_eq!(validate_server_opts(&opts).len(), 3);
+    }
+
+    #[test]
+    fn test_reload_config() {
+        let path = std::env::temp_dir().join(format!("pixeldike-reload-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"listen = ["tcp://127.0.0.1:1234", "udp://127.0.0.1:1234"]"#,
+        )
+        .unwrap();
+        let opts = load_server_opts(&path).unwrap();
+        let listeners = opts
+            .listen
+            .iter()
+            .map(|url| ListenerReloadables {
+                url: url.clone(),
+                access_control: Reloadable::new(crate::access_control_for(url).unwrap()),
+                rate_limit: Reloadable::new(crate::rate_limit_for(url).unwrap()),
+            })
+            .collect::<Vec<_>>();
+
+        // invalid configurations are not applied
+        std::fs::write(
+            &path,
+            r#"listen = ["tcp://127.0.0.1:1234?allow=10.0.0.0/33", "udp://127.0.0.1:1234"]"#,
+        )
+        .unwrap();
+        assert!(reload_server_opts(&path, &opts, &listeners).is_err());
+        assert!(!listeners[0].access_control.get().is_restricted());
+
+        std::fs::write(
+            &path,
+            r#"listen = ["tcp://127.0.0.1:1234?allow=10.0.0.0/8&max-pixels-per-sec=100", "udp://127.0.0.1:1234?deny=10.1.0.0/16", "tcp://127.0.0.1:1235"]"#,
+        )
+        .unwrap();
+        let reloaded = reload_server_opts(&path, &opts, &listeners).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(reloaded.listen.len(), 3);
+        assert!(!listeners[0]
+            .access_control
+            .get()
+            .permits("192.0.2.1".parse().unwrap()));
+        assert_eq!(listeners[0].rate_limit.get().max_pixels_per_sec, Some(100));
+        assert!(!listeners[1]
+            .access_control
+            .get()
+            .permits("10.1.0.1".parse().unwrap()));
+        assert!(listeners[1]
+            .access_control
+            .get()
+            .permits("10.2.0.1".parse().unwrap()));
+    }
+}
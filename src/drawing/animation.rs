@@ -0,0 +1,208 @@
+//! Playback of animated images like GIFs
+
+use crate::net::clients::BulkClient;
+use crate::net::protocol::Request;
+use crate::pixmap::Color;
+use image::codecs::gif::GifDecoder;
+use image::imageops::FilterType;
+use image::{AnimationDecoder, ImageResult, RgbaImage};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Seek};
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::AsyncWrite;
+use tokio::time::Instant;
+
+/// Frames which are shown for a shorter time than this are shown for [`DEFAULT_DELAY`] instead
+///
+/// Many GIFs rely on this behavior of browsers and are otherwise played far too fast.
+const MIN_DELAY: Duration = Duration::from_millis(20);
+/// How long frames are shown which are supposed to be shown for less than [`MIN_DELAY`]
+const DEFAULT_DELAY: Duration = Duration::from_millis(100);
+
+/// A single frame of an [`Animation`]
+#[derive(Debug, Clone)]
+pub struct Frame {
+    /// The complete image which is shown, already composited with the preceding frames
+    pub image: RgbaImage,
+    /// How long the frame is shown before the next one
+    pub delay: Duration,
+}
+
+/// An animation whose frames are drawn one after another, only sending the pixels which changed in between
+#[derive(Debug, Clone)]
+pub struct Animation {
+    frames: Vec<Frame>,
+}
+
+impl Animation {
+    /// Create an animation from its frames
+    ///
+    /// # Panics
+    ///
+    /// This panics if there are no frames or they have different sizes.
+    pub fn new(frames: Vec<Frame>) -> Self {
+        assert!(!frames.is_empty(), "an animation needs at least one frame");
+        assert!(
+            frames
+                .iter()
+                .all(|frame| frame.image.dimensions() == frames[0].image.dimensions()),
+            "all frames of an animation must have the same size"
+        );
+        Self { frames }
+    }
+
+    /// Decode an animated GIF
+    pub fn from_gif(reader: impl BufRead + Seek) -> ImageResult<Self> {
+        let frames = GifDecoder::new(reader)?
+            .into_frames()
+            .map(|frame| {
+                let frame = frame?;
+                let (numer, denom) = frame.delay().numer_denom_ms();
+                let delay = Duration::from_secs_f64(numer as f64 / denom.max(1) as f64 / 1000.0);
+                Ok(Frame {
+                    image: frame.into_buffer(),
+                    delay: if delay < MIN_DELAY { DEFAULT_DELAY } else { delay },
+                })
+            })
+            .collect::<ImageResult<Vec<_>>>()?;
+        if frames.is_empty() {
+            return Err(image::ImageError::Decoding(image::error::DecodingError::new(
+                image::ImageFormat::Gif.into(),
+                "the GIF contains no frames",
+            )));
+        }
+        Ok(Self::new(frames))
+    }
+
+    /// Load an animated GIF from a file
+    pub fn load_gif(path: &Path) -> ImageResult<Self> {
+        Self::from_gif(BufReader::new(File::open(path)?))
+    }
+
+    /// The frames of this animation
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    /// The size of all frames as `(width, height)`
+    pub fn size(&self) -> (usize, usize) {
+        let (width, height) = self.frames[0].image.dimensions();
+        (width as usize, height as usize)
+    }
+
+    /// Scale all frames to the given size
+    pub fn resized(&self, width: usize, height: usize) -> Self {
+        Self::new(
+            self.frames
+                .iter()
+                .map(|frame| Frame {
+                    image: image::imageops::resize(
+                        &frame.image,
+                        width as u32,
+                        height as u32,
+                        FilterType::Triangle,
+                    ),
+                    delay: frame.delay,
+                })
+                .collect(),
+        )
+    }
+
+    /// Get the requests which draw all visible pixels of frame `i` with its top-left corner placed at `(x, y)`
+    pub fn frame(&self, i: usize, x: usize, y: usize) -> Vec<Request> {
+        changed_pixels(None, &self.frames[i].image, x, y)
+    }
+
+    /// Get the requests which draw frame `i` with its top-left corner placed at `(x, y)` over the frame before it
+    ///
+    /// Only pixels which changed since the previous frame are drawn, where the first frame follows the last one.
+    /// Pixels which are more than half transparent are never drawn so that the canvas shows through.
+    pub fn changes(&self, i: usize, x: usize, y: usize) -> Vec<Request> {
+        let previous = &self.frames[(i + self.frames.len() - 1) % self.frames.len()].image;
+        changed_pixels(Some(previous), &self.frames[i].image, x, y)
+    }
+
+    /// Draw the animation with its top-left corner placed at `(x, y)`, once or repeatedly
+    ///
+    /// The first frame is drawn completely while every following one only sends the pixels which changed.
+    /// Every frame is flushed before waiting for its delay.
+    pub async fn play<W: AsyncWrite + Unpin>(
+        &self,
+        client: &mut BulkClient<W>,
+        (x, y): (usize, usize),
+        repeat: bool,
+    ) -> std::io::Result<()> {
+        let changes = (0..self.frames.len())
+            .map(|i| self.changes(i, x, y))
+            .collect::<Vec<_>>();
+        let mut deadline = Instant::now();
+        client.send_all(self.frame(0, x, y)).await?;
+        let mut i = 0;
+        loop {
+            client.flush().await?;
+            deadline += self.frames[i].delay;
+            tokio::time::sleep_until(deadline).await;
+            i = (i + 1) % self.frames.len();
+            if i == 0 && !repeat {
+                return Ok(());
+            }
+            client.send_all(changes[i].iter().cloned()).await?;
+        }
+    }
+}
+
+/// Get the requests which draw all visible pixels of `next` that differ from `previous`
+fn changed_pixels(previous: Option<&RgbaImage>, next: &RgbaImage, x: usize, y: usize) -> Vec<Request> {
+    next.enumerate_pixels()
+        .filter(|(px, py, pixel)| {
+            pixel.0[3] >= 128 && previous.is_none_or(|previous| previous.get_pixel(*px, *py) != *pixel)
+        })
+        .map(|(px, py, pixel)| {
+            let [r, g, b, _] = pixel.0;
+            Request::SetPixel {
+                x: x + px as usize,
+                y: y + py as usize,
+                color: Color::from((r, g, b)),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use image::codecs::gif::GifEncoder;
+    use image::{Delay, Rgba};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_changes_between_frames() {
+        let black = RgbaImage::from_pixel(3, 2, Rgba([0, 0, 0, 0xFF]));
+        let mut dotted = black.clone();
+        dotted.put_pixel(1, 1, Rgba([0xFF, 0xFF, 0xFF, 0xFF]));
+
+        let mut gif = Vec::new();
+        GifEncoder::new(&mut gif)
+            .encode_frames(
+                [black, dotted]
+                    .map(|image| image::Frame::from_parts(image, 0, 0, Delay::from_numer_denom_ms(50, 1))),
+            )
+            .unwrap();
+        let animation = Animation::from_gif(Cursor::new(gif)).unwrap();
+        assert_eq!(animation.size(), (3, 2));
+        assert_eq!(animation.frames()[0].delay, Duration::from_millis(50));
+
+        let changes = [Request::SetPixel {
+            x: 11,
+            y: 21,
+            color: Color::from((0xFF, 0xFF, 0xFF)),
+        }];
+        assert_eq!(animation.changes(1, 10, 20), changes);
+        let Request::SetPixel { color, .. } = animation.changes(0, 10, 20)[0] else {
+            panic!("only pixels are set");
+        };
+        assert_eq!(color, Color::from((0, 0, 0)));
+        assert_eq!(animation.changes(0, 10, 20).len(), 1);
+    }
+}
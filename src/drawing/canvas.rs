@@ -0,0 +1,122 @@
+//! Decoding of the canvas state which servers send as `STATE` frames
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+use image::{Rgba, RgbaImage};
+use thiserror::Error;
+
+/// The size of one pixel record in the data of a `STATE delta` frame: x and y as u32 followed by r, g and b
+const RECORD_LEN: usize = 11;
+
+/// An error which indicates that a `STATE` frame could not be applied to an image of the canvas
+#[derive(Debug, Error, Clone, Eq, PartialEq)]
+pub enum InvalidStateFrameError {
+    /// The line is no `STATE` frame or lacks some of its fields
+    #[error("The line is no valid STATE frame")]
+    Malformed,
+    /// The frame uses an algorithm whose frames cannot be decoded into pixels
+    #[error("Unsupported state algorithm {0:?}; expected rgb64 or delta")]
+    UnsupportedAlgorithm(String),
+    /// The data of the frame is no valid base64
+    #[error("The data of the frame is no valid base64")]
+    InvalidBase64,
+    /// The frame contains a different number of pixels than the image or pixels outside of it
+    #[error("The frame does not fit a canvas of {width}x{height} pixels")]
+    SizeMismatch {
+        /// The width of the image onto which the frame should be applied
+        width: u32,
+        /// The height of the image onto which the frame should be applied
+        height: u32,
+    },
+}
+
+/// Decode a `STATE rgb64 <data>` or `STATE delta <token> <base> <data>` line and apply it to an image of the canvas
+///
+/// `rgb64` frames must cover the whole image and replace all of its pixels while `delta` frames only replace the
+/// pixels which they contain. All decoded pixels are opaque. Frames of streamed regions can be applied to an image of
+/// that region as long as the frame only contains `rgb64` data.
+pub fn apply_state_frame(img: &mut RgbaImage, frame: &str) -> Result<(), InvalidStateFrameError> {
+    let mut fields = frame
+        .strip_prefix("STATE ")
+        .ok_or(InvalidStateFrameError::Malformed)?
+        .split_whitespace();
+    let algorithm = fields.next().ok_or(InvalidStateFrameError::Malformed)?;
+    let data = match algorithm {
+        "rgb64" => fields.next(),
+        // skip the token and base
+        "delta" => fields.nth(2),
+        _ => {
+            return Err(InvalidStateFrameError::UnsupportedAlgorithm(
+                algorithm.to_string(),
+            ))
+        }
+    }
+    .ok_or(InvalidStateFrameError::Malformed)?;
+    let data = BASE64_STANDARD
+        .decode(data)
+        .map_err(|_| InvalidStateFrameError::InvalidBase64)?;
+    let size_mismatch = InvalidStateFrameError::SizeMismatch {
+        width: img.width(),
+        height: img.height(),
+    };
+
+    if algorithm == "rgb64" {
+        if data.len() != img.width() as usize * img.height() as usize * 3 {
+            return Err(size_mismatch);
+        }
+        for (pixel, rgb) in img.pixels_mut().zip(data.chunks_exact(3)) {
+            *pixel = Rgba([rgb[0], rgb[1], rgb[2], 0xFF]);
+        }
+        return Ok(());
+    }
+
+    if data.len() % RECORD_LEN != 0 {
+        return Err(size_mismatch);
+    }
+    for record in data.chunks_exact(RECORD_LEN) {
+        let x = u32::from_be_bytes(record[0..4].try_into().unwrap());
+        let y = u32::from_be_bytes(record[4..8].try_into().unwrap());
+        let pixel = img.get_pixel_mut_checked(x, y).ok_or(size_mismatch.clone())?;
+        *pixel = Rgba([record[8], record[9], record[10], 0xFF]);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_apply_state_frame() {
+        let mut img = RgbaImage::new(2, 2);
+        apply_state_frame(&mut img, "STATE rgb64 AAAA/wCAAAAAAAAA\n").unwrap();
+        assert_eq!(img.get_pixel(1, 0), &Rgba([0xFF, 0, 0x80, 0xFF]));
+        assert_eq!(img.get_pixel(0, 1), &Rgba([0, 0, 0, 0xFF]));
+
+        let mut record = Vec::new();
+        record.extend_from_slice(&1u32.to_be_bytes());
+        record.extend_from_slice(&1u32.to_be_bytes());
+        record.extend_from_slice(&[0, 0xFF, 0]);
+        let delta = format!("STATE delta 2 1 {}", BASE64_STANDARD.encode(&record));
+        apply_state_frame(&mut img, &delta).unwrap();
+        assert_eq!(img.get_pixel(1, 1), &Rgba([0, 0xFF, 0, 0xFF]));
+        assert_eq!(img.get_pixel(1, 0), &Rgba([0xFF, 0, 0x80, 0xFF]));
+
+        let mut small = RgbaImage::new(1, 1);
+        assert_eq!(
+            apply_state_frame(&mut small, &delta),
+            Err(InvalidStateFrameError::SizeMismatch { width: 1, height: 1 })
+        );
+        assert_eq!(
+            apply_state_frame(&mut small, "STATE rgb64 AAAA/wCA"),
+            Err(InvalidStateFrameError::SizeMismatch { width: 1, height: 1 })
+        );
+        assert_eq!(
+            apply_state_frame(&mut img, "PX 1 1 FF0000"),
+            Err(InvalidStateFrameError::Malformed)
+        );
+        assert_eq!(
+            apply_state_frame(&mut img, "STATE px AAAA"),
+            Err(InvalidStateFrameError::UnsupportedAlgorithm("px".to_string()))
+        );
+    }
+}
@@ -0,0 +1,116 @@
+//! Redrawing only those pixels of an image which differ from the canvas
+
+use crate::net::protocol::{Region, Request, MAX_BLOCK_PIXELS};
+use crate::pixmap::Color;
+use image::RgbaImage;
+
+/// Split a region into blocks which can each be fetched with one `PXGET` request
+///
+/// The blocks span as many complete rows as possible and are ordered row by row.
+pub fn block_regions(region: Region) -> Vec<Region> {
+    let block_width = region.width.clamp(1, MAX_BLOCK_PIXELS);
+    let block_height = (MAX_BLOCK_PIXELS / block_width).max(1);
+    let mut blocks = Vec::new();
+    for y in (region.y..region.y + region.height).step_by(block_height) {
+        for x in (region.x..region.x + region.width).step_by(block_width) {
+            blocks.push(Region {
+                x,
+                y,
+                width: block_width.min(region.x + region.width - x),
+                height: block_height.min(region.y + region.height - y),
+            });
+        }
+    }
+    blocks
+}
+
+/// Get the requests which draw the pixels of an image with its top-left corner placed at `(x, y)` whose colors
+/// differ from the canvas
+///
+/// `colors` are the current colors of the pixels in `block` as returned by a `PXGET` request. Pixels of the block
+/// which lie outside of the image or are more than half transparent in it are left alone.
+pub fn diff_requests(img: &RgbaImage, x: usize, y: usize, block: &Region, colors: &[Color]) -> Vec<Request> {
+    let mut requests = Vec::new();
+    for (i, current) in colors.iter().enumerate() {
+        let (px, py) = (block.x + i % block.width, block.y + i / block.width);
+        let (Some(ix), Some(iy)) = (px.checked_sub(x), py.checked_sub(y)) else {
+            continue;
+        };
+        let Some(pixel) = img.get_pixel_checked(ix as u32, iy as u32) else {
+            continue;
+        };
+        let [r, g, b, a] = pixel.0;
+        let color = Color::from((r, g, b));
+        if a >= 128 && *current != color {
+            requests.push(Request::SetPixel { x: px, y: py, color });
+        }
+    }
+    requests
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use image::Rgba;
+
+    #[test]
+    fn test_block_regions() {
+        let blocks = block_regions(Region {
+            x: 10,
+            y: 20,
+            width: 100,
+            height: 100,
+        });
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(
+            blocks[2],
+            Region {
+                x: 10,
+                y: 100,
+                width: 100,
+                height: 20
+            }
+        );
+        assert!(blocks.iter().all(|b| b.width * b.height <= MAX_BLOCK_PIXELS));
+
+        let wide = block_regions(Region {
+            x: 0,
+            y: 0,
+            width: MAX_BLOCK_PIXELS + 1,
+            height: 2,
+        });
+        assert_eq!(wide.len(), 4);
+        assert_eq!(wide[1].width, 1);
+    }
+
+    #[test]
+    fn test_diff_requests() {
+        let red = Color::from((0xFF, 0, 0));
+        let mut img = RgbaImage::from_pixel(2, 2, Rgba([0xFF, 0, 0, 0xFF]));
+        img.put_pixel(1, 1, Rgba([0, 0, 0, 0]));
+        // the block covers the image and one column to its left
+        let block = Region {
+            x: 4,
+            y: 5,
+            width: 3,
+            height: 2,
+        };
+        let black = Color::from((0, 0, 0));
+        let colors = [black, red, black, black, black, black];
+        assert_eq!(
+            diff_requests(&img, 5, 5, &block, &colors),
+            vec![
+                Request::SetPixel {
+                    x: 6,
+                    y: 5,
+                    color: red
+                },
+                Request::SetPixel {
+                    x: 5,
+                    y: 6,
+                    color: red
+                },
+            ]
+        );
+    }
+}
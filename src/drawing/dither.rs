@@ -0,0 +1,132 @@
+//! Reduction of the color depth of images with dithering
+
+use image::RgbaImage;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// The 4x4 Bayer matrix with which ordered dithering decides whether to round up or down
+const BAYER_4X4: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+/// How the colors of an image are reduced to a limited color depth
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum Dithering {
+    /// Every color is rounded to the nearest available one, which causes visible banding in gradients
+    #[default]
+    None,
+    /// The rounding error of every pixel is spread onto its neighbors to the right and below
+    ///
+    /// This gives the most accurate result but any change of the image changes the pattern of the whole area below.
+    FloydSteinberg,
+    /// Colors are rounded up or down according to a repeating 4x4 threshold pattern
+    ///
+    /// The result is a regular crosshatch pattern which stays the same for parts of an image which do not change,
+    /// e.g. between the frames of an animation.
+    Bayer,
+}
+
+/// An error which indicates that a string could not be parsed into a [`Dithering`]
+#[derive(Debug, Error, Clone, Eq, PartialEq)]
+#[error("Invalid dithering {0:?}; expected none, floyd-steinberg or bayer")]
+pub struct InvalidDitheringError(String);
+
+impl FromStr for Dithering {
+    type Err = InvalidDitheringError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Dithering::None),
+            "floyd-steinberg" => Ok(Dithering::FloydSteinberg),
+            "bayer" => Ok(Dithering::Bayer),
+            _ => Err(InvalidDitheringError(s.to_string())),
+        }
+    }
+}
+
+/// Reduce the colors of an image to `bits_per_channel` bits for each of red, green and blue
+///
+/// The reduced colors are scaled back to the full range so that the image can be drawn as usual. Alpha is kept as
+/// it is and 8 or more bits leave the image unchanged.
+pub fn dither(img: &mut RgbaImage, dithering: Dithering, bits_per_channel: u8) {
+    if bits_per_channel >= 8 {
+        return;
+    }
+    let step = 255.0 / ((1u32 << bits_per_channel.max(1)) - 1) as f32;
+    let quantize = |value: f32| ((value / step).round() * step).clamp(0.0, 255.0);
+
+    match dithering {
+        Dithering::None => {
+            for pixel in img.pixels_mut() {
+                for channel in &mut pixel.0[..3] {
+                    *channel = quantize(*channel as f32) as u8;
+                }
+            }
+        }
+        Dithering::Bayer => {
+            for (x, y, pixel) in img.enumerate_pixels_mut() {
+                let threshold = (BAYER_4X4[y as usize % 4][x as usize % 4] as f32 + 0.5) / 16.0 - 0.5;
+                for channel in &mut pixel.0[..3] {
+                    *channel = quantize(*channel as f32 + threshold * step) as u8;
+                }
+            }
+        }
+        Dithering::FloydSteinberg => {
+            let (width, height) = (img.width() as usize, img.height() as usize);
+            // the rounding errors which were spread onto the current and the next row
+            let mut errors = vec![[0f32; 3]; width];
+            let mut next_errors = vec![[0f32; 3]; width];
+            for y in 0..height {
+                for x in 0..width {
+                    let pixel = img.get_pixel_mut(x as u32, y as u32);
+                    for c in 0..3 {
+                        let value = pixel.0[c] as f32 + errors[x][c];
+                        let quantized = quantize(value);
+                        pixel.0[c] = quantized as u8;
+                        let error = value - quantized;
+                        if x + 1 < width {
+                            errors[x + 1][c] += error * 7.0 / 16.0;
+                            next_errors[x + 1][c] += error / 16.0;
+                        }
+                        if x > 0 {
+                            next_errors[x - 1][c] += error * 3.0 / 16.0;
+                        }
+                        next_errors[x][c] += error * 5.0 / 16.0;
+                    }
+                }
+                std::mem::swap(&mut errors, &mut next_errors);
+                next_errors.fill([0.0; 3]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use image::Rgba;
+
+    #[test]
+    fn test_dither() {
+        let gray = RgbaImage::from_pixel(8, 8, Rgba([0x40, 0x40, 0x40, 0x80]));
+        let mean = |img: &RgbaImage| img.pixels().map(|p| p.0[0] as f32).sum::<f32>() / 64.0;
+
+        // one bit per channel only allows black and white
+        let mut rounded = gray.clone();
+        dither(&mut rounded, Dithering::None, 1);
+        assert!(rounded.pixels().all(|p| p.0 == [0, 0, 0, 0x80]));
+
+        for dithering in [Dithering::FloydSteinberg, Dithering::Bayer] {
+            let mut dithered = gray.clone();
+            dither(&mut dithered, dithering, 1);
+            assert!(dithered
+                .pixels()
+                .all(|p| matches!(p.0[0], 0 | 0xFF) && p.0[3] == 0x80));
+            assert!((mean(&dithered) - 0x40 as f32).abs() < 16.0, "{:?}", dithering);
+        }
+
+        let mut unchanged = gray.clone();
+        dither(&mut unchanged, Dithering::FloydSteinberg, 8);
+        assert_eq!(unchanged, gray);
+        assert_eq!("bayer".parse(), Ok(Dithering::Bayer));
+        assert!("ordered".parse::<Dithering>().is_err());
+    }
+}
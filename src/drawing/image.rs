@@ -0,0 +1,158 @@
+//! Conversion of images into the requests which draw them
+
+use crate::drawing::{dither, Dithering};
+use crate::net::clients::BulkClient;
+use crate::net::protocol::{Request, MAX_BATCH_SIZE};
+use crate::pixmap::Color;
+use image::DynamicImage;
+use tokio::io::AsyncWrite;
+
+/// Options which control with which requests an image is drawn
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ImageOptions {
+    /// Whether pixels which are more than half transparent are left out so that the canvas shows through
+    pub skip_transparent: bool,
+    /// Whether horizontal runs of pixels with the same color are drawn with a single `RECT` request
+    ///
+    /// The server must support the `RECT` feature.
+    pub rects: bool,
+    /// Whether the remaining pixels are grouped into `PXB` batches instead of being sent as single `PX` requests
+    ///
+    /// The server must support the `PXB` feature.
+    pub batches: bool,
+    /// How colors are reduced to `bits_per_channel`
+    pub dithering: Dithering,
+    /// How many bits of every color channel are used, e.g. to match a server with a limited color depth
+    ///
+    /// 8 bits keep the colors as they are.
+    pub bits_per_channel: u8,
+}
+
+impl Default for ImageOptions {
+    fn default() -> Self {
+        Self {
+            skip_transparent: true,
+            rects: false,
+            batches: false,
+            dithering: Dithering::None,
+            bits_per_channel: 8,
+        }
+    }
+}
+
+/// Convert an image into the requests which draw it with its top-left corner placed at `(x, y)`
+///
+/// Only requests which every server understands are used unless `options` allow more efficient ones.
+pub fn image_requests(img: &DynamicImage, x: usize, y: usize, options: &ImageOptions) -> Vec<Request> {
+    let mut img = img.to_rgba8();
+    dither(&mut img, options.dithering, options.bits_per_channel);
+    let mut requests = Vec::new();
+    let mut pixels = Vec::new();
+    for (row, line) in img.rows().enumerate() {
+        let mut line = line
+            .enumerate()
+            .map(|(column, pixel)| {
+                let [r, g, b, a] = pixel.0;
+                let visible = !options.skip_transparent || a >= 128;
+                (column, visible.then(|| Color::from((r, g, b))))
+            })
+            .peekable();
+        while let Some((column, color)) = line.next() {
+            let Some(color) = color else {
+                continue;
+            };
+            let mut width = 1;
+            while options.rects && line.next_if(|(_, next)| *next == Some(color)).is_some() {
+                width += 1;
+            }
+            match width {
+                1 => pixels.push((x + column, y + row, color)),
+                width => requests.push(Request::FillRect {
+                    x: x + column,
+                    y: y + row,
+                    width,
+                    height: 1,
+                    color,
+                }),
+            }
+        }
+    }
+
+    match options.batches {
+        true => requests.extend(
+            pixels
+                .chunks(MAX_BATCH_SIZE)
+                .map(|batch| Request::SetPixelBatch(batch.to_vec())),
+        ),
+        false => requests.extend(
+            pixels
+                .into_iter()
+                .map(|(x, y, color)| Request::SetPixel { x, y, color }),
+        ),
+    }
+    requests
+}
+
+/// Draw an image with its top-left corner placed at `(x, y)` without waiting for any responses
+///
+/// The requests are only enqueued, so flush or finish the client afterwards.
+pub async fn draw_image<W: AsyncWrite + Unpin>(
+    client: &mut BulkClient<W>,
+    img: &DynamicImage,
+    (x, y): (usize, usize),
+    options: &ImageOptions,
+) -> std::io::Result<()> {
+    client.send_all(image_requests(img, x, y, options)).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+
+    #[test]
+    fn test_image_requests() {
+        let red = Rgba([0xFF, 0, 0, 0xFF]);
+        let clear = Rgba([0, 0, 0xFF, 0]);
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(4, 2, |x, y| match (x, y) {
+            (3, 0) => clear,
+            (_, 0) => red,
+            (x, _) => Rgba([x as u8, 0, 0, 0xFF]),
+        }));
+        let red = Color::from((0xFF, 0, 0));
+
+        let requests = image_requests(&img, 10, 20, &ImageOptions::default());
+        assert_eq!(requests.len(), 7);
+        assert_eq!(
+            requests[0],
+            Request::SetPixel {
+                x: 10,
+                y: 20,
+                color: red
+            }
+        );
+
+        let options = ImageOptions {
+            skip_transparent: false,
+            rects: true,
+            batches: true,
+            ..Default::default()
+        };
+        let requests = image_requests(&img, 10, 20, &options);
+        assert_eq!(
+            requests[0],
+            Request::FillRect {
+                x: 10,
+                y: 20,
+                width: 3,
+                height: 1,
+                color: red
+            }
+        );
+        let Request::SetPixelBatch(batch) = &requests[1] else {
+            panic!("remaining pixels should be batched");
+        };
+        assert_eq!(batch.len(), 5);
+        assert_eq!(batch[0], (13, 20, Color::from((0, 0, 0xFF))));
+    }
+}
@@ -0,0 +1,29 @@
+//!
+//! Client-side helpers for turning higher level graphics into pixelflut requests
+//!
+
+#[cfg(feature = "images")]
+mod animation;
+#[cfg(feature = "images")]
+mod canvas;
+#[cfg(feature = "images")]
+mod diff;
+#[cfg(feature = "images")]
+mod dither;
+#[cfg(feature = "images")]
+mod image;
+#[cfg(feature = "text")]
+mod text;
+
+#[cfg(feature = "images")]
+pub use animation::{Animation, Frame};
+#[cfg(feature = "images")]
+pub use canvas::{apply_state_frame, InvalidStateFrameError};
+#[cfg(feature = "images")]
+pub use diff::{block_regions, diff_requests};
+#[cfg(feature = "images")]
+pub use dither::{dither, Dithering, InvalidDitheringError};
+#[cfg(feature = "images")]
+pub use image::{draw_image, image_requests, ImageOptions};
+#[cfg(feature = "text")]
+pub use text::{draw_text, Outline, TextOptions, TextRenderer};
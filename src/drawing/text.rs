@@ -0,0 +1,200 @@
+//! Text rendering using TrueType/OpenType fonts
+
+use crate::net::clients::BulkClient;
+use crate::net::protocol::Request;
+use crate::pixmap::Color;
+use ab_glyph::{point, Font, FontArc, FontVec, GlyphId, InvalidFont, ScaleFont};
+use tokio::io::AsyncWrite;
+
+/// The monospace Hermit font which is bundled so that text can be rendered without a font file
+const BUNDLED_FONT: &[u8] = include_bytes!("../../resources/Hermit-Regular.otf");
+
+/// An outline which is drawn around rendered text
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Outline {
+    /// The color of the outline
+    pub color: Color,
+    /// How many pixels the outline extends outwards from the glyphs
+    pub width: usize,
+}
+
+/// Options which control how text is rendered
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TextOptions {
+    /// The font size in pixels, measured from the lowest descender to the highest ascender
+    pub size: f32,
+    /// The color with which glyphs are filled
+    pub color: Color,
+    /// An optional outline that is drawn around all glyphs
+    pub outline: Option<Outline>,
+}
+
+/// A renderer that rasterizes text into individual pixels using a specific font
+#[derive(Debug, Clone)]
+pub struct TextRenderer<F> {
+    font: F,
+}
+
+impl TextRenderer<FontVec> {
+    /// Create a renderer from the raw data of a TTF or OTF font file
+    pub fn from_font_data(data: Vec<u8>) -> Result<Self, InvalidFont> {
+        Ok(Self::new(FontVec::try_from_vec(data)?))
+    }
+}
+
+impl TextRenderer<FontArc> {
+    /// Create a renderer which uses the bundled monospace font
+    pub fn bundled() -> Self {
+        Self::new(FontArc::try_from_slice(BUNDLED_FONT).expect("the bundled font is valid"))
+    }
+}
+
+impl<F: Font> TextRenderer<F> {
+    /// Create a new renderer that renders text with the given font
+    pub fn new(font: F) -> Self {
+        Self { font }
+    }
+
+    /// Get the font which is used by this renderer
+    pub fn font(&self) -> &F {
+        &self.font
+    }
+
+    /// Calculate the size in pixels as `(width, height)` which the given text occupies when rendered
+    pub fn measure(&self, text: &str, options: &TextOptions) -> (usize, usize) {
+        let font = self.font.as_scaled(options.size);
+        let padding = options.outline.map(|o| o.width).unwrap_or(0);
+
+        let mut width = 0.0;
+        let mut last_glyph: Option<GlyphId> = None;
+        for c in text.chars() {
+            let glyph_id = font.glyph_id(c);
+            if let Some(last_glyph) = last_glyph {
+                width += font.kern(last_glyph, glyph_id);
+            }
+            width += font.h_advance(glyph_id);
+            last_glyph = Some(glyph_id);
+        }
+
+        (
+            width.ceil() as usize + 2 * padding,
+            font.height().ceil() as usize + 2 * padding,
+        )
+    }
+
+    /// Rasterize the given text into pixels
+    ///
+    /// The returned coordinates are relative to the top-left corner of the texts bounding box (see
+    /// [`measure()`](Self::measure)) and only contain pixels which are actually covered by glyphs or their outline.
+    /// Everything else is considered transparent.
+    pub fn rasterize(&self, text: &str, options: &TextOptions) -> Vec<(usize, usize, Color)> {
+        let font = self.font.as_scaled(options.size);
+        let padding = options.outline.map(|o| o.width).unwrap_or(0);
+        let (width, height) = self.measure(text, options);
+
+        // compute a coverage mask of all glyphs laid out on one line
+        let mut coverage = vec![false; width * height];
+        let mut caret = padding as f32;
+        let mut last_glyph: Option<GlyphId> = None;
+        for c in text.chars() {
+            let glyph_id = font.glyph_id(c);
+            if let Some(last_glyph) = last_glyph {
+                caret += font.kern(last_glyph, glyph_id);
+            }
+            let glyph =
+                glyph_id.with_scale_and_position(options.size, point(caret, padding as f32 + font.ascent()));
+            caret += font.h_advance(glyph_id);
+            last_glyph = Some(glyph_id);
+
+            let Some(outlined) = self.font.outline_glyph(glyph) else {
+                continue;
+            };
+            let bounds = outlined.px_bounds();
+            outlined.draw(|x, y, glyph_coverage| {
+                let x = bounds.min.x as i64 + x as i64;
+                let y = bounds.min.y as i64 + y as i64;
+                if glyph_coverage >= 0.5 && (0..width as i64).contains(&x) && (0..height as i64).contains(&y)
+                {
+                    coverage[y as usize * width + x as usize] = true;
+                }
+            });
+        }
+
+        // convert the mask into colored pixels, adding an outline around covered areas if requested
+        let mut pixels = Vec::new();
+        for y in 0..height {
+            for x in 0..width {
+                if coverage[y * width + x] {
+                    pixels.push((x, y, options.color));
+                } else if let Some(outline) = options.outline {
+                    let is_near_glyph =
+                        (y.saturating_sub(outline.width)..=(y + outline.width).min(height - 1)).any(|ny| {
+                            (x.saturating_sub(outline.width)..=(x + outline.width).min(width - 1))
+                                .any(|nx| coverage[ny * width + nx])
+                        });
+                    if is_near_glyph {
+                        pixels.push((x, y, outline.color));
+                    }
+                }
+            }
+        }
+
+        pixels
+    }
+
+    /// Render the given text so that its top-left corner is placed at `(x, y)` and return the required requests
+    pub fn render(&self, text: &str, x: usize, y: usize, options: &TextOptions) -> Vec<Request> {
+        self.rasterize(text, options)
+            .into_iter()
+            .map(|(px, py, color)| Request::SetPixel {
+                x: x + px,
+                y: y + py,
+                color,
+            })
+            .collect()
+    }
+}
+
+/// Draw text with its top-left corner placed at `(x, y)` without waiting for any responses
+///
+/// The requests are only enqueued, so flush or finish the client afterwards.
+pub async fn draw_text<F: Font, W: AsyncWrite + Unpin>(
+    client: &mut BulkClient<W>,
+    renderer: &TextRenderer<F>,
+    text: &str,
+    (x, y): (usize, usize),
+    options: &TextOptions,
+) -> std::io::Result<()> {
+    client.send_all(renderer.render(text, x, y, options)).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use itertools::Itertools;
+
+    #[test]
+    fn test_rasterize_with_outline() {
+        let renderer = TextRenderer::bundled();
+        let fill = Color::from(0xFFFFFF);
+        let outline = Color::from(0x000000);
+        let options = TextOptions {
+            size: 24.0,
+            color: fill,
+            outline: Some(Outline {
+                color: outline,
+                width: 2,
+            }),
+        };
+
+        let (width, height) = renderer.measure("Hi", &options);
+        let pixels = renderer.rasterize("Hi", &options);
+        assert!(pixels.iter().any(|(_, _, c)| *c == fill));
+        assert!(pixels.iter().any(|(_, _, c)| *c == outline));
+        assert!(pixels.iter().all(|(x, y, _)| *x < width && *y < height));
+        assert_eq!(
+            pixels.iter().map(|(x, y, _)| (x, y)).unique().count(),
+            pixels.len()
+        );
+    }
+}
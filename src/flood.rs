@@ -0,0 +1,219 @@
+//! A client which floods a server with pixels to measure its throughput end-to-end
+
+use crate::cli::{FloodOpts, FloodPattern};
+use crate::main_utils::DynClient;
+use bytes::{BufMut, BytesMut};
+use itertools::Itertools;
+use pixeldike::net::protocol::Request;
+use pixeldike::pixmap::Color;
+use rand::prelude::*;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::task::JoinSet;
+use tokio::time::{interval, Instant};
+use url::Url;
+
+/// How many pixels are encoded into the buffer of every connection for the random pattern
+const RANDOM_PIXELS: usize = 64 * 1024;
+
+/// What all connections sent and received so far
+#[derive(Debug, Default)]
+struct FloodCounters {
+    pixels: AtomicU64,
+    bytes: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl FloodCounters {
+    /// Get the current `(pixels, bytes, errors)`
+    fn snapshot(&self) -> (u64, u64, u64) {
+        (
+            self.pixels.load(Ordering::Relaxed),
+            self.bytes.load(Ordering::Relaxed),
+            self.errors.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Flood the server with pixels over several connections until the duration elapsed or the process is interrupted
+/// while periodically reporting the throughput
+pub(crate) async fn flood(opts: &FloodOpts) {
+    let (width, height) = DynClient::connect(&opts.server)
+        .await
+        .expect("Could not connect to pixelflut server")
+        .get_size()
+        .await;
+
+    tracing::info!("Preparing command buffers for {} connections", opts.connections);
+    let counters = Arc::new(FloodCounters::default());
+    let mut connections = JoinSet::new();
+    for i in 0..opts.connections {
+        let (buf, pixels) = fill_buf(opts.pattern, width, height, i, opts.connections);
+        let url = opts.server.clone();
+        let counters = counters.clone();
+        connections.spawn(async move { run_connection(&url, &buf, pixels, &counters).await });
+    }
+
+    tracing::info!("Flooding server with {} connections", opts.connections);
+    let start = Instant::now();
+    let deadline = async {
+        match opts.duration_secs {
+            Some(secs) => tokio::time::sleep(Duration::from_secs(secs)).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(deadline);
+    let mut report = interval(Duration::from_secs(opts.report_interval_secs.max(1)));
+    report.tick().await;
+    let mut last = (start, counters.snapshot());
+    loop {
+        tokio::select! {
+            now = report.tick() => {
+                let current = counters.snapshot();
+                report_throughput(now - last.0, last.1, current);
+                last = (now, current);
+            }
+            Some(result) = connections.join_next() => {
+                if let Err(e) = result.expect("Connection task panicked") {
+                    tracing::warn!("Connection to pixelflut server failed: {}", e);
+                }
+                if connections.is_empty() {
+                    tracing::error!("All connections to the pixelflut server failed");
+                    break;
+                }
+            }
+            _ = &mut deadline => break,
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+    connections.abort_all();
+
+    let (pixels, bytes, errors) = counters.snapshot();
+    tracing::info!(
+        "Sent {} pixels ({:.1} MiB) in {:.1}s with {} errors",
+        pixels,
+        bytes as f64 / (1024.0 * 1024.0),
+        start.elapsed().as_secs_f64(),
+        errors
+    );
+    report_throughput(start.elapsed(), (0, 0, 0), (pixels, bytes, errors));
+}
+
+/// Log the throughput between two snapshots of the counters which were taken `elapsed` apart
+fn report_throughput(elapsed: Duration, (pixels, bytes, errors): (u64, u64, u64), current: (u64, u64, u64)) {
+    let secs = elapsed.as_secs_f64().max(f64::EPSILON);
+    tracing::info!(
+        "{:.2} Mpixels/s, {:.1} MiB/s, {} errors",
+        (current.0 - pixels) as f64 / secs / 1_000_000.0,
+        (current.1 - bytes) as f64 / secs / (1024.0 * 1024.0),
+        current.2 - errors
+    );
+}
+
+/// Encode the pixels which connection `i` of `n` draws onto a canvas of the given size
+///
+/// Returns the buffer together with the number of pixels in it.
+fn fill_buf(pattern: FloodPattern, width: usize, height: usize, i: usize, n: usize) -> (Vec<u8>, u64) {
+    let mut rng = thread_rng();
+    let pixels: Vec<Request> = match pattern {
+        FloodPattern::Random => (0..RANDOM_PIXELS)
+            .map(|_| Request::SetPixel {
+                x: rng.gen_range(0..width),
+                y: rng.gen_range(0..height),
+                color: Color::from((rng.gen(), rng.gen(), rng.gen())),
+            })
+            .collect(),
+        FloodPattern::Gradient => (0..height)
+            .cartesian_product(0..width)
+            .map(|(y, x)| Request::SetPixel {
+                x,
+                y,
+                color: Color::from((
+                    (x * 255 / width) as u8,
+                    (y * 255 / height) as u8,
+                    (i * 255 / n) as u8,
+                )),
+            })
+            .collect(),
+        FloodPattern::Solid => {
+            let color = Color::from((rng.gen(), rng.gen(), rng.gen()));
+            (0..height)
+                .cartesian_product(0..width)
+                .map(|(y, x)| Request::SetPixel { x, y, color })
+                .collect()
+        }
+    };
+
+    let mut buf = BytesMut::new().writer();
+    for request in &pixels {
+        request.write(&mut buf).unwrap();
+    }
+    (buf.into_inner().to_vec(), pixels.len() as u64)
+}
+
+/// Connect to the server and send the buffer over and over again until the connection fails
+async fn run_connection(url: &Url, buf: &[u8], pixels: u64, counters: &FloodCounters) -> std::io::Result<()> {
+    match url.scheme() {
+        "tcp" => {
+            let addr = url.socket_addrs(|| Some(1234))?[0];
+            let (reader, writer) = TcpStream::connect(addr).await?.into_split();
+            flood_stream(reader, writer, buf, pixels, counters).await
+        }
+        "unix" => {
+            let (reader, writer) = UnixStream::connect(url.path()).await?.into_split();
+            flood_stream(reader, writer, buf, pixels, counters).await
+        }
+        // datagram based servers don't send errors so there is nothing to read
+        _ => {
+            let mut client = DynClient::connect(url).await?;
+            loop {
+                client.send_bulk(buf).await;
+                counters.pixels.fetch_add(pixels, Ordering::Relaxed);
+                counters.bytes.fetch_add(buf.len() as u64, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Send the buffer over a stream over and over again while counting the errors which the server sends back
+async fn flood_stream<R, W>(
+    reader: R,
+    mut writer: W,
+    buf: &[u8],
+    pixels: u64,
+    counters: &FloodCounters,
+) -> std::io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let send = async {
+        loop {
+            writer.write_all(buf).await?;
+            counters.pixels.fetch_add(pixels, Ordering::Relaxed);
+            counters.bytes.fetch_add(buf.len() as u64, Ordering::Relaxed);
+        }
+    };
+    tokio::select! {
+        result = send => result,
+        result = count_errors(reader, counters) => result,
+    }
+}
+
+/// Read all responses of the server and count the errors among them until it closes the connection
+async fn count_errors<R: AsyncRead + Unpin>(reader: R, counters: &FloodCounters) -> std::io::Result<()> {
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line).await? == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        if line.starts_with(b"ERR ") && counters.errors.fetch_add(1, Ordering::Relaxed) == 0 {
+            tracing::debug!("Server sent error: {}", String::from_utf8_lossy(&line).trim_end());
+        }
+    }
+}
@@ -28,10 +28,14 @@ extern crate quickcheck;
 #[cfg(test)]
 extern crate test;
 
+pub mod drawing;
 pub mod net;
 pub mod pixmap;
+pub mod shutdown;
 pub mod sinks;
 mod texts;
 
+pub use texts::{set_message_templates, MessageTemplates};
+
 /// The result type which all background tasks return
 pub type DaemonResult = anyhow::Result<!>;
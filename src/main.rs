@@ -1,30 +1,49 @@
 #![feature(never_type)]
 
-use ab_glyph::{Font, FontRef};
+use ab_glyph::FontArc;
 use bytes::buf::Writer;
 use bytes::{BufMut, BytesMut};
 use clap::Parser;
 use image::imageops::FilterType;
 use rand::prelude::*;
-use std::net::ToSocketAddrs;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
 use std::path::PathBuf;
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
 use std::time::Duration;
 use tokio::io::AsyncWriteExt;
-use tokio::task::{JoinSet, LocalSet};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task::{AbortHandle, JoinSet, LocalSet};
 use tokio::time::interval;
 use tracing::metadata::LevelFilter;
 use tracing_subscriber::filter;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{reload, Registry};
 
 use crate::cli::{CliOpts, TargetColor, TargetDimension};
+use anyhow::anyhow;
 use image::io::Reader as ImageReader;
 use itertools::Itertools;
+use pixeldike::drawing::{dither, Animation, Outline, TextOptions, TextRenderer};
 use pixeldike::net::clients::{TcpClient, UdpClient, UnixSocketClient};
-use pixeldike::net::protocol::{Request, Response};
-use pixeldike::net::servers::{GenServer, TcpServer, TcpServerOptions, UnixSocketOptions, UnixSocketServer};
+use pixeldike::net::protocol::{Features, Request, Response, MAX_STREAM_FPS};
+#[cfg(feature = "tls")]
+use pixeldike::net::servers::TlsOptions;
+use pixeldike::net::servers::{
+    close_unused_sockets, reload_requests, take_handed_over_canvas, upgrade_requests, AccessControlOptions,
+    AdminEndpoint, AdminServer, AdminServerOptions, BufferOptions, GenServer, RateLimitOptions, Reloadable,
+    SocketOptions, StormProtectionOptions, Successor, TcpServer, TcpServerOptions, UnixSocketOptions,
+    UnixSocketServer, WriteProtectionOptions,
+};
+#[cfg(feature = "dtls")]
+use pixeldike::net::servers::{DtlsServer, DtlsServerOptions};
+#[cfg(feature = "grpc")]
+use pixeldike::net::servers::{GrpcServer, GrpcServerOptions};
+#[cfg(feature = "mqtt")]
+use pixeldike::net::servers::{MqttServer, MqttServerOptions};
+#[cfg(feature = "quic")]
+use pixeldike::net::servers::{QuicServer, QuicServerOptions};
 #[cfg(feature = "udp")]
 use pixeldike::net::servers::{UdpServer, UdpServerOptions};
 #[cfg(feature = "ws")]
@@ -32,15 +51,16 @@ use pixeldike::net::servers::{WsServer, WsServerOptions};
 use pixeldike::pixmap::{Color, Pixmap};
 use pixeldike::sinks::ffmpeg::{FfmpegOptions, FfmpegSink};
 use pixeldike::sinks::framebuffer::{FramebufferSink, FramebufferSinkOptions};
+use pixeldike::sinks::multicast::{MulticastSink, MulticastSinkOptions};
 use pixeldike::sinks::pixmap_file::{FileSink, FileSinkOptions};
-use pixeldike::DaemonResult;
+use pixeldike::{shutdown, DaemonResult, MessageTemplates};
 use url::Url;
 
 mod cli;
+mod config;
+mod flood;
 mod main_utils;
 
-const FONT_HERMIT_REGULAR: &[u8] = include_bytes!("../resources/Hermit-Regular.otf");
-
 #[tokio::main]
 async fn main() {
     let args = cli::CliOpts::parse();
@@ -55,6 +75,9 @@ async fn main() {
                 cli::Command::PutRectangle(opts) => put_rectangle(opts).await,
                 cli::Command::PutImage(opts) => put_image(opts).await,
                 cli::Command::PutText(opts) => put_text(opts).await,
+                cli::Command::PutAnimation(opts) => put_animation(opts).await,
+                cli::Command::Flood(opts) => flood::flood(opts).await,
+                cli::Command::CheckConfig(opts) => check_config(opts),
             };
         })
         .await;
@@ -76,22 +99,89 @@ fn init_logger(args: &CliOpts) {
         _ => LevelFilter::TRACE,
     };
 
-    // configure appropriate level filter
-    // tokio is very spammy on higher log levels which is usually not interesting so we filter it out
-    let filter = filter::Targets::new()
-        .with_default(log_level)
-        .with_target("tokio", Ord::min(LevelFilter::WARN, log_level))
-        .with_target("runtime", Ord::min(LevelFilter::WARN, log_level));
+    // configure appropriate level filter which can be replaced when the server reloads its configuration
+    let (filter, handle) = reload::Layer::new(log_filter(log_level));
     tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer())
         .with(filter)
+        .with(tracing_subscriber::fmt::layer())
         .init();
+    LOG_FILTER
+        .set((handle, log_level))
+        .expect("The logger is only initialized once");
+}
+
+/// The filter of the logger and the level which was given on the commandline
+static LOG_FILTER: OnceLock<(reload::Handle<filter::Targets, Registry>, LevelFilter)> = OnceLock::new();
+
+/// The filter of the logger which shows messages of `log_level`
+fn log_filter(log_level: LevelFilter) -> filter::Targets {
+    // tokio is very spammy on higher log levels which is usually not interesting so we filter it out
+    filter::Targets::new()
+        .with_default(log_level)
+        .with_target("tokio", Ord::min(LevelFilter::WARN, log_level))
+        .with_target("runtime", Ord::min(LevelFilter::WARN, log_level))
+}
+
+/// Show log messages of `log_level` from now on, or of the level given on the commandline if it is `None`
+fn set_log_level(log_level: Option<LevelFilter>) {
+    let Some((handle, default_level)) = LOG_FILTER.get() else {
+        return;
+    };
+    if let Err(e) = handle.reload(log_filter(log_level.unwrap_or(*default_level))) {
+        tracing::error!("Could not change the log level: {}", e);
+    }
+}
+
+fn check_config(opts: &cli::CheckConfigOpts) {
+    let server_opts = match config::load_server_opts(&opts.path) {
+        Ok(server_opts) => server_opts,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let problems = config::validate_server_opts(&server_opts);
+    if problems.is_empty() {
+        println!("{} is valid", opts.path.display());
+    } else {
+        eprintln!("{} has {} problem(s):", opts.path.display(), problems.len());
+        for problem in problems {
+            eprintln!("  - {}", problem);
+        }
+        std::process::exit(1);
+    }
 }
 
 async fn start_server(opts: &cli::ServerOpts) {
-    // create a pixmap or load an existing snapshot
-    let pixmap = match &opts.file_opts.load_snapshot {
-        None => Arc::new(Pixmap::new(opts.width, opts.height).unwrap()),
+    // replace commandline options with the ones from a config file
+    let loaded_opts;
+    let opts = match &opts.config {
+        None => opts,
+        Some(path) => {
+            loaded_opts = config::load_server_opts(path).unwrap_or_else(|e| {
+                eprintln!("{:#}", e);
+                std::process::exit(1);
+            });
+            for problem in config::validate_server_opts(&loaded_opts) {
+                tracing::warn!("{}: {}", path.display(), problem);
+            }
+            &loaded_opts
+        }
+    };
+    if opts.log_level.is_some() {
+        set_log_level(opts.log_level);
+    }
+
+    // create a pixmap or load an existing snapshot, preferring the canvas which a previous server process handed over
+    let transform = opts.transform_opts.to_transform();
+    let handed_over_canvas = take_handed_over_canvas();
+    let snapshot = match &handed_over_canvas {
+        Some(canvas) => Some(PathBuf::from(format!("/proc/self/fd/{}", canvas.as_raw_fd()))),
+        None => opts.file_opts.load_snapshot.clone(),
+    };
+    let pixmap = match &snapshot {
+        None => Arc::new(Pixmap::new_transformed(opts.width, opts.height, transform).unwrap()),
         Some(path) => {
             let loaded_pixmap = pixeldike::sinks::pixmap_file::load_pixmap_file(path).await;
             match loaded_pixmap {
@@ -101,7 +191,7 @@ async fn start_server(opts: &cli::ServerOpts) {
                         path.display(),
                         e
                     );
-                    Arc::new(Pixmap::new(opts.width, opts.height).unwrap())
+                    Arc::new(Pixmap::new_transformed(opts.width, opts.height, transform).unwrap())
                 }
                 Ok(loaded_pixmap) => {
                     let (width, height) = loaded_pixmap.get_size();
@@ -111,17 +201,24 @@ async fn start_server(opts: &cli::ServerOpts) {
                     opts.width,
                     opts.height
                 );
-                        Arc::new(Pixmap::new(opts.width, opts.height).unwrap())
+                        Arc::new(Pixmap::new_transformed(opts.width, opts.height, transform).unwrap())
                     } else {
-                        Arc::new(loaded_pixmap)
+                        Arc::new(loaded_pixmap.with_transform(transform))
                     }
                 }
             }
         }
     };
+    drop(handed_over_canvas);
 
     let mut join_set: JoinSet<DaemonResult> = JoinSet::new();
 
+    // load customized messages
+    if let Some(dir) = &opts.message_templates {
+        let templates = MessageTemplates::load(dir).expect("Could not load message templates");
+        pixeldike::set_message_templates(templates);
+    }
+
     // configure snapshotting
     if let Some(path) = &opts.file_opts.snapshot_file {
         let pixmap = pixmap.clone();
@@ -141,7 +238,7 @@ async fn start_server(opts: &cli::ServerOpts) {
     #[cfg(feature = "windowing")]
     if opts.open_window {
         let pixmap = pixmap.clone();
-        pixeldike::sinks::window::start(&mut join_set, pixmap)
+        pixeldike::sinks::window::start(&mut join_set, pixmap, opts.window_transform)
             .expect("Could not open window for live rendering");
     }
 
@@ -169,6 +266,7 @@ async fn start_server(opts: &cli::ServerOpts) {
                 framerate: opts.stream_opts.framerate,
                 synthesize_audio: true,
                 log_level: "warning".to_string(),
+                transform: opts.stream_opts.transform,
                 output_spec,
             },
             pixmap,
@@ -186,6 +284,7 @@ async fn start_server(opts: &cli::ServerOpts) {
             FramebufferSinkOptions {
                 path: fb_device.to_owned(),
                 framerate: opts.fb_opts.fb_framerate,
+                transform: opts.fb_opts.fb_transform,
             },
             pixmap,
         );
@@ -194,8 +293,63 @@ async fn start_server(opts: &cli::ServerOpts) {
             .expect("Coult not start task for framebuffer rendering");
     }
 
+    // configure announcements on the local network
+    if let Some(group) = opts.announce_opts.announce_group {
+        // only listeners which can be reached over the network are worth announcing
+        let listeners = opts
+            .listen
+            .iter()
+            .filter(|url| !matches!(url.scheme(), "unix" | "mqtt"))
+            .map(|url| url[..url::Position::AfterPort].to_string())
+            .collect();
+        let sink = MulticastSink::new(
+            MulticastSinkOptions {
+                group,
+                interval: Duration::from_secs(opts.announce_opts.announce_interval_secs),
+                listeners,
+                state_interval: opts
+                    .announce_opts
+                    .announce_state_interval_secs
+                    .map(Duration::from_secs),
+                ttl: opts.announce_opts.announce_ttl,
+            },
+            pixmap.clone(),
+        );
+        sink.start(&mut join_set)
+            .await
+            .expect("Could not start task for multicast announcements");
+    }
+
     // configure and start all servers
+    let mut listeners = Vec::new();
+    let storm_protection = opts
+        .max_connects_per_sec
+        .map(|max_connects_per_sec| StormProtectionOptions {
+            max_connects_per_sec,
+            burst: opts.connect_burst,
+            exemptions: opts.storm_exempt.clone(),
+            ..Default::default()
+        });
+    let write_protection = (!opts.write_tokens.is_empty()).then(|| WriteProtectionOptions {
+        tokens: opts.write_tokens.clone(),
+    });
+    let buffers = BufferOptions {
+        read_buffer_size: opts.read_buffer_size,
+        write_buffer_size: opts.write_buffer_size,
+        max_line_len: opts.max_line_len,
+    };
+    let mut reloadables = Vec::new();
     for url in &opts.listen {
+        let access_control = Reloadable::new(
+            access_control_for(url).expect("Could not parse the access control of the listener url"),
+        );
+        let rate_limit =
+            Reloadable::new(rate_limit_for(url).expect("Could not parse the rate limit of the listener url"));
+        reloadables.push(config::ListenerReloadables {
+            url: url.clone(),
+            access_control: access_control.clone(),
+            rate_limit: rate_limit.clone(),
+        });
         match url.scheme() {
             #[cfg(feature = "tcp")]
             "tcp" => {
@@ -211,22 +365,136 @@ async fn start_server(opts: &cli::ServerOpts) {
                         url
                     );
                 }
-                for bind_addr in (url.host_str().unwrap(), url.port().unwrap_or(1234))
-                    .to_socket_addrs()
+                let acceptors =
+                    acceptors_for(url).expect("Could not parse the number of acceptors of the listener url");
+                for bind_addr in url
+                    .socket_addrs(|| Some(1234))
                     .expect("Could not resolve socket addr from listener url")
                 {
-                    TcpServer::new(TcpServerOptions { bind_addr })
-                        .start(pixmap.clone(), &mut join_set)
-                        .await
-                        .expect(&format!("Could not start tcp server on {}", url));
+                    let server = TcpServer::new(TcpServerOptions {
+                        bind_addr,
+                        socket: socket_options_for(url)
+                            .expect("Could not parse the socket options of the listener url"),
+                        access_control: access_control.clone(),
+                        storm_protection: storm_protection_for(url, &storm_protection),
+                        rate_limit: rate_limit.clone(),
+                        max_connections_per_ip: max_connections_per_ip_for(url, opts.max_connections_per_ip)
+                            .expect("Could not parse the connection limit of the listener url"),
+                        proxy_protocol: proxy_protocol_for(url),
+                        idle_timeout: idle_timeout_for(url, opts.idle_timeout_secs)
+                            .expect("Could not parse the idle timeout of the listener url"),
+                        buffers,
+                        write_protection: write_protection.clone(),
+                        claims: opts.claims,
+                        features: features_for(url)
+                            .expect("Could not parse the features of the listener url"),
+                        #[cfg(feature = "io-uring")]
+                        io_uring_workers: io_uring_workers_for(url)
+                            .expect("Could not parse the number of io_uring workers of the listener url"),
+                        #[cfg(feature = "tls")]
+                        tls: None,
+                    });
+                    let handles = match acceptors {
+                        1 => server
+                            .start(pixmap.clone(), &mut join_set)
+                            .await
+                            .map(|handle| vec![handle]),
+                        n => server.start_many(pixmap.clone(), n, &mut join_set).await,
+                    }
+                    .expect(&format!("Could not start tcp server on {}", url));
+                    listeners.extend(handles);
                 }
             }
-            "unix" => {
-                let path = PathBuf::from_str(url.path()).expect("Could not turn url path into system path");
-                UnixSocketServer::new(UnixSocketOptions { path })
+            #[cfg(feature = "tls")]
+            "tls" => {
+                let tls = TlsOptions {
+                    cert_path: opts.tls_cert.clone().expect("tls listeners require --tls-cert"),
+                    key_path: opts.tls_key.clone().expect("tls listeners require --tls-key"),
+                };
+                let acceptors =
+                    acceptors_for(url).expect("Could not parse the number of acceptors of the listener url");
+                for bind_addr in url
+                    .socket_addrs(|| Some(1237))
+                    .expect("Could not resolve socket addr from listener url")
+                {
+                    let server = TcpServer::new(TcpServerOptions {
+                        bind_addr,
+                        socket: socket_options_for(url)
+                            .expect("Could not parse the socket options of the listener url"),
+                        access_control: access_control.clone(),
+                        storm_protection: storm_protection_for(url, &storm_protection),
+                        rate_limit: rate_limit.clone(),
+                        max_connections_per_ip: max_connections_per_ip_for(url, opts.max_connections_per_ip)
+                            .expect("Could not parse the connection limit of the listener url"),
+                        proxy_protocol: proxy_protocol_for(url),
+                        idle_timeout: idle_timeout_for(url, opts.idle_timeout_secs)
+                            .expect("Could not parse the idle timeout of the listener url"),
+                        buffers,
+                        write_protection: write_protection.clone(),
+                        claims: opts.claims,
+                        features: features_for(url)
+                            .expect("Could not parse the features of the listener url"),
+                        #[cfg(feature = "io-uring")]
+                        io_uring_workers: None,
+                        tls: Some(tls.clone()),
+                    });
+                    let handles = match acceptors {
+                        1 => server
+                            .start(pixmap.clone(), &mut join_set)
+                            .await
+                            .map(|handle| vec![handle]),
+                        n => server.start_many(pixmap.clone(), n, &mut join_set).await,
+                    }
+                    .expect(&format!("Could not start tls server on {}", url));
+                    listeners.extend(handles);
+                }
+            }
+            #[cfg(feature = "quic")]
+            "quic" => {
+                let tls = TlsOptions {
+                    cert_path: opts.tls_cert.clone().expect("quic listeners require --tls-cert"),
+                    key_path: opts.tls_key.clone().expect("quic listeners require --tls-key"),
+                };
+                for bind_addr in url
+                    .socket_addrs(|| Some(1237))
+                    .expect("Could not resolve socket addr from listener url")
+                {
+                    let handle = QuicServer::new(QuicServerOptions {
+                        bind_addr,
+                        socket: socket_options_for(url)
+                            .expect("Could not parse the socket options of the listener url"),
+                        access_control: access_control.clone(),
+                        tls: tls.clone(),
+                        storm_protection: storm_protection_for(url, &storm_protection),
+                        rate_limit: rate_limit.clone(),
+                        buffers,
+                        write_protection: write_protection.clone(),
+                        claims: opts.claims,
+                        features: features_for(url)
+                            .expect("Could not parse the features of the listener url"),
+                    })
                     .start(pixmap.clone(), &mut join_set)
                     .await
-                    .expect(&format!("Could not start unix socket listener on {}", url));
+                    .expect(&format!("Could not start quic server on {}", url));
+                    listeners.push(handle);
+                }
+            }
+            "unix" => {
+                let path = PathBuf::from_str(url.path()).expect("Could not turn url path into system path");
+                let handle = UnixSocketServer::new(UnixSocketOptions {
+                    path,
+                    permissions: socket_mode_for(url).expect("Could not parse the mode of the listener url"),
+                    idle_timeout: idle_timeout_for(url, opts.idle_timeout_secs)
+                        .expect("Could not parse the idle timeout of the listener url"),
+                    buffers,
+                    write_protection: write_protection.clone(),
+                    claims: opts.claims,
+                    features: features_for(url).expect("Could not parse the features of the listener url"),
+                })
+                .start(pixmap.clone(), &mut join_set)
+                .await
+                .expect(&format!("Could not start unix socket listener on {}", url));
+                listeners.push(handle);
             }
             #[cfg(feature = "udp")]
             "udp" => {
@@ -243,14 +511,52 @@ async fn start_server(opts: &cli::ServerOpts) {
                         url
                     );
                 }
-                for bind_addr in (url.host_str().unwrap(), url.port().unwrap_or(1234))
-                    .to_socket_addrs()
+                for bind_addr in url
+                    .socket_addrs(|| Some(1234))
+                    .expect("Could not resolve socket addr from listener url")
+                {
+                    let handle = UdpServer::new(UdpServerOptions {
+                        bind_addr,
+                        socket: socket_options_for(url)
+                            .expect("Could not parse the socket options of the listener url"),
+                        access_control: access_control.clone(),
+                        write_protection: write_protection.clone(),
+                        claims: opts.claims,
+                        features: features_for(url)
+                            .expect("Could not parse the features of the listener url"),
+                        batch_responses: !url
+                            .query_pairs()
+                            .any(|(key, value)| key == "batch-responses" && value == "off"),
+                    })
+                    .start(pixmap.clone(), &mut join_set)
+                    .await
+                    .expect(&format!("Could not start tcp server on {}", url));
+                    listeners.push(handle);
+                }
+            }
+            #[cfg(feature = "dtls")]
+            "dtls" => {
+                for bind_addr in url
+                    .socket_addrs(|| Some(1238))
                     .expect("Could not resolve socket addr from listener url")
                 {
-                    UdpServer::new(UdpServerOptions { bind_addr })
-                        .start(pixmap.clone(), &mut join_set)
-                        .await
-                        .expect(&format!("Could not start tcp server on {}", url));
+                    let handle = DtlsServer::new(DtlsServerOptions {
+                        bind_addr,
+                        socket: socket_options_for(url)
+                            .expect("Could not parse the socket options of the listener url"),
+                        access_control: access_control.clone(),
+                        cert_path: opts.tls_cert.clone().expect("dtls listeners require --tls-cert"),
+                        key_path: opts.tls_key.clone().expect("dtls listeners require --tls-key"),
+                        client_ca_path: client_ca_for(url),
+                        write_protection: write_protection.clone(),
+                        claims: opts.claims,
+                        features: features_for(url)
+                            .expect("Could not parse the features of the listener url"),
+                    })
+                    .start(pixmap.clone(), &mut join_set)
+                    .await
+                    .expect(&format!("Could not start dtls server on {}", url));
+                    listeners.push(handle);
                 }
             }
             #[cfg(feature = "ws")]
@@ -269,35 +575,566 @@ async fn start_server(opts: &cli::ServerOpts) {
                     );
                 }
 
-                for bind_addr in (url.host_str().unwrap(), url.port().unwrap_or(1235))
-                    .to_socket_addrs()
+                for bind_addr in url
+                    .socket_addrs(|| Some(1235))
+                    .expect("Could not resolve socket addr from listener url")
+                {
+                    let handle = WsServer::new(WsServerOptions {
+                        bind_addr,
+                        socket: socket_options_for(url)
+                            .expect("Could not parse the socket options of the listener url"),
+                        access_control: access_control.clone(),
+                        storm_protection: storm_protection_for(url, &storm_protection),
+                        rate_limit: rate_limit.clone(),
+                        max_connections_per_ip: max_connections_per_ip_for(url, opts.max_connections_per_ip)
+                            .expect("Could not parse the connection limit of the listener url"),
+                        proxy_protocol: proxy_protocol_for(url),
+                        idle_timeout: idle_timeout_for(url, opts.idle_timeout_secs)
+                            .expect("Could not parse the idle timeout of the listener url"),
+                        write_protection: write_protection.clone(),
+                        claims: opts.claims,
+                        features: features_for(url)
+                            .expect("Could not parse the features of the listener url"),
+                        deflate_threshold: deflate_threshold_for(url)
+                            .expect("Could not parse the deflate threshold of the listener url"),
+                        ping_interval: ping_interval_for(url)
+                            .expect("Could not parse the ping interval of the listener url"),
+                    })
+                    .start(pixmap.clone(), &mut join_set)
+                    .await
+                    .expect(&format!("Could not start tcp server on {}", url));
+                    listeners.push(handle);
+                }
+            }
+            #[cfg(feature = "grpc")]
+            "grpc" => {
+                if !url.username().is_empty() {
+                    tracing::warn!(
+                        "{} listen directive specifies credentials which is not supported by the gRPC server",
+                        url
+                    )
+                }
+                if !url.path().is_empty() && url.path() != "/" {
+                    tracing::warn!(
+                        "{} listen directive specifies a path which is not supported by the gRPC server",
+                        url
+                    );
+                }
+                for bind_addr in url
+                    .socket_addrs(|| Some(1236))
                     .expect("Could not resolve socket addr from listener url")
                 {
-                    WsServer::new(WsServerOptions { bind_addr })
-                        .start(pixmap.clone(), &mut join_set)
-                        .await
-                        .expect(&format!("Could not start tcp server on {}", url));
+                    let handle = GrpcServer::new(GrpcServerOptions {
+                        bind_addr,
+                        write_protection: write_protection.clone(),
+                        claims: opts.claims,
+                    })
+                    .start(pixmap.clone(), &mut join_set)
+                    .await
+                    .expect(&format!("Could not start grpc server on {}", url));
+                    listeners.push(handle);
                 }
             }
+            #[cfg(feature = "mqtt")]
+            "mqtt" => {
+                let (updates_fps, state_interval) =
+                    mqtt_publishing_for(url).expect("Could not parse what the mqtt listener url publishes");
+                let credentials = (!url.username().is_empty()).then(|| {
+                    (
+                        url.username().to_string(),
+                        url.password().unwrap_or_default().to_string(),
+                    )
+                });
+                let topic_prefix = match url.path().trim_matches('/') {
+                    "" => "pixelflut",
+                    prefix => prefix,
+                };
+                let handle = MqttServer::new(MqttServerOptions {
+                    broker_host: match url.host() {
+                        Some(url::Host::Ipv6(addr)) => addr.to_string(),
+                        _ => url.host_str().unwrap().to_string(),
+                    },
+                    broker_port: url.port().unwrap_or(1883),
+                    credentials,
+                    topic_prefix: topic_prefix.to_string(),
+                    updates_fps,
+                    state_interval,
+                    write_protection: write_protection.clone(),
+                    claims: opts.claims,
+                    features: features_for(url).expect("Could not parse the features of the listener url"),
+                })
+                .start(pixmap.clone(), &mut join_set)
+                .await
+                .expect(&format!("Could not start mqtt bridge to {}", url));
+                listeners.push(handle);
+            }
             proto => {
                 panic!("Unsupported server protocol {}", proto);
             }
         }
     }
 
-    // wait until one tasks exits
-    let result = join_set
-        .join_next()
-        .await
-        .expect("Nothing is supposed to be started which makes no sense. Review commandline flags.")
-        .expect("Could not join background task")
-        .unwrap_err();
-    tracing::error!("A background task exited unexpectedly: {}", result);
+    // start the admin interface
+    if let Some(url) = &opts.admin {
+        for endpoint in admin_endpoints_for(url).expect("Could not parse the admin url") {
+            let handle = AdminServer::new(AdminServerOptions { endpoint })
+                .start(pixmap.clone(), &mut join_set)
+                .await
+                .expect(&format!("Could not start admin interface on {}", url));
+            listeners.push(handle);
+        }
+    }
+
+    // sockets which the service manager passed for other addresses would never be served
+    close_unused_sockets();
+
+    // reload the configuration and message templates on SIGHUP or when the admin interface requests it
+    if opts.config.is_some() || opts.message_templates.is_some() {
+        let mut config = opts.config.clone().map(|path| (path, opts.clone()));
+        let templates = opts.message_templates.clone();
+        join_set
+            .build_task()
+            .name("reload")
+            .spawn(async move {
+                let mut sighup = signal(SignalKind::hangup())?;
+                let mut requests = reload_requests();
+                loop {
+                    tokio::select! {
+                        _ = sighup.recv() => {}
+                        _ = requests.changed() => {}
+                    }
+                    if let Some((path, current)) = &mut config {
+                        match config::reload_server_opts(path, current, &reloadables) {
+                            Ok(reloaded) => {
+                                tracing::info!("Reloaded configuration from {}", path.display());
+                                *current = reloaded;
+                            }
+                            Err(e) => tracing::error!(
+                                "Could not reload configuration from {}, keeping the previous one: {:#}",
+                                path.display(),
+                                e
+                            ),
+                        }
+                    }
+                    if let Some(dir) = &templates {
+                        match MessageTemplates::load(dir) {
+                            Ok(templates) => {
+                                tracing::info!("Reloaded message templates from {}", dir.display());
+                                pixeldike::set_message_templates(templates);
+                            }
+                            Err(e) => tracing::error!(
+                                "Could not reload message templates from {}, keeping the previous ones: {}",
+                                dir.display(),
+                                e
+                            ),
+                        }
+                    }
+                }
+            })
+            .expect("Could not start task for reloading the configuration");
+    }
+
+    // wait until one tasks exits, a shutdown is requested or a handover to a new process is requested via SIGUSR2 or
+    // the admin interface
+    let mut sigterm = signal(SignalKind::terminate()).expect("Could not listen for SIGTERM");
+    let mut sigusr2 = signal(SignalKind::user_defined2()).expect("Could not listen for SIGUSR2");
+    let mut upgrades = upgrade_requests();
+    let timeout = Duration::from_secs(opts.shutdown_timeout_secs);
+    loop {
+        tokio::select! {
+            result = join_set.join_next() => {
+                let result = result
+                    .expect("Nothing is supposed to be started which makes no sense. Review commandline flags.")
+                    .expect("Could not join background task")
+                    .unwrap_err();
+                tracing::error!("A background task exited unexpectedly: {}", result);
+                break;
+            }
+            _ = tokio::signal::ctrl_c() => {
+                shutdown_gracefully(listeners, timeout).await;
+                break;
+            }
+            _ = sigterm.recv() => {
+                shutdown_gracefully(listeners, timeout).await;
+                break;
+            }
+            _ = sigusr2.recv() => {}
+            _ = upgrades.changed() => {}
+        }
+        match Successor::prepare() {
+            Ok(successor) => {
+                hand_over(successor, listeners, &pixmap, timeout).await;
+                break;
+            }
+            Err(e) => tracing::error!("Could not hand over to a new server process, continuing: {}", e),
+        }
+    }
 
     // cancel all other tasks
     join_set.shutdown().await;
 }
 
+/// Stop accepting connections, close the open ones and let sinks complete their pending work
+async fn shutdown_gracefully(listeners: Vec<AbortHandle>, timeout: Duration) {
+    tracing::info!("Shutting down");
+    for listener in listeners {
+        listener.abort();
+    }
+    if !shutdown::drain(timeout).await {
+        tracing::warn!("Not all connections were closed within {:?}", timeout);
+    }
+    if !shutdown::flush(timeout).await {
+        tracing::warn!("Not all sinks completed their pending work within {:?}", timeout);
+    }
+}
+
+/// Stop all listeners and start a new server process which takes over their sockets and the canvas
+///
+/// Clients which connect in between wait in the backlog of the sockets until the new process accepts them.
+async fn hand_over(successor: Successor, listeners: Vec<AbortHandle>, pixmap: &Pixmap, timeout: Duration) {
+    tracing::info!("Handing over to a new server process");
+    shutdown_gracefully(listeners, timeout).await;
+    let canvas = match canvas_snapshot(pixmap).await {
+        Ok(canvas) => Some(canvas),
+        Err(e) => {
+            tracing::error!(
+                "Could not snapshot the canvas, the new server process starts without it: {}",
+                e
+            );
+            None
+        }
+    };
+    match successor.start(canvas, timeout).await {
+        Ok(pid) => tracing::info!("Handed over to the new server process {}", pid),
+        Err(e) => tracing::error!("Could not hand over to a new server process: {}", e),
+    }
+}
+
+/// Write a snapshot of the canvas into an anonymous in-memory file which can be handed over to another process
+async fn canvas_snapshot(pixmap: &Pixmap) -> anyhow::Result<OwnedFd> {
+    let fd = unsafe { libc::memfd_create(c"pixeldike-canvas".as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    // SAFETY: the file descriptor was just created and is owned by nothing else
+    let mut file = tokio::fs::File::from_std(unsafe { std::fs::File::from_raw_fd(fd) });
+    pixeldike::sinks::pixmap_file::write_pixmap_file(pixmap, &mut file).await?;
+    Ok(file.into_std().await.into())
+}
+
+/// Determine the storm protection of a listener which can be disabled with `?storm-protection=off`
+fn storm_protection_for(
+    url: &Url,
+    storm_protection: &Option<StormProtectionOptions>,
+) -> Option<StormProtectionOptions> {
+    let disabled = url
+        .query_pairs()
+        .any(|(key, value)| key == "storm-protection" && value == "off");
+    match disabled {
+        true => None,
+        false => storm_protection.clone(),
+    }
+}
+
+/// Determine the networks from which a listener accepts clients which can be set with e.g.
+/// `?allow=10.0.0.0/8,2001:db8::/32&deny=10.1.0.0/16`
+///
+/// Single IP addresses are accepted as well. Clients in a denied network are always rejected.
+fn access_control_for(url: &Url) -> anyhow::Result<AccessControlOptions> {
+    let networks = |name: &str| {
+        url.query_pairs()
+            .filter(|(key, _)| key == name)
+            .flat_map(|(_, networks)| {
+                networks
+                    .split(',')
+                    .filter(|network| !network.is_empty())
+                    .map(|network| cli::parse_ip_net(network).map_err(|e| anyhow!("Invalid {}: {}", name, e)))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<anyhow::Result<Vec<_>>>()
+    };
+    Ok(AccessControlOptions {
+        allow: networks("allow")?,
+        deny: networks("deny")?,
+    })
+}
+
+/// Determine the tuning of the sockets of a listener which can be set with e.g.
+/// `?nodelay=on&recv-buffer=262144&send-buffer=262144&keepalive=60&keepalive-interval=10`
+///
+/// Buffer sizes are given in bytes and keepalive durations in seconds. Unset options keep the system defaults.
+fn socket_options_for(url: &Url) -> anyhow::Result<SocketOptions> {
+    let positive = |name: &str| {
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| {
+                value
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|&value| value > 0)
+                    .ok_or_else(|| anyhow!("Invalid {} {:?}; expected a positive number", name, value))
+            })
+            .transpose()
+    };
+    let nodelay = url
+        .query_pairs()
+        .find(|(key, _)| key == "nodelay")
+        .map(|(_, value)| match value.as_ref() {
+            "on" => Ok(true),
+            "off" => Ok(false),
+            _ => Err(anyhow!("Invalid nodelay {:?}; expected on or off", value)),
+        })
+        .transpose()?;
+    let secs = |secs: usize| Duration::from_secs(secs as u64);
+    Ok(SocketOptions {
+        nodelay,
+        recv_buffer_size: positive("recv-buffer")?,
+        send_buffer_size: positive("send-buffer")?,
+        keepalive_time: positive("keepalive")?.map(secs),
+        keepalive_interval: positive("keepalive-interval")?.map(secs),
+    })
+}
+
+/// Determine from which size on WebSocket messages are compressed with permessage-deflate, which can be set in bytes
+/// with e.g. `?deflate=256` or disabled with `?deflate=off`
+///
+/// Messages of at least 1024 bytes are compressed by default.
+fn deflate_threshold_for(url: &Url) -> anyhow::Result<Option<usize>> {
+    match url.query_pairs().find(|(key, _)| key == "deflate") {
+        None => Ok(Some(1024)),
+        Some((_, value)) if value == "off" => Ok(None),
+        Some((_, value)) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| anyhow!("Invalid deflate {:?}; expected a number of bytes or off", value)),
+    }
+}
+
+/// Determine how often WebSocket connections are pinged, which can be set in seconds with e.g. `?ping-interval=10`
+/// or disabled with `?ping-interval=off`
+///
+/// Connections are pinged every 30 seconds by default.
+fn ping_interval_for(url: &Url) -> anyhow::Result<Option<Duration>> {
+    match url.query_pairs().find(|(key, _)| key == "ping-interval") {
+        None => Ok(Some(Duration::from_secs(30))),
+        Some((_, value)) if value == "off" => Ok(None),
+        Some((_, value)) => value
+            .parse::<u64>()
+            .ok()
+            .filter(|&secs| secs > 0)
+            .map(|secs| Some(Duration::from_secs(secs)))
+            .ok_or_else(|| {
+                anyhow!(
+                    "Invalid ping-interval {:?}; expected a positive number of seconds or off",
+                    value
+                )
+            }),
+    }
+}
+
+/// Determine how many connections a single IP address may have open on a listener, which overrides
+/// `--max-connections-per-ip` with e.g. `?max-connections-per-ip=4` or lifts the limit with `?max-connections-per-ip=off`
+fn max_connections_per_ip_for(url: &Url, default: Option<usize>) -> anyhow::Result<Option<usize>> {
+    match url.query_pairs().find(|(key, _)| key == "max-connections-per-ip") {
+        None => Ok(default),
+        Some((_, value)) if value == "off" => Ok(None),
+        Some((_, value)) => value
+            .parse::<usize>()
+            .ok()
+            .filter(|&max| max > 0)
+            .map(Some)
+            .ok_or_else(|| {
+                anyhow!(
+                    "Invalid max-connections-per-ip {:?}; expected a positive number or off",
+                    value
+                )
+            }),
+    }
+}
+
+/// Determine after how long without requests connections to a listener are closed, which overrides `--idle-timeout`
+/// in seconds with e.g. `?idle-timeout=60` or keeps idle connections open with `?idle-timeout=off`
+fn idle_timeout_for(url: &Url, default: Option<u64>) -> anyhow::Result<Option<Duration>> {
+    match url.query_pairs().find(|(key, _)| key == "idle-timeout") {
+        None => Ok(default.map(Duration::from_secs)),
+        Some((_, value)) if value == "off" => Ok(None),
+        Some((_, value)) => value
+            .parse::<u64>()
+            .map(|secs| Some(Duration::from_secs(secs)))
+            .map_err(|_| {
+                anyhow!(
+                    "Invalid idle-timeout {:?}; expected a number of seconds or off",
+                    value
+                )
+            }),
+    }
+}
+
+/// Determine whether connections to a listener start with a PROXY protocol header which is enabled with
+/// `?proxy-protocol=on`
+fn proxy_protocol_for(url: &Url) -> bool {
+    url.query_pairs()
+        .any(|(key, value)| key == "proxy-protocol" && value == "on")
+}
+
+/// Determine the CA whose client certificates authenticate the sessions of a DTLS listener, which is set with e.g.
+/// `?client-ca=/etc/pixeldike/clients.pem`
+fn client_ca_for(url: &Url) -> Option<PathBuf> {
+    url.query_pairs()
+        .find(|(key, _)| key == "client-ca")
+        .map(|(_, path)| PathBuf::from(path.as_ref()))
+}
+
+/// Determine the optional protocol features of a listener which can be restricted with e.g. `?features=PXB,RECT`
+///
+/// An empty list disables all optional features so that only basic commands like PX and SIZE remain.
+fn features_for(url: &Url) -> anyhow::Result<Option<Features>> {
+    url.query_pairs()
+        .find(|(key, _)| key == "features")
+        .map(|(_, names)| {
+            names
+                .split(',')
+                .filter(|name| !name.is_empty())
+                .map(|name| Features::from_name(name).ok_or_else(|| anyhow!("Unknown feature {:?}", name)))
+                .collect()
+        })
+        .transpose()
+}
+
+/// Determine the file mode of a unix socket listener which can be set in octal with e.g. `?mode=660`
+fn socket_mode_for(url: &Url) -> anyhow::Result<Option<u32>> {
+    url.query_pairs()
+        .find(|(key, _)| key == "mode")
+        .map(|(_, mode)| {
+            u32::from_str_radix(&mode, 8)
+                .ok()
+                .filter(|&mode| mode <= 0o777)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Invalid file mode {:?}; expected octal permissions like 660",
+                        mode
+                    )
+                })
+        })
+        .transpose()
+}
+
+/// Determine where the admin interface listens, which is either a unix socket like `unix:///run/admin.sock?mode=600`
+/// or loopback addresses like `tcp://127.0.0.1:1240`
+fn admin_endpoints_for(url: &Url) -> anyhow::Result<Vec<AdminEndpoint>> {
+    match url.scheme() {
+        "unix" => match url.path() {
+            "" | "/" => Err(anyhow!("The admin url {} does not specify a socket path", url)),
+            path => Ok(vec![AdminEndpoint::Unix {
+                path: PathBuf::from(path),
+                permissions: socket_mode_for(url)?,
+            }]),
+        },
+        "tcp" => {
+            let addrs = url.socket_addrs(|| Some(1240))?;
+            if let Some(addr) = addrs.iter().find(|addr| !addr.ip().is_loopback()) {
+                return Err(anyhow!(
+                    "The admin interface must only listen on loopback addresses but {} resolves to {}",
+                    url,
+                    addr.ip()
+                ));
+            }
+            Ok(addrs.into_iter().map(AdminEndpoint::Tcp).collect())
+        }
+        scheme => Err(anyhow!(
+            "Unsupported admin url scheme {}; expected unix or tcp",
+            scheme
+        )),
+    }
+}
+
+/// Determine the rate limits of the connections of a listener which can be set with e.g. `?max-pixels-per-sec=1000`
+fn rate_limit_for(url: &Url) -> anyhow::Result<RateLimitOptions> {
+    let limit = |name: &str| {
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| {
+                value
+                    .parse()
+                    .ok()
+                    .filter(|&limit| limit > 0)
+                    .ok_or_else(|| anyhow!("Invalid {} {:?}; expected a positive number", name, value))
+            })
+            .transpose()
+    };
+    Ok(RateLimitOptions {
+        max_commands_per_sec: limit("max-commands-per-sec")?,
+        max_pixels_per_sec: limit("max-pixels-per-sec")?,
+    })
+}
+
+/// Determine how often an mqtt listener publishes the pixels which changed and the whole canvas, which can be set
+/// with e.g. `?updates-fps=10&state-interval=10`
+///
+/// Both default to 10 and 0 disables publishing.
+fn mqtt_publishing_for(url: &Url) -> anyhow::Result<(u32, Option<Duration>)> {
+    let setting = |name: &str| {
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map_or(Ok(10), |(_, value)| {
+                value
+                    .parse::<u32>()
+                    .map_err(|_| anyhow!("Invalid {} {:?}; expected a number", name, value))
+            })
+    };
+    let updates_fps = setting("updates-fps")?;
+    if updates_fps > MAX_STREAM_FPS {
+        return Err(anyhow!(
+            "Invalid updates-fps {}; at most {} are supported",
+            updates_fps,
+            MAX_STREAM_FPS
+        ));
+    }
+    let state_interval = match setting("state-interval")? {
+        0 => None,
+        secs => Some(Duration::from_secs(secs.into())),
+    };
+    Ok((updates_fps, state_interval))
+}
+
+/// Determine how many sockets accept the connections of a tcp listener which can be set with e.g. `?acceptors=4`
+fn acceptors_for(url: &Url) -> anyhow::Result<usize> {
+    url.query_pairs()
+        .find(|(key, _)| key == "acceptors")
+        .map_or(Ok(1), |(_, acceptors)| {
+            acceptors
+                .parse()
+                .ok()
+                .filter(|&acceptors| acceptors > 0)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Invalid number of acceptors {:?}; expected a positive number",
+                        acceptors
+                    )
+                })
+        })
+}
+
+/// Determine how many io_uring workers handle the connections of a tcp listener which can be set with e.g.
+/// `?io-uring-workers=4`
+fn io_uring_workers_for(url: &Url) -> anyhow::Result<Option<usize>> {
+    url.query_pairs()
+        .find(|(key, _)| key == "io-uring-workers")
+        .map(|(_, workers)| {
+            workers
+                .parse()
+                .ok()
+                .filter(|&workers| workers > 0)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Invalid number of io_uring workers {:?}; expected a positive number",
+                        workers
+                    )
+                })
+        })
+        .transpose()
+}
+
 async fn put_rectangle(opts: &cli::PutRectangleData) {
     // define how a request buffer is filled
     let fill_buf = |buf: &mut Writer<BytesMut>, x_min: usize, x_max: usize, y_min: usize, y_max: usize| {
@@ -331,33 +1168,43 @@ async fn put_rectangle(opts: &cli::PutRectangleData) {
 }
 
 async fn put_image(opts: &cli::PutImageData) {
-    // define how a request buffer is filled
-    let fill_buf = |buf: &mut Writer<BytesMut>, x_min: usize, x_max: usize, y_min: usize, y_max: usize| {
+    // define how the image is loaded once the size to which it is scaled is known
+    let load_image = |width: usize, height: usize| {
         tracing::debug!("Opening image at {}", &opts.path.display());
         let img = ImageReader::open(&opts.path)
             .expect("Could not open image file")
             .decode()
             .expect("Could not decode image")
-            .to_rgb8();
-
-        tracing::debug!("Resizing image to dimensions {}x{}", x_max - x_min, y_max - y_min);
-        let img = image::imageops::resize(
-            &img,
-            (x_max - x_min) as u32,
-            (y_max - y_min) as u32,
-            FilterType::Triangle,
-        );
+            .to_rgba8();
+
+        tracing::debug!("Resizing image to dimensions {}x{}", width, height);
+        let mut img = image::imageops::resize(&img, width as u32, height as u32, FilterType::Triangle);
+        dither(&mut img, opts.dithering, opts.bits_per_channel);
+        img
+    };
+
+    let client = main_utils::DynClient::connect(&opts.common.server)
+        .await
+        .expect("Could not connect to pixelflut server");
+    if opts.diff {
+        client.defend_image(load_image, &opts.common).await;
+        return;
+    }
+
+    // define how a request buffer is filled
+    let fill_buf = |buf: &mut Writer<BytesMut>, x_min: usize, x_max: usize, y_min: usize, y_max: usize| {
+        let img = load_image(x_max - x_min, y_max - y_min);
 
         // accumulate color commands into one large buffer buffer
         tracing::debug!("Converting image to pixelflut commands");
         let mut coords = (x_min..x_max).cartesian_product(y_min..y_max).collect::<Vec<_>>();
         coords.shuffle(&mut thread_rng());
         for (x, y) in coords {
-            let color = img.get_pixel(x as u32, y as u32);
+            let [r, g, b, _] = img.get_pixel((x - x_min) as u32, (y - y_min) as u32).0;
             Request::SetPixel {
                 x,
                 y,
-                color: color.0.into(),
+                color: Color::from((r, g, b)),
             }
             .write(buf)
             .unwrap();
@@ -365,57 +1212,77 @@ async fn put_image(opts: &cli::PutImageData) {
     };
 
     // run main client loop
+    client.run_loop(fill_buf, &opts.common, false).await;
+}
+
+async fn put_animation(opts: &cli::PutAnimationData) {
+    tracing::debug!("Opening animation at {}", &opts.path.display());
+    let animation = Animation::load_gif(&opts.path).expect("Could not load animation");
+
     main_utils::DynClient::connect(&opts.common.server)
         .await
         .expect("Could not connect to pixelflut server")
-        .run_loop(fill_buf, &opts.common, false)
+        .play_animation(&animation, &opts.common)
         .await;
 }
 
 async fn put_text(opts: &cli::PutTextOpts) {
-    let font = FontRef::try_from_slice(FONT_HERMIT_REGULAR).unwrap();
+    let renderer = match &opts.font {
+        None => TextRenderer::bundled(),
+        Some(path) => {
+            tracing::debug!("Loading font from {}", path.display());
+            let data = std::fs::read(path).expect("Could not read font file");
+            TextRenderer::new(FontArc::try_from_vec(data).expect("Could not parse font file"))
+        }
+    };
 
     // define how a request buffer is filled
     let fill_buf = |buf: &mut Writer<BytesMut>, x_min: usize, x_max: usize, y_min: usize, y_max: usize| {
-        // select a color
-        let color = match opts.color {
+        // select colors
+        let select_color = |target: &TargetColor| match target {
             TargetColor::RandomPerIteration | TargetColor::RandomOnce => {
                 Color::from((random(), random(), random()))
             }
-            TargetColor::Specific(c) => c,
+            TargetColor::Specific(c) => *c,
         };
+        let color = select_color(&opts.color);
+        let outline = opts.outline_color.as_ref().map(|outline_color| Outline {
+            color: select_color(outline_color),
+            width: opts.outline_width,
+        });
         tracing::debug!("Determined color to be #{color:X}");
 
-        // calculate scaling depending on requested text width (this is only possible because we use a monospace font)
-        let scaling = {
-            let glyph = font.glyph_id('_').with_scale(1.0);
-            let glyph_width = font.glyph_bounds(&glyph).width();
-            let target_width = (x_max - x_min) as f32 / opts.text.chars().count() as f32;
-            target_width / glyph_width
+        // calculate font size so that the text fills the requested width without exceeding the requested height
+        let padding = 2 * outline.map(|o| o.width).unwrap_or(0);
+        let size = {
+            const REFERENCE_SIZE: f32 = 100.0;
+            let reference = TextOptions {
+                size: REFERENCE_SIZE,
+                color,
+                outline: None,
+            };
+            let (reference_width, _) = renderer.measure(&opts.text, &reference);
+            let target_width = (x_max - x_min).saturating_sub(padding) as f32;
+            let target_height = (y_max - y_min).saturating_sub(padding) as f32;
+            f32::min(
+                REFERENCE_SIZE * target_width / reference_width.max(1) as f32,
+                target_height,
+            )
         };
-        tracing::debug!("Determined font scaling to be {scaling}");
+        tracing::debug!("Determined font size to be {size}");
 
-        // iterate over all characters
+        // rasterize the text and put it into the buffer
         tracing::debug!(
             "Filling command-buffer to draw {:?} in #{color:X} from {x_min},{y_min} to {x_max},{y_max}",
             opts.text
         );
-        for (i, char) in opts.text.chars().enumerate() {
-            let glyph = font.glyph_id(char).with_scale(scaling);
-            let glyph_width = font.glyph_bounds(&glyph).width() as usize;
-
-            let outline = font.outline_glyph(glyph).unwrap();
-            outline.draw(|x, y, coverage| {
-                if coverage >= 0.5 {
-                    Request::SetPixel {
-                        x: x_min + (x as usize + i * glyph_width),
-                        y: y_min + (y as usize),
-                        color,
-                    }
-                    .write(buf)
-                    .unwrap();
+        let options = TextOptions { size, color, outline };
+        for request in renderer.render(&opts.text, x_min, y_min, &options) {
+            if let Request::SetPixel { x, y, .. } = request {
+                if x < x_max && y < y_max {
+                    request.write(buf).unwrap();
                 }
-            });
+            }
         }
     };
 
@@ -426,7 +1293,8 @@ async fn put_text(opts: &cli::PutTextOpts) {
         .run_loop(
             fill_buf,
             &opts.common,
-            matches!(opts.color, TargetColor::RandomPerIteration),
+            matches!(opts.color, TargetColor::RandomPerIteration)
+                || matches!(opts.outline_color, Some(TargetColor::RandomPerIteration)),
         )
         .await;
 }
@@ -2,12 +2,29 @@ use crate::cli;
 use crate::cli::TargetDimension;
 use bytes::buf::Writer;
 use bytes::{BufMut, BytesMut};
-use pixeldike::net::clients::{TcpClient, UdpClient, UnixSocketClient};
-use pixeldike::net::protocol::{Request, Response};
+use image::RgbaImage;
+use pixeldike::drawing::{block_regions, diff_requests, Animation};
+use pixeldike::net::clients::{Pacer, PacingOptions, TcpClient, UdpClient, UnixSocketClient};
+use pixeldike::net::protocol::{Region, Request, Response};
 use std::path::PathBuf;
+use std::time::Duration;
 use tokio::io::AsyncWriteExt;
+use tokio::time::Instant;
 use url::Url;
 
+/// The size of the chunks in which paced buffers are sent
+const PACED_CHUNK_LEN: usize = 4096;
+/// How long to wait before checking the canvas again when none of the defended pixels were overwritten
+const DEFEND_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Build the pacing options of a client from its cli args
+fn pacing(opts: &cli::CommonClientOps) -> PacingOptions {
+    PacingOptions {
+        max_pixels_per_sec: opts.max_pixels_per_sec,
+        max_bytes_per_sec: opts.max_bytes_per_sec,
+    }
+}
+
 pub enum DynClient {
     Tcp(TcpClient),
     Udp(UdpClient),
@@ -30,7 +47,11 @@ impl DynClient {
                 let addr = url
                     .socket_addrs(|| Some(1234))
                     .expect("Could not resolve servers address")[0];
-                Ok(Self::Udp(UdpClient::connect(&addr).await?))
+                let mut client = UdpClient::connect(&addr).await?;
+                if let Some((_, mtu)) = url.query_pairs().find(|(key, _)| key == "mtu") {
+                    client.set_mtu(mtu.parse().expect("Could not parse mtu from server url"));
+                }
+                Ok(Self::Udp(client))
             }
             "unix" => {
                 let path = PathBuf::from(url.path());
@@ -40,7 +61,6 @@ impl DynClient {
         }
     }
 
-    #[allow(unused)]
     async fn send_request(&mut self, request: Request) -> std::io::Result<()> {
         match self {
             DynClient::Tcp(tcp) => tcp.send_request(request).await,
@@ -49,7 +69,6 @@ impl DynClient {
         }
     }
 
-    #[allow(unused)]
     async fn await_response(&mut self) -> anyhow::Result<Response> {
         match self {
             DynClient::Tcp(tcp) => tcp.await_response().await,
@@ -58,6 +77,15 @@ impl DynClient {
         }
     }
 
+    async fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            DynClient::Tcp(tcp) => tcp.flush().await,
+            // datagrams are sent right away
+            DynClient::Udp(_) => Ok(()),
+            DynClient::Unix(unix) => unix.flush().await,
+        }
+    }
+
     async fn exchange(&mut self, request: Request) -> anyhow::Result<Response> {
         match self {
             DynClient::Tcp(tcp) => tcp.exchange(request).await,
@@ -82,6 +110,7 @@ impl DynClient {
         let (canvas_width, canvas_height) = self.get_size().await;
         let (x_min, x_max, y_min, y_max) = self.calc_bounds(canvas_width, canvas_height, opts);
         let mut buf = BytesMut::new().writer();
+        let mut pacer = Pacer::new(pacing(opts));
 
         tracing::info!("Preparing command buffer");
         fill_buf(&mut buf, x_min, x_max, y_min, y_max);
@@ -89,24 +118,8 @@ impl DynClient {
         // main loop
         tracing::info!("Running client loop");
         loop {
-            // send whole buffer to server (using the most performant method available)
             tracing::debug!("Sending prepared commands to server");
-            match &mut self {
-                DynClient::Tcp(tcp) => tcp
-                    .get_writer()
-                    .write_all(buf.get_ref())
-                    .await
-                    .expect("Could not write commands to server"),
-                DynClient::Unix(unix) => unix
-                    .get_writer()
-                    .write_all(buf.get_ref())
-                    .await
-                    .expect("Could not write commands to server"),
-                DynClient::Udp(udp) => udp
-                    .send_bulk(buf.get_ref())
-                    .await
-                    .expect("Could not send commands to server"),
-            }
+            self.send_paced(buf.get_ref(), &mut pacer).await;
 
             // abort loop if only one iteration is requested
             if !opts.do_loop {
@@ -121,8 +134,164 @@ impl DynClient {
         }
     }
 
+    /// Play an animation within the bounds given by the cli args, only sending the pixels which change between frames
+    ///
+    /// The animation is repeated until the process is stopped unless it should only be drawn once.
+    pub async fn play_animation(mut self, animation: &Animation, opts: &cli::CommonClientOps) {
+        // preparation
+        let (canvas_width, canvas_height) = self.get_size().await;
+        let (x_min, x_max, y_min, y_max) = self.calc_bounds(canvas_width, canvas_height, opts);
+        tracing::debug!(
+            "Resizing animation to dimensions {}x{}",
+            x_max - x_min,
+            y_max - y_min
+        );
+        let animation = animation.resized(x_max - x_min, y_max - y_min);
+
+        tracing::info!(
+            "Preparing command buffers for {} frames",
+            animation.frames().len()
+        );
+        let encode = |requests: Vec<Request>| {
+            let mut buf = BytesMut::new().writer();
+            for request in requests {
+                request.write(&mut buf).unwrap();
+            }
+            buf.into_inner()
+        };
+        let first = encode(animation.frame(0, x_min, y_min));
+        let changes = (0..animation.frames().len())
+            .map(|i| encode(animation.changes(i, x_min, y_min)))
+            .collect::<Vec<_>>();
+
+        // main loop
+        tracing::info!("Running animation loop");
+        let mut pacer = Pacer::new(pacing(opts));
+        let mut deadline = Instant::now();
+        self.send_paced(&first, &mut pacer).await;
+        let mut i = 0;
+        loop {
+            deadline += animation.frames()[i].delay;
+            tokio::time::sleep_until(deadline).await;
+            i = (i + 1) % changes.len();
+            if i == 0 && !opts.do_loop {
+                break;
+            }
+            tracing::debug!("Sending changes of frame {} to server", i);
+            self.send_paced(&changes[i], &mut pacer).await;
+        }
+    }
+
+    /// Draw an image within the bounds given by the cli args, only sending the pixels which differ from the canvas
+    ///
+    /// `load_image` should return the image scaled to the given width and height.
+    /// Unless the image should only be drawn once, the canvas is checked over and over again so that pixels which
+    /// others overwrite are repaired.
+    pub async fn defend_image<F>(mut self, load_image: F, opts: &cli::CommonClientOps)
+    where
+        F: FnOnce(usize, usize) -> RgbaImage,
+    {
+        // preparation
+        let (canvas_width, canvas_height) = self.get_size().await;
+        let (x_min, x_max, y_min, y_max) = self.calc_bounds(canvas_width, canvas_height, opts);
+        let img = load_image(x_max - x_min, y_max - y_min);
+        let blocks = block_regions(Region {
+            x: x_min,
+            y: y_min,
+            width: x_max - x_min,
+            height: y_max - y_min,
+        });
+        let mut pacer = Pacer::new(pacing(opts));
+
+        // main loop
+        tracing::info!("Running defend loop");
+        loop {
+            // fetch all blocks at once so that there is only a single round trip
+            for block in &blocks {
+                self.send_request(Request::GetPixelBlock(*block))
+                    .await
+                    .expect("Could not request canvas state");
+            }
+            self.flush().await.expect("Could not request canvas state");
+
+            let mut buf = BytesMut::new().writer();
+            let mut changed = 0;
+            for _ in &blocks {
+                let Response::PxBlock { region, colors } = self
+                    .await_response()
+                    .await
+                    .expect("Could not fetch canvas state (does the server support PXGET?)")
+                else {
+                    panic!("Server sent invalid response to PXGET request")
+                };
+                for request in diff_requests(&img, x_min, y_min, &region, &colors) {
+                    request.write(&mut buf).unwrap();
+                    changed += 1;
+                }
+            }
+
+            tracing::debug!("Repairing {} pixels which differ from the image", changed);
+            self.send_paced(buf.get_ref(), &mut pacer).await;
+            if !opts.do_loop {
+                break;
+            }
+            if changed == 0 {
+                tokio::time::sleep(DEFEND_INTERVAL).await;
+            }
+        }
+    }
+
+    /// Send a buffer of encoded commands to the server right away (using the most performant method available)
+    pub async fn send_bulk(&mut self, buf: &[u8]) {
+        match self {
+            DynClient::Tcp(tcp) => {
+                tcp.get_writer()
+                    .write_all(buf)
+                    .await
+                    .expect("Could not write commands to server");
+                tcp.flush().await.expect("Could not write commands to server");
+            }
+            DynClient::Unix(unix) => {
+                unix.get_writer()
+                    .write_all(buf)
+                    .await
+                    .expect("Could not write commands to server");
+                unix.flush().await.expect("Could not write commands to server");
+            }
+            DynClient::Udp(udp) => udp
+                .send_bulk(buf)
+                .await
+                .expect("Could not send commands to server"),
+        }
+    }
+
+    /// Send a buffer of encoded commands to the server while staying within the limits of the pacer
+    ///
+    /// Every line of the buffer is counted as one pixel.
+    async fn send_paced(&mut self, buf: &[u8], pacer: &mut Pacer) {
+        if pacer.is_unlimited() {
+            return self.send_bulk(buf).await;
+        }
+        let mut rest = buf;
+        while !rest.is_empty() {
+            // only split after complete lines so that the server never sees a partial command
+            let len = match rest[..rest.len().min(PACED_CHUNK_LEN)]
+                .iter()
+                .rposition(|&b| b == b'\n')
+            {
+                Some(i) => i + 1,
+                None => rest.len().min(PACED_CHUNK_LEN),
+            };
+            let (chunk, remaining) = rest.split_at(len);
+            self.send_bulk(chunk).await;
+            let lines = chunk.iter().filter(|&&b| b == b'\n').count();
+            pacer.pace(lines as u64, chunk.len() as u64).await;
+            rest = remaining;
+        }
+    }
+
     /// Get the remote canvas's size
-    async fn get_size(&mut self) -> (usize, usize) {
+    pub async fn get_size(&mut self) -> (usize, usize) {
         let Response::Size { width, height } = self
             .exchange(Request::GetSize)
             .await
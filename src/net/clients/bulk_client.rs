@@ -0,0 +1,256 @@
+use crate::net::clients::{codec, Pacer, PacingOptions};
+use crate::net::protocol::{ProtocolVariant, Request, ResponseError};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tokio::task::JoinHandle;
+
+/// The size of the write buffer of a [`BulkClient`] which is sent to the server once it is full
+const WRITE_BUFFER_LEN: usize = 64 * 1024;
+
+/// Options with which a [`BulkClient`] is configured
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct BulkClientOptions {
+    /// After how many requests the write buffer is flushed even if it is not full yet, or `None` to only send full
+    /// buffers until the client is finished
+    pub flush_every: Option<usize>,
+    /// Whether responses are read and counted by a background task
+    ///
+    /// Without it, nothing reads the responses so that the connection stalls once the socket buffers are full.
+    /// Disable it only when the requests have no responses, e.g. because `NOREPLY on` was sent first.
+    pub drain_responses: bool,
+    /// Limits on how fast requests are enqueued, which every client of a [`PooledClient`](super::PooledClient)
+    /// enforces on its own
+    pub pacing: PacingOptions,
+    /// The protocol in which requests are sent, which must be the one that the connection was switched to
+    ///
+    /// With the binary protocol, most pixels take less than half the bytes but only requests with a binary encoding
+    /// can be sent.
+    pub protocol: ProtocolVariant,
+}
+
+impl Default for BulkClientOptions {
+    fn default() -> Self {
+        Self {
+            flush_every: Some(4096),
+            drain_responses: true,
+            pacing: PacingOptions::default(),
+            protocol: ProtocolVariant::Text,
+        }
+    }
+}
+
+/// What a [`BulkClient`] sent and received until it was finished
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct BulkStats {
+    /// How many requests were sent
+    pub requests: u64,
+    /// How many responses were received, including errors
+    pub responses: u64,
+    /// How many of the responses were errors
+    pub errors: u64,
+}
+
+/// A pixelflut client that sends large amounts of requests without waiting for their responses
+///
+/// Unlike `exchange()` on the other clients, which waits for one round trip per request, requests are pipelined
+/// through a large write buffer so that throughput is only limited by the link. Responses are optionally drained on a
+/// separate task and only counted.
+/// Create one via `into_bulk()` of a [`TcpClient`](super::TcpClient) or [`UnixSocketClient`](super::UnixSocketClient)
+/// or from any pair of reader and writer via [`new()`](Self::new).
+#[derive(Debug)]
+pub struct BulkClient<W: AsyncWrite + Unpin> {
+    writer: BufWriter<W>,
+    options: BulkClientOptions,
+    drain: Option<JoinHandle<std::io::Result<BulkStats>>>,
+    pacer: Pacer,
+    encoded: Vec<u8>,
+    requests: u64,
+    unflushed: usize,
+}
+
+impl<W: AsyncWrite + Unpin> BulkClient<W> {
+    /// Create a client which writes requests into `writer` and reads responses from `reader`
+    pub fn new<R>(reader: R, writer: W, options: BulkClientOptions) -> Self
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        let drain = options
+            .drain_responses
+            .then(|| tokio::spawn(drain_responses(reader)));
+        Self {
+            writer: BufWriter::with_capacity(WRITE_BUFFER_LEN, writer),
+            options,
+            drain,
+            pacer: Pacer::new(options.pacing),
+            encoded: Vec::new(),
+            requests: 0,
+            unflushed: 0,
+        }
+    }
+
+    /// Enqueue a single request without waiting for its response
+    ///
+    /// If the client is paced, this waits until it may send the request.
+    pub async fn send(&mut self, request: &Request) -> std::io::Result<()> {
+        if self.pacer.is_unlimited() {
+            codec::write_request(request, self.options.protocol, &mut self.writer).await?;
+        } else {
+            self.encoded.clear();
+            codec::write_request(request, self.options.protocol, &mut self.encoded).await?;
+            self.writer.write_all(&self.encoded).await?;
+            self.pacer.pace_request(request, self.encoded.len()).await;
+        }
+        self.requests += 1;
+        self.unflushed += 1;
+        if self.options.flush_every.is_some_and(|n| self.unflushed >= n) {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Enqueue all requests of an iterator without waiting for their responses
+    pub async fn send_all(&mut self, requests: impl IntoIterator<Item = Request>) -> std::io::Result<()> {
+        for request in requests {
+            self.send(&request).await?;
+        }
+        Ok(())
+    }
+
+    /// Flush the write buffer to immediately send all enqueued requests to the server
+    pub async fn flush(&mut self) -> std::io::Result<()> {
+        self.unflushed = 0;
+        self.writer.flush().await
+    }
+
+    /// Send all enqueued requests, close the sending side of the connection and wait until the server sent all
+    /// responses
+    pub async fn finish(mut self) -> std::io::Result<BulkStats> {
+        self.flush().await?;
+        self.writer.shutdown().await?;
+        let stats = match self.drain.take() {
+            Some(drain) => drain.await??,
+            None => BulkStats::default(),
+        };
+        Ok(BulkStats {
+            requests: self.requests,
+            ..stats
+        })
+    }
+}
+
+impl<W: AsyncWrite + Unpin> Drop for BulkClient<W> {
+    fn drop(&mut self) {
+        if let Some(drain) = &self.drain {
+            drain.abort();
+        }
+    }
+}
+
+/// Read and count all responses until the server closes the connection
+async fn drain_responses<R: AsyncRead + Unpin>(reader: R) -> std::io::Result<BulkStats> {
+    let mut reader = BufReader::with_capacity(WRITE_BUFFER_LEN, reader);
+    let mut stats = BulkStats::default();
+    let mut frame = Vec::new();
+    loop {
+        if !codec::read_frame(&mut reader, &mut frame).await? {
+            return Ok(stats);
+        }
+        stats.responses += 1;
+        if let Err(e) = codec::parse_frame(&frame) {
+            // responses which merely could not be parsed are no errors of the server
+            if !e.is::<ResponseError>() {
+                continue;
+            }
+            if stats.errors == 0 {
+                tracing::debug!("Server sent error: {}", e);
+            }
+            stats.errors += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::protocol::write_error_binary;
+    use crate::pixmap::Color;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_pipelined_requests() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let (reader, writer) = tokio::io::split(client);
+        let mut client = BulkClient::new(
+            reader,
+            writer,
+            BulkClientOptions {
+                flush_every: Some(2),
+                drain_responses: true,
+                pacing: PacingOptions::default(),
+                protocol: ProtocolVariant::Text,
+            },
+        );
+        let requests = (0..3).map(|x| Request::SetPixel {
+            x,
+            y: 0,
+            color: Color::from((0xFF, 0, 0)),
+        });
+        client.send_all(requests).await.unwrap();
+        client.send(&Request::GetSize).await.unwrap();
+
+        server
+            .write_all(b"SIZE 800 600\nERR OUT_OF_BOUNDS nope\n")
+            .await
+            .unwrap();
+        let finished = tokio::spawn(client.finish());
+        let mut received = String::new();
+        server.read_to_string(&mut received).await.unwrap();
+        assert_eq!(received, "PX 0 0 FF0000\nPX 1 0 FF0000\nPX 2 0 FF0000\nSIZE\n");
+        drop(server);
+        assert_eq!(
+            finished.await.unwrap().unwrap(),
+            BulkStats {
+                requests: 4,
+                responses: 2,
+                errors: 1
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_binary_requests() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let (reader, writer) = tokio::io::split(client);
+        let options = BulkClientOptions {
+            protocol: ProtocolVariant::Binary,
+            ..Default::default()
+        };
+        let mut client = BulkClient::new(reader, writer, options);
+        client
+            .send(&Request::SetPixel {
+                x: 1,
+                y: 2,
+                color: Color::from((0xFF, 0, 0)),
+            })
+            .await
+            .unwrap();
+        assert!(client.send(&Request::GetInfo).await.is_err());
+
+        let mut responses = Vec::new();
+        write_error_binary("ERR OUT_OF_BOUNDS nope", &mut responses).unwrap();
+        responses.extend_from_slice(b"PROTOCOL TEXT\n");
+        server.write_all(&responses).await.unwrap();
+        let finished = tokio::spawn(client.finish());
+        let mut received = Vec::new();
+        server.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, [0x01, 0, 1, 0, 2, 0, 0xFF, 0, 0]);
+        drop(server);
+        assert_eq!(
+            finished.await.unwrap().unwrap(),
+            BulkStats {
+                requests: 1,
+                responses: 2,
+                errors: 1
+            }
+        );
+    }
+}
@@ -0,0 +1,110 @@
+use crate::drawing::apply_state_frame;
+use crate::net::protocol::{parse_error_response, parse_response_str, Request, Response, StateAlgorithm};
+use anyhow::anyhow;
+use image::RgbaImage;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+
+/// Fetch the whole canvas over a connection which is not used for anything else in the meantime
+///
+/// The protocol has no request which returns the canvas state once, so a `STREAM rgb64` is started and stopped again
+/// after its first frame arrived. Since stopping the stream is not confirmed, a `SIZE` request is sent after it and
+/// frames which were sent before its response are skipped.
+pub(crate) async fn fetch_canvas<R, W>(
+    reader: &mut BufReader<R>,
+    writer: &mut BufWriter<W>,
+) -> anyhow::Result<RgbaImage>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let stream = |fps| Request::StreamState {
+        algorithm: StateAlgorithm::Rgb64,
+        fps,
+        region: None,
+    };
+    Request::GetSize.write_async(writer).await?;
+    stream(1).write_async(writer).await?;
+    writer.flush().await?;
+
+    let mut line = String::new();
+    let (width, height) = match read_response(reader, &mut line).await? {
+        Some(Response::Size { width, height }) => (width as u32, height as u32),
+        _ => {
+            return Err(anyhow!(
+                "expected a SIZE response but the server sent {:?}",
+                line.trim_end()
+            ))
+        }
+    };
+    let mut img = RgbaImage::new(width, height);
+    while read_response(reader, &mut line).await?.is_some() {
+        tracing::debug!("Skipping unexpected response {:?}", line.trim_end());
+    }
+    apply_state_frame(&mut img, &line)?;
+
+    stream(0).write_async(writer).await?;
+    Request::GetSize.write_async(writer).await?;
+    writer.flush().await?;
+    while read_response(reader, &mut line).await?.is_none() {}
+    Ok(img)
+}
+
+/// Read the next line into `line` and parse it as a response unless it is a `STATE` frame
+async fn read_response<R: AsyncRead + Unpin>(
+    reader: &mut BufReader<R>,
+    line: &mut String,
+) -> anyhow::Result<Option<Response>> {
+    line.clear();
+    if reader.read_line(line).await? == 0 {
+        return Err(anyhow!("server closed the connection"));
+    }
+    if line.starts_with("STATE ") {
+        return Ok(None);
+    }
+    if let Some(error) = parse_error_response(line) {
+        return Err(error.into());
+    }
+    Ok(Some(parse_response_str(line)?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use image::Rgba;
+
+    #[tokio::test]
+    async fn test_fetch_canvas() {
+        let (client, server) = tokio::io::duplex(1024);
+        let (reader, writer) = tokio::io::split(client);
+        let (mut reader, mut writer) = (BufReader::new(reader), BufWriter::new(writer));
+        let server = tokio::spawn(async move {
+            let (server_reader, mut server_writer) = tokio::io::split(server);
+            let mut lines = BufReader::new(server_reader).lines();
+            let mut received = Vec::new();
+            for response in [
+                "SIZE 2 2",
+                "STATE rgb64 AAAA/wCAAAAAAAAA",
+                "STATE rgb64 AAAA/wCAAAAAAAAA",
+                "SIZE 2 2",
+            ] {
+                if response.starts_with("SIZE") {
+                    received.push(lines.next_line().await.unwrap().unwrap());
+                    received.push(lines.next_line().await.unwrap().unwrap());
+                }
+                server_writer
+                    .write_all(format!("{}\n", response).as_bytes())
+                    .await
+                    .unwrap();
+            }
+            received
+        });
+
+        let img = fetch_canvas(&mut reader, &mut writer).await.unwrap();
+        assert_eq!(img.dimensions(), (2, 2));
+        assert_eq!(img.get_pixel(1, 0), &Rgba([0xFF, 0, 0x80, 0xFF]));
+        assert_eq!(
+            server.await.unwrap(),
+            vec!["SIZE", "STREAM rgb64 1", "STREAM rgb64 0", "SIZE"]
+        );
+    }
+}
@@ -0,0 +1,151 @@
+use crate::net::protocol::{
+    has_binary_encoding, parse_response_bin, parse_response_binary, write_request_binary, ProtocolVariant,
+    Request, Response,
+};
+use std::io::{Error, ErrorKind};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Write a request in the given protocol
+///
+/// Requests without a binary encoding cannot be sent while the binary protocol is used because the server would not
+/// understand them, so an [`ErrorKind::InvalidInput`] error is returned for them instead.
+pub(crate) async fn write_request(
+    request: &Request,
+    protocol: ProtocolVariant,
+    writer: &mut (impl AsyncWrite + Unpin),
+) -> std::io::Result<()> {
+    match protocol {
+        ProtocolVariant::Text => request.write_async(writer).await,
+        ProtocolVariant::Binary if has_binary_encoding(request) => {
+            let mut buf = Vec::with_capacity(9);
+            write_request_binary(request, &mut buf)?;
+            writer.write_all(&buf).await
+        }
+        ProtocolVariant::Binary => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "{} has no binary encoding, switch back to the text protocol to send it",
+                request
+            ),
+        )),
+    }
+}
+
+/// Read the next response into `buf`, which is either a text line or a message of the binary protocol
+///
+/// Since text responses never start with one of the opcodes of binary ones, this works independently of the protocol
+/// which the connection uses. `false` is returned if the server closed the connection instead.
+pub(crate) async fn read_frame<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+) -> std::io::Result<bool> {
+    buf.clear();
+    loop {
+        let byte = match reader.read_u8().await {
+            Ok(byte) => byte,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof && buf.is_empty() => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        buf.push(byte);
+        match parse_response_binary(buf) {
+            Ok(Some(_)) => return Ok(true),
+            Ok(None) => {}
+            Err(_) => {
+                reader.read_until(b'\n', buf).await?;
+                return Ok(true);
+            }
+        }
+    }
+}
+
+/// Parse a response which was read via [`read_frame`]
+///
+/// If the server sent an error instead, it is returned as a [`ResponseError`](crate::net::protocol::ResponseError).
+pub(crate) fn parse_frame(buf: &[u8]) -> anyhow::Result<Response> {
+    match parse_response_binary(buf) {
+        Ok(Some((response, _))) => Ok(response?),
+        _ => parse_response_bin(buf),
+    }
+}
+
+/// Read and parse the next response
+pub(crate) async fn read_response<R: AsyncBufRead + Unpin>(reader: &mut R) -> anyhow::Result<Response> {
+    let mut buf = Vec::with_capacity(32);
+    if !read_frame(reader, &mut buf).await? {
+        return Err(Error::from(ErrorKind::UnexpectedEof).into());
+    }
+    parse_frame(&buf)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::protocol::{write_error_binary, write_response_binary, ResponseError};
+    use crate::pixmap::Color;
+
+    #[tokio::test]
+    async fn test_mixed_responses() {
+        let mut data = Vec::new();
+        write_response_binary(
+            &Response::Size {
+                width: 800,
+                height: 600,
+            },
+            &mut data,
+        )
+        .unwrap();
+        data.extend_from_slice(b"PROTOCOL TEXT\n");
+        write_error_binary("ERR OUT_OF_BOUNDS nope", &mut data).unwrap();
+        data.extend_from_slice(b"PX 1 2 FF0000\n");
+
+        let mut reader = data.as_slice();
+        assert_eq!(
+            read_response(&mut reader).await.unwrap(),
+            Response::Size {
+                width: 800,
+                height: 600
+            }
+        );
+        assert_eq!(
+            read_response(&mut reader).await.unwrap(),
+            Response::Protocol(ProtocolVariant::Text)
+        );
+        let error = read_response(&mut reader).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(ResponseError::OutOfBounds(_))
+        ));
+        assert_eq!(
+            read_response(&mut reader).await.unwrap(),
+            Response::PxData {
+                x: 1,
+                y: 2,
+                color: Color::from((0xFF, 0, 0))
+            }
+        );
+        assert!(read_response(&mut reader).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_write_request() {
+        let mut buf = Vec::new();
+        let request = Request::SetPixel {
+            x: 1,
+            y: 2,
+            color: Color::from((0xFF, 0, 0)),
+        };
+        write_request(&request, ProtocolVariant::Binary, &mut buf)
+            .await
+            .unwrap();
+        assert_eq!(buf, [0x01, 0, 1, 0, 2, 0, 0xFF, 0, 0]);
+
+        let error = write_request(&Request::GetInfo, ProtocolVariant::Binary, &mut buf)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        write_request(&Request::GetInfo, ProtocolVariant::Text, &mut buf)
+            .await
+            .unwrap();
+        assert!(buf.ends_with(b"INFO\n"));
+    }
+}
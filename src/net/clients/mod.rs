@@ -1,13 +1,30 @@
 //! Client implementation for different transport protocols
 
+mod bulk_client;
+#[cfg(feature = "images")]
+mod canvas;
+mod codec;
+mod pacing;
+mod pooled_client;
+mod reconnecting_client;
+mod streaming_client;
 #[cfg(feature = "tcp")]
 mod tcp_client;
 #[cfg(feature = "udp")]
 mod udp_client;
 mod unix_socket_client;
+#[cfg(feature = "ws")]
+mod ws_client;
 
+pub use bulk_client::{BulkClient, BulkClientOptions, BulkStats};
+pub use pacing::{Pacer, PacingOptions};
+pub use pooled_client::{PooledClient, Sharding};
+pub use reconnecting_client::{ReconnectOptions, ReconnectTarget, ReconnectingClient};
+pub use streaming_client::{PixelUpdate, PixelUpdates, StreamingClient};
 #[cfg(feature = "tcp")]
 pub use tcp_client::TcpClient;
 #[cfg(feature = "udp")]
 pub use udp_client::UdpClient;
 pub use unix_socket_client::UnixSocketClient;
+#[cfg(feature = "ws")]
+pub use ws_client::{WebSocketClient, WebSocketMode};
@@ -0,0 +1,82 @@
+use crate::net::protocol::Request;
+use crate::net::servers::rate_limit::TokenBucket;
+use crate::net::servers::statistics;
+use std::time::{Duration, Instant};
+
+/// Options for limiting how fast a client sends, e.g. to share the bandwidth of an event network with others
+///
+/// Like the rate limits of servers, each limit is enforced with a token bucket which holds one second worth of tokens
+/// so that short bursts are sent at full speed.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct PacingOptions {
+    /// How many pixels may be drawn per second, or `None` for no limit
+    pub max_pixels_per_sec: Option<u32>,
+    /// How many bytes may be sent per second, or `None` for no limit
+    pub max_bytes_per_sec: Option<u32>,
+}
+
+/// Bookkeeping which decides how long a client must pause to stay within its [`PacingOptions`]
+#[derive(Debug, Copy, Clone)]
+pub struct Pacer {
+    pixels: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+impl Pacer {
+    /// Create a pacer which starts with full buckets
+    pub fn new(options: PacingOptions) -> Self {
+        let now = Instant::now();
+        Self {
+            pixels: options.max_pixels_per_sec.map(|rate| TokenBucket::new(rate, now)),
+            bytes: options.max_bytes_per_sec.map(|rate| TokenBucket::new(rate, now)),
+        }
+    }
+
+    /// Whether the pacer never pauses
+    pub fn is_unlimited(&self) -> bool {
+        self.pixels.is_none() && self.bytes.is_none()
+    }
+
+    /// Account for a request which was sent as `bytes` bytes and wait until the client is within its limits again
+    pub async fn pace_request(&mut self, request: &Request, bytes: usize) {
+        self.pace(statistics::pixels_drawn(request), bytes as u64).await
+    }
+
+    /// Account for data which was sent and wait until the client is within its limits again
+    pub async fn pace(&mut self, pixels: u64, bytes: u64) {
+        let delay = self.take_at(pixels, bytes, Instant::now());
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    fn take_at(&mut self, pixels: u64, bytes: u64, now: Instant) -> Duration {
+        let pixels = self
+            .pixels
+            .as_mut()
+            .map_or(Duration::ZERO, |bucket| bucket.take(pixels, now));
+        let bytes = self
+            .bytes
+            .as_mut()
+            .map_or(Duration::ZERO, |bucket| bucket.take(bytes, now));
+        pixels.max(bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pacing() {
+        let mut pacer = Pacer::new(PacingOptions {
+            max_pixels_per_sec: Some(100),
+            max_bytes_per_sec: Some(1000),
+        });
+        let start = Instant::now();
+        assert_eq!(pacer.take_at(100, 500, start), Duration::ZERO);
+        assert_eq!(pacer.take_at(0, 1000, start), Duration::from_millis(500));
+        assert_eq!(pacer.take_at(50, 0, start), Duration::from_millis(500));
+        assert!(Pacer::new(PacingOptions::default()).is_unlimited());
+    }
+}
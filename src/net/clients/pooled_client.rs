@@ -0,0 +1,180 @@
+use crate::net::clients::{BulkClient, BulkStats};
+use crate::net::protocol::Request;
+use tokio::io::AsyncWrite;
+
+/// How a [`PooledClient`] distributes requests across its connections
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum Sharding {
+    /// Requests are distributed evenly across all connections in the order in which they are sent
+    #[default]
+    RoundRobin,
+    /// The canvas is split into horizontal bands of equal height, one per connection, and requests which address
+    /// pixels are sent over the connection of the band which contains them
+    ///
+    /// This keeps the pixels of every connection close together in the servers memory.
+    /// Requests without coordinates are distributed round-robin.
+    Region {
+        /// The height of the servers canvas
+        canvas_height: usize,
+    },
+}
+
+/// A pixelflut client that sends requests over several connections to the same server at once
+///
+/// Servers typically handle every connection on a single task so that the throughput of a single connection is
+/// limited by how fast the server can parse its requests. Sharding requests across connections allows the server to
+/// handle them in parallel.
+/// Like the [`BulkClient`]s it consists of, requests are sent without waiting for their responses and there is no
+/// ordering between requests which are sent over different connections.
+#[derive(Debug)]
+pub struct PooledClient<W: AsyncWrite + Unpin> {
+    clients: Vec<BulkClient<W>>,
+    sharding: Sharding,
+    next: usize,
+}
+
+impl<W: AsyncWrite + Unpin> PooledClient<W> {
+    /// Create a client which distributes requests across the given connections
+    ///
+    /// # Panics
+    ///
+    /// This panics if no connections are given.
+    pub fn new(clients: Vec<BulkClient<W>>, sharding: Sharding) -> Self {
+        assert!(!clients.is_empty(), "a pool needs at least one connection");
+        Self {
+            clients,
+            sharding,
+            next: 0,
+        }
+    }
+
+    /// How many connections requests are distributed across
+    pub fn connections(&self) -> usize {
+        self.clients.len()
+    }
+
+    /// Enqueue a single request on the connection which it is sharded to without waiting for its response
+    pub async fn send(&mut self, request: &Request) -> std::io::Result<()> {
+        let i = self.shard(request);
+        self.clients[i].send(request).await
+    }
+
+    /// Enqueue all requests of an iterator without waiting for their responses
+    pub async fn send_all(&mut self, requests: impl IntoIterator<Item = Request>) -> std::io::Result<()> {
+        for request in requests {
+            self.send(&request).await?;
+        }
+        Ok(())
+    }
+
+    /// Flush the write buffers of all connections to immediately send all enqueued requests to the server
+    pub async fn flush(&mut self) -> std::io::Result<()> {
+        for client in &mut self.clients {
+            client.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Finish all connections and sum up what was sent and received over them
+    ///
+    /// See [`BulkClient::finish()`].
+    pub async fn finish(self) -> std::io::Result<BulkStats> {
+        let mut total = BulkStats::default();
+        for client in self.clients {
+            let stats = client.finish().await?;
+            total.requests += stats.requests;
+            total.responses += stats.responses;
+            total.errors += stats.errors;
+        }
+        Ok(total)
+    }
+
+    /// Determine the index of the connection over which a request is sent
+    fn shard(&mut self, request: &Request) -> usize {
+        let n = self.clients.len();
+        let y = match request {
+            Request::SetPixel { y, .. } | Request::GetPixel { y, .. } | Request::FillRect { y, .. } => {
+                Some(*y)
+            }
+            Request::SetPixelBatch(pixels) => pixels.first().map(|(_, y, _)| *y),
+            _ => None,
+        };
+        match (self.sharding, y) {
+            (Sharding::Region { canvas_height }, Some(y)) => (y * n / canvas_height.max(1)).min(n - 1),
+            _ => {
+                let i = self.next;
+                self.next = (i + 1) % n;
+                i
+            }
+        }
+    }
+}
+
+#[cfg(feature = "tcp")]
+impl PooledClient<tokio::net::tcp::OwnedWriteHalf> {
+    /// Open `connections` TCP connections to the server running at the given address
+    pub async fn connect(
+        addr: &std::net::SocketAddr,
+        connections: usize,
+        sharding: Sharding,
+        options: super::BulkClientOptions,
+    ) -> std::io::Result<Self> {
+        let mut clients = Vec::with_capacity(connections);
+        for _ in 0..connections {
+            let client = super::TcpClient::connect(addr).await?;
+            clients.push(client.into_bulk(options).await?);
+        }
+        Ok(Self::new(clients, sharding))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::clients::BulkClientOptions;
+    use crate::pixmap::Color;
+    use tokio::io::{AsyncReadExt, DuplexStream};
+
+    #[tokio::test]
+    async fn test_sharding() {
+        let options = BulkClientOptions {
+            flush_every: None,
+            drain_responses: true,
+            pacing: Default::default(),
+            protocol: Default::default(),
+        };
+        let (clients, servers): (Vec<_>, Vec<DuplexStream>) = (0..2)
+            .map(|_| {
+                let (client, server) = tokio::io::duplex(1024);
+                let (reader, writer) = tokio::io::split(client);
+                (BulkClient::new(reader, writer, options), server)
+            })
+            .unzip();
+        let mut client = PooledClient::new(clients, Sharding::Region { canvas_height: 10 });
+        assert_eq!(client.connections(), 2);
+
+        let color = Color::from((0xFF, 0, 0));
+        client
+            .send_all([
+                Request::SetPixel { x: 0, y: 9, color },
+                Request::SetPixel { x: 0, y: 1, color },
+                Request::SetPixelBatch(vec![(1, 5, color)]),
+                Request::GetSize,
+                Request::GetSize,
+            ])
+            .await
+            .unwrap();
+
+        let finished = tokio::spawn(client.finish());
+        let mut received = Vec::new();
+        for mut server in servers {
+            // like real servers, close the connection once the client is done
+            let mut buf = String::new();
+            server.read_to_string(&mut buf).await.unwrap();
+            received.push(buf);
+        }
+        assert_eq!(received[0], "PX 0 1 FF0000\nSIZE\n");
+        assert_eq!(received[1], "PX 0 9 FF0000\nPXB 1 1 5 FF0000\nSIZE\n");
+        assert_eq!(finished.await.unwrap().unwrap().requests, 5);
+    }
+}
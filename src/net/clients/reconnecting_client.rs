@@ -0,0 +1,293 @@
+#[cfg(feature = "tcp")]
+use crate::net::clients::TcpClient;
+use crate::net::clients::UnixSocketClient;
+use crate::net::protocol::{Request, Response, ResponseError};
+use anyhow::anyhow;
+use std::mem::discriminant;
+#[cfg(feature = "tcp")]
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// The server to which a [`ReconnectingClient`] connects
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ReconnectTarget {
+    /// A server which listens on a TCP socket
+    #[cfg(feature = "tcp")]
+    Tcp(SocketAddr),
+    /// A server which provides a unix domain socket at the given path
+    Unix(PathBuf),
+}
+
+/// Options which control how a [`ReconnectingClient`] re-establishes dropped connections
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ReconnectOptions {
+    /// How long to wait before the first attempt to reconnect
+    pub initial_backoff: Duration,
+    /// The longest time to wait between two attempts, up to which the backoff is doubled after every failed attempt
+    pub max_backoff: Duration,
+    /// After how many failed attempts the client gives up, or `None` to try forever
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectOptions {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(30),
+            max_attempts: None,
+        }
+    }
+}
+
+/// A single connection of a [`ReconnectingClient`]
+#[derive(Debug)]
+enum Connection {
+    #[cfg(feature = "tcp")]
+    Tcp(TcpClient),
+    Unix(UnixSocketClient),
+}
+
+impl Connection {
+    async fn open(target: &ReconnectTarget) -> std::io::Result<Self> {
+        match target {
+            #[cfg(feature = "tcp")]
+            ReconnectTarget::Tcp(addr) => Ok(Self::Tcp(TcpClient::connect(addr).await?)),
+            ReconnectTarget::Unix(path) => Ok(Self::Unix(UnixSocketClient::connect(path).await?)),
+        }
+    }
+
+    async fn send_request(&mut self, request: Request) -> std::io::Result<()> {
+        match self {
+            #[cfg(feature = "tcp")]
+            Connection::Tcp(tcp) => tcp.send_request(request).await,
+            Connection::Unix(unix) => unix.send_request(request).await,
+        }
+    }
+
+    async fn await_response(&mut self) -> anyhow::Result<Response> {
+        match self {
+            #[cfg(feature = "tcp")]
+            Connection::Tcp(tcp) => tcp.await_response().await,
+            Connection::Unix(unix) => unix.await_response().await,
+        }
+    }
+
+    async fn exchange(&mut self, request: Request) -> anyhow::Result<Response> {
+        match self {
+            #[cfg(feature = "tcp")]
+            Connection::Tcp(tcp) => tcp.exchange(request).await,
+            Connection::Unix(unix) => unix.exchange(request).await,
+        }
+    }
+
+    async fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            #[cfg(feature = "tcp")]
+            Connection::Tcp(tcp) => tcp.flush().await,
+            Connection::Unix(unix) => unix.flush().await,
+        }
+    }
+}
+
+/// A pixelflut client that transparently re-establishes its connection when it drops, e.g. because the server was
+/// restarted
+///
+/// Reconnecting is retried with an exponential backoff as configured by [`ReconnectOptions`].
+/// The requests which configure a connection (`HELLO`, `AUTH`, `NOREPLY`, `NICK` and `PROTOCOL`) are remembered and
+/// sent again over every new connection so that it behaves like the one that dropped.
+///
+/// Requests which were still buffered or waiting for their response when the connection dropped are lost, except
+/// that `exchange()` sends its request again once.
+#[derive(Debug)]
+pub struct ReconnectingClient {
+    target: ReconnectTarget,
+    options: ReconnectOptions,
+    connection: Connection,
+    session: Vec<Request>,
+    reconnects: u64,
+}
+
+impl ReconnectingClient {
+    /// Connect to the given server
+    ///
+    /// Only later connections are retried, so this fails right away if the server cannot be reached.
+    pub async fn connect(target: ReconnectTarget, options: ReconnectOptions) -> std::io::Result<Self> {
+        let connection = Connection::open(&target).await?;
+        Ok(Self {
+            target,
+            options,
+            connection,
+            session: Vec::new(),
+            reconnects: 0,
+        })
+    }
+
+    /// How often the connection was re-established so far
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects
+    }
+
+    /// Enqueue a single request to be sent to the connected server
+    ///
+    /// Note that because the client uses buffered IO, your request may not be sent immediately.
+    /// Use either `flush()` or `exchange()` appropriately.
+    pub async fn send_request(&mut self, request: Request) -> anyhow::Result<()> {
+        self.remember(&request);
+        if let Err(e) = self.connection.send_request(request.clone()).await {
+            self.reconnect(e.into()).await?;
+            self.connection.send_request(request).await?;
+        }
+        Ok(())
+    }
+
+    /// Wait for the connected server to send a response
+    ///
+    /// If the server sent an error instead, it is returned as a [`ResponseError`]. If the connection dropped
+    /// instead, it is re-established and an error is returned since the response is lost.
+    pub async fn await_response(&mut self) -> anyhow::Result<Response> {
+        match self.connection.await_response().await {
+            Err(e) if !e.is::<ResponseError>() => {
+                self.reconnect(e).await?;
+                Err(anyhow!(
+                    "the connection dropped while waiting for a response and was re-established"
+                ))
+            }
+            result => result,
+        }
+    }
+
+    /// Send a single request to the connected server and wait for a response
+    ///
+    /// If the connection drops in the meantime, it is re-established and the request is sent again once.
+    pub async fn exchange(&mut self, request: Request) -> anyhow::Result<Response> {
+        self.remember(&request);
+        match self.connection.exchange(request.clone()).await {
+            Err(e) if !e.is::<ResponseError>() => {
+                self.reconnect(e).await?;
+                self.connection.exchange(request).await
+            }
+            result => result,
+        }
+    }
+
+    /// Flush the write buffer to immediately send all enqueued requests to the server
+    ///
+    /// If the connection dropped, it is re-established while the buffered requests are lost.
+    pub async fn flush(&mut self) -> anyhow::Result<()> {
+        if let Err(e) = self.connection.flush().await {
+            self.reconnect(e.into()).await?;
+        }
+        Ok(())
+    }
+
+    /// Remember requests which configure the connection so that they can be replayed after reconnecting
+    ///
+    /// Only the latest request of every kind is kept.
+    fn remember(&mut self, request: &Request) {
+        if matches!(
+            request,
+            Request::Hello { .. }
+                | Request::Authenticate(_)
+                | Request::SetNoReply(_)
+                | Request::SetNick(_)
+                | Request::SetProtocol(_)
+        ) {
+            self.session
+                .retain(|known| discriminant(known) != discriminant(request));
+            self.session.push(request.clone());
+        }
+    }
+
+    /// Re-establish the connection after it dropped because of `cause`, retrying with an exponential backoff
+    async fn reconnect(&mut self, cause: anyhow::Error) -> anyhow::Result<()> {
+        tracing::warn!("Connection to pixelflut server dropped ({}), reconnecting", cause);
+        let mut backoff = self.options.initial_backoff;
+        let mut attempts = 0;
+        loop {
+            tokio::time::sleep(backoff).await;
+            attempts += 1;
+            match self.reopen().await {
+                Ok(connection) => {
+                    tracing::info!("Reconnected to pixelflut server after {} attempts", attempts);
+                    self.connection = connection;
+                    self.reconnects += 1;
+                    return Ok(());
+                }
+                Err(e) if self.options.max_attempts.is_some_and(|max| attempts >= max) => {
+                    return Err(e.context(format!("Could not reconnect after {} attempts", attempts)));
+                }
+                Err(e) => {
+                    tracing::debug!("Could not reconnect to pixelflut server: {}", e);
+                    backoff = (backoff * 2).min(self.options.max_backoff);
+                }
+            }
+        }
+    }
+
+    /// Open a new connection and replay the remembered requests over it
+    async fn reopen(&self) -> anyhow::Result<Connection> {
+        let mut connection = Connection::open(&self.target).await?;
+        for request in &self.session {
+            connection.exchange(request.clone()).await?;
+        }
+        Ok(connection)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::UnixListener;
+
+    #[tokio::test]
+    async fn test_reconnect_replays_session() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pixelflut.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = tokio::spawn(async move {
+            let mut received = Vec::new();
+            for responses in [vec!["AUTH OK"], vec!["AUTH OK", "SIZE 800 600"]] {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut stream = BufReader::new(stream);
+                for response in responses {
+                    let mut line = String::new();
+                    stream.read_line(&mut line).await.unwrap();
+                    received.push(line.trim_end().to_string());
+                    stream
+                        .write_all(format!("{}\n", response).as_bytes())
+                        .await
+                        .unwrap();
+                }
+            }
+            received
+        });
+
+        let options = ReconnectOptions {
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(100),
+            max_attempts: Some(5),
+        };
+        let mut client = ReconnectingClient::connect(ReconnectTarget::Unix(path), options)
+            .await
+            .unwrap();
+        assert_eq!(
+            client
+                .exchange(Request::Authenticate("s3cr3t".to_string()))
+                .await
+                .unwrap(),
+            Response::Authenticated
+        );
+        // the server dropped the first connection after answering
+        assert_eq!(
+            client.exchange(Request::GetSize).await.unwrap(),
+            Response::Size {
+                width: 800,
+                height: 600
+            }
+        );
+        assert_eq!(client.reconnects(), 1);
+        assert_eq!(server.await.unwrap(), vec!["AUTH s3cr3t", "AUTH s3cr3t", "SIZE"]);
+    }
+}
@@ -0,0 +1,252 @@
+use crate::net::protocol::{
+    parse_error_response, parse_response_str, Region, Request, Response, StateAlgorithm,
+};
+use crate::pixmap::Color;
+use anyhow::anyhow;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use futures_core::Stream;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// The size of one pixel record in the data of a `STATE delta` frame: x and y as u32 followed by r, g and b
+const RECORD_LEN: usize = 11;
+
+/// A pixel whose color changed on the server
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PixelUpdate {
+    /// The x coordinate of the pixel
+    pub x: usize,
+    /// The y coordinate of the pixel
+    pub y: usize,
+    /// The new color of the pixel
+    pub color: Color,
+}
+
+/// The pixel updates which the server pushes to a [`StreamingClient`] after it subscribed
+///
+/// Updates are buffered until they are consumed, either via [`recv()`](Self::recv) or as a [`Stream`].
+#[derive(Debug)]
+pub struct PixelUpdates {
+    receiver: mpsc::UnboundedReceiver<PixelUpdate>,
+}
+
+impl PixelUpdates {
+    /// Wait for the next update or return `None` once the connection is closed
+    pub async fn recv(&mut self) -> Option<PixelUpdate> {
+        self.receiver.recv().await
+    }
+}
+
+impl Stream for PixelUpdates {
+    type Item = PixelUpdate;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+/// A pixelflut client that receives the pixel updates which the server pushes while requests are exchanged over the
+/// same connection
+///
+/// A background task reads everything the server sends and separates the `STATE delta` frames which the server pushes
+/// after [`subscribe()`](Self::subscribe) from the responses to requests, so that both can be interleaved freely.
+/// Create one via `into_streaming()` of a [`TcpClient`](super::TcpClient) or
+/// [`UnixSocketClient`](super::UnixSocketClient) or from any pair of reader and writer via [`new()`](Self::new).
+#[derive(Debug)]
+pub struct StreamingClient<W: AsyncWrite + Unpin> {
+    writer: BufWriter<W>,
+    responses: mpsc::UnboundedReceiver<anyhow::Result<Response>>,
+    updates: Option<PixelUpdates>,
+    reader: JoinHandle<()>,
+}
+
+impl<W: AsyncWrite + Unpin> StreamingClient<W> {
+    /// Create a client which writes requests into `writer` and reads responses and updates from `reader`
+    pub fn new<R>(reader: R, writer: W) -> Self
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        let (response_tx, responses) = mpsc::unbounded_channel();
+        let (update_tx, receiver) = mpsc::unbounded_channel();
+        Self {
+            writer: BufWriter::new(writer),
+            responses,
+            updates: Some(PixelUpdates { receiver }),
+            reader: tokio::spawn(read_messages(reader, response_tx, update_tx)),
+        }
+    }
+
+    /// Ask the server to push the pixels which change in `region` (or the whole canvas) up to `fps` times per second
+    ///
+    /// The first updates contain all pixels of the region so that the current canvas state is known as well.
+    /// Only a single subscription can be made per client while [`unsubscribe()`](Self::unsubscribe) merely pauses it.
+    pub async fn subscribe(&mut self, fps: u32, region: Option<Region>) -> anyhow::Result<PixelUpdates> {
+        let updates = self
+            .updates
+            .take()
+            .ok_or_else(|| anyhow!("the client already subscribed to updates"))?;
+        self.send_request(Request::StreamState {
+            algorithm: StateAlgorithm::Delta,
+            fps,
+            region,
+        })
+        .await?;
+        self.flush().await?;
+        Ok(updates)
+    }
+
+    /// Ask the server to stop pushing updates
+    pub async fn unsubscribe(&mut self) -> std::io::Result<()> {
+        self.send_request(Request::StreamState {
+            algorithm: StateAlgorithm::Delta,
+            fps: 0,
+            region: None,
+        })
+        .await?;
+        self.flush().await
+    }
+
+    /// Enqueue a single request to be sent to the connected server
+    ///
+    /// Note that because the client uses buffered IO, your request may not be sent immediately.
+    /// Use either `flush()` or `exchange()` appropriately.
+    pub async fn send_request(&mut self, request: Request) -> std::io::Result<()> {
+        request.write_async(&mut self.writer).await
+    }
+
+    /// Wait for the connected server to send a response, skipping all pushed updates
+    ///
+    /// If the server sent an error instead, it is returned as a [`ResponseError`](crate::net::protocol::ResponseError).
+    pub async fn await_response(&mut self) -> anyhow::Result<Response> {
+        self.responses
+            .recv()
+            .await
+            .ok_or_else(|| anyhow!("server closed the connection"))?
+    }
+
+    /// Send a single request to the connected server and wait for a response
+    ///
+    /// This method automatically flushes the underlying buffer so that the request is sent immediately.
+    pub async fn exchange(&mut self, request: Request) -> anyhow::Result<Response> {
+        self.send_request(request).await?;
+        self.flush().await?;
+        self.await_response().await
+    }
+
+    /// Flush the write buffer to immediately send all enqueued requests to the server
+    pub async fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush().await
+    }
+}
+
+impl<W: AsyncWrite + Unpin> Drop for StreamingClient<W> {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+/// Read all lines which the server sends and pass them on as either responses or pixel updates
+async fn read_messages<R: AsyncRead + Unpin>(
+    reader: R,
+    responses: mpsc::UnboundedSender<anyhow::Result<Response>>,
+    updates: mpsc::UnboundedSender<PixelUpdate>,
+) {
+    let mut reader = BufReader::new(reader);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match reader.read_line(&mut line).await {
+            Ok(0) => return,
+            Ok(_) => {}
+            Err(e) => {
+                let _ = responses.send(Err(e.into()));
+                return;
+            }
+        }
+
+        if let Some(frame) = line.strip_prefix("STATE delta ") {
+            match decode_delta(frame) {
+                Some(pixels) => {
+                    for pixel in pixels {
+                        // updates are dropped if nobody is interested in them
+                        let _ = updates.send(pixel);
+                    }
+                }
+                None => tracing::warn!("Server sent an invalid state frame"),
+            }
+            continue;
+        }
+        let response = match parse_error_response(&line) {
+            Some(error) => Err(error.into()),
+            None => parse_response_str(&line).map_err(Into::into),
+        };
+        if responses.send(response).is_err() {
+            return;
+        }
+    }
+}
+
+/// Decode the pixels of a `STATE delta <token> <base> <data>` frame from which the prefix was already removed
+fn decode_delta(frame: &str) -> Option<impl Iterator<Item = PixelUpdate>> {
+    let data = BASE64_STANDARD.decode(frame.split_whitespace().nth(2)?).ok()?;
+    if data.len() % RECORD_LEN != 0 {
+        return None;
+    }
+    let pixels = data
+        .chunks_exact(RECORD_LEN)
+        .map(|record| PixelUpdate {
+            x: u32::from_be_bytes(record[0..4].try_into().unwrap()) as usize,
+            y: u32::from_be_bytes(record[4..8].try_into().unwrap()) as usize,
+            color: Color::from((record[8], record[9], record[10])),
+        })
+        .collect::<Vec<_>>();
+    Some(pixels.into_iter())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_interleaved_updates() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let (reader, writer) = tokio::io::split(client);
+        let mut client = StreamingClient::new(reader, writer);
+        let mut updates = client.subscribe(10, None).await.unwrap();
+        assert!(client.subscribe(10, None).await.is_err());
+
+        let mut record = Vec::new();
+        record.extend_from_slice(&3u32.to_be_bytes());
+        record.extend_from_slice(&4u32.to_be_bytes());
+        record.extend_from_slice(&[0xFF, 0, 0]);
+        let frame = format!("STATE delta 1 0 {}\n", BASE64_STANDARD.encode(&record));
+        server.write_all(frame.as_bytes()).await.unwrap();
+        server.write_all(b"SIZE 800 600\n").await.unwrap();
+        server.write_all(frame.as_bytes()).await.unwrap();
+
+        assert_eq!(
+            client.exchange(Request::GetSize).await.unwrap(),
+            Response::Size {
+                width: 800,
+                height: 600
+            }
+        );
+        let update = PixelUpdate {
+            x: 3,
+            y: 4,
+            color: Color::from((0xFF, 0, 0)),
+        };
+        assert_eq!(updates.recv().await, Some(update));
+        assert_eq!(updates.recv().await, Some(update));
+
+        client.unsubscribe().await.unwrap();
+        drop(client);
+        let mut received = String::new();
+        server.read_to_string(&mut received).await.unwrap();
+        assert_eq!(received, "STREAM delta 10\nSIZE\nSTREAM delta 0\n");
+    }
+}
@@ -3,15 +3,35 @@ use anyhow::anyhow;
 use bytes::{BufMut, BytesMut};
 use std::net::SocketAddr;
 use std::str::FromStr;
+use std::time::Duration;
 use tokio::net::UdpSocket;
 
+/// The MTU which is assumed if the path MTU cannot be discovered (typical for ethernet)
+const DEFAULT_MTU: usize = 1500;
+/// The smallest MTU that every IPv4 host must support
+const MIN_MTU_V4: usize = 576;
+/// The smallest MTU that every IPv6 link must support
+const MIN_MTU_V6: usize = 1280;
+/// Size of the UDP header which is added to every datagram
+const UDP_HEADER_SIZE: usize = 8;
+/// The largest payload that a UDP datagram can carry (e.g. on loopback interfaces with a huge MTU)
+const MAX_UDP_PAYLOAD: usize = 65_507;
+
 /// A pixelflut client that uses UDP for communication with a pixelflut server.
 ///
-/// Not that requests are not buffered or assembled into larger UDP packets in any way.
-/// Instead, every request is sent as its own datagram which is very inefficient.
+/// Single requests are sent as their own datagram while bulk commands are packed into datagrams that fit into the
+/// path MTU to the server.
+/// The MTU is discovered automatically when supported by the operating system (currently only on Linux) but can also
+/// be configured via [`set_mtu()`](Self::set_mtu).
+///
+/// Since datagrams may be lost, waiting for a response can be limited via
+/// [`set_response_timeout()`](Self::set_response_timeout).
 #[derive(Debug)]
 pub struct UdpClient {
     socket: UdpSocket,
+    is_ipv4: bool,
+    mtu: usize,
+    response_timeout: Option<Duration>,
 }
 
 impl UdpClient {
@@ -23,7 +43,76 @@ impl UdpClient {
             UdpSocket::bind(SocketAddr::from_str("[::]:0").unwrap()).await?
         };
         socket.connect(addr).await?;
-        Ok(Self { socket })
+
+        let is_ipv4 = addr.is_ipv4();
+        let mtu = match path_mtu::enable_discovery(&socket, is_ipv4) {
+            Ok(()) => path_mtu::query(&socket, is_ipv4).unwrap_or(DEFAULT_MTU),
+            Err(e) => {
+                tracing::debug!(
+                    "Path MTU discovery is not available, assuming {}: {}",
+                    DEFAULT_MTU,
+                    e
+                );
+                DEFAULT_MTU
+            }
+        };
+        tracing::debug!("Using an MTU of {} for UDP datagrams to {}", mtu, addr);
+
+        Ok(Self {
+            socket,
+            is_ipv4,
+            mtu,
+            response_timeout: None,
+        })
+    }
+
+    /// Use the given MTU instead of the discovered one
+    ///
+    /// The MTU is still lowered if the network reports that datagrams are too large.
+    pub fn set_mtu(&mut self, mtu: usize) {
+        self.mtu = mtu.max(self.min_mtu());
+    }
+
+    /// The MTU which is currently used to size datagrams
+    pub fn get_mtu(&self) -> usize {
+        self.mtu
+    }
+
+    /// Give up waiting for a response after the given time instead of waiting forever, or wait forever with `None`
+    pub fn set_response_timeout(&mut self, timeout: Option<Duration>) {
+        self.response_timeout = timeout;
+    }
+
+    /// The maximum number of payload bytes that fit into one datagram without fragmentation
+    pub fn max_datagram_size(&self) -> usize {
+        let ip_header_size = if self.is_ipv4 { 20 } else { 40 };
+        (self.mtu - ip_header_size - UDP_HEADER_SIZE).min(MAX_UDP_PAYLOAD)
+    }
+
+    fn min_mtu(&self) -> usize {
+        if self.is_ipv4 {
+            MIN_MTU_V4
+        } else {
+            MIN_MTU_V6
+        }
+    }
+
+    /// Lower the MTU after the network reported that a datagram was too large
+    ///
+    /// Returns whether the MTU could be lowered.
+    fn lower_mtu(&mut self) -> bool {
+        // the kernel updates its path MTU from ICMP feedback so it is preferred over guessing
+        let new_mtu = match path_mtu::query(&self.socket, self.is_ipv4) {
+            Some(mtu) if mtu < self.mtu => mtu,
+            _ => self.min_mtu(),
+        };
+        if new_mtu < self.mtu {
+            tracing::info!("Lowering UDP MTU from {} to {}", self.mtu, new_mtu);
+            self.mtu = new_mtu;
+            true
+        } else {
+            false
+        }
     }
 
     /// Send a single request to the configured server
@@ -35,9 +124,20 @@ impl UdpClient {
     }
 
     /// Wait for the server to send a response back
+    ///
+    /// If a response timeout is configured and no response arrives in time, an error is returned.
     pub async fn await_response(&mut self) -> anyhow::Result<Response> {
         let mut buf = BytesMut::with_capacity(64);
-        self.socket.recv_buf(&mut buf).await?;
+        match self.response_timeout {
+            None => {
+                self.socket.recv_buf(&mut buf).await?;
+            }
+            Some(timeout) => {
+                tokio::time::timeout(timeout, self.socket.recv_buf(&mut buf))
+                    .await
+                    .map_err(|_| anyhow!("server did not respond within {:?}", timeout))??;
+            }
+        }
         match buf.iter().enumerate().find(|(_, b)| **b == b'\n') {
             Some((i, _)) => {
                 let response = parse_response_bin(&buf[0..i])?;
@@ -56,11 +156,168 @@ impl UdpClient {
 
     /// Send pre-encoded commands in bulk
     ///
-    /// Note that because UDP is an unreliable transport mechanism, not all bytes might actually be sent.
-    /// If the buffer size is larger than the maximum size supported by UDP or the maximum transmission unit of the
-    /// underlying network layer, the packet might even be discarded completely.
-    pub async fn send_bulk(&mut self, buf: &[u8]) -> std::io::Result<()> {
-        let _ = self.socket.send(buf).await?;
+    /// The commands are split at line boundaries into as few datagrams as possible that each fit into the MTU.
+    /// If the network reports that datagrams are too large, the MTU is lowered and the remaining commands are sent
+    /// using smaller datagrams.
+    ///
+    /// Note that because UDP is an unreliable transport mechanism, not all commands might actually arrive at the
+    /// server.
+    pub async fn send_bulk(&mut self, mut buf: &[u8]) -> std::io::Result<()> {
+        while !buf.is_empty() {
+            let len = next_datagram_len(buf, self.max_datagram_size());
+            match self.socket.send(&buf[..len]).await {
+                Ok(_) => buf = &buf[len..],
+                Err(e) if path_mtu::is_too_big(&e) && self.lower_mtu() => {}
+                Err(e) => return Err(e),
+            }
+        }
         Ok(())
     }
 }
+
+/// Determine how many bytes from the start of `buf` should be sent in the next datagram
+///
+/// Datagrams always end at a line boundary unless a single line is longer than `max_size`.
+fn next_datagram_len(buf: &[u8], max_size: usize) -> usize {
+    if buf.len() <= max_size {
+        return buf.len();
+    }
+    match buf[..max_size].iter().rposition(|&b| b == b'\n') {
+        Some(i) => i + 1,
+        None => buf.iter().position(|&b| b == b'\n').map_or(buf.len(), |i| i + 1),
+    }
+}
+
+/// Operating system specific access to path MTU discovery
+#[cfg(target_os = "linux")]
+mod path_mtu {
+    use std::os::fd::AsRawFd;
+    use tokio::net::UdpSocket;
+
+    fn set_option(socket: &UdpSocket, level: i32, name: i32, value: i32) -> std::io::Result<()> {
+        let result = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                std::ptr::from_ref(&value).cast::<libc::c_void>(),
+                size_of::<i32>() as libc::socklen_t,
+            )
+        };
+        match result {
+            0 => Ok(()),
+            _ => Err(std::io::Error::last_os_error()),
+        }
+    }
+
+    fn get_option(socket: &UdpSocket, level: i32, name: i32) -> std::io::Result<i32> {
+        let mut value: i32 = 0;
+        let mut len = size_of::<i32>() as libc::socklen_t;
+        let result = unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                std::ptr::from_mut(&mut value).cast::<libc::c_void>(),
+                &mut len,
+            )
+        };
+        match result {
+            0 => Ok(value),
+            _ => Err(std::io::Error::last_os_error()),
+        }
+    }
+
+    /// Set the don't-fragment flag so that the kernel tracks the path MTU and reports datagrams which are too large
+    pub(super) fn enable_discovery(socket: &UdpSocket, is_ipv4: bool) -> std::io::Result<()> {
+        match is_ipv4 {
+            true => set_option(
+                socket,
+                libc::IPPROTO_IP,
+                libc::IP_MTU_DISCOVER,
+                libc::IP_PMTUDISC_DO,
+            ),
+            false => set_option(
+                socket,
+                libc::IPPROTO_IPV6,
+                libc::IPV6_MTU_DISCOVER,
+                libc::IPV6_PMTUDISC_DO,
+            ),
+        }
+    }
+
+    /// Get the path MTU that the kernel currently knows for the connected socket
+    pub(super) fn query(socket: &UdpSocket, is_ipv4: bool) -> Option<usize> {
+        let mtu = match is_ipv4 {
+            true => get_option(socket, libc::IPPROTO_IP, libc::IP_MTU),
+            false => get_option(socket, libc::IPPROTO_IPV6, libc::IPV6_MTU),
+        };
+        mtu.ok().map(|mtu| mtu as usize)
+    }
+
+    /// Whether an error indicates that a datagram was larger than the path MTU
+    pub(super) fn is_too_big(e: &std::io::Error) -> bool {
+        e.raw_os_error() == Some(libc::EMSGSIZE)
+    }
+}
+
+/// Operating system specific access to path MTU discovery
+#[cfg(not(target_os = "linux"))]
+mod path_mtu {
+    use tokio::net::UdpSocket;
+
+    pub(super) fn enable_discovery(_socket: &UdpSocket, _is_ipv4: bool) -> std::io::Result<()> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "path MTU discovery is only supported on linux",
+        ))
+    }
+
+    pub(super) fn query(_socket: &UdpSocket, _is_ipv4: bool) -> Option<usize> {
+        None
+    }
+
+    pub(super) fn is_too_big(_e: &std::io::Error) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_datagrams_end_at_line_boundaries() {
+        let buf = b"PX 1 1 FFFFFF\nPX 2 2 FFFFFF\nPX 3 3 FFFFFF\n";
+        assert_eq!(next_datagram_len(buf, 100), buf.len());
+        assert_eq!(next_datagram_len(buf, 30), 28);
+        assert_eq!(next_datagram_len(buf, 27), 14);
+        assert_eq!(next_datagram_len(buf, 5), 14);
+    }
+
+    #[tokio::test]
+    async fn test_discover_loopback_mtu() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = UdpClient::connect(&server.local_addr().unwrap()).await.unwrap();
+        assert!(client.max_datagram_size() >= MIN_MTU_V4 - 28);
+    }
+
+    #[tokio::test]
+    async fn test_response_timeout() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut client = UdpClient::connect(&server.local_addr().unwrap()).await.unwrap();
+        client.set_response_timeout(Some(Duration::from_millis(50)));
+        assert!(client.exchange(Request::GetSize).await.is_err());
+
+        let mut buf = [0; 64];
+        let (_, addr) = server.recv_from(&mut buf).await.unwrap();
+        server.send_to(b"SIZE 800 600\n", addr).await.unwrap();
+        assert_eq!(
+            client.await_response().await.unwrap(),
+            Response::Size {
+                width: 800,
+                height: 600
+            }
+        );
+    }
+}
@@ -1,6 +1,12 @@
-use crate::net::protocol::{parse_response_str, Request, Response};
+#[cfg(feature = "images")]
+use crate::net::clients::canvas;
+use crate::net::clients::{codec, BulkClient, BulkClientOptions, StreamingClient};
+use crate::net::protocol::{ProtocolVariant, Request, Response, ResponseError};
+use anyhow::anyhow;
+#[cfg(feature = "images")]
+use image::RgbaImage;
 use std::path::Path;
-use tokio::io::{AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
 use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::UnixStream;
 
@@ -9,6 +15,7 @@ use tokio::net::UnixStream;
 pub struct UnixSocketClient {
     reader: BufReader<OwnedReadHalf>,
     writer: BufWriter<OwnedWriteHalf>,
+    protocol: ProtocolVariant,
 }
 
 impl UnixSocketClient {
@@ -18,6 +25,7 @@ impl UnixSocketClient {
         Ok(Self {
             reader: BufReader::new(reader),
             writer: BufWriter::new(writer),
+            protocol: ProtocolVariant::Text,
         })
     }
 
@@ -25,16 +33,22 @@ impl UnixSocketClient {
     ///
     /// Note that because the TCP-Client uses buffered IO, your request may not be sent immediately.
     /// Use either `flush()` or `exchange()` appropriately.
+    ///
+    /// While the binary protocol is used, only requests with a binary encoding can be sent. Sending a `PROTOCOL`
+    /// request switches the encoding of all following requests, even before the server confirmed it.
     pub async fn send_request(&mut self, request: Request) -> std::io::Result<()> {
-        request.write_async(&mut self.writer).await
+        codec::write_request(&request, self.protocol, &mut self.writer).await?;
+        if let Request::SetProtocol(variant) = request {
+            self.protocol = variant;
+        }
+        Ok(())
     }
 
     /// Wait for the connected server to send a response
+    ///
+    /// If the server sent an error instead, it is returned as a [`ResponseError`].
     pub async fn await_response(&mut self) -> anyhow::Result<Response> {
-        let mut buf = String::with_capacity(32);
-        self.reader.read_line(&mut buf).await?;
-        let response = parse_response_str(&buf)?;
-        Ok(response)
+        codec::read_response(&mut self.reader).await
     }
 
     /// Send a single request to the connected server and wait for a response
@@ -47,11 +61,92 @@ impl UnixSocketClient {
         Ok(response)
     }
 
+    /// The protocol in which requests are currently sent
+    pub fn protocol(&self) -> ProtocolVariant {
+        self.protocol
+    }
+
+    /// Switch the connection to the binary protocol if the server supports it
+    ///
+    /// Support is detected via `INFO` and the client keeps using the text protocol if the server does not support
+    /// the binary one or refuses to switch, e.g. because it was not negotiated via `HELLO`.
+    /// Returns whether the binary protocol is used now.
+    pub async fn negotiate_binary(&mut self) -> anyhow::Result<bool> {
+        if self.protocol == ProtocolVariant::Binary {
+            return Ok(true);
+        }
+        match self.exchange(Request::GetInfo).await {
+            Ok(Response::Info(info)) if !info.binary_protocol => return Ok(false),
+            Err(e) if !e.is::<ResponseError>() => return Err(e),
+            // servers which don't describe themselves might still support it
+            _ => {}
+        }
+        match self.exchange(Request::SetProtocol(ProtocolVariant::Binary)).await {
+            Ok(Response::Protocol(ProtocolVariant::Binary)) => Ok(true),
+            Err(e) if e.is::<ResponseError>() => {
+                self.protocol = ProtocolVariant::Text;
+                Ok(false)
+            }
+            Err(e) => Err(e),
+            Ok(response) => {
+                self.protocol = ProtocolVariant::Text;
+                Err(anyhow!(
+                    "server sent {:?} instead of switching protocols",
+                    response
+                ))
+            }
+        }
+    }
+
     /// Flush the write buffer to immediately send all enqueued requests to the server.
     pub async fn flush(&mut self) -> std::io::Result<()> {
         self.writer.flush().await
     }
 
+    /// Fetch the whole canvas of the server as an image
+    ///
+    /// The canvas is requested as one `STREAM rgb64` frame which is decoded into opaque pixels, which is only possible
+    /// while the text protocol is used.
+    #[cfg(feature = "images")]
+    pub async fn fetch_canvas(&mut self) -> anyhow::Result<RgbaImage> {
+        if self.protocol == ProtocolVariant::Binary {
+            return Err(anyhow!(
+                "the canvas can only be fetched while the text protocol is used"
+            ));
+        }
+        canvas::fetch_canvas(&mut self.reader, &mut self.writer).await
+    }
+
+    /// Turn this client into one which sends requests without waiting for their responses
+    ///
+    /// Requests which are still buffered are sent first and the bulk client keeps using the protocol of this one,
+    /// regardless of `options.protocol`.
+    pub async fn into_bulk(
+        mut self,
+        options: BulkClientOptions,
+    ) -> std::io::Result<BulkClient<OwnedWriteHalf>> {
+        self.flush().await?;
+        let options = BulkClientOptions {
+            protocol: self.protocol,
+            ..options
+        };
+        Ok(BulkClient::new(self.reader, self.writer.into_inner(), options))
+    }
+
+    /// Turn this client into one which receives the pixel updates that the server pushes after subscribing
+    ///
+    /// Requests which are still buffered are sent first. Streaming is only possible while the text protocol is used.
+    pub async fn into_streaming(mut self) -> std::io::Result<StreamingClient<OwnedWriteHalf>> {
+        if self.protocol == ProtocolVariant::Binary {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "streaming is only possible while the text protocol is used",
+            ));
+        }
+        self.flush().await?;
+        Ok(StreamingClient::new(self.reader, self.writer.into_inner()))
+    }
+
     /// Get the raw writer that is connected to the pixelflut server.
     pub fn get_writer(&mut self) -> &mut BufWriter<impl AsyncWrite> {
         &mut self.writer
@@ -0,0 +1,177 @@
+use crate::net::protocol::{
+    has_binary_encoding, parse_response_bin, parse_response_binary, write_request_binary, Request, Response,
+};
+use crate::net::servers::ws_json;
+use anyhow::anyhow;
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use url::Url;
+
+/// How a [`WebSocketClient`] exchanges requests and responses with the server
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum WebSocketMode {
+    /// Every request is sent as a text message which contains one line of the text protocol
+    #[default]
+    Text,
+    /// Every request is sent as a JSON object in a text message, which the client asks for via `?format=json`
+    Json,
+    /// Requests which have a binary representation are sent as binary messages and all others as text messages
+    Binary,
+}
+
+/// A pixelflut client that connects to a WebSocket listener, e.g. of deployments which are only reachable through
+/// an HTTP proxy
+///
+/// Like the TCP client, requests are buffered until they are flushed. Every request is sent as a message of its own.
+/// Only `ws://` urls are supported.
+#[derive(Debug)]
+pub struct WebSocketClient {
+    stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    mode: WebSocketMode,
+}
+
+impl WebSocketClient {
+    /// Try to connect to the server running at the given url and exchange messages in the given mode
+    pub async fn connect(url: &Url, mode: WebSocketMode) -> anyhow::Result<Self> {
+        let mut url = url.clone();
+        if mode == WebSocketMode::Json {
+            url.query_pairs_mut().append_pair("format", "json");
+        }
+        let (stream, _) = tokio_tungstenite::connect_async(url.as_str()).await?;
+        Ok(Self { stream, mode })
+    }
+
+    /// Enqueue a single request to be sent to the connected server
+    ///
+    /// Note that because the client buffers messages, your request may not be sent immediately.
+    /// Use either `flush()` or `exchange()` appropriately.
+    pub async fn send_request(&mut self, request: Request) -> anyhow::Result<()> {
+        let msg = match self.mode {
+            WebSocketMode::Json => Message::Text(ws_json::encode_request(&request)),
+            WebSocketMode::Binary if has_binary_encoding(&request) => {
+                let mut buf = Vec::new();
+                write_request_binary(&request, &mut buf)?;
+                Message::Binary(buf)
+            }
+            WebSocketMode::Text | WebSocketMode::Binary => {
+                let mut buf = Vec::new();
+                request.write(&mut buf)?;
+                let line = String::from_utf8(buf)?;
+                Message::Text(line.trim_end().to_string())
+            }
+        };
+        self.stream.feed(msg).await?;
+        Ok(())
+    }
+
+    /// Wait for the connected server to send a response
+    ///
+    /// If the server sent an error instead, it is returned as a [`ResponseError`](crate::net::protocol::ResponseError).
+    /// Streamed state frames are skipped.
+    pub async fn await_response(&mut self) -> anyhow::Result<Response> {
+        loop {
+            let msg = self
+                .stream
+                .next()
+                .await
+                .ok_or_else(|| anyhow!("server closed the connection"))??;
+            match msg {
+                Message::Text(text) if self.mode == WebSocketMode::Json => {
+                    return ws_json::decode_result(&text)
+                }
+                Message::Text(text) => return parse_response_bin(text.as_bytes()),
+                Message::Binary(data) => match parse_response_binary(&data) {
+                    Ok(Some((response, _))) => return Ok(response?),
+                    Ok(None) => return Err(anyhow!("server sent an incomplete binary response")),
+                    Err(_) => tracing::trace!("Skipping binary message which is no response"),
+                },
+                Message::Close(_) => return Err(anyhow!("server closed the connection")),
+                // tungstenite answers pings of the server on its own
+                _ => {}
+            }
+        }
+    }
+
+    /// Send a single request to the connected server and wait for a response
+    ///
+    /// This method automatically flushes the underlying buffer so that the request is sent immediately.
+    pub async fn exchange(&mut self, request: Request) -> anyhow::Result<Response> {
+        self.send_request(request).await?;
+        self.flush().await?;
+        let response = self.await_response().await?;
+        Ok(response)
+    }
+
+    /// Flush the write buffer to immediately send all enqueued requests to the server.
+    pub async fn flush(&mut self) -> anyhow::Result<()> {
+        self.stream.flush().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::protocol::write_response_binary;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_modes() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("ws://{}/", listener.local_addr().unwrap())).unwrap();
+        let server = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for _ in 0..3 {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut json = false;
+                #[allow(clippy::result_large_err)]
+                let callback = |request: &tokio_tungstenite::tungstenite::handshake::server::Request,
+                                response| {
+                    json = request.uri().query() == Some("format=json");
+                    Ok(response)
+                };
+                let mut stream = tokio_tungstenite::accept_hdr_async(stream, callback)
+                    .await
+                    .unwrap();
+                let request = stream.next().await.unwrap().unwrap();
+                let size = Response::Size {
+                    width: 800,
+                    height: 600,
+                };
+                let response = match &request {
+                    Message::Binary(_) => {
+                        let mut buf = Vec::new();
+                        write_response_binary(&size, &mut buf).unwrap();
+                        Message::Binary(buf)
+                    }
+                    _ if json => Message::Text(r#"{"cmd":"size","width":800,"height":600}"#.to_string()),
+                    _ => Message::Text(size.to_string()),
+                };
+                stream.send(response).await.unwrap();
+                requests.push(request);
+            }
+            requests
+        });
+
+        for mode in [WebSocketMode::Text, WebSocketMode::Json, WebSocketMode::Binary] {
+            let mut client = WebSocketClient::connect(&url, mode).await.unwrap();
+            assert_eq!(
+                client.exchange(Request::GetSize).await.unwrap(),
+                Response::Size {
+                    width: 800,
+                    height: 600
+                }
+            );
+        }
+        assert_eq!(
+            server.await.unwrap(),
+            vec![
+                Message::Text("SIZE".to_string()),
+                Message::Text(r#"{"cmd":"size"}"#.to_string()),
+                Message::Binary(vec![0x03])
+            ]
+        );
+    }
+}
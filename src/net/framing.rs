@@ -0,0 +1,380 @@
+//! Splitting of connection input buffers into individual request frames
+
+use crate::net::protocol::{
+    parse_image_header_bytes, parse_request_bin, parse_request_binary, parse_request_pb, split_tag, ParseErr,
+    ProtocolVariant, Request, PB_PREFIX, PB_RECORD_LEN,
+};
+use bytes::{Buf, BytesMut};
+
+/// A single request frame that was split off of a connections input buffer
+///
+/// Frames of the text protocol carry the tag with which the client prefixed the request, if any.
+#[derive(Debug)]
+pub(crate) enum Frame {
+    /// One line of the text protocol which was parsed in place, or the reason why it is no valid request
+    Text(Option<u32>, Result<Request, ParseErr>),
+    /// A request that was already decoded, e.g. from the binary protocol or an upload with a binary payload
+    Decoded(Option<u32>, Request),
+}
+
+/// The input buffer of a connection from which request frames are split off as soon as they are complete
+///
+/// The buffer remembers how much of an incomplete line it has already searched for its end so that the search
+/// resumes where it stopped when more data is received.
+#[derive(Debug, Default)]
+pub(crate) struct FrameBuffer {
+    data: BytesMut,
+    /// How many bytes at the start of `data` are known to not contain a newline
+    scanned: usize,
+    /// How long lines of the text protocol may be, or `None` if they are not limited
+    max_line_len: Option<usize>,
+    /// Whether the rest of a line which was too long is skipped until its end is received
+    discarding: bool,
+    /// Whether the buffer holds a whole self-contained message like a datagram, so that its last line is complete
+    /// even if it is not terminated
+    message: bool,
+}
+
+impl FrameBuffer {
+    /// Create an empty buffer which can hold `capacity` bytes without reallocating
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            data: BytesMut::with_capacity(capacity),
+            ..Self::default()
+        }
+    }
+
+    /// Create a buffer which holds a whole self-contained message like a datagram
+    ///
+    /// The last line of the message does not need to be terminated.
+    pub fn message(data: BytesMut) -> Self {
+        Self {
+            data,
+            message: true,
+            ..Self::default()
+        }
+    }
+
+    /// Reject lines of the text protocol which are longer than `max_line_len`
+    pub fn with_max_line_len(mut self, max_line_len: Option<usize>) -> Self {
+        self.max_line_len = max_line_len;
+        self
+    }
+
+    /// The buffered data into which received data should be read
+    ///
+    /// Data must only be appended, otherwise the buffer has to be cleared via [`FrameBuffer::clear`].
+    pub fn data_mut(&mut self) -> &mut BytesMut {
+        &mut self.data
+    }
+
+    /// How many bytes are buffered
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Discard all buffered data
+    pub fn clear(&mut self) {
+        self.data.clear();
+        self.scanned = 0;
+        self.discarding = false;
+    }
+
+    /// Discard all buffered data if it is longer than the maximum line length but contains no complete frame
+    ///
+    /// The buffer may grow beyond the maximum line length until the data of an image upload is complete.
+    /// The rest of a discarded line is skipped as it is received so that it is not mistaken for further requests.
+    /// Returns whether data was discarded.
+    pub fn discard_overlong_frame(&mut self, protocol: ProtocolVariant) -> bool {
+        let Some(max_len) = self.pending_frame_len(protocol).or(self.max_line_len) else {
+            return false;
+        };
+        if self.data.len() <= max_len {
+            return false;
+        }
+        self.clear();
+        self.discarding = protocol == ProtocolVariant::Text;
+        true
+    }
+
+    /// The position of the next newline which continues the search where the previous one stopped
+    ///
+    /// The end of a message counts as the end of its last line.
+    fn find_newline(&mut self) -> Option<usize> {
+        match self.data[self.scanned..].iter().position(|&b| b == b'\n') {
+            Some(i) => Some(self.scanned + i),
+            None if self.message && !self.data.is_empty() => Some(self.data.len()),
+            None => {
+                self.scanned = self.data.len();
+                None
+            }
+        }
+    }
+
+    /// Discard the line which ends at position `i` together with its newline, if it has one
+    fn consume_line(&mut self, i: usize) {
+        self.data.advance((i + 1).min(self.data.len()));
+        self.scanned = 0;
+    }
+
+    /// Split the next complete frame off of the start of the buffer
+    ///
+    /// `Ok(None)` is returned if the buffer does not contain a complete frame yet.
+    /// If an error is returned, the buffer is left untouched but cannot be split into further frames because the
+    /// binary protocol has no way to find the start of the next frame.
+    pub fn next_frame(&mut self, protocol: ProtocolVariant) -> Result<Option<Frame>, ParseErr> {
+        if self.discarding && protocol == ProtocolVariant::Text {
+            match self.find_newline() {
+                None => {
+                    self.data.clear();
+                    self.scanned = 0;
+                    return Ok(None);
+                }
+                Some(i) => {
+                    self.consume_line(i);
+                    self.discarding = false;
+                }
+            }
+        }
+        match protocol {
+            // pixel records are not newline terminated and may contain newlines in their binary fields
+            ProtocolVariant::Text if self.data.starts_with(PB_PREFIX) => match parse_request_pb(&self.data) {
+                Some(request) => {
+                    self.data.advance(PB_RECORD_LEN);
+                    self.scanned = 0;
+                    Ok(Some(Frame::Decoded(None, request)))
+                }
+                None => Ok(None),
+            },
+            ProtocolVariant::Text => {
+                let Some(i) = self.find_newline() else {
+                    return Ok(None);
+                };
+                if self.max_line_len.is_some_and(|max_len| i > max_len) {
+                    self.consume_line(i);
+                    return Ok(Some(Frame::Text(None, Err(ParseErr::LineTooLong))));
+                }
+                let (tag, line) = split_tag(&self.data[..i]);
+                let frame = match image_header(line) {
+                    // image data directly follows the header line
+                    Some((x, y, len)) => {
+                        if self.data.len() < i + 1 + len {
+                            return Ok(None);
+                        }
+                        self.data.advance(i + 1);
+                        let data = self.data.split_to(len).freeze();
+                        Frame::Decoded(tag, Request::PutImage { x, y, data })
+                    }
+                    None => {
+                        tracing::trace!("Handling single request {:?}", String::from_utf8_lossy(line));
+                        let request = parse_request_bin(line);
+                        self.consume_line(i);
+                        Frame::Text(tag, request)
+                    }
+                };
+                self.scanned = 0;
+                Ok(Some(frame))
+            }
+            ProtocolVariant::Binary => match parse_request_binary(&self.data)? {
+                Some((request, len)) => {
+                    self.data.advance(len);
+                    self.scanned = 0;
+                    Ok(Some(Frame::Decoded(None, request)))
+                }
+                None => Ok(None),
+            },
+        }
+    }
+
+    /// How many bytes the incomplete frame at the start of the buffer will have once it is completely received
+    ///
+    /// This is only known for image uploads whose header line has already been received and `None` otherwise.
+    pub fn pending_frame_len(&self, protocol: ProtocolVariant) -> Option<usize> {
+        match protocol {
+            ProtocolVariant::Text => {
+                let i = self.data.iter().position(|&b| b == b'\n')?;
+                let (_, line) = split_tag(&self.data[..i]);
+                let (_, _, len) = image_header(line)?;
+                Some(i + 1 + len)
+            }
+            ProtocolVariant::Binary => None,
+        }
+    }
+}
+
+impl From<BytesMut> for FrameBuffer {
+    fn from(data: BytesMut) -> Self {
+        Self {
+            data,
+            ..Self::default()
+        }
+    }
+}
+
+/// Parse `line` as the header of an image upload
+///
+/// Invalid headers are not reported here but later when the line is parsed as a normal request.
+fn image_header(line: &[u8]) -> Option<(usize, usize, usize)> {
+    if !line
+        .get(..3)
+        .is_some_and(|command| command.eq_ignore_ascii_case(b"IMG"))
+    {
+        return None;
+    }
+    parse_image_header_bytes(line).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pixmap::Color;
+
+    #[test]
+    fn test_image_upload_frame() {
+        let mut buf = FrameBuffer::from(BytesMut::from(&b"IMG 1 2 5\n\x89PN"[..]));
+        assert_eq!(buf.pending_frame_len(ProtocolVariant::Text), Some(15));
+        assert!(buf.next_frame(ProtocolVariant::Text).unwrap().is_none());
+
+        buf.data_mut().extend_from_slice(b"G\nSIZE\n");
+        match buf.next_frame(ProtocolVariant::Text).unwrap() {
+            Some(Frame::Decoded(None, Request::PutImage { x: 1, y: 2, data })) => {
+                assert_eq!(&data[..], b"\x89PNG\n")
+            }
+            frame => panic!("expected an image upload but got {:?}", frame),
+        }
+        assert!(matches!(
+            buf.next_frame(ProtocolVariant::Text),
+            Ok(Some(Frame::Text(None, Ok(Request::GetSize))))
+        ));
+
+        // image uploads are recognized with the same tolerance as other requests
+        let mut buf = FrameBuffer::from(BytesMut::from(&b"img\t3 4 2\r\nOK"[..]));
+        assert!(matches!(
+            buf.next_frame(ProtocolVariant::Text),
+            Ok(Some(Frame::Decoded(None, Request::PutImage { x: 3, y: 4, data }))) if &data[..] == b"OK"
+        ));
+    }
+
+    #[test]
+    fn test_resume_across_reads() {
+        let mut buf = FrameBuffer::with_capacity(64);
+        buf.data_mut().extend_from_slice(b"PX 1 2 AA");
+        assert!(buf.next_frame(ProtocolVariant::Text).unwrap().is_none());
+        assert_eq!(buf.scanned, 9);
+
+        buf.data_mut().extend_from_slice(b"BBCC\nSI");
+        let px = Request::SetPixel {
+            x: 1,
+            y: 2,
+            color: Color::from((0xAA, 0xBB, 0xCC)),
+        };
+        assert!(matches!(
+            buf.next_frame(ProtocolVariant::Text),
+            Ok(Some(Frame::Text(None, Ok(request)))) if request == px
+        ));
+        assert!(buf.next_frame(ProtocolVariant::Text).unwrap().is_none());
+
+        buf.data_mut().extend_from_slice(b"ZE\nFOO\n");
+        assert!(matches!(
+            buf.next_frame(ProtocolVariant::Text),
+            Ok(Some(Frame::Text(None, Ok(Request::GetSize))))
+        ));
+        assert!(matches!(
+            buf.next_frame(ProtocolVariant::Text),
+            Ok(Some(Frame::Text(None, Err(ParseErr::UnknownCommand))))
+        ));
+        assert_eq!(buf.len(), 0);
+    }
+
+    #[test]
+    fn test_overlong_lines() {
+        let mut buf = FrameBuffer::with_capacity(64).with_max_line_len(Some(8));
+        buf.data_mut().extend_from_slice(b"PX 1 2 AABBCC\nSIZE\n");
+        assert!(matches!(
+            buf.next_frame(ProtocolVariant::Text),
+            Ok(Some(Frame::Text(None, Err(ParseErr::LineTooLong))))
+        ));
+        assert!(matches!(
+            buf.next_frame(ProtocolVariant::Text),
+            Ok(Some(Frame::Text(None, Ok(Request::GetSize))))
+        ));
+
+        // incomplete lines are discarded together with their rest once it arrives
+        buf.data_mut().extend_from_slice(b"PX 1 2 AA");
+        assert!(buf.next_frame(ProtocolVariant::Text).unwrap().is_none());
+        assert!(buf.discard_overlong_frame(ProtocolVariant::Text));
+        assert_eq!(buf.len(), 0);
+        buf.data_mut().extend_from_slice(b"PB\x01\x00\x0B\x00");
+        assert!(buf.next_frame(ProtocolVariant::Text).unwrap().is_none());
+        assert_eq!(buf.len(), 0);
+        buf.data_mut().extend_from_slice(b"\xFF\x00\x00\xFF\nSIZE\n");
+        assert!(matches!(
+            buf.next_frame(ProtocolVariant::Text),
+            Ok(Some(Frame::Text(None, Ok(Request::GetSize))))
+        ));
+
+        // image uploads may exceed the limit
+        buf.data_mut().extend_from_slice(b"IMG 0 0 9\n\x89PNG");
+        assert!(!buf.discard_overlong_frame(ProtocolVariant::Text));
+    }
+
+    #[test]
+    fn test_message_frames() {
+        let mut buf = FrameBuffer::message(BytesMut::from(&b"SIZE\nPX 1 2"[..]));
+        assert!(matches!(
+            buf.next_frame(ProtocolVariant::Text),
+            Ok(Some(Frame::Text(None, Ok(Request::GetSize))))
+        ));
+        assert!(matches!(
+            buf.next_frame(ProtocolVariant::Text),
+            Ok(Some(Frame::Text(None, Ok(Request::GetPixel { x: 1, y: 2 }))))
+        ));
+        assert_eq!(buf.len(), 0);
+        assert!(buf.next_frame(ProtocolVariant::Text).unwrap().is_none());
+
+        // image uploads must still be complete
+        let mut buf = FrameBuffer::message(BytesMut::from(&b"IMG 1 2 5"[..]));
+        assert!(buf.next_frame(ProtocolVariant::Text).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_pb_frames() {
+        let mut buf = FrameBuffer::from(BytesMut::from(
+            &b"PB\x01\x00\x0A\x00\xFF\x00\x00\xFFSIZE\nPB\x02"[..],
+        ));
+        assert!(matches!(
+            buf.next_frame(ProtocolVariant::Text),
+            Ok(Some(Frame::Decoded(None, Request::SetPixel { x: 1, y: 10, .. })))
+        ));
+        assert!(matches!(
+            buf.next_frame(ProtocolVariant::Text),
+            Ok(Some(Frame::Text(None, Ok(Request::GetSize))))
+        ));
+        assert!(buf.next_frame(ProtocolVariant::Text).unwrap().is_none());
+        buf.data_mut().extend_from_slice(b"\x00\x03\x00\x00\x00\x00\x00");
+        assert!(matches!(
+            buf.next_frame(ProtocolVariant::Text),
+            Ok(Some(Frame::Decoded(None, Request::SetPixel { x: 2, y: 3, .. })))
+        ));
+        assert_eq!(buf.len(), 0);
+    }
+
+    #[test]
+    fn test_tagged_frames() {
+        let mut buf = FrameBuffer::from(BytesMut::from(&b"#42 SIZE\n#7 IMG 1 2 2\nOK#x SIZE\n"[..]));
+        assert!(matches!(
+            buf.next_frame(ProtocolVariant::Text),
+            Ok(Some(Frame::Text(Some(42), Ok(Request::GetSize))))
+        ));
+        assert_eq!(buf.pending_frame_len(ProtocolVariant::Text), Some(15));
+        assert!(matches!(
+            buf.next_frame(ProtocolVariant::Text),
+            Ok(Some(Frame::Decoded(Some(7), Request::PutImage { x: 1, y: 2, data }))) if &data[..] == b"OK"
+        ));
+        // tags must be decimal numbers
+        assert!(matches!(
+            buf.next_frame(ProtocolVariant::Text),
+            Ok(Some(Frame::Text(None, Err(ParseErr::UnknownCommand))))
+        ));
+    }
+}
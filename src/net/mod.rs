@@ -3,5 +3,6 @@
 //!
 
 pub mod clients;
+pub(crate) mod framing;
 pub mod protocol;
 pub mod servers;
@@ -0,0 +1,329 @@
+//! A compact binary encoding of the pixelflut protocol
+//!
+//! Every message starts with a one byte opcode which is followed by a fixed number of big-endian encoded arguments.
+//! Coordinates and sizes are encoded as `u16` while colors are encoded as `u32` in `0RGB` format.
+//!
+//! Independently of that, the compact `PB` pixel records which other pixelflut servers accept can be mixed into the
+//! text protocol.
+
+use crate::net::protocol::compliant_parser::{parse_error_response, ParseErr};
+use crate::net::protocol::{ProtocolVariant, Request, Response, ResponseError, StateAlgorithm};
+use crate::pixmap::Color;
+use std::io::{Error, ErrorKind, Write};
+
+/// Opcode for switching back to the text protocol
+const OP_TEXT: u8 = 0x00;
+/// Opcode for setting a pixel
+const OP_SET_PIXEL: u8 = 0x01;
+/// Opcode for getting a pixels color (and its response)
+const OP_GET_PIXEL: u8 = 0x02;
+/// Opcode for getting the canvas size (and its response)
+const OP_SIZE: u8 = 0x03;
+/// Opcode of a streamed state frame
+const OP_STATE: u8 = 0x04;
+/// Opcode of an error response
+const OP_ERROR: u8 = 0xFF;
+
+/// The prefix of a `PB` pixel record
+pub const PB_PREFIX: &[u8; 2] = b"PB";
+
+/// The length of a `PB` pixel record
+///
+/// A record consists of [`PB_PREFIX`], the coordinates as little-endian `u16` and the color as `RGBA` bytes.
+/// It is not terminated by a newline.
+pub const PB_RECORD_LEN: usize = 10;
+
+#[inline(always)]
+fn read_u16(buf: &[u8], offset: usize) -> usize {
+    u16::from_be_bytes([buf[offset], buf[offset + 1]]) as usize
+}
+
+#[inline(always)]
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([buf[offset], buf[offset + 1], buf[offset + 2], buf[offset + 3]])
+}
+
+#[inline(always)]
+fn encode_u16(value: usize) -> std::io::Result<[u8; 2]> {
+    u16::try_from(value).map(u16::to_be_bytes).map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            "value is too large for the binary protocol",
+        )
+    })
+}
+
+/// Try to parse a single binary encoded request from the start of `buf`
+///
+/// On success, the request is returned together with the number of bytes it occupied.
+/// If `buf` does not yet contain a complete request, `Ok(None)` is returned.
+#[inline(always)]
+pub fn parse_request_binary(buf: &[u8]) -> Result<Option<(Request, usize)>, ParseErr> {
+    let Some(opcode) = buf.first() else {
+        return Ok(None);
+    };
+    let len = match *opcode {
+        OP_TEXT | OP_SIZE => 1,
+        OP_GET_PIXEL => 5,
+        OP_SET_PIXEL => 9,
+        _ => return Err(ParseErr::UnknownCommand),
+    };
+    if buf.len() < len {
+        return Ok(None);
+    }
+
+    let request = match *opcode {
+        OP_TEXT => Request::SetProtocol(ProtocolVariant::Text),
+        OP_SIZE => Request::GetSize,
+        OP_GET_PIXEL => Request::GetPixel {
+            x: read_u16(buf, 1),
+            y: read_u16(buf, 3),
+        },
+        OP_SET_PIXEL => Request::SetPixel {
+            x: read_u16(buf, 1),
+            y: read_u16(buf, 3),
+            color: Color::from(read_u32(buf, 5)),
+        },
+        _ => unreachable!(),
+    };
+    Ok(Some((request, len)))
+}
+
+/// Try to parse a `PB` pixel record from the start of `buf`
+///
+/// `None` is returned if `buf` does not start with a complete record.
+/// Like other servers which support these records, the alpha channel is ignored.
+#[inline(always)]
+pub fn parse_request_pb(buf: &[u8]) -> Option<Request> {
+    if buf.len() < PB_RECORD_LEN || !buf.starts_with(PB_PREFIX) {
+        return None;
+    }
+    Some(Request::SetPixel {
+        x: u16::from_le_bytes([buf[2], buf[3]]) as usize,
+        y: u16::from_le_bytes([buf[4], buf[5]]) as usize,
+        color: Color::from((buf[6], buf[7], buf[8])),
+    })
+}
+
+/// Write a request which sets a pixel as `PB` pixel record into the given writer
+///
+/// Other requests have no such representation and are encoded using the text protocol.
+pub fn write_request_pb(request: &Request, writer: &mut impl Write) -> std::io::Result<()> {
+    let (x, y, color, alpha) = match request {
+        Request::SetPixel { x, y, color } => (x, y, color, u8::MAX),
+        Request::BlendPixel { x, y, color, alpha } => (x, y, color, *alpha),
+        _ => return request.write(writer),
+    };
+    let [r, g, b] = <[u8; 3]>::from(*color);
+    let (x, y) = (encode_u16(*x)?, encode_u16(*y)?);
+    writer.write_all(PB_PREFIX)?;
+    // unlike in the binary protocol, coordinates are little-endian
+    writer.write_all(&[x[1], x[0], y[1], y[0], r, g, b, alpha])
+}
+
+/// Whether a request has a binary representation which servers understand while they use the binary protocol
+pub fn has_binary_encoding(request: &Request) -> bool {
+    matches!(
+        request,
+        Request::SetProtocol(ProtocolVariant::Text)
+            | Request::GetSize
+            | Request::GetPixel { .. }
+            | Request::SetPixel { .. }
+    )
+}
+
+/// Write the binary representation of a request into the given writer
+///
+/// Requests which have no binary representation (e.g. help requests, see [`has_binary_encoding`]) are encoded using
+/// the text protocol.
+pub fn write_request_binary(request: &Request, writer: &mut impl Write) -> std::io::Result<()> {
+    match request {
+        Request::SetProtocol(ProtocolVariant::Text) => writer.write_all(&[OP_TEXT]),
+        Request::GetSize => writer.write_all(&[OP_SIZE]),
+        Request::GetPixel { x, y } => {
+            writer.write_all(&[OP_GET_PIXEL])?;
+            writer.write_all(&encode_u16(*x)?)?;
+            writer.write_all(&encode_u16(*y)?)
+        }
+        Request::SetPixel { x, y, color } => {
+            writer.write_all(&[OP_SET_PIXEL])?;
+            writer.write_all(&encode_u16(*x)?)?;
+            writer.write_all(&encode_u16(*y)?)?;
+            writer.write_all(&u32::from(*color).to_be_bytes())
+        }
+        _ => request.write(writer),
+    }
+}
+
+/// Write the binary representation of a response into the given writer
+///
+/// Responses which have no binary representation (e.g. help texts or protocol confirmations) are encoded using the
+/// text protocol.
+pub fn write_response_binary(response: &Response, writer: &mut impl Write) -> std::io::Result<()> {
+    match response {
+        Response::Size { width, height } => {
+            writer.write_all(&[OP_SIZE])?;
+            writer.write_all(&encode_u16(*width)?)?;
+            writer.write_all(&encode_u16(*height)?)
+        }
+        Response::PxData { x, y, color } => {
+            writer.write_all(&[OP_GET_PIXEL])?;
+            writer.write_all(&encode_u16(*x)?)?;
+            writer.write_all(&encode_u16(*y)?)?;
+            writer.write_all(&u32::from(*color).to_be_bytes())
+        }
+        _ => response.write(writer),
+    }
+}
+
+/// Try to parse a single binary encoded response from the start of `buf`
+///
+/// On success, the response or the error which the server sent instead is returned together with the number of
+/// bytes it occupied. If `buf` does not yet contain a complete response, `Ok(None)` is returned.
+/// Responses which have no binary representation and streamed state frames cannot be parsed with this.
+#[inline(always)]
+#[allow(clippy::type_complexity)]
+pub fn parse_response_binary(
+    buf: &[u8],
+) -> Result<Option<(Result<Response, ResponseError>, usize)>, ParseErr> {
+    let Some(opcode) = buf.first() else {
+        return Ok(None);
+    };
+    let len = match *opcode {
+        OP_SIZE => 5,
+        OP_GET_PIXEL => 9,
+        OP_ERROR if buf.len() >= 2 => 2 + buf[1] as usize,
+        OP_ERROR => 2,
+        _ => return Err(ParseErr::UnknownCommand),
+    };
+    if buf.len() < len {
+        return Ok(None);
+    }
+
+    let response = match *opcode {
+        OP_SIZE => Ok(Response::Size {
+            width: read_u16(buf, 1),
+            height: read_u16(buf, 3),
+        }),
+        OP_GET_PIXEL => Ok(Response::PxData {
+            x: read_u16(buf, 1),
+            y: read_u16(buf, 3),
+            color: Color::from(read_u32(buf, 5)),
+        }),
+        OP_ERROR => {
+            let message = std::str::from_utf8(&buf[2..len]).map_err(|_| ParseErr::InvalidCommand)?;
+            Err(parse_error_response(message).ok_or(ParseErr::InvalidCommand)?)
+        }
+        _ => unreachable!(),
+    };
+    Ok(Some((response, len)))
+}
+
+/// Write a streamed state frame in its binary representation into the given writer
+///
+/// The frame consists of the opcode, the algorithm (0 for `rgb64`, 1 for `delta`, 2 for `px`), the `token` and
+/// `base` of delta frames as `u64` (0 otherwise) and the length of the data as `u32`, followed by the data itself.
+/// Unlike in text frames, the data is not encoded as base64.
+pub fn write_state_binary(
+    algorithm: StateAlgorithm,
+    token: u64,
+    base: u64,
+    data: &[u8],
+    writer: &mut impl Write,
+) -> std::io::Result<()> {
+    let algorithm = match algorithm {
+        StateAlgorithm::Rgb64 => 0u8,
+        StateAlgorithm::Delta => 1u8,
+        StateAlgorithm::Px => 2u8,
+    };
+    let len = u32::try_from(data.len())
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, "state frame is too large"))?;
+    writer.write_all(&[OP_STATE, algorithm])?;
+    writer.write_all(&token.to_be_bytes())?;
+    writer.write_all(&base.to_be_bytes())?;
+    writer.write_all(&len.to_be_bytes())?;
+    writer.write_all(data)
+}
+
+/// Write an error message in its binary representation into the given writer
+///
+/// Messages longer than 255 bytes are truncated.
+pub fn write_error_binary(message: &str, writer: &mut impl Write) -> std::io::Result<()> {
+    let message = &message.as_bytes()[..message.len().min(u8::MAX as usize)];
+    writer.write_all(&[OP_ERROR, message.len() as u8])?;
+    writer.write_all(message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    quickcheck! {
+        fn test_request_encoding_inversion(x: u16, y: u16, color: Color) -> bool {
+            let request = Request::SetPixel { x: x as usize, y: y as usize, color };
+            let mut buf = Vec::new();
+            write_request_binary(&request, &mut buf).unwrap();
+            parse_request_binary(&buf) == Ok(Some((request, buf.len())))
+        }
+
+        fn test_pb_encoding_inversion(x: u16, y: u16, color: Color) -> bool {
+            // records only carry the rgb channels of a color
+            let color = Color::from(<[u8; 3]>::from(color));
+            let request = Request::SetPixel { x: x as usize, y: y as usize, color };
+            let mut buf = Vec::new();
+            write_request_pb(&request, &mut buf).unwrap();
+            buf.len() == PB_RECORD_LEN && parse_request_pb(&buf) == Some(request)
+        }
+    }
+
+    #[test]
+    fn test_parse_incomplete_request() {
+        assert_eq!(parse_request_binary(&[]), Ok(None));
+        assert_eq!(parse_request_binary(&[OP_SET_PIXEL, 0, 1, 0, 2]), Ok(None));
+        assert_eq!(
+            parse_request_binary(&[OP_GET_PIXEL, 0, 1, 0, 2, OP_SIZE]),
+            Ok(Some((Request::GetPixel { x: 1, y: 2 }, 5)))
+        );
+        assert_eq!(parse_request_binary(&[0x42]), Err(ParseErr::UnknownCommand));
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let response = Response::PxData {
+            x: 1,
+            y: 2,
+            color: Color::from((0xAA, 0xBB, 0xCC)),
+        };
+        let mut buf = Vec::new();
+        write_response_binary(&response, &mut buf).unwrap();
+        write_error_binary("ERR OUT_OF_BOUNDS Pixel 900,20 is outside the canvas", &mut buf).unwrap();
+        assert_eq!(parse_response_binary(&buf), Ok(Some((Ok(response), 9))));
+        assert_eq!(
+            parse_response_binary(&buf[9..]),
+            Ok(Some((
+                Err(ResponseError::OutOfBounds(
+                    "Pixel 900,20 is outside the canvas".to_string()
+                )),
+                buf.len() - 9
+            )))
+        );
+        assert_eq!(parse_response_binary(&buf[..4]), Ok(None));
+        assert_eq!(parse_response_binary(&buf[9..12]), Ok(None));
+        assert_eq!(parse_response_binary(b"HELP"), Err(ParseErr::UnknownCommand));
+    }
+
+    #[test]
+    fn test_parse_pb_record() {
+        let record = b"PB\x01\x02\x03\x04\xAA\xBB\xCC\x00";
+        assert_eq!(
+            parse_request_pb(record),
+            Some(Request::SetPixel {
+                x: 0x0201,
+                y: 0x0403,
+                color: Color::from((0xAA, 0xBB, 0xCC)),
+            })
+        );
+        assert_eq!(parse_request_pb(&record[..9]), None);
+        assert_eq!(parse_request_pb(b"PX 1 2 FF0000\n"), None);
+    }
+}
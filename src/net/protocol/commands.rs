@@ -0,0 +1,536 @@
+//! Declarative descriptions of all commands from which the HELP texts are generated
+//!
+//! Every command that a server understands has one entry in [`COMMANDS`] so that `HELP` always lists all of them
+//! and `HELP <command>` explains them consistently.
+
+use crate::net::protocol::HelpTopic;
+
+/// The description of a single command as it is presented in its help text
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct CommandDescription {
+    /// The help topic which explains this command
+    pub topic: HelpTopic,
+    /// The name of the command as it is sent in requests
+    pub name: &'static str,
+    /// A one-line summary which is shown in the list of all commands
+    pub summary: &'static str,
+    /// The syntax of a request
+    pub syntax: &'static str,
+    /// The syntax of the response or `None` if the server does not respond
+    pub response: Option<&'static str>,
+    /// Lines which describe what the command does
+    pub description: &'static [&'static str],
+    /// The arguments of the command together with the type of their values and a description of each
+    ///
+    /// Descriptions may span multiple lines.
+    pub arguments: &'static [(&'static str, ArgumentType, &'static str)],
+    /// Lines with further notes that are shown after the arguments
+    pub notes: &'static [&'static str],
+    /// Example requests
+    pub examples: &'static [&'static str],
+}
+
+/// The type of the values which an argument of a command accepts
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ArgumentType {
+    /// A non-negative decimal number
+    Integer,
+    /// A HEX encoded color
+    Color,
+    /// Printable ASCII characters without whitespace
+    Text,
+    /// One of the names which are listed in the description of the argument
+    Choice,
+    /// Raw binary data
+    Bytes,
+    /// Binary data encoded as base64
+    Base64,
+}
+
+impl ArgumentType {
+    /// The name of this type as it is used in machine-readable descriptions of the commands
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ArgumentType::Integer => "integer",
+            ArgumentType::Color => "color",
+            ArgumentType::Text => "text",
+            ArgumentType::Choice => "choice",
+            ArgumentType::Bytes => "bytes",
+            ArgumentType::Base64 => "base64",
+        }
+    }
+}
+
+/// All commands of the pixelflut protocol
+pub static COMMANDS: &[CommandDescription] = &[
+    CommandDescription {
+        topic: HelpTopic::Size,
+        name: "SIZE",
+        summary: "Get the current canvas size",
+        syntax: "SIZE",
+        response: Some("SIZE <width> <height>"),
+        description: &[
+            "Returns the current canvas size.",
+            "This server does not support changing the canvas size at runtime so the result can safely be cached",
+        ],
+        arguments: &[],
+        notes: &[],
+        examples: &["SIZE"],
+    },
+    CommandDescription {
+        topic: HelpTopic::Px,
+        name: "PX",
+        summary: "Get or set one specific pixels color",
+        syntax: "PX <x> <y> [<rgb> [<mode>]|<gray> [<mode>]|<rgba>]",
+        response: Some("[PX <x> <y> <rgb>]"),
+        description: &[
+            "Gets or sets the pixel color addressed by the coordinates <x> and <y>.",
+            "The mode of operation is determined by the third argument (<rgb>, <gray> or <rgba>) being present or not.",
+            "If it is present, the pixel will be set to that color and no response will be sent.",
+            "It it is not present, the current color will be returned.",
+        ],
+        arguments: &[
+            ("<x>", ArgumentType::Integer, "X position on the canvas counted from the left side"),
+            ("<y>", ArgumentType::Integer, "Y position on the canvas counted from the top"),
+            ("<rgb>", ArgumentType::Color, "HEX encoded rgb color (000000 - FFFFFF)"),
+            (
+                "<gray>",
+                ArgumentType::Color,
+                "HEX encoded shade of gray (00 - FF) which is short for the rgb color <gray><gray><gray>",
+            ),
+            (
+                "<rgba>",
+                ArgumentType::Color,
+                "HEX encoded rgb color with alpha (00000000 - FFFFFFFF) which is blended onto the current color",
+            ),
+            (
+                "<mode>",
+                ArgumentType::Choice,
+                "How the color is combined with the current color: normal, add, multiply or screen",
+            ),
+        ],
+        notes: &[
+            "Up to 100 pixels can be set at once using 'PXB <count> <x> <y> <rgb> [<x> <y> <rgb> ...]'.",
+            "Pixels can also be set with 10 byte binary records as supported by other servers: 'PB' followed by <x>",
+            "and <y> as little-endian u16 and the color as r, g, b and a bytes, without newline. Alpha is ignored.",
+        ],
+        examples: &["PX 10 20", "PX 10 20 FF0000", "PX 10 20 80", "PX 10 20 FF000080", "PX 10 20 400000 add", "PXB 2 10 20 FF0000 11 20 00FF00"],
+    },
+    CommandDescription {
+        topic: HelpTopic::PxGet,
+        name: "PXGET",
+        summary: "Get the colors of all pixels in a rectangle",
+        syntax: "PXGET <x> <y> <width> <height>",
+        response: Some("PXGET <x> <y> <width> <height> <data>"),
+        description: &[
+            "Gets the colors of all pixels in the rectangle whose top-left corner is at <x> and <y> at once.",
+            "The rectangle must lie completely inside the canvas and contain at most 4096 pixels.",
+        ],
+        arguments: &[
+            ("<x>", ArgumentType::Integer, "X position of the top-left corner counted from the left side"),
+            ("<y>", ArgumentType::Integer, "Y position of the top-left corner counted from the top"),
+            ("<width>", ArgumentType::Integer, "Width of the rectangle"),
+            ("<height>", ArgumentType::Integer, "Height of the rectangle"),
+            ("<data>", ArgumentType::Base64, "The rgb values of all pixels, row by row, encoded as base64"),
+        ],
+        notes: &[],
+        examples: &["PXGET 10 20 64 64"],
+    },
+    CommandDescription {
+        topic: HelpTopic::Protocol,
+        name: "PROTOCOL",
+        summary: "Switch between the text and binary protocol",
+        syntax: "PROTOCOL <TEXT|BINARY>",
+        response: Some("PROTOCOL <TEXT|BINARY>"),
+        description: &[
+            "Switches the encoding of all following requests and responses on this connection.",
+            "The response is always sent as text and confirms the switch.",
+        ],
+        arguments: &[],
+        notes: &[
+            "In binary mode, every message starts with a one byte opcode followed by big-endian encoded arguments:",
+            "0x00                           - Switch back to the text protocol",
+            "0x01 <x:u16> <y:u16> <rgb:u32> - Set a pixels color (PX <x> <y> <rgb>)",
+            "0x02 <x:u16> <y:u16>           - Get a pixels color, answered with 0x02 <x:u16> <y:u16> <rgb:u32>",
+            "0x03                           - Get the canvas size, answered with 0x03 <width:u16> <height:u16>",
+            "Errors are answered with 0xFF <len:u8> followed by <len> bytes of an ASCII ERR <code> <message> line.",
+            "WebSocket connections don't switch protocols. Instead, binary messages carry any number of binary",
+            "requests and are answered with a binary message containing their responses.",
+        ],
+        examples: &["PROTOCOL BINARY"],
+    },
+    CommandDescription {
+        topic: HelpTopic::Info,
+        name: "INFO",
+        summary: "Get information about the capabilities of this server",
+        syntax: "INFO",
+        response: Some("INFO <key>=<value> ..."),
+        description: &[
+            "Returns information about the capabilities of this server as a space separated list of key-value pairs.",
+            "Clients should ignore keys which they don't know since more information may be added in the future.",
+        ],
+        arguments: &[],
+        notes: &[
+            "size                 - The canvas size as <width>x<height>",
+            "protocols            - Comma separated list of the protocols which can be selected via PROTOCOL",
+            "extensions           - Comma separated list of optional protocol features, e.g. RGBA for PX with alpha blending",
+            "max-connects-per-sec - How many connections a single IP address may open per second (if limited)",
+        ],
+        examples: &["INFO"],
+    },
+    CommandDescription {
+        topic: HelpTopic::Rect,
+        name: "RECT",
+        summary: "Fill a rectangle with one color",
+        syntax: "RECT <x> <y> <width> <height> <rgb>",
+        response: None,
+        description: &[
+            "Fills the rectangle whose top-left corner is at <x> and <y> with one color.",
+            "The rectangle must lie completely inside the canvas.",
+        ],
+        arguments: &[
+            ("<x>", ArgumentType::Integer, "X position of the top-left corner counted from the left side"),
+            ("<y>", ArgumentType::Integer, "Y position of the top-left corner counted from the top"),
+            ("<width>", ArgumentType::Integer, "Width of the rectangle"),
+            ("<height>", ArgumentType::Integer, "Height of the rectangle"),
+            ("<rgb>", ArgumentType::Color, "HEX encoded rgb color (000000 - FFFFFF)"),
+        ],
+        notes: &[],
+        examples: &["RECT 10 20 100 50 00FF00"],
+    },
+    CommandDescription {
+        topic: HelpTopic::Line,
+        name: "LINE",
+        summary: "Draw a straight line with one color",
+        syntax: "LINE <x1> <y1> <x2> <y2> <rgb>",
+        response: None,
+        description: &[
+            "Draws a straight line from <x1> <y1> to <x2> <y2> with one color.",
+            "Both end points are part of the line and must lie inside the canvas.",
+        ],
+        arguments: &[
+            ("<x1> <y1>", ArgumentType::Integer, "Position of the start point"),
+            ("<x2> <y2>", ArgumentType::Integer, "Position of the end point"),
+            ("<rgb>", ArgumentType::Color, "HEX encoded rgb color (000000 - FFFFFF)"),
+        ],
+        notes: &[],
+        examples: &["LINE 0 0 99 49 0000FF"],
+    },
+    CommandDescription {
+        topic: HelpTopic::Img,
+        name: "IMG",
+        summary: "Upload an encoded image onto the canvas",
+        syntax: "IMG <x> <y> <len>\\n<data>",
+        response: None,
+        description: &[
+            "Draws an encoded image (e.g. PNG, JPEG or GIF) onto the canvas with its top-left corner at <x> and <y>.",
+            "The request line is directly followed by <len> bytes of image data.",
+            "Transparent parts of the image are blended onto the canvas and the image must fit completely inside of it.",
+        ],
+        arguments: &[
+            ("<x>", ArgumentType::Integer, "X position of the top-left corner counted from the left side"),
+            ("<y>", ArgumentType::Integer, "Y position of the top-left corner counted from the top"),
+            ("<len>", ArgumentType::Integer, "Size of the encoded image in bytes (at most 4MiB)"),
+            ("<data>", ArgumentType::Bytes, "The encoded image"),
+        ],
+        notes: &[],
+        examples: &["IMG 10 20 1337\\n<1337 bytes of PNG data>"],
+    },
+    CommandDescription {
+        topic: HelpTopic::Stream,
+        name: "STREAM",
+        summary: "Continuously receive the canvas state",
+        syntax: "STREAM <algorithm> <fps> [<x> <y> <width> <height>]",
+        response: Some("STATE <algorithm> [<token> <base>] <data> or PX <x> <y> <rrggbb> (repeatedly)"),
+        description: &[
+            "Makes the server send the current canvas state <fps> times per second until streaming is stopped by",
+            "sending STREAM with an <fps> of 0.",
+            "If a region is given, only its pixels are included in the frames so that clients which display a part of",
+            "the canvas don't receive the rest of it. The region must lie completely inside the canvas.",
+            "State frames are always sent as text lines, even if the binary protocol is used.",
+            "On WebSocket connections, each frame is instead sent as one binary message containing",
+            "0x04 <algorithm:u8> <token:u64> <base:u64> <len:u32> followed by <len> bytes of data which are not base64",
+            "encoded. <algorithm> is 0 for rgb64, 1 for delta and 2 for px and <token> and <base> are 0 unless the",
+            "algorithm is delta. The data of px frames consists of the same records as the data of delta frames.",
+        ],
+        arguments: &[
+            (
+                "<algorithm>",
+                ArgumentType::Choice,
+                "How the canvas state is encoded:\n\
+                 rgb64: The rgb values of all pixels of the region, row by row, encoded as base64\n\
+                 delta: Only the pixels which changed since the frame numbered <base> (0 for all pixels)\n\
+                 \x20      as base64 encoded records of x (u32), y (u32), r, g and b (u8 each).\n\
+                 \x20      Pixels which were drawn multiple times are only included once and\n\
+                 \x20      frames without changes are skipped.\n\
+                 px:    One PX <x> <y> <rrggbb> line for every pixel which changed since the\n\
+                 \x20      previous frame. The first frame is skipped so that only updates are sent.",
+            ),
+            ("<fps>", ArgumentType::Integer, "How many frames are sent per second (0 - 60)"),
+            ("<x>", ArgumentType::Integer, "X position of the regions top-left corner (optional, defaults to the whole canvas)"),
+            ("<y>", ArgumentType::Integer, "Y position of the regions top-left corner"),
+            ("<width>", ArgumentType::Integer, "Width of the region"),
+            ("<height>", ArgumentType::Integer, "Height of the region"),
+        ],
+        notes: &[],
+        examples: &[
+            "STREAM delta 10",
+            "STREAM delta 10 100 50 320 240",
+            "STREAM px 30",
+            "STREAM rgb64 0",
+        ],
+    },
+    CommandDescription {
+        topic: HelpTopic::Compress,
+        name: "COMPRESS",
+        summary: "Compress all data which the server sends",
+        syntax: "COMPRESS <none|gzip|zstd>",
+        response: Some("COMPRESS <none|gzip|zstd>"),
+        description: &[
+            "Compresses all data which the server sends after the response to this command, which is still sent with the",
+            "previous compression.",
+            "The data of one connection is compressed as one continuous stream that is flushed whenever the server sends",
+            "something. When compression is switched off again, the stream is finished properly.",
+            "On WebSocket connections, compressed data is sent in binary messages.",
+        ],
+        arguments: &[],
+        notes: &[],
+        examples: &["COMPRESS zstd", "COMPRESS none"],
+    },
+    CommandDescription {
+        topic: HelpTopic::Auth,
+        name: "AUTH",
+        summary: "Authenticate to draw on a write protected canvas",
+        syntax: "AUTH <token>",
+        response: Some("AUTH OK"),
+        description: &[
+            "Authenticates this connection with a token that was handed out by the operators of this server.",
+            "If the server is write protected (indicated by AUTH in the extensions reported by INFO), all requests",
+            "which draw on the canvas are rejected until the connection is authenticated.",
+            "Reading the canvas is always possible without authentication.",
+        ],
+        arguments: &[("<token>", ArgumentType::Text, "The secret token without any whitespace")],
+        notes: &[],
+        examples: &["AUTH s3cr3t"],
+    },
+    CommandDescription {
+        topic: HelpTopic::Stats,
+        name: "STATS",
+        summary: "Get live statistics about this server",
+        syntax: "STATS",
+        response: Some("STATS <key>=<value> ..."),
+        description: &[
+            "Returns live statistics about this server as a space separated list of key-value pairs.",
+            "Clients should ignore keys which they don't know since more statistics may be added in the future.",
+        ],
+        arguments: &[],
+        notes: &[
+            "pixels               - How many pixels were drawn since the server started",
+            "pixels-per-sec       - How many pixels were drawn during the last second",
+            "clients              - How many clients are currently connected",
+            "uptime               - For how many seconds the server has been running",
+            "idle-timeouts        - How many connections were closed because they did not send anything for too long",
+            "storms-detected      - How many connection storms from single IP addresses were detected",
+            "rejected-connections - How many connections were rejected because their IP address was throttled",
+        ],
+        examples: &["STATS"],
+    },
+    CommandDescription {
+        topic: HelpTopic::Limits,
+        name: "LIMITS",
+        summary: "Get the limits which this server enforces on the connection",
+        syntax: "LIMITS",
+        response: Some("LIMITS <key>=<value> ..."),
+        description: &[
+            "Returns the limits which apply to this connection as a space separated list of key-value pairs.",
+            "Clients can use them to size their requests instead of discovering the limits through errors.",
+            "Clients should ignore keys which they don't know since more limits may be added in the future.",
+        ],
+        arguments: &[],
+        notes: &[
+            "max-line-length        - The maximum length of one request line in bytes (if requests are lines)",
+            "max-batch-size         - The maximum number of pixels in one PXB request",
+            "max-image-size         - The maximum number of encoded bytes in one IMG request",
+            "max-block-pixels       - The maximum number of pixels in one PXGET request",
+            "max-transaction-len    - The maximum number of requests which can be queued between MULTI and EXEC",
+            "max-stream-fps         - The maximum rate at which the canvas state can be streamed via STREAM",
+            "max-claim-secs         - The maximum number of seconds for which a region can be claimed via CLAIM",
+            "max-connects-per-sec   - How many connections a single IP address may open per second (if limited)",
+            "max-connections-per-ip - How many connections a single IP address may have open at once (if limited)",
+            "max-commands-per-sec   - How many requests this connection may send per second (if limited)",
+            "max-pixels-per-sec     - How many pixels this connection may draw per second (if limited)",
+            "idle-timeout           - After how many seconds without requests this connection is closed (if ever)",
+        ],
+        examples: &["LIMITS"],
+    },
+    CommandDescription {
+        topic: HelpTopic::Hello,
+        name: "HELLO",
+        summary: "Negotiate the protocol version and optional features",
+        syntax: "HELLO <version> [<feature> ...]",
+        response: Some("HELLO <version> [<feature> ...]"),
+        description: &[
+            "Tells the server which protocol version and optional features the client wants to use.",
+            "The server answers with the version that is used on this connection and the features which it granted.",
+            "Afterwards, requests which need a feature that was not granted are rejected.",
+            "Clients which never send HELLO implicitly use version 1 and may use all features.",
+        ],
+        arguments: &[
+            ("<version>", ArgumentType::Integer, "The newest protocol version which the client supports (currently 2)"),
+            (
+                "<feature>",
+                ArgumentType::Choice,
+                "The name of an optional feature: BINARY, RGBA, PXB, RECT, LINE, IMG, STREAM, COMPRESS, STATS, CLAIM, PXGET, MULTI\n\
+                 or BLEND. Unknown features are ignored.",
+            ),
+        ],
+        notes: &[],
+        examples: &["HELLO 2 BINARY RGBA PXB"],
+    },
+    CommandDescription {
+        topic: HelpTopic::NoReply,
+        name: "NOREPLY",
+        summary: "Suppress responses to drawing commands",
+        syntax: "NOREPLY <on|off>",
+        response: Some("NOREPLY <on|off>"),
+        description: &[
+            "Switches off all responses to commands which draw on the canvas, including errors, so that clients which",
+            "send lots of pixels are not slowed down by responses that they never read.",
+            "Commands which read something from the server are still answered and so are commands which could not be",
+            "parsed at all.",
+            "The setting only applies to this connection.",
+        ],
+        arguments: &[],
+        notes: &[],
+        examples: &["NOREPLY on", "NOREPLY off"],
+    },
+    CommandDescription {
+        topic: HelpTopic::Claim,
+        name: "CLAIM",
+        summary: "Reserve a region of the canvas for a limited time",
+        syntax: "CLAIM <x> <y> <width> <height> <secs>",
+        response: Some("CLAIM OK"),
+        description: &[
+            "Reserves a rectangle of the canvas for this connection so that groups can coordinate who draws where.",
+            "Other clients which draw into the region get an ERR CLAIMED response. Depending on the server",
+            "configuration, their pixels are either still drawn or rejected.",
+            "A connection holds at most one claim, so claiming again replaces the previous claim.",
+            "Regions which overlap the claim of another connection cannot be claimed.",
+            "Claims expire after <secs> seconds or when the connection closes.",
+            "A claim may cover at most 1/16 of the canvas, the connections of one IP address (or IPv6 /64 network)",
+            "may hold at most 4 claims and regions can only be claimed over continuous connections, not via UDP.",
+        ],
+        arguments: &[
+            ("<x>", ArgumentType::Integer, "X position of the top-left corner counted from the left side"),
+            ("<y>", ArgumentType::Integer, "Y position of the top-left corner counted from the top"),
+            ("<width>", ArgumentType::Integer, "Width of the region"),
+            ("<height>", ArgumentType::Integer, "Height of the region"),
+            ("<secs>", ArgumentType::Integer, "For how many seconds the region is claimed (1 - 300)"),
+        ],
+        notes: &[],
+        examples: &["CLAIM 10 20 100 50 60"],
+    },
+    CommandDescription {
+        topic: HelpTopic::Release,
+        name: "RELEASE",
+        summary: "Give up the claim of this connection",
+        syntax: "RELEASE",
+        response: Some("RELEASE OK"),
+        description: &["Releases the region which this connection claimed via CLAIM, if any."],
+        arguments: &[],
+        notes: &[],
+        examples: &["RELEASE"],
+    },
+    CommandDescription {
+        topic: HelpTopic::Nick,
+        name: "NICK",
+        summary: "Label this connection with a name",
+        syntax: "NICK <name>",
+        response: Some("NICK OK"),
+        description: &[
+            "Labels this connection with a name so that the operators of this server can tell who drew what.",
+            "Sending NICK again replaces the previous name.",
+        ],
+        arguments: &[("<name>", ArgumentType::Text, "Up to 32 printable ASCII characters without whitespace")],
+        notes: &[],
+        examples: &["NICK team-rocket"],
+    },
+    CommandDescription {
+        topic: HelpTopic::Multi,
+        name: "MULTI",
+        summary: "Start a transaction of drawing commands",
+        syntax: "MULTI",
+        response: Some("MULTI OK"),
+        description: &[
+            "Starts a transaction in which all following commands that draw on the canvas are queued instead of being",
+            "applied until EXEC is sent. Other commands are still answered immediately.",
+            "At most 1024 commands which draw at most 1048576 pixels can be queued. If more are sent, the whole",
+            "transaction is discarded. IMG is rejected inside a transaction.",
+        ],
+        arguments: &[],
+        notes: &[],
+        examples: &["MULTI"],
+    },
+    CommandDescription {
+        topic: HelpTopic::Exec,
+        name: "EXEC",
+        summary: "Apply all drawing commands of a transaction at once",
+        syntax: "EXEC",
+        response: Some("EXEC <count>"),
+        description: &[
+            "Applies all commands which were queued since MULTI at once and ends the transaction.",
+            "State frames and PXGET never contain only a part of a transaction, so sprites are not shown half-drawn.",
+            "If some of the commands fail, the others are still applied and the error of the first failed command is",
+            "returned instead of the response.",
+        ],
+        arguments: &[("<count>", ArgumentType::Integer, "How many commands were applied")],
+        notes: &[],
+        examples: &["EXEC"],
+    },
+];
+
+impl HelpTopic {
+    /// All topics about which help is available
+    pub fn all() -> impl Iterator<Item = HelpTopic> {
+        std::iter::once(HelpTopic::General).chain(COMMANDS.iter().map(|command| command.topic))
+    }
+
+    /// The name of this topic as it is used in `HELP <topic>`
+    pub fn name(self) -> &'static str {
+        match self.command() {
+            Some(command) => command.name,
+            None => "GENERAL",
+        }
+    }
+
+    /// Find the topic with the given name, ignoring the case
+    pub fn from_name(name: &str) -> Option<HelpTopic> {
+        if name.eq_ignore_ascii_case("HELP") || name.eq_ignore_ascii_case("GENERAL") {
+            return Some(HelpTopic::General);
+        }
+        COMMANDS
+            .iter()
+            .find(|command| command.name.eq_ignore_ascii_case(name))
+            .map(|command| command.topic)
+    }
+
+    /// The description of the command which this topic explains
+    pub fn command(self) -> Option<&'static CommandDescription> {
+        COMMANDS.iter().find(|command| command.topic == self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_topic_names() {
+        for topic in HelpTopic::all() {
+            assert_eq!(HelpTopic::from_name(topic.name()), Some(topic));
+            assert_eq!(HelpTopic::from_name(&topic.name().to_lowercase()), Some(topic));
+        }
+        assert_eq!(HelpTopic::from_name("help"), Some(HelpTopic::General));
+        assert_eq!(HelpTopic::from_name("FOO"), None);
+    }
+}
@@ -1,10 +1,16 @@
 //! A pixelflut request parser implementation that is fully compliant to the wire protocol
 
 use anyhow::anyhow;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use std::str::FromStr;
 use thiserror::Error;
 
-use crate::net::protocol::{HelpTopic, Request, Response};
-use crate::pixmap::Color;
+use crate::net::protocol::{
+    Compression, Features, HelpTopic, ProtocolVariant, Region, Request, Response, ResponseError, ServerInfo,
+    ServerLimits, ServerStats, StateAlgorithm, MAX_BATCH_SIZE, MAX_BLOCK_PIXELS, MAX_CLAIM_SECS,
+    MAX_IMAGE_SIZE, MAX_NICK_LEN, MAX_STREAM_FPS,
+};
+use crate::pixmap::{BlendMode, Color};
 
 /// Errors that can occur while parsing an input buffer
 #[derive(Debug, Error, Copy, Clone, Eq, PartialEq)]
@@ -15,42 +21,361 @@ pub enum ParseErr {
     /// The passed pixelflut command is known but its invocation was invalid
     #[error("Invalid Command Invocation")]
     InvalidCommand,
+    /// The line of a request is longer than the server accepts
+    #[error("Line Too Long")]
+    LineTooLong,
+}
+
+impl From<ParseErr> for ResponseError {
+    fn from(e: ParseErr) -> Self {
+        ResponseError::ParseError(e.to_string())
+    }
+}
+
+/// Parse a decimal number without going through a `str`
+#[inline(always)]
+fn parse_dec<T: TryFrom<u64>>(token: &[u8]) -> Option<T> {
+    if token.is_empty() {
+        return None;
+    }
+    let mut value: u64 = 0;
+    for &digit in token {
+        if !digit.is_ascii_digit() {
+            return None;
+        }
+        value = value.checked_mul(10)?.checked_add((digit - b'0') as u64)?;
+    }
+    T::try_from(value).ok()
+}
+
+/// Parse a hexadecimal number of up to 8 digits without going through a `str`
+#[inline(always)]
+fn parse_hex(token: &[u8]) -> Option<u32> {
+    if token.is_empty() || token.len() > 8 {
+        return None;
+    }
+    let mut value = 0;
+    for &digit in token {
+        let nibble = match digit {
+            b'0'..=b'9' => digit - b'0',
+            b'a'..=b'f' => digit - b'a' + 10,
+            b'A'..=b'F' => digit - b'A' + 10,
+            _ => return None,
+        };
+        value = value << 4 | nibble as u32;
+    }
+    Some(value)
+}
+
+/// Parse an rgb color of up to 6 hex digits
+///
+/// Colors of exactly 2 digits are shades of gray, i.e. `VV` is short for `VVVVVV`.
+#[inline(always)]
+fn parse_rgb(token: &[u8]) -> Option<Color> {
+    match token.len() {
+        2 => parse_hex(token).map(|value| Color::from((value as u8, value as u8, value as u8))),
+        0..=6 => parse_hex(token).map(Color::from),
+        _ => None,
+    }
+}
+
+/// Interpret a token as a `str`, which is only needed for the arguments of rarely used commands
+#[inline(always)]
+fn token_str(token: &[u8]) -> Result<&str, ParseErr> {
+    std::str::from_utf8(token).map_err(|_| ParseErr::InvalidCommand)
+}
+
+/// Split `args` into its whitespace separated tokens
+#[inline(always)]
+fn split_tokens(args: &[u8]) -> impl Iterator<Item = &[u8]> {
+    args.split(u8::is_ascii_whitespace)
+        .filter(|token| !token.is_empty())
 }
 
 /// Parse the arguments to a PxSet command
+///
+/// Colors can be given as `RRGGBB`, as `VV` for a shade of gray or as `RRGGBBAA` in which case they are blended
+/// onto the current color.
 #[inline(always)]
-fn parse_px_set_args(x: &str, y: &str, px: &str) -> Result<Request, ParseErr> {
-    let xres = x.parse();
-    let yres = y.parse();
-    let cres = u32::from_str_radix(px, 16);
-    match (xres, yres, cres) {
-        (Ok(x), Ok(y), Ok(color)) => Ok(Request::SetPixel {
+fn parse_px_set_args(x: &[u8], y: &[u8], px: &[u8]) -> Result<Request, ParseErr> {
+    match (parse_dec(x), parse_dec(y)) {
+        (Some(x), Some(y)) if px.len() == 8 => match parse_hex(px) {
+            Some(rgba) => Ok(Request::BlendPixel {
+                x,
+                y,
+                color: Color::from(rgba >> 8),
+                alpha: rgba as u8,
+            }),
+            None => Err(ParseErr::UnknownCommand),
+        },
+        (Some(x), Some(y)) => match parse_rgb(px) {
+            Some(color) => Ok(Request::SetPixel { x, y, color }),
+            None => Err(ParseErr::UnknownCommand),
+        },
+        (_, _) => Err(ParseErr::UnknownCommand),
+    }
+}
+
+/// Parse the arguments to a PX command which combines an rgb color with a pixel according to a blend mode
+#[inline(always)]
+fn parse_px_compose_args(x: &[u8], y: &[u8], px: &[u8], mode: &[u8]) -> Result<Request, ParseErr> {
+    let mode: BlendMode = token_str(mode)?.parse().map_err(|_| ParseErr::InvalidCommand)?;
+    match (parse_dec(x), parse_dec(y), parse_rgb(px)) {
+        (Some(x), Some(y), Some(color)) => Ok(Request::ComposePixel { x, y, color, mode }),
+        (_, _, _) => Err(ParseErr::UnknownCommand),
+    }
+}
+
+/// Parse the arguments to a PxBatch command which consist of a pixel count followed by that many `x y rgb` triples
+#[inline(always)]
+fn parse_px_batch_args(args: &[u8]) -> Result<Request, ParseErr> {
+    let mut tokens = split_tokens(args);
+    let count: usize = tokens
+        .next()
+        .and_then(parse_dec)
+        .filter(|&count| count <= MAX_BATCH_SIZE)
+        .ok_or(ParseErr::InvalidCommand)?;
+
+    let mut pixels = Vec::with_capacity(count);
+    for _ in 0..count {
+        let (x, y, px) = (tokens.next(), tokens.next(), tokens.next());
+        match (
+            x.and_then(parse_dec),
+            y.and_then(parse_dec),
+            px.and_then(parse_rgb),
+        ) {
+            (Some(x), Some(y), Some(color)) => pixels.push((x, y, color)),
+            _ => return Err(ParseErr::InvalidCommand),
+        }
+    }
+    if tokens.next().is_some() {
+        return Err(ParseErr::InvalidCommand);
+    }
+
+    Ok(Request::SetPixelBatch(pixels))
+}
+
+/// Parse the arguments to a Rect command which are `x y width height rgb`
+#[inline(always)]
+fn parse_rect_args(args: &[u8]) -> Result<Request, ParseErr> {
+    let tokens: TokBuf<&[u8], 6> = split_tokens(args).collect();
+    let &[x, y, width, height, px] = tokens.tokens() else {
+        return Err(ParseErr::InvalidCommand);
+    };
+    match (
+        parse_dec(x),
+        parse_dec(y),
+        parse_dec(width),
+        parse_dec(height),
+        parse_rgb(px),
+    ) {
+        (Some(x), Some(y), Some(width), Some(height), Some(color)) => Ok(Request::FillRect {
             x,
             y,
-            color: Color::from(color),
+            width,
+            height,
+            color,
         }),
-        (_, _, _) => Err(ParseErr::UnknownCommand),
+        _ => Err(ParseErr::InvalidCommand),
+    }
+}
+
+/// Parse the arguments to a Line command which are `x1 y1 x2 y2 rgb`
+#[inline(always)]
+fn parse_line_args(args: &[u8]) -> Result<Request, ParseErr> {
+    let tokens: TokBuf<&[u8], 6> = split_tokens(args).collect();
+    let &[x1, y1, x2, y2, px] = tokens.tokens() else {
+        return Err(ParseErr::InvalidCommand);
+    };
+    match (
+        parse_dec(x1),
+        parse_dec(y1),
+        parse_dec(x2),
+        parse_dec(y2),
+        parse_rgb(px),
+    ) {
+        (Some(x1), Some(y1), Some(x2), Some(y2), Some(color)) => Ok(Request::DrawLine {
+            x1,
+            y1,
+            x2,
+            y2,
+            color,
+        }),
+        _ => Err(ParseErr::InvalidCommand),
+    }
+}
+
+/// Parse the header line of an image upload which is `IMG x y len` into its arguments
+///
+/// The image data which follows the header is not part of the line and must be split off by the caller.
+#[inline(always)]
+pub fn parse_image_header(line: &str) -> Result<(usize, usize, usize), ParseErr> {
+    parse_image_header_bytes(line.as_bytes())
+}
+
+/// Parse the header line of an image upload directly from the bytes of a connections input buffer
+#[inline(always)]
+pub(crate) fn parse_image_header_bytes(line: &[u8]) -> Result<(usize, usize, usize), ParseErr> {
+    let (command, args) = split_command(line);
+    if !command.eq_ignore_ascii_case(b"IMG") {
+        return Err(ParseErr::UnknownCommand);
+    }
+    let tokens: TokBuf<&[u8], 4> = split_tokens(args).collect();
+    let &[x, y, len] = tokens.tokens() else {
+        return Err(ParseErr::InvalidCommand);
+    };
+    match (parse_dec(x), parse_dec(y), parse_dec(len)) {
+        (Some(x), Some(y), Some(len)) if len <= MAX_IMAGE_SIZE => Ok((x, y, len)),
+        _ => Err(ParseErr::InvalidCommand),
+    }
+}
+
+/// Parse the arguments to a Stream command which are `algorithm fps`
+#[inline(always)]
+fn parse_stream_args(args: &[u8]) -> Result<Request, ParseErr> {
+    let tokens: TokBuf<&[u8], 7> = split_tokens(args).collect();
+    let (algorithm, fps, region) = match *tokens.tokens() {
+        [algorithm, fps] => (algorithm, fps, None),
+        [algorithm, fps, x, y, width, height] => (algorithm, fps, Some(parse_region(x, y, width, height)?)),
+        _ => return Err(ParseErr::InvalidCommand),
+    };
+    match parse_dec(fps) {
+        Some(fps) if fps <= MAX_STREAM_FPS => Ok(Request::StreamState {
+            algorithm: parse_state_algorithm(algorithm)?,
+            fps,
+            region,
+        }),
+        _ => Err(ParseErr::InvalidCommand),
+    }
+}
+
+/// Parse the arguments to a CLAIM command
+#[inline(always)]
+fn parse_claim_args(args: &[u8]) -> Result<Request, ParseErr> {
+    let tokens: TokBuf<&[u8], 6> = split_tokens(args).collect();
+    let [x, y, width, height, secs] = *tokens.tokens() else {
+        return Err(ParseErr::InvalidCommand);
+    };
+    match parse_dec(secs) {
+        Some(secs) if (1..=MAX_CLAIM_SECS).contains(&secs) => Ok(Request::Claim {
+            region: parse_region(x, y, width, height)?,
+            secs,
+        }),
+        _ => Err(ParseErr::InvalidCommand),
+    }
+}
+
+/// Parse the name of a NICK command
+#[inline(always)]
+fn parse_nick_args(token: &[u8]) -> Result<Request, ParseErr> {
+    match token.len() <= MAX_NICK_LEN && token.iter().all(u8::is_ascii_graphic) {
+        true => Ok(Request::SetNick(token_str(token)?.to_string())),
+        false => Err(ParseErr::InvalidCommand),
+    }
+}
+
+/// Parse the arguments to a PXGET command
+#[inline(always)]
+fn parse_px_block_args(args: &[u8]) -> Result<Request, ParseErr> {
+    let tokens: TokBuf<&[u8], 5> = split_tokens(args).collect();
+    let [x, y, width, height] = *tokens.tokens() else {
+        return Err(ParseErr::InvalidCommand);
+    };
+    let region = parse_region(x, y, width, height)?;
+    match region.width.checked_mul(region.height) {
+        Some(pixels) if pixels <= MAX_BLOCK_PIXELS => Ok(Request::GetPixelBlock(region)),
+        _ => Err(ParseErr::InvalidCommand),
+    }
+}
+
+/// Parse a non-empty region given by its top-left corner and size
+#[inline(always)]
+fn parse_region(x: &[u8], y: &[u8], width: &[u8], height: &[u8]) -> Result<Region, ParseErr> {
+    match (parse_dec(x), parse_dec(y), parse_dec(width), parse_dec(height)) {
+        (Some(x), Some(y), Some(width), Some(height)) if width > 0 && height > 0 => {
+            Ok(Region { x, y, width, height })
+        }
+        _ => Err(ParseErr::InvalidCommand),
     }
 }
 
 /// Parse the arguments to a PxGet command
 #[inline(always)]
-fn parse_px_get_args(x: &str, y: &str) -> Result<Request, ParseErr> {
-    let xres = x.parse();
-    let yres = y.parse();
-    match (xres, yres) {
-        (Ok(x), Ok(y)) => Ok(Request::GetPixel { x, y }),
+fn parse_px_get_args(x: &[u8], y: &[u8]) -> Result<Request, ParseErr> {
+    match (parse_dec(x), parse_dec(y)) {
+        (Some(x), Some(y)) => Ok(Request::GetPixel { x, y }),
         (_, _) => Err(ParseErr::UnknownCommand),
     }
 }
 
 /// Parse the arguments to a Help command
 #[inline(always)]
-fn parse_help_args(token: &str) -> Result<Request, ParseErr> {
+fn parse_help_args(token: &[u8]) -> Result<Request, ParseErr> {
+    if token.eq_ignore_ascii_case(b"JSON") {
+        return Ok(Request::HelpJson);
+    }
+    HelpTopic::from_name(token_str(token)?)
+        .map(Request::Help)
+        .ok_or(ParseErr::InvalidCommand)
+}
+
+/// Parse the version and feature names of a HELLO request or response
+///
+/// Unknown features are ignored so that clients can offer features which a server does not know yet.
+#[inline(always)]
+fn parse_hello_args(args: &[u8]) -> Result<(u32, Features), ParseErr> {
+    let mut tokens = split_tokens(args);
+    let version = tokens
+        .next()
+        .and_then(parse_dec)
+        .ok_or(ParseErr::InvalidCommand)?;
+    Ok((
+        version,
+        tokens
+            .filter_map(|token| std::str::from_utf8(token).ok())
+            .filter_map(Features::from_name)
+            .collect(),
+    ))
+}
+
+/// Parse the name of a protocol variant
+#[inline(always)]
+fn parse_protocol_variant(token: &[u8]) -> Result<ProtocolVariant, ParseErr> {
     match token {
-        "help" | "HELP" | "general" | "GENERAL" => Ok(Request::Help(HelpTopic::General)),
-        "size" | "SIZE" => Ok(Request::Help(HelpTopic::Size)),
-        "px" | "PX" => Ok(Request::Help(HelpTopic::Px)),
+        _ if token.eq_ignore_ascii_case(b"text") => Ok(ProtocolVariant::Text),
+        _ if token.eq_ignore_ascii_case(b"binary") => Ok(ProtocolVariant::Binary),
+        _ => Err(ParseErr::InvalidCommand),
+    }
+}
+
+/// Parse the name of a compression algorithm
+#[inline(always)]
+fn parse_compression(token: &[u8]) -> Result<Compression, ParseErr> {
+    match token {
+        _ if token.eq_ignore_ascii_case(b"none") => Ok(Compression::None),
+        _ if token.eq_ignore_ascii_case(b"gzip") => Ok(Compression::Gzip),
+        _ if token.eq_ignore_ascii_case(b"zstd") => Ok(Compression::Zstd),
+        _ => Err(ParseErr::InvalidCommand),
+    }
+}
+
+/// Parse the state of a switch like NOREPLY
+#[inline(always)]
+fn parse_switch(token: &[u8]) -> Result<bool, ParseErr> {
+    match token {
+        _ if token.eq_ignore_ascii_case(b"on") => Ok(true),
+        _ if token.eq_ignore_ascii_case(b"off") => Ok(false),
+        _ => Err(ParseErr::InvalidCommand),
+    }
+}
+
+/// Parse the name of a state encoding algorithm
+#[inline(always)]
+fn parse_state_algorithm(token: &[u8]) -> Result<StateAlgorithm, ParseErr> {
+    match token {
+        _ if token.eq_ignore_ascii_case(b"rgb64") => Ok(StateAlgorithm::Rgb64),
+        _ if token.eq_ignore_ascii_case(b"delta") => Ok(StateAlgorithm::Delta),
+        _ if token.eq_ignore_ascii_case(b"px") => Ok(StateAlgorithm::Px),
         _ => Err(ParseErr::InvalidCommand),
     }
 }
@@ -71,6 +396,34 @@ fn parse_px_data(x: &str, y: &str, px: &str) -> Result<Response, ParseErr> {
     }
 }
 
+/// Parse the region and base64 encoded colors of a PXGET response
+#[inline(always)]
+fn parse_px_block_data(args: &str) -> Result<Response, ParseErr> {
+    let tokens: TokBuf<&[u8], 6> = split_tokens(args.as_bytes()).collect();
+    let [x, y, width, height, data] = *tokens.tokens() else {
+        return Err(ParseErr::InvalidCommand);
+    };
+    let region = parse_region(x, y, width, height)?;
+    let rgb = BASE64_STANDARD
+        .decode(data)
+        .map_err(|_| ParseErr::InvalidCommand)?;
+    if Some(rgb.len())
+        != region
+            .width
+            .checked_mul(region.height)
+            .and_then(|pixels| pixels.checked_mul(3))
+    {
+        return Err(ParseErr::InvalidCommand);
+    }
+    Ok(Response::PxBlock {
+        region,
+        colors: rgb
+            .chunks_exact(3)
+            .map(|rgb| Color::from((rgb[0], rgb[1], rgb[2])))
+            .collect(),
+    })
+}
+
 #[inline(always)]
 fn parse_size_data(width: &str, height: &str) -> Result<Response, ParseErr> {
     let width = width.parse();
@@ -83,43 +436,149 @@ fn parse_size_data(width: &str, height: &str) -> Result<Response, ParseErr> {
 
 #[inline(always)]
 fn parse_help_data(topic: &str) -> Result<Response, ParseErr> {
-    match topic {
-        "help" | "HELP" | "general" | "GENERAL" => Ok(Response::Help(HelpTopic::General)),
-        "size" | "SIZE" => Ok(Response::Help(HelpTopic::Size)),
-        "px" | "PX" => Ok(Response::Help(HelpTopic::Px)),
-        _ => Err(ParseErr::InvalidCommand),
+    HelpTopic::from_name(topic)
+        .map(Response::Help)
+        .ok_or(ParseErr::InvalidCommand)
+}
+
+/// Parse the `key=value` pairs of an INFO response
+///
+/// Unknown keys are ignored so that servers can add more information in the future.
+#[inline(always)]
+fn parse_info_data(pairs: &str) -> Result<Response, ParseErr> {
+    let mut size = None;
+    let mut info = ServerInfo {
+        width: 0,
+        height: 0,
+        binary_protocol: false,
+        alpha_blending: false,
+        pixel_batches: false,
+        rect_fill: false,
+        line_drawing: false,
+        image_upload: false,
+        state_streaming: false,
+        compression: false,
+        write_protection: false,
+        statistics: false,
+        region_claims: false,
+        pixel_blocks: false,
+        transactions: false,
+        blend_modes: false,
+        max_connects_per_sec: None,
+    };
+    for (key, value) in pairs.split_whitespace().filter_map(|pair| pair.split_once('=')) {
+        match key {
+            "size" => {
+                let (width, height) = value.split_once('x').ok_or(ParseErr::InvalidCommand)?;
+                size = Some((
+                    width.parse().map_err(|_| ParseErr::InvalidCommand)?,
+                    height.parse().map_err(|_| ParseErr::InvalidCommand)?,
+                ));
+            }
+            "protocols" => info.binary_protocol = value.split(',').any(|p| p == "BINARY"),
+            "extensions" => {
+                info.alpha_blending = value.split(',').any(|e| e == "RGBA");
+                info.pixel_batches = value.split(',').any(|e| e == "PXB");
+                info.rect_fill = value.split(',').any(|e| e == "RECT");
+                info.line_drawing = value.split(',').any(|e| e == "LINE");
+                info.image_upload = value.split(',').any(|e| e == "IMG");
+                info.state_streaming = value.split(',').any(|e| e == "STREAM");
+                info.compression = value.split(',').any(|e| e == "COMPRESS");
+                info.write_protection = value.split(',').any(|e| e == "AUTH");
+                info.statistics = value.split(',').any(|e| e == "STATS");
+                info.region_claims = value.split(',').any(|e| e == "CLAIM");
+                info.pixel_blocks = value.split(',').any(|e| e == "PXGET");
+                info.transactions = value.split(',').any(|e| e == "MULTI");
+                info.blend_modes = value.split(',').any(|e| e == "BLEND");
+            }
+            "max-connects-per-sec" => {
+                info.max_connects_per_sec = Some(value.parse().map_err(|_| ParseErr::InvalidCommand)?)
+            }
+            _ => {}
+        }
+    }
+    (info.width, info.height) = size.ok_or(ParseErr::InvalidCommand)?;
+    Ok(Response::Info(info))
+}
+
+/// Parse the `key=value` pairs of a STATS response
+///
+/// Unknown keys are ignored so that servers can add more statistics in the future.
+#[inline(always)]
+fn parse_stats_data(pairs: &str) -> Result<Response, ParseErr> {
+    let mut stats = ServerStats::default();
+    for (key, value) in pairs.split_whitespace().filter_map(|pair| pair.split_once('=')) {
+        let counter = match key {
+            "pixels" => &mut stats.pixels_set,
+            "pixels-per-sec" => &mut stats.pixels_per_sec,
+            "clients" => &mut stats.clients,
+            "uptime" => &mut stats.uptime_secs,
+            "idle-timeouts" => &mut stats.idle_timeouts,
+            "storms-detected" => &mut stats.storms_detected,
+            "rejected-connections" => &mut stats.rejected_connections,
+            _ => continue,
+        };
+        *counter = value.parse().map_err(|_| ParseErr::InvalidCommand)?;
+    }
+    Ok(Response::Stats(stats))
+}
+
+/// Parse the `key=value` pairs of a LIMITS response
+///
+/// Unknown keys are ignored so that servers can report more limits in the future.
+#[inline(always)]
+fn parse_limits_data(pairs: &str) -> Result<Response, ParseErr> {
+    let mut limits = ServerLimits::default();
+    for (key, value) in pairs.split_whitespace().filter_map(|pair| pair.split_once('=')) {
+        let parse_err = |_| ParseErr::InvalidCommand;
+        match key {
+            "max-line-length" => limits.max_line_length = Some(value.parse().map_err(parse_err)?),
+            "max-batch-size" => limits.max_batch_size = value.parse().map_err(parse_err)?,
+            "max-image-size" => limits.max_image_size = value.parse().map_err(parse_err)?,
+            "max-block-pixels" => limits.max_block_pixels = value.parse().map_err(parse_err)?,
+            "max-transaction-len" => limits.max_transaction_len = value.parse().map_err(parse_err)?,
+            "max-stream-fps" => limits.max_stream_fps = value.parse().map_err(parse_err)?,
+            "max-claim-secs" => limits.max_claim_secs = value.parse().map_err(parse_err)?,
+            "max-connects-per-sec" => limits.max_connects_per_sec = Some(value.parse().map_err(parse_err)?),
+            "max-connections-per-ip" => {
+                limits.max_connections_per_ip = Some(value.parse().map_err(parse_err)?)
+            }
+            "max-commands-per-sec" => limits.max_commands_per_sec = Some(value.parse().map_err(parse_err)?),
+            "max-pixels-per-sec" => limits.max_pixels_per_sec = Some(value.parse().map_err(parse_err)?),
+            "idle-timeout" => limits.idle_timeout_secs = Some(value.parse().map_err(parse_err)?),
+            _ => {}
+        }
     }
+    Ok(Response::Limits(limits))
 }
 
 /// A statically sized buffer containing input tokens.
 ///
 /// This is useful during parsing because it can be allocated on the stack instead of the heap as a Vec would.
-struct TokBuf<'s, const MAX_TOKS: usize> {
-    /// Storage for up to 4 input tokens
-    tokens: [Option<&'s str>; MAX_TOKS],
+struct TokBuf<T, const MAX_TOKS: usize> {
+    /// Storage for up to `MAX_TOKS` input tokens
+    tokens: [T; MAX_TOKS],
     /// How many tokens are actually present in the buffer
     len: usize,
 }
 
-impl<'s, const MAX_TOKS: usize> TokBuf<'s, MAX_TOKS> {
+impl<T, const MAX_TOKS: usize> TokBuf<T, MAX_TOKS> {
     #[inline(always)]
-    fn tokens(&self) -> &[&'s str] {
-        debug_assert_eq!(self.len, self.tokens.iter().filter(|i| i.is_some()).count());
-        // Safety: Option is repr(transparent) and we know how many of them are a Some variant
-        unsafe { std::mem::transmute(&self.tokens[0..self.len]) }
+    fn tokens(&self) -> &[T] {
+        &self.tokens[..self.len]
     }
 }
 
-impl<'s, const MAX_TOKS: usize> FromIterator<&'s str> for TokBuf<'s, MAX_TOKS> {
+impl<T: Copy + Default, const MAX_TOKS: usize> FromIterator<T> for TokBuf<T, MAX_TOKS> {
     #[inline(always)]
-    fn from_iter<T: IntoIterator<Item = &'s str>>(iter: T) -> Self {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
         let mut this = Self {
-            tokens: [None; MAX_TOKS],
+            tokens: [T::default(); MAX_TOKS],
             len: 0,
         };
 
         for (i, token) in iter.into_iter().take(MAX_TOKS).enumerate() {
-            this.tokens[i] = Some(token);
+            this.tokens[i] = token;
             this.len += 1;
         }
 
@@ -127,62 +586,227 @@ impl<'s, const MAX_TOKS: usize> FromIterator<&'s str> for TokBuf<'s, MAX_TOKS> {
     }
 }
 
+/// The length of the longest request keyword
+const MAX_COMMAND_LEN: usize = 8;
+
+/// Split a line into its command keyword and the remaining arguments
+///
+/// Leading whitespace is ignored and the keyword may be followed by any kind of whitespace.
+#[inline(always)]
+fn split_command(line: &[u8]) -> (&[u8], &[u8]) {
+    let line = line.trim_ascii_start();
+    match line.iter().position(u8::is_ascii_whitespace) {
+        Some(i) => (&line[..i], &line[i + 1..]),
+        None => (line, &[]),
+    }
+}
+
+/// Copy a command keyword into `buf` in upper case so that it can be matched case-insensitively
+///
+/// `None` is returned if the keyword is longer than any known command.
+#[inline(always)]
+fn upper_case_command<'b>(command: &[u8], buf: &'b mut [u8; MAX_COMMAND_LEN]) -> Option<&'b [u8]> {
+    let buf = buf.get_mut(..command.len())?;
+    buf.copy_from_slice(command);
+    buf.make_ascii_uppercase();
+    Some(buf)
+}
+
+/// Split the arguments of a command which takes exactly one argument
+#[inline(always)]
+fn single_arg(args: &[u8]) -> Result<&[u8], ParseErr> {
+    let tokens: TokBuf<&[u8], 2> = split_tokens(args).collect();
+    match *tokens.tokens() {
+        [arg] => Ok(arg),
+        _ => Err(ParseErr::InvalidCommand),
+    }
+}
+
+/// Check that a command which takes no arguments was given none
+#[inline(always)]
+fn no_args(args: &[u8], request: Request) -> Result<Request, ParseErr> {
+    match args.trim_ascii().is_empty() {
+        true => Ok(request),
+        false => Err(ParseErr::InvalidCommand),
+    }
+}
+
 /// Try to parse a single pixelflut request
+///
+/// Parsing is tolerant towards the variations that are common among clients:
+/// Command keywords are case-insensitive, tokens may be separated by any number of spaces or tabs and lines may end
+/// with `\r\n` instead of `\n`.
 #[inline(always)]
 pub fn parse_request_str(line: &str) -> Result<Request, ParseErr> {
-    let tokens: TokBuf<'_, 4> = line.split_whitespace().collect();
-    let tokens = tokens.tokens();
-    match tokens.len() {
-        4 => parse_px_set_args(tokens[1], tokens[2], tokens[3]),
-        3 => parse_px_get_args(tokens[1], tokens[2]),
-        2 => parse_help_args(tokens[1]),
-        1 => match tokens[0] {
-            "SIZE" | "size" => Ok(Request::GetSize),
-            "HELP" | "help" => Ok(Request::Help(HelpTopic::General)),
-            _ => Err(ParseErr::UnknownCommand),
-        },
-        0 => Err(ParseErr::InvalidCommand),
-        _ => unreachable!(),
-    }
+    parse_request_bin(line.as_bytes())
 }
 
 /// Parse a single request from a byte slice
+///
+/// The line is parsed directly from its bytes so that requests can be parsed in place in a connections input
+/// buffer without validating or copying them first.
 #[inline(always)]
-pub fn parse_request_bin(line: &[u8]) -> anyhow::Result<Request> {
-    if line.is_ascii() {
-        // Safety: This is fine because the bytes are already checked to be ascii
-        let str = unsafe { std::str::from_utf8_unchecked(line) };
-        Ok(parse_request_str(str)?)
-    } else {
-        Err(anyhow!("request buffer does not contain an ascii string"))
+pub fn parse_request_bin(line: &[u8]) -> Result<Request, ParseErr> {
+    let (command, args) = split_command(line);
+    let mut buf = [0; MAX_COMMAND_LEN];
+    let command = upper_case_command(command, &mut buf).ok_or(ParseErr::UnknownCommand)?;
+    match command {
+        b"PX" => {
+            let tokens: TokBuf<&[u8], 5> = split_tokens(args).collect();
+            match *tokens.tokens() {
+                [x, y, px, mode] => parse_px_compose_args(x, y, px, mode),
+                [x, y, px] => parse_px_set_args(x, y, px),
+                [x, y] => parse_px_get_args(x, y),
+                _ => Err(ParseErr::InvalidCommand),
+            }
+        }
+        b"PXB" => parse_px_batch_args(args),
+        b"PXGET" => parse_px_block_args(args),
+        b"RECT" => parse_rect_args(args),
+        b"LINE" => parse_line_args(args),
+        b"STREAM" => parse_stream_args(args),
+        b"HELLO" => {
+            let (version, features) = parse_hello_args(args)?;
+            Ok(Request::Hello { version, features })
+        }
+        // the image data does not fit into a line and must be handled by the connection framing instead
+        b"IMG" => Err(ParseErr::InvalidCommand),
+        b"HELP" => match args.trim_ascii().is_empty() {
+            true => Ok(Request::Help(HelpTopic::General)),
+            false => parse_help_args(single_arg(args)?),
+        },
+        b"PROTOCOL" => Ok(Request::SetProtocol(parse_protocol_variant(single_arg(args)?)?)),
+        b"COMPRESS" => Ok(Request::SetCompression(parse_compression(single_arg(args)?)?)),
+        b"AUTH" => Ok(Request::Authenticate(token_str(single_arg(args)?)?.to_string())),
+        b"NOREPLY" => Ok(Request::SetNoReply(parse_switch(single_arg(args)?)?)),
+        b"CLAIM" => parse_claim_args(args),
+        b"RELEASE" => no_args(args, Request::Release),
+        b"NICK" => parse_nick_args(single_arg(args)?),
+        b"MULTI" => no_args(args, Request::Multi),
+        b"EXEC" => no_args(args, Request::Exec),
+        b"SIZE" => no_args(args, Request::GetSize),
+        b"INFO" => no_args(args, Request::GetInfo),
+        b"STATS" => no_args(args, Request::GetStats),
+        b"LIMITS" => no_args(args, Request::GetLimits),
+        b"" => Err(ParseErr::InvalidCommand),
+        _ => Err(ParseErr::UnknownCommand),
+    }
+}
+
+/// Split the optional `#<tag>` prefix off of a request or response line
+///
+/// Tags are decimal numbers which servers echo in front of the response to a tagged request so that clients
+/// which pipeline requests can match responses to them.
+/// Lines without a valid tag are returned unchanged.
+pub fn split_tag(line: &[u8]) -> (Option<u32>, &[u8]) {
+    let Some(tagged) = line.trim_ascii_start().strip_prefix(b"#") else {
+        return (None, line);
+    };
+    let (tag, rest) = match tagged.iter().position(u8::is_ascii_whitespace) {
+        Some(i) => (&tagged[..i], &tagged[i + 1..]),
+        None => (tagged, &[][..]),
+    };
+    match parse_dec(tag) {
+        Some(tag) => (Some(tag), rest),
+        None => (None, line),
     }
 }
 
 /// Try to parse a single pixelflut response
 #[inline(always)]
 pub fn parse_response_str(line: &str) -> Result<Response, ParseErr> {
-    let tokens: TokBuf<'_, 4> = line.split_whitespace().collect();
+    if let Some(pairs) = line.strip_prefix("INFO ") {
+        return parse_info_data(pairs);
+    }
+    if let Some(pairs) = line.strip_prefix("STATS ") {
+        return parse_stats_data(pairs);
+    }
+    if let Some(pairs) = line.strip_prefix("LIMITS ") {
+        return parse_limits_data(pairs);
+    }
+    if let Some(json) = line.strip_prefix("HELP JSON ") {
+        return Ok(Response::HelpJson(json.trim_end().to_string()));
+    }
+    if let Some(args) = line.strip_prefix("PXGET ") {
+        return parse_px_block_data(args);
+    }
+    if let Some(args) = line.strip_prefix("HELLO ") {
+        let (version, features) = parse_hello_args(args.as_bytes())?;
+        return Ok(Response::Hello { version, features });
+    }
+
+    let tokens: TokBuf<&str, 4> = line.split_whitespace().collect();
     let tokens = tokens.tokens();
     match tokens.len() {
         4 => parse_px_data(tokens[1], tokens[2], tokens[3]),
         3 => parse_size_data(tokens[1], tokens[2]),
-        2 => parse_help_data(tokens[1]),
+        2 => match tokens[0] {
+            "PROTOCOL" | "protocol" => Ok(Response::Protocol(parse_protocol_variant(tokens[1].as_bytes())?)),
+            "COMPRESS" | "compress" => Ok(Response::Compression(parse_compression(tokens[1].as_bytes())?)),
+            "AUTH" | "auth" if tokens[1] == "OK" => Ok(Response::Authenticated),
+            "NOREPLY" | "noreply" => Ok(Response::NoReply(parse_switch(tokens[1].as_bytes())?)),
+            "CLAIM" | "claim" if tokens[1] == "OK" => Ok(Response::Claimed),
+            "RELEASE" | "release" if tokens[1] == "OK" => Ok(Response::Released),
+            "NICK" | "nick" if tokens[1] == "OK" => Ok(Response::NickSet),
+            "MULTI" | "multi" if tokens[1] == "OK" => Ok(Response::TransactionStarted),
+            "EXEC" | "exec" => parse_dec(tokens[1].as_bytes())
+                .map(Response::Executed)
+                .ok_or(ParseErr::InvalidCommand),
+            _ => parse_help_data(tokens[1]),
+        },
         _ => Err(ParseErr::UnknownCommand),
     }
 }
 
+/// Parse an `ERR <code> <message>` line which a server sends instead of a response if a request failed
+///
+/// `None` is returned if the line is no error or if its code is unknown.
+pub fn parse_error_response(line: &str) -> Option<ResponseError> {
+    let (code, message) = line.strip_prefix("ERR ")?.split_once(' ')?;
+    ResponseError::from_code(code, message.trim_end().to_string())
+}
+
 /// Parse a single pixelflut response from a byte slice
+///
+/// If the server sent an error instead of a response, the [`ResponseError`] is returned.
 #[inline(always)]
 pub fn parse_response_bin(line: &[u8]) -> anyhow::Result<Response> {
     if line.is_ascii() {
         // Safety: This is fine because the bytes are already checked to be ascii
         let str = unsafe { std::str::from_utf8_unchecked(line) };
+        if let Some(error) = parse_error_response(str) {
+            return Err(error.into());
+        }
         Ok(parse_response_str(str)?)
     } else {
         Err(anyhow!("response buffer does not contain an ascii string"))
     }
 }
 
+impl FromStr for Request {
+    type Err = ParseErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_request_str(s)
+    }
+}
+
+impl FromStr for Response {
+    type Err = ParseErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_response_str(s)
+    }
+}
+
+impl FromStr for StateAlgorithm {
+    type Err = ParseErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_state_algorithm(s.as_bytes())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -193,7 +817,7 @@ mod test {
     fn test_parse_commands() {
         fn run_test(line: &str, res: Request) {
             let req = parse_request_str(line);
-            assert_eq!(req, Ok(res), "{:06x?} != Ok({:06x?})", req, res);
+            assert_eq!(req, Ok(res.clone()), "{:06x?} != Ok({:06x?})", req, res);
         }
 
         run_test("HELP", Request::Help(HelpTopic::General));
@@ -206,6 +830,53 @@ mod test {
                 color: Color::from((0xAA, 0xBB, 0xCC)),
             },
         );
+        run_test("PROTOCOL BINARY", Request::SetProtocol(ProtocolVariant::Binary));
+        run_test(
+            "PXB 2 1 2 AABBCC 3 4 DDEEFF",
+            Request::SetPixelBatch(vec![
+                (1, 2, Color::from((0xAA, 0xBB, 0xCC))),
+                (3, 4, Color::from((0xDD, 0xEE, 0xFF))),
+            ]),
+        );
+        run_test(
+            "RECT 10 20 30 40 AABBCC",
+            Request::FillRect {
+                x: 10,
+                y: 20,
+                width: 30,
+                height: 40,
+                color: Color::from((0xAA, 0xBB, 0xCC)),
+            },
+        );
+        run_test(
+            "LINE 40 30 20 10 AABBCC",
+            Request::DrawLine {
+                x1: 40,
+                y1: 30,
+                x2: 20,
+                y2: 10,
+                color: Color::from((0xAA, 0xBB, 0xCC)),
+            },
+        );
+        run_test("COMPRESS zstd", Request::SetCompression(Compression::Zstd));
+        run_test("AUTH s3cr3t", Request::Authenticate("s3cr3t".to_string()));
+        run_test(
+            "STREAM rgb64 30",
+            Request::StreamState {
+                algorithm: StateAlgorithm::Rgb64,
+                fps: 30,
+                region: None,
+            },
+        );
+        run_test(
+            "PX 1 2 AABBCC80",
+            Request::BlendPixel {
+                x: 1,
+                y: 2,
+                color: Color::from((0xAA, 0xBB, 0xCC)),
+                alpha: 0x80,
+            },
+        );
         run_test(
             "PX 0 0 AABBCC",
             Request::SetPixel {
@@ -216,6 +887,456 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_parse_tolerant_commands() {
+        let px = Request::SetPixel {
+            x: 1,
+            y: 2,
+            color: Color::from((0xAA, 0xBB, 0xCC)),
+        };
+        for line in [
+            "px 1 2 AABBCC",
+            "Px 1 2 aabbcc\r\n",
+            "PX  1\t2   AABBCC  \n",
+            "\tpX 1 2 AABBCC \r\n",
+        ] {
+            assert_eq!(parse_request_str(line), Ok(px.clone()), "{:?}", line);
+        }
+        assert_eq!(parse_request_str("size\r\n"), Ok(Request::GetSize));
+        assert_eq!(parse_request_str(" Size \n"), Ok(Request::GetSize));
+        assert_eq!(parse_request_str("help\tPX"), Ok(Request::Help(HelpTopic::Px)));
+        assert_eq!(
+            parse_request_str("protocol  binary\r\n"),
+            Ok(Request::SetProtocol(ProtocolVariant::Binary))
+        );
+        assert_eq!(
+            parse_request_str("pxb\t1  1 2 AABBCC\r\n"),
+            Ok(Request::SetPixelBatch(vec![(
+                1,
+                2,
+                Color::from((0xAA, 0xBB, 0xCC))
+            )]))
+        );
+        assert_eq!(
+            parse_request_str("Stream DELTA 10"),
+            Ok(Request::StreamState {
+                algorithm: StateAlgorithm::Delta,
+                fps: 10,
+                region: None,
+            })
+        );
+        assert_eq!(parse_image_header("img\t10 20  300\r"), Ok((10, 20, 300)));
+
+        assert_eq!(parse_request_str("PIX 1 2 AABBCC"), Err(ParseErr::UnknownCommand));
+        assert_eq!(parse_request_str("PXX 1 2"), Err(ParseErr::UnknownCommand));
+        assert_eq!(parse_request_str("SIZE 1"), Err(ParseErr::InvalidCommand));
+        assert_eq!(parse_request_str(" \r\n"), Err(ParseErr::InvalidCommand));
+    }
+
+    #[test]
+    fn test_parse_gray_colors() {
+        let gray = Color::from((0x80, 0x80, 0x80));
+        assert_eq!(
+            parse_request_str("PX 1 2 80"),
+            Ok(Request::SetPixel {
+                x: 1,
+                y: 2,
+                color: gray
+            })
+        );
+        assert_eq!(
+            parse_request_str("PXB 1 1 2 80"),
+            Ok(Request::SetPixelBatch(vec![(1, 2, gray)]))
+        );
+        // gray pixels are sent back in the same format as all other colors
+        assert_eq!(
+            Response::PxData {
+                x: 1,
+                y: 2,
+                color: gray
+            }
+            .to_string(),
+            "PX 1 2 808080"
+        );
+
+        // only exactly two digits are a shade of gray
+        assert_eq!(
+            parse_request_str("PX 1 2 0080"),
+            Ok(Request::SetPixel {
+                x: 1,
+                y: 2,
+                color: Color::from((0, 0, 0x80))
+            })
+        );
+        assert_eq!(parse_request_str("PX 1 2 8G"), Err(ParseErr::UnknownCommand));
+    }
+
+    #[test]
+    fn test_compose_encoding_inversion() {
+        let request = Request::ComposePixel {
+            x: 1,
+            y: 2,
+            color: Color::from((0xAA, 0xBB, 0xCC)),
+            mode: BlendMode::Add,
+        };
+        assert_eq!(request.to_string(), "PX 1 2 AABBCC add");
+        assert_eq!(parse_request_str("px 1 2 aabbcc ADD"), Ok(request));
+        assert_eq!(
+            parse_request_str("PX 1 2 AABBCC overlay"),
+            Err(ParseErr::InvalidCommand)
+        );
+        assert_eq!(
+            parse_request_str("PX 1 2 AABBCC add extra"),
+            Err(ParseErr::InvalidCommand)
+        );
+        // alpha blending and blend modes cannot be combined
+        assert_eq!(
+            parse_request_str("PX 1 2 AABBCCDD add"),
+            Err(ParseErr::UnknownCommand)
+        );
+    }
+
+    #[test]
+    fn test_parse_stream_region() {
+        let request = Request::StreamState {
+            algorithm: StateAlgorithm::Delta,
+            fps: 10,
+            region: Some(Region {
+                x: 100,
+                y: 50,
+                width: 320,
+                height: 240,
+            }),
+        };
+        assert_eq!(request.to_string(), "STREAM delta 10 100 50 320 240");
+        assert_eq!(parse_request_str("STREAM delta 10 100 50 320 240"), Ok(request));
+        assert_eq!(
+            parse_request_str("STREAM delta 10 100 50 0 240"),
+            Err(ParseErr::InvalidCommand)
+        );
+        assert_eq!(
+            parse_request_str("STREAM delta 10 100 50 320"),
+            Err(ParseErr::InvalidCommand)
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_numbers() {
+        let too_large = format!("PX {}0 1 AABBCC", u64::MAX);
+        assert_eq!(parse_request_str(&too_large), Err(ParseErr::UnknownCommand));
+        assert_eq!(parse_request_str("PX -1 1 AABBCC"), Err(ParseErr::UnknownCommand));
+        assert_eq!(parse_request_str("PX 1 1 AABBCG"), Err(ParseErr::UnknownCommand));
+        assert_eq!(
+            parse_request_str("PX 1 1 AABBCCDDE"),
+            Err(ParseErr::UnknownCommand)
+        );
+        assert_eq!(
+            parse_request_bin(b"PX 1 1 \xC3\x84"),
+            Err(ParseErr::UnknownCommand)
+        );
+        assert_eq!(parse_request_bin(b"\xC3\x84 1 1"), Err(ParseErr::UnknownCommand));
+    }
+
+    #[test]
+    fn test_parse_invalid_batches() {
+        assert_eq!(
+            parse_request_str("PXB 2 1 2 AABBCC"),
+            Err(ParseErr::InvalidCommand)
+        );
+        assert_eq!(
+            parse_request_str("PXB 1 1 2 AABBCC 3"),
+            Err(ParseErr::InvalidCommand)
+        );
+        assert_eq!(
+            parse_request_str("PXB 1 1 2 AABBCC80"),
+            Err(ParseErr::InvalidCommand)
+        );
+        assert_eq!(parse_request_str("PXB 101"), Err(ParseErr::InvalidCommand));
+    }
+
+    #[test]
+    fn test_parse_image_header() {
+        assert_eq!(parse_image_header("IMG 10 20 300"), Ok((10, 20, 300)));
+        assert_eq!(parse_image_header("IMG 10 20"), Err(ParseErr::InvalidCommand));
+        assert_eq!(
+            parse_image_header(&format!("IMG 0 0 {}", MAX_IMAGE_SIZE + 1)),
+            Err(ParseErr::InvalidCommand)
+        );
+        assert_eq!(parse_request_str("IMG 10 20 300"), Err(ParseErr::InvalidCommand));
+    }
+
+    #[test]
+    fn test_info_encoding_inversion() {
+        let info = ServerInfo {
+            width: 800,
+            height: 600,
+            binary_protocol: true,
+            alpha_blending: true,
+            pixel_batches: true,
+            rect_fill: true,
+            line_drawing: true,
+            image_upload: true,
+            state_streaming: true,
+            compression: true,
+            write_protection: true,
+            statistics: true,
+            region_claims: true,
+            pixel_blocks: true,
+            transactions: true,
+            blend_modes: true,
+            max_connects_per_sec: Some(100),
+        };
+        let encoded = Response::Info(info).to_string();
+        assert_eq!(
+            encoded,
+            "INFO size=800x600 protocols=TEXT,BINARY extensions=RGBA,PXB,RECT,LINE,IMG,STREAM,COMPRESS,AUTH,STATS,CLAIM,PXGET,MULTI,BLEND max-connects-per-sec=100"
+        );
+        assert_eq!(parse_response_str(&encoded), Ok(Response::Info(info)));
+        assert_eq!(
+            parse_response_str("INFO size=10x20 future-key=42"),
+            Ok(Response::Info(ServerInfo {
+                width: 10,
+                height: 20,
+                binary_protocol: false,
+                alpha_blending: false,
+                pixel_batches: false,
+                rect_fill: false,
+                line_drawing: false,
+                image_upload: false,
+                state_streaming: false,
+                compression: false,
+                write_protection: false,
+                statistics: false,
+                region_claims: false,
+                pixel_blocks: false,
+                transactions: false,
+                blend_modes: false,
+                max_connects_per_sec: None,
+            }))
+        );
+    }
+
+    #[test]
+    fn test_stats_encoding_inversion() {
+        let stats = ServerStats {
+            pixels_set: 123456,
+            pixels_per_sec: 420,
+            clients: 3,
+            uptime_secs: 3600,
+            idle_timeouts: 7,
+            storms_detected: 2,
+            rejected_connections: 5,
+        };
+        let encoded = Response::Stats(stats).to_string();
+        assert_eq!(
+            encoded,
+            "STATS pixels=123456 pixels-per-sec=420 clients=3 uptime=3600 idle-timeouts=7 storms-detected=2 \
+             rejected-connections=5"
+        );
+        assert_eq!(parse_response_str(&encoded), Ok(Response::Stats(stats)));
+        assert_eq!(parse_request_str("STATS"), Ok(Request::GetStats));
+    }
+
+    #[test]
+    fn test_limits_encoding_inversion() {
+        let limits = ServerLimits {
+            max_line_length: Some(4096),
+            max_batch_size: 100,
+            max_image_size: 1024,
+            max_block_pixels: 4096,
+            max_transaction_len: 1024,
+            max_stream_fps: 60,
+            max_claim_secs: 300,
+            max_connects_per_sec: None,
+            max_connections_per_ip: Some(16),
+            max_commands_per_sec: None,
+            max_pixels_per_sec: Some(10000),
+            idle_timeout_secs: Some(300),
+        };
+        let encoded = Response::Limits(limits).to_string();
+        assert_eq!(
+            encoded,
+            "LIMITS max-line-length=4096 max-batch-size=100 max-image-size=1024 max-block-pixels=4096 \
+             max-transaction-len=1024 max-stream-fps=60 max-claim-secs=300 max-connections-per-ip=16 \
+             max-pixels-per-sec=10000 idle-timeout=300"
+        );
+        assert_eq!(parse_response_str(&encoded), Ok(Response::Limits(limits)));
+        assert_eq!(
+            parse_response_str("LIMITS max-batch-size=10 future-limit=1"),
+            Ok(Response::Limits(ServerLimits {
+                max_batch_size: 10,
+                ..ServerLimits::default()
+            }))
+        );
+        assert_eq!(parse_request_str("limits"), Ok(Request::GetLimits));
+    }
+
+    #[test]
+    fn test_split_tag() {
+        assert_eq!(split_tag(b"#42 PX 1 2"), (Some(42), &b"PX 1 2"[..]));
+        assert_eq!(split_tag(b" #0\tSIZE"), (Some(0), &b"SIZE"[..]));
+        assert_eq!(split_tag(b"#7"), (Some(7), &b""[..]));
+        assert_eq!(split_tag(b"PX 1 2"), (None, &b"PX 1 2"[..]));
+        assert_eq!(split_tag(b"#abc SIZE"), (None, &b"#abc SIZE"[..]));
+        assert_eq!(split_tag(b"#99999999999 SIZE"), (None, &b"#99999999999 SIZE"[..]));
+        assert_eq!(split_tag(b"#42 PX 1 2 FF0000"), (Some(42), &b"PX 1 2 FF0000"[..]));
+    }
+
+    #[test]
+    fn test_hello_encoding_inversion() {
+        let features = Features::BINARY | Features::RECT | Features::STATS;
+        let request = Request::Hello { version: 2, features };
+        assert_eq!(request.to_string(), "HELLO 2 BINARY RECT STATS");
+        assert_eq!(parse_request_str("HELLO 2 rect BINARY FUTURE stats"), Ok(request));
+        assert_eq!(parse_request_str("HELLO x RECT"), Err(ParseErr::InvalidCommand));
+
+        let response = Response::Hello {
+            version: 2,
+            features: Features::empty(),
+        };
+        assert_eq!(response.to_string(), "HELLO 2");
+        assert_eq!(parse_response_str("HELLO 2"), Ok(response));
+    }
+
+    #[test]
+    fn test_no_reply_encoding_inversion() {
+        assert_eq!(Request::SetNoReply(true).to_string(), "NOREPLY on");
+        assert_eq!(parse_request_str("NOREPLY on"), Ok(Request::SetNoReply(true)));
+        assert_eq!(
+            parse_request_str("noreply OFF\r\n"),
+            Ok(Request::SetNoReply(false))
+        );
+        assert_eq!(parse_request_str("NOREPLY maybe"), Err(ParseErr::InvalidCommand));
+        assert_eq!(parse_request_str("NOREPLY"), Err(ParseErr::InvalidCommand));
+
+        let response = Response::NoReply(false);
+        assert_eq!(response.to_string(), "NOREPLY off");
+        assert_eq!(parse_response_str("NOREPLY off"), Ok(response));
+    }
+
+    #[test]
+    fn test_claim_encoding_inversion() {
+        let request = Request::Claim {
+            region: Region {
+                x: 10,
+                y: 20,
+                width: 100,
+                height: 50,
+            },
+            secs: 60,
+        };
+        assert_eq!(request.to_string(), "CLAIM 10 20 100 50 60");
+        assert_eq!(parse_request_str("claim 10 20 100 50 60"), Ok(request));
+        assert_eq!(
+            parse_request_str("CLAIM 10 20 100 50 0"),
+            Err(ParseErr::InvalidCommand)
+        );
+        assert_eq!(
+            parse_request_str("CLAIM 10 20 100 50 301"),
+            Err(ParseErr::InvalidCommand)
+        );
+        assert_eq!(
+            parse_request_str("CLAIM 10 20 100 50"),
+            Err(ParseErr::InvalidCommand)
+        );
+        assert_eq!(parse_request_str("RELEASE"), Ok(Request::Release));
+        assert_eq!(parse_response_str("CLAIM OK"), Ok(Response::Claimed));
+        assert_eq!(parse_response_str("RELEASE OK"), Ok(Response::Released));
+    }
+
+    #[test]
+    fn test_nick_encoding_inversion() {
+        let request = Request::SetNick("team-rocket".to_string());
+        assert_eq!(request.to_string(), "NICK team-rocket");
+        assert_eq!(parse_request_str("nick team-rocket\r\n"), Ok(request));
+        assert_eq!(
+            parse_request_str("NICK team rocket"),
+            Err(ParseErr::InvalidCommand)
+        );
+        assert_eq!(parse_request_str("NICK"), Err(ParseErr::InvalidCommand));
+        let too_long = format!("NICK {}", "x".repeat(MAX_NICK_LEN + 1));
+        assert_eq!(parse_request_str(&too_long), Err(ParseErr::InvalidCommand));
+        assert_eq!(parse_response_str("NICK OK"), Ok(Response::NickSet));
+    }
+
+    #[test]
+    fn test_transaction_encoding_inversion() {
+        assert_eq!(parse_request_str("MULTI"), Ok(Request::Multi));
+        assert_eq!(parse_request_str("exec\n"), Ok(Request::Exec));
+        assert_eq!(parse_request_str("EXEC 3"), Err(ParseErr::InvalidCommand));
+        for response in [Response::TransactionStarted, Response::Executed(3)] {
+            assert_eq!(parse_response_str(&response.to_string()), Ok(response));
+        }
+        assert_eq!(parse_response_str("EXEC many"), Err(ParseErr::InvalidCommand));
+    }
+
+    #[test]
+    fn test_px_block_encoding_inversion() {
+        let region = Region {
+            x: 1,
+            y: 2,
+            width: 2,
+            height: 1,
+        };
+        assert_eq!(Request::GetPixelBlock(region).to_string(), "PXGET 1 2 2 1");
+        assert_eq!(
+            parse_request_str("pxget 1 2 2 1"),
+            Ok(Request::GetPixelBlock(region))
+        );
+        assert_eq!(
+            parse_request_str("PXGET 0 0 100 100"),
+            Err(ParseErr::InvalidCommand)
+        );
+        assert_eq!(parse_request_str("PXGET 0 0 0 1"), Err(ParseErr::InvalidCommand));
+
+        let response = Response::PxBlock {
+            region,
+            colors: vec![Color::from((0xFF, 0x00, 0x80)), Color::from((0, 0, 0))],
+        };
+        let encoded = response.to_string();
+        assert_eq!(encoded, "PXGET 1 2 2 1 /wCAAAAA");
+        assert_eq!(parse_response_str(&encoded), Ok(response));
+        assert_eq!(
+            parse_response_str("PXGET 1 2 2 2 /wCAAAAA"),
+            Err(ParseErr::InvalidCommand)
+        );
+    }
+
+    #[test]
+    fn test_error_encoding_inversion() {
+        let error = ResponseError::OutOfBounds("Pixel 900,20 is outside the canvas".to_string());
+        let encoded = error.to_string();
+        assert_eq!(encoded, "ERR OUT_OF_BOUNDS Pixel 900,20 is outside the canvas");
+        assert_eq!(parse_error_response(&format!("{}\r\n", encoded)), Some(error));
+        assert_eq!(parse_error_response("ERR FUTURE_CODE something"), None);
+        assert_eq!(parse_error_response("SIZE 800 600"), None);
+        assert_eq!(
+            ResponseError::from(ParseErr::UnknownCommand).to_string(),
+            "ERR PARSE_ERROR Unknown Command"
+        );
+    }
+
+    #[test]
+    fn test_from_str() {
+        let request: Request = "PX 1 2 FF0080".parse().unwrap();
+        assert_eq!(request.to_string().parse(), Ok(request));
+        let response: Response = "SIZE 800 600".parse().unwrap();
+        assert_eq!(response.to_string().parse(), Ok(response));
+        for algorithm in [StateAlgorithm::Rgb64, StateAlgorithm::Delta, StateAlgorithm::Px] {
+            assert_eq!(algorithm.to_string().parse(), Ok(algorithm));
+        }
+        assert_eq!("FOO".parse::<Request>(), Err(ParseErr::UnknownCommand));
+    }
+
+    #[test]
+    fn test_help_json_encoding_inversion() {
+        assert_eq!(parse_request_str("help json"), Ok(Request::HelpJson));
+        assert_eq!(Request::HelpJson.to_string(), "HELP JSON");
+        let response = Response::HelpJson(r#"{"version":2}"#.to_string());
+        assert_eq!(response.to_string(), r#"HELP JSON {"version":2}"#);
+        assert_eq!(parse_response_str(&format!("{}\n", response)), Ok(response));
+    }
+
     #[bench]
     fn bench_parse_get_pixel(b: &mut Bencher) {
         let cmd = black_box("PX 17 7632");
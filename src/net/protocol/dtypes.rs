@@ -1,13 +1,19 @@
 //! Data types that describe all protocol interactions as safe-to-use structs
 
-use crate::pixmap::Color;
+use crate::pixmap::{BlendMode, Color};
 use crate::texts;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use bytes::Bytes;
 use std::fmt::{Display, Formatter};
 use std::io::Write;
+use thiserror::Error;
 use tokio::io::{AsyncWrite, AsyncWriteExt};
 
 /// The help topics that can be requested from the server
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+///
+/// Each topic except [`General`](HelpTopic::General) explains one of the [`COMMANDS`](super::COMMANDS).
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum HelpTopic {
     /// Help about the general pixelflut protocol and links to further topics
     General,
@@ -15,15 +21,504 @@ pub enum HelpTopic {
     Size,
     /// Help about the *PX* command (both set and get variants)
     Px,
+    /// Help about the *PROTOCOL* command
+    Protocol,
+    /// Help about the *INFO* command
+    Info,
+    /// Help about the *RECT* command
+    Rect,
+    /// Help about the *LINE* command
+    Line,
+    /// Help about the *IMG* command
+    Img,
+    /// Help about the *STREAM* command
+    Stream,
+    /// Help about the *COMPRESS* command
+    Compress,
+    /// Help about the *AUTH* command
+    Auth,
+    /// Help about the *STATS* command
+    Stats,
+    /// Help about the *HELLO* command
+    Hello,
+    /// Help about the *NOREPLY* command
+    NoReply,
+    /// Help about the *CLAIM* command
+    Claim,
+    /// Help about the *RELEASE* command
+    Release,
+    /// Help about the *NICK* command
+    Nick,
+    /// Help about the *PXGET* command
+    PxGet,
+    /// Help about the *MULTI* command
+    Multi,
+    /// Help about the *EXEC* command
+    Exec,
+    /// Help about the *LIMITS* command
+    Limits,
 }
 
-/// A request to a pixelflut server
+/// The encodings in which requests and responses can be exchanged over a connection
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ProtocolVariant {
+    /// The human readable, newline terminated text protocol
+    #[default]
+    Text,
+    /// A compact binary encoding with fixed size messages (see [`parse_request_binary`](super::parse_request_binary))
+    Binary,
+}
+
+impl ProtocolVariant {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ProtocolVariant::Text => "TEXT",
+            ProtocolVariant::Binary => "BINARY",
+        }
+    }
+}
+
+/// The algorithms with which all data that a server sends on a connection can be compressed
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Compression {
+    /// Data is sent as-is
+    #[default]
+    None,
+    /// Data is compressed as one continuous gzip stream
+    Gzip,
+    /// Data is compressed as one continuous zstd stream
+    Zstd,
+}
+
+impl Compression {
+    /// The name by which clients select this compression
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        }
+    }
+}
+
+/// The algorithms with which the canvas state can be encoded when it is sent to clients
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum StateAlgorithm {
+    /// The rgb values of all pixels, row by row, encoded as base64
+    Rgb64,
+    /// Only the pixels which changed since the previous frame, encoded as base64
+    ///
+    /// Frames are numbered by tokens so that clients can detect which frame the changes are based on.
+    Delta,
+    /// Only the pixels which changed since the previous frame, sent as one `PX <x> <y> <color>` line per pixel
+    ///
+    /// Unlike with [`Delta`](StateAlgorithm::Delta), the first frame contains no pixels so that clients are only
+    /// notified about updates.
+    Px,
+}
+
+impl StateAlgorithm {
+    /// The name by which clients select this algorithm
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StateAlgorithm::Rgb64 => "rgb64",
+            StateAlgorithm::Delta => "delta",
+            StateAlgorithm::Px => "px",
+        }
+    }
+}
+
+impl Display for StateAlgorithm {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A rectangular part of the canvas
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Region {
+    /// The x coordinate of the regions top-left corner
+    pub x: usize,
+    /// The y coordinate of the regions top-left corner
+    pub y: usize,
+    /// The width of the region
+    pub width: usize,
+    /// The height of the region
+    pub height: usize,
+}
+
+impl Region {
+    /// Whether the region lies completely within a canvas of the given size
+    pub fn fits_into(&self, width: usize, height: usize) -> bool {
+        self.x.checked_add(self.width).is_some_and(|x_end| x_end <= width)
+            && self
+                .y
+                .checked_add(self.height)
+                .is_some_and(|y_end| y_end <= height)
+    }
+
+    /// Whether the pixel at (x,y) lies within the region
+    pub fn contains(&self, x: usize, y: usize) -> bool {
+        (self.x..self.x.saturating_add(self.width)).contains(&x)
+            && (self.y..self.y.saturating_add(self.height)).contains(&y)
+    }
+
+    /// Whether the region and `other` have at least one pixel in common
+    pub fn intersects(&self, other: &Region) -> bool {
+        self.x < other.x.saturating_add(other.width)
+            && other.x < self.x.saturating_add(self.width)
+            && self.y < other.y.saturating_add(other.height)
+            && other.y < self.y.saturating_add(self.height)
+    }
+}
+
+impl Display for Region {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "{} {} {} {}",
+            self.x, self.y, self.width, self.height
+        ))
+    }
+}
+
+/// A set of optional protocol features which a client and server can negotiate via HELLO
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Features(u16);
+
+impl Features {
+    /// Switching to the binary protocol via PROTOCOL
+    pub const BINARY: Self = Self(1 << 0);
+    /// Colors with an alpha channel which are blended onto the canvas
+    pub const RGBA: Self = Self(1 << 1);
+    /// Setting multiple pixels at once via PXB
+    pub const PXB: Self = Self(1 << 2);
+    /// Filling rectangles via RECT
+    pub const RECT: Self = Self(1 << 3);
+    /// Drawing lines via LINE
+    pub const LINE: Self = Self(1 << 4);
+    /// Uploading encoded images via IMG
+    pub const IMG: Self = Self(1 << 5);
+    /// Streaming the canvas state via STREAM
+    pub const STREAM: Self = Self(1 << 6);
+    /// Compressing responses via COMPRESS
+    pub const COMPRESS: Self = Self(1 << 7);
+    /// Requesting live statistics via STATS
+    pub const STATS: Self = Self(1 << 8);
+    /// Claiming regions of the canvas via CLAIM and RELEASE
+    pub const CLAIM: Self = Self(1 << 9);
+    /// Getting the colors of multiple pixels at once via PXGET
+    pub const PXGET: Self = Self(1 << 10);
+    /// Applying groups of drawing requests at once via MULTI and EXEC
+    pub const MULTI: Self = Self(1 << 11);
+    /// Combining pixels with the canvas via PX with a blend mode
+    pub const BLEND: Self = Self(1 << 12);
+
+    /// All features together with the names by which they are negotiated
+    const NAMES: [(Self, &'static str); 13] = [
+        (Self::BINARY, "BINARY"),
+        (Self::RGBA, "RGBA"),
+        (Self::PXB, "PXB"),
+        (Self::RECT, "RECT"),
+        (Self::LINE, "LINE"),
+        (Self::IMG, "IMG"),
+        (Self::STREAM, "STREAM"),
+        (Self::COMPRESS, "COMPRESS"),
+        (Self::STATS, "STATS"),
+        (Self::CLAIM, "CLAIM"),
+        (Self::PXGET, "PXGET"),
+        (Self::MULTI, "MULTI"),
+        (Self::BLEND, "BLEND"),
+    ];
+
+    /// The empty set
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// The feature with the given name, ignoring the case
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::NAMES
+            .iter()
+            .find(|(_, candidate)| candidate.eq_ignore_ascii_case(name))
+            .map(|(feature, _)| *feature)
+    }
+
+    /// The names of all features in this set
+    pub fn names(self) -> impl Iterator<Item = &'static str> {
+        Self::NAMES
+            .into_iter()
+            .filter(move |(feature, _)| self.contains(*feature))
+            .map(|(_, name)| name)
+    }
+
+    /// Whether all features of `other` are also in this set
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The features which are in both sets
+    pub fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+impl std::ops::BitOr for Features {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl FromIterator<Features> for Features {
+    fn from_iter<T: IntoIterator<Item = Features>>(iter: T) -> Self {
+        iter.into_iter().fold(Self::empty(), |set, feature| set | feature)
+    }
+}
+
+/// Information about the capabilities of a server
+///
+/// On the wire, this is encoded as a list of `key=value` pairs so that clients can ignore keys which they don't
+/// understand, e.g. `INFO size=800x600 protocols=TEXT,BINARY extensions=RGBA max-connects-per-sec=100`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ServerInfo {
+    /// Width of the canvas in number of pixels
+    pub width: usize,
+    /// Height of the canvas in number of pixels
+    pub height: usize,
+    /// Whether the connection can be switched to the binary protocol
+    pub binary_protocol: bool,
+    /// Whether PX accepts colors with an alpha channel which are blended onto the canvas
+    pub alpha_blending: bool,
+    /// Whether multiple pixels can be set at once via PXB
+    pub pixel_batches: bool,
+    /// Whether rectangles can be filled via RECT
+    pub rect_fill: bool,
+    /// Whether lines can be drawn via LINE
+    pub line_drawing: bool,
+    /// Whether encoded images can be uploaded via IMG
+    pub image_upload: bool,
+    /// Whether the canvas state can be streamed via STREAM
+    pub state_streaming: bool,
+    /// Whether responses can be compressed via COMPRESS
+    pub compression: bool,
+    /// Whether connections must authenticate via AUTH before they can draw on the canvas
+    pub write_protection: bool,
+    /// Whether live statistics can be requested via STATS
+    pub statistics: bool,
+    /// Whether regions of the canvas can be claimed via CLAIM
+    pub region_claims: bool,
+    /// Whether the colors of multiple pixels can be requested at once via PXGET
+    pub pixel_blocks: bool,
+    /// Whether groups of drawing requests can be applied at once via MULTI and EXEC
+    pub transactions: bool,
+    /// Whether PX accepts a blend mode with which pixels are combined with the canvas
+    pub blend_modes: bool,
+    /// How many connections a single IP address may open per second, if limited
+    pub max_connects_per_sec: Option<u32>,
+}
+
+impl Display for ServerInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "INFO size={}x{} protocols=TEXT",
+            self.width, self.height
+        ))?;
+        if self.binary_protocol {
+            f.write_str(",BINARY")?;
+        }
+        let extensions = [
+            ("RGBA", self.alpha_blending),
+            ("PXB", self.pixel_batches),
+            ("RECT", self.rect_fill),
+            ("LINE", self.line_drawing),
+            ("IMG", self.image_upload),
+            ("STREAM", self.state_streaming),
+            ("COMPRESS", self.compression),
+            ("AUTH", self.write_protection),
+            ("STATS", self.statistics),
+            ("CLAIM", self.region_claims),
+            ("PXGET", self.pixel_blocks),
+            ("MULTI", self.transactions),
+            ("BLEND", self.blend_modes),
+        ]
+        .into_iter()
+        .filter_map(|(name, enabled)| enabled.then_some(name))
+        .collect::<Vec<_>>();
+        if !extensions.is_empty() {
+            f.write_fmt(format_args!(" extensions={}", extensions.join(",")))?;
+        }
+        if let Some(max_connects_per_sec) = self.max_connects_per_sec {
+            f.write_fmt(format_args!(" max-connects-per-sec={}", max_connects_per_sec))?;
+        }
+        Ok(())
+    }
+}
+
+/// Live statistics about a server
+///
+/// On the wire, this is encoded as a list of `key=value` pairs like [`ServerInfo`], e.g.
+/// `STATS pixels=123456 pixels-per-sec=420 clients=3 uptime=3600 idle-timeouts=0 storms-detected=0
+/// rejected-connections=0`.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ServerStats {
+    /// How many pixels were drawn on the canvas since the server started
+    pub pixels_set: u64,
+    /// How many pixels were drawn on the canvas during the last second
+    pub pixels_per_sec: u64,
+    /// How many clients are currently connected
+    pub clients: u64,
+    /// For how many seconds the server has been running
+    pub uptime_secs: u64,
+    /// How many connections were closed because they did not send anything for too long
+    pub idle_timeouts: u64,
+    /// How many connection storms from single IP addresses were detected
+    pub storms_detected: u64,
+    /// How many connections were rejected because their IP address was throttled after a connection storm
+    pub rejected_connections: u64,
+}
+
+impl Display for ServerStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "STATS pixels={} pixels-per-sec={} clients={} uptime={} idle-timeouts={} storms-detected={} \
+             rejected-connections={}",
+            self.pixels_set,
+            self.pixels_per_sec,
+            self.clients,
+            self.uptime_secs,
+            self.idle_timeouts,
+            self.storms_detected,
+            self.rejected_connections
+        ))
+    }
+}
+
+/// The limits which a server enforces on a connection
+///
+/// On the wire, this is encoded as a list of `key=value` pairs like [`ServerInfo`], e.g.
+/// `LIMITS max-line-length=4096 max-batch-size=100 max-stream-fps=60`.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ServerLimits {
+    /// The maximum length of one request line in bytes, if requests are sent as lines of a stream
+    pub max_line_length: Option<usize>,
+    /// The maximum number of pixels that can be set with one PXB request
+    pub max_batch_size: usize,
+    /// The maximum number of encoded bytes that can be uploaded with one IMG request
+    pub max_image_size: usize,
+    /// The maximum number of pixels whose colors can be requested with one PXGET request
+    pub max_block_pixels: usize,
+    /// The maximum number of requests which can be queued between MULTI and EXEC
+    pub max_transaction_len: usize,
+    /// The maximum rate at which the canvas state can be streamed via STREAM
+    pub max_stream_fps: u32,
+    /// The maximum number of seconds for which a region can be claimed via CLAIM
+    pub max_claim_secs: u32,
+    /// How many connections a single IP address may open per second, if limited
+    pub max_connects_per_sec: Option<u32>,
+    /// How many connections a single IP address may have open at the same time, if limited
+    pub max_connections_per_ip: Option<usize>,
+    /// How many requests a single connection may send per second, if limited
+    pub max_commands_per_sec: Option<u32>,
+    /// How many pixels a single connection may draw per second, if limited
+    pub max_pixels_per_sec: Option<u32>,
+    /// After how many seconds without requests a connection is closed, if ever
+    pub idle_timeout_secs: Option<u64>,
+}
+
+impl Display for ServerLimits {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("LIMITS")?;
+        if let Some(max_line_length) = self.max_line_length {
+            f.write_fmt(format_args!(" max-line-length={}", max_line_length))?;
+        }
+        f.write_fmt(format_args!(
+            " max-batch-size={} max-image-size={} max-block-pixels={} max-transaction-len={}",
+            self.max_batch_size, self.max_image_size, self.max_block_pixels, self.max_transaction_len
+        ))?;
+        f.write_fmt(format_args!(
+            " max-stream-fps={} max-claim-secs={}",
+            self.max_stream_fps, self.max_claim_secs
+        ))?;
+        if let Some(max_connects_per_sec) = self.max_connects_per_sec {
+            f.write_fmt(format_args!(" max-connects-per-sec={}", max_connects_per_sec))?;
+        }
+        if let Some(max_connections_per_ip) = self.max_connections_per_ip {
+            f.write_fmt(format_args!(" max-connections-per-ip={}", max_connections_per_ip))?;
+        }
+        if let Some(max_commands_per_sec) = self.max_commands_per_sec {
+            f.write_fmt(format_args!(" max-commands-per-sec={}", max_commands_per_sec))?;
+        }
+        if let Some(max_pixels_per_sec) = self.max_pixels_per_sec {
+            f.write_fmt(format_args!(" max-pixels-per-sec={}", max_pixels_per_sec))?;
+        }
+        if let Some(idle_timeout_secs) = self.idle_timeout_secs {
+            f.write_fmt(format_args!(" idle-timeout={}", idle_timeout_secs))?;
+        }
+        Ok(())
+    }
+}
+
+/// The newest protocol version which this implementation supports
+///
+/// Clients which never send HELLO implicitly use version 1, the plain text protocol.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// The maximum number of pixels that can be set with one [`Request::SetPixelBatch`]
+pub const MAX_BATCH_SIZE: usize = 100;
+
+/// The maximum number of encoded bytes that can be uploaded with one [`Request::PutImage`]
+pub const MAX_IMAGE_SIZE: usize = 4 * 1024 * 1024;
+
+/// The maximum rate at which the canvas state can be streamed with [`Request::StreamState`]
+pub const MAX_STREAM_FPS: u32 = 60;
+
+/// The maximum number of seconds for which a region can be claimed with one [`Request::Claim`]
+pub const MAX_CLAIM_SECS: u32 = 300;
+
+/// The maximum number of pixels whose colors can be requested with one [`Request::GetPixelBlock`]
+pub const MAX_BLOCK_PIXELS: usize = 4096;
+
+/// The maximum number of requests which can be queued between [`Request::Multi`] and [`Request::Exec`]
+pub const MAX_TRANSACTION_LEN: usize = 1024;
+
+/// The maximum number of pixels which all requests queued between [`Request::Multi`] and [`Request::Exec`] draw
+pub const MAX_TRANSACTION_PIXELS: u64 = 1024 * 1024;
+
+/// The maximum length of a name which is set with [`Request::SetNick`]
+pub const MAX_NICK_LEN: usize = 32;
+
+/// A request to a pixelflut server
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Request {
     /// Request help about a specific topic
     Help(HelpTopic),
+    /// Request a machine-readable description of all commands as JSON
+    HelpJson,
+    /// Negotiate the protocol version and which optional features are enabled on this connection
+    Hello {
+        /// The newest protocol version which the client supports
+        version: u32,
+        /// The features which the client wants to use
+        features: Features,
+    },
     /// Get the size of the canvas
     GetSize,
+    /// Get information about the capabilities of the server
+    GetInfo,
+    /// Get live statistics about the server
+    GetStats,
+    /// Get the limits which the server enforces on this connection
+    GetLimits,
     /// Get the color of one pixel from the server
     GetPixel {
         /// The x coordinate of the pixel
@@ -31,6 +526,10 @@ pub enum Request {
         /// The y coordinate of the pixel
         y: usize,
     },
+    /// Get the colors of all pixels in a region of the canvas at once
+    ///
+    /// At most [`MAX_BLOCK_PIXELS`] pixels can be requested with one request.
+    GetPixelBlock(Region),
     /// Set the color of one pixel
     SetPixel {
         /// The x coordinate of the pixel
@@ -40,40 +539,244 @@ pub enum Request {
         /// The color to which the pixel should be set
         color: Color,
     },
+    /// Set the colors of multiple pixels given as `(x, y, color)` at once
+    ///
+    /// At most [`MAX_BATCH_SIZE`] pixels can be set with one request.
+    SetPixelBatch(Vec<(usize, usize, Color)>),
+    /// Fill a rectangle with one color
+    FillRect {
+        /// The x coordinate of the rectangles top-left corner
+        x: usize,
+        /// The y coordinate of the rectangles top-left corner
+        y: usize,
+        /// The width of the rectangle
+        width: usize,
+        /// The height of the rectangle
+        height: usize,
+        /// The color with which the rectangle is filled
+        color: Color,
+    },
+    /// Draw a straight line with one color
+    DrawLine {
+        /// The x coordinate of the lines start point
+        x1: usize,
+        /// The y coordinate of the lines start point
+        y1: usize,
+        /// The x coordinate of the lines end point
+        x2: usize,
+        /// The y coordinate of the lines end point
+        y2: usize,
+        /// The color with which the line is drawn
+        color: Color,
+    },
+    /// Blend a color onto one pixel
+    BlendPixel {
+        /// The x coordinate of the pixel
+        x: usize,
+        /// The y coordinate of the pixel
+        y: usize,
+        /// The color which should be blended onto the pixel
+        color: Color,
+        /// The opacity of `color` where 0 is fully transparent and 255 is fully opaque
+        alpha: u8,
+    },
+    /// Combine a color with one pixel according to a blend mode
+    ComposePixel {
+        /// The x coordinate of the pixel
+        x: usize,
+        /// The y coordinate of the pixel
+        y: usize,
+        /// The color which should be combined with the pixel
+        color: Color,
+        /// How the color is combined with the pixel
+        mode: BlendMode,
+    },
+    /// Draw an encoded image (e.g. a PNG file) onto the canvas
+    ///
+    /// On the wire, the request line `IMG <x> <y> <len>` is directly followed by `len` bytes of image data.
+    /// At most [`MAX_IMAGE_SIZE`] bytes can be uploaded with one request.
+    PutImage {
+        /// The x coordinate of the images top-left corner
+        x: usize,
+        /// The y coordinate of the images top-left corner
+        y: usize,
+        /// The encoded image
+        data: Bytes,
+    },
+    /// Continuously send the canvas state to this connection until streaming is stopped
+    ///
+    /// At most [`MAX_STREAM_FPS`] frames can be requested per second and an `fps` of 0 stops the stream.
+    StreamState {
+        /// The algorithm with which each frame is encoded
+        algorithm: StateAlgorithm,
+        /// How many frames should be sent per second
+        fps: u32,
+        /// The part of the canvas which should be streamed or `None` for the whole canvas
+        region: Option<Region>,
+    },
+    /// Compress all data which the server sends on this connection after confirming the request
+    SetCompression(Compression),
+    /// Authenticate this connection with a token so that it may draw on a write protected canvas
+    Authenticate(String),
+    /// Switch the encoding which is used for all following requests and responses on this connection
+    SetProtocol(ProtocolVariant),
+    /// Suppress the responses to all following requests which draw on the canvas, including errors
+    SetNoReply(bool),
+    /// Reserve a region of the canvas for this connection so that other clients are told not to draw into it
+    ///
+    /// A connection holds at most one claim which lasts for at most [`MAX_CLAIM_SECS`] seconds or until the
+    /// connection is closed.
+    Claim {
+        /// The claimed region
+        region: Region,
+        /// For how many seconds the region is claimed
+        secs: u32,
+    },
+    /// Give up the claim of this connection
+    Release,
+    /// Label this connection with a name by which the server operators can attribute its requests
+    ///
+    /// Names consist of at most [`MAX_NICK_LEN`] printable ASCII characters.
+    SetNick(String),
+    /// Queue all following requests of this connection which draw on the canvas instead of applying them
+    ///
+    /// At most [`MAX_TRANSACTION_LEN`] requests which draw at most [`MAX_TRANSACTION_PIXELS`] pixels can be queued.
+    /// Images cannot be uploaded inside a transaction.
+    Multi,
+    /// Apply all requests which were queued since [`Request::Multi`] at once
+    ///
+    /// Clients which stream the canvas state never receive a frame in which only some of them are applied.
+    Exec,
 }
 
 impl Request {
+    /// Whether this request modifies the canvas
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Request::SetPixel { .. }
+                | Request::SetPixelBatch(_)
+                | Request::FillRect { .. }
+                | Request::DrawLine { .. }
+                | Request::BlendPixel { .. }
+                | Request::ComposePixel { .. }
+                | Request::PutImage { .. }
+        )
+    }
+
+    /// The optional feature which is needed for this request, if any
+    pub fn required_feature(&self) -> Option<Features> {
+        match self {
+            Request::BlendPixel { .. } => Some(Features::RGBA),
+            Request::ComposePixel { .. } => Some(Features::BLEND),
+            Request::SetPixelBatch(_) => Some(Features::PXB),
+            Request::FillRect { .. } => Some(Features::RECT),
+            Request::DrawLine { .. } => Some(Features::LINE),
+            Request::PutImage { .. } => Some(Features::IMG),
+            Request::StreamState { .. } => Some(Features::STREAM),
+            Request::SetCompression(_) => Some(Features::COMPRESS),
+            Request::GetStats => Some(Features::STATS),
+            Request::Claim { .. } | Request::Release => Some(Features::CLAIM),
+            Request::GetPixelBlock(_) => Some(Features::PXGET),
+            Request::Multi | Request::Exec => Some(Features::MULTI),
+            Request::SetProtocol(ProtocolVariant::Binary) => Some(Features::BINARY),
+            _ => None,
+        }
+    }
+
     /// Write the binary representation of this request into the given writer
     pub fn write(&self, writer: &mut impl Write) -> std::io::Result<()> {
         match self {
-            Request::Help(topic) => match topic {
-                HelpTopic::General => writer.write_all("HELP\n".as_bytes()),
-                HelpTopic::Size => writer.write_all("HELP SIZE\n".as_bytes()),
-                HelpTopic::Px => writer.write_all("HELP PX\n".as_bytes()),
-            },
+            Request::Help(_) => writer.write_all(format!("{}\n", self).as_bytes()),
+            Request::HelpJson => writer.write_all("HELP JSON\n".as_bytes()),
+            Request::Hello { .. } => writer.write_all(format!("{}\n", self).as_bytes()),
             Request::GetSize => writer.write_all("SIZE\n".as_bytes()),
+            Request::GetInfo => writer.write_all("INFO\n".as_bytes()),
+            Request::GetStats => writer.write_all("STATS\n".as_bytes()),
+            Request::GetLimits => writer.write_all("LIMITS\n".as_bytes()),
             Request::GetPixel { x, y } => writer.write_all(format!("PX {} {}\n", x, y).as_bytes()),
+            Request::GetPixelBlock(region) => writer.write_all(format!("PXGET {}\n", region).as_bytes()),
             Request::SetPixel { x, y, color } => {
                 writer.write_all(format!("PX {} {} {:X}\n", x, y, color).as_bytes())
             }
+            Request::SetPixelBatch(_) => writer.write_all(format!("{}\n", self).as_bytes()),
+            Request::FillRect { .. } => writer.write_all(format!("{}\n", self).as_bytes()),
+            Request::DrawLine { .. } => writer.write_all(format!("{}\n", self).as_bytes()),
+            Request::PutImage { data, .. } => {
+                writer.write_all(format!("{}\n", self).as_bytes())?;
+                writer.write_all(data)
+            }
+            Request::StreamState { .. } => writer.write_all(format!("{}\n", self).as_bytes()),
+            Request::Authenticate(_) => writer.write_all(format!("{}\n", self).as_bytes()),
+            Request::SetCompression(compression) => {
+                writer.write_all(format!("COMPRESS {}\n", compression.as_str()).as_bytes())
+            }
+            Request::BlendPixel { x, y, color, alpha } => {
+                writer.write_all(format!("PX {} {} {:X}{:02X}\n", x, y, color, alpha).as_bytes())
+            }
+            Request::ComposePixel { .. } => writer.write_all(format!("{}\n", self).as_bytes()),
+            Request::SetProtocol(variant) => {
+                writer.write_all(format!("PROTOCOL {}\n", variant.as_str()).as_bytes())
+            }
+            Request::SetNoReply(_) => writer.write_all(format!("{}\n", self).as_bytes()),
+            Request::Claim { .. } => writer.write_all(format!("{}\n", self).as_bytes()),
+            Request::Release => writer.write_all("RELEASE\n".as_bytes()),
+            Request::SetNick(_) => writer.write_all(format!("{}\n", self).as_bytes()),
+            Request::Multi => writer.write_all("MULTI\n".as_bytes()),
+            Request::Exec => writer.write_all("EXEC\n".as_bytes()),
         }
     }
 
     /// Write the binary representation of this request into the given async writer
     pub async fn write_async(&self, writer: &mut (impl AsyncWrite + Unpin)) -> std::io::Result<()> {
         match self {
-            Request::Help(topic) => match topic {
-                HelpTopic::General => writer.write_all("HELP\n".as_bytes()).await,
-                HelpTopic::Size => writer.write_all("HELP SIZE\n".as_bytes()).await,
-                HelpTopic::Px => writer.write_all("HELP PX\n".as_bytes()).await,
-            },
+            Request::Help(_) => writer.write_all(format!("{}\n", self).as_bytes()).await,
+            Request::HelpJson => writer.write_all("HELP JSON\n".as_bytes()).await,
+            Request::Hello { .. } => writer.write_all(format!("{}\n", self).as_bytes()).await,
             Request::GetSize => writer.write_all("SIZE\n".as_bytes()).await,
+            Request::GetInfo => writer.write_all("INFO\n".as_bytes()).await,
+            Request::GetStats => writer.write_all("STATS\n".as_bytes()).await,
+            Request::GetLimits => writer.write_all("LIMITS\n".as_bytes()).await,
             Request::GetPixel { x, y } => writer.write_all(format!("PX {} {}\n", x, y).as_bytes()).await,
+            Request::GetPixelBlock(region) => {
+                writer.write_all(format!("PXGET {}\n", region).as_bytes()).await
+            }
             Request::SetPixel { x, y, color } => {
                 writer
                     .write_all(format!("PX {} {} {:X}\n", x, y, color).as_bytes())
                     .await
             }
+            Request::SetPixelBatch(_) => writer.write_all(format!("{}\n", self).as_bytes()).await,
+            Request::FillRect { .. } => writer.write_all(format!("{}\n", self).as_bytes()).await,
+            Request::DrawLine { .. } => writer.write_all(format!("{}\n", self).as_bytes()).await,
+            Request::PutImage { data, .. } => {
+                writer.write_all(format!("{}\n", self).as_bytes()).await?;
+                writer.write_all(data).await
+            }
+            Request::StreamState { .. } => writer.write_all(format!("{}\n", self).as_bytes()).await,
+            Request::Authenticate(_) => writer.write_all(format!("{}\n", self).as_bytes()).await,
+            Request::SetCompression(compression) => {
+                writer
+                    .write_all(format!("COMPRESS {}\n", compression.as_str()).as_bytes())
+                    .await
+            }
+            Request::BlendPixel { x, y, color, alpha } => {
+                writer
+                    .write_all(format!("PX {} {} {:X}{:02X}\n", x, y, color, alpha).as_bytes())
+                    .await
+            }
+            Request::ComposePixel { .. } => writer.write_all(format!("{}\n", self).as_bytes()).await,
+            Request::SetProtocol(variant) => {
+                writer
+                    .write_all(format!("PROTOCOL {}\n", variant.as_str()).as_bytes())
+                    .await
+            }
+            Request::SetNoReply(_) => writer.write_all(format!("{}\n", self).as_bytes()).await,
+            Request::Claim { .. } => writer.write_all(format!("{}\n", self).as_bytes()).await,
+            Request::Release => writer.write_all("RELEASE\n".as_bytes()).await,
+            Request::SetNick(_) => writer.write_all(format!("{}\n", self).as_bytes()).await,
+            Request::Multi => writer.write_all("MULTI\n".as_bytes()).await,
+            Request::Exec => writer.write_all("EXEC\n".as_bytes()).await,
         }
     }
 }
@@ -81,23 +784,86 @@ impl Request {
 impl Display for Request {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            Request::Help(topic) => match topic {
-                HelpTopic::General => f.write_str("HELP"),
-                HelpTopic::Size => f.write_str("HELP SIZE"),
-                HelpTopic::Px => f.write_str("HELP PX"),
-            },
+            Request::Help(HelpTopic::General) => f.write_str("HELP"),
+            Request::Help(topic) => f.write_fmt(format_args!("HELP {}", topic.name())),
+            Request::HelpJson => f.write_str("HELP JSON"),
+            Request::Hello { version, features } => write_hello(f, *version, *features),
             Request::GetSize => f.write_str("SIZE"),
+            Request::GetInfo => f.write_str("INFO"),
+            Request::GetStats => f.write_str("STATS"),
+            Request::GetLimits => f.write_str("LIMITS"),
             Request::GetPixel { x, y } => f.write_fmt(format_args!("PX {} {}", x, y)),
+            Request::GetPixelBlock(region) => f.write_fmt(format_args!("PXGET {}", region)),
             Request::SetPixel { x, y, color } => f.write_fmt(format_args!("PX {} {} {:X}", x, y, color)),
+            Request::SetPixelBatch(pixels) => {
+                f.write_fmt(format_args!("PXB {}", pixels.len()))?;
+                for (x, y, color) in pixels {
+                    f.write_fmt(format_args!(" {} {} {:X}", x, y, color))?;
+                }
+                Ok(())
+            }
+            Request::FillRect {
+                x,
+                y,
+                width,
+                height,
+                color,
+            } => f.write_fmt(format_args!("RECT {} {} {} {} {:X}", x, y, width, height, color)),
+            Request::DrawLine {
+                x1,
+                y1,
+                x2,
+                y2,
+                color,
+            } => f.write_fmt(format_args!("LINE {} {} {} {} {:X}", x1, y1, x2, y2, color)),
+            Request::PutImage { x, y, data } => f.write_fmt(format_args!("IMG {} {} {}", x, y, data.len())),
+            Request::StreamState {
+                algorithm,
+                fps,
+                region,
+            } => {
+                f.write_fmt(format_args!("STREAM {} {}", algorithm.as_str(), fps))?;
+                match region {
+                    Some(region) => f.write_fmt(format_args!(" {}", region)),
+                    None => Ok(()),
+                }
+            }
+            Request::SetCompression(compression) => {
+                f.write_fmt(format_args!("COMPRESS {}", compression.as_str()))
+            }
+            Request::Authenticate(token) => f.write_fmt(format_args!("AUTH {}", token)),
+            Request::BlendPixel { x, y, color, alpha } => {
+                f.write_fmt(format_args!("PX {} {} {:X}{:02X}", x, y, color, alpha))
+            }
+            Request::ComposePixel { x, y, color, mode } => {
+                f.write_fmt(format_args!("PX {} {} {:X} {}", x, y, color, mode))
+            }
+            Request::SetProtocol(variant) => f.write_fmt(format_args!("PROTOCOL {}", variant.as_str())),
+            Request::SetNoReply(enabled) => f.write_fmt(format_args!("NOREPLY {}", switch_name(*enabled))),
+            Request::Claim { region, secs } => f.write_fmt(format_args!("CLAIM {} {}", region, secs)),
+            Request::Release => f.write_str("RELEASE"),
+            Request::SetNick(name) => f.write_fmt(format_args!("NICK {}", name)),
+            Request::Multi => f.write_str("MULTI"),
+            Request::Exec => f.write_str("EXEC"),
         }
     }
 }
 
 /// The response of a pixelflut server
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Response {
     /// Help about a specific topic with more information about that topic
     Help(HelpTopic),
+    /// A machine-readable description of all commands and the enabled optional features as one line of JSON
+    HelpJson(String),
+    /// The protocol version and optional features which the server enabled on this connection
+    Hello {
+        /// The protocol version which is used on this connection
+        version: u32,
+        /// The features which the client may use on this connection
+        features: Features,
+    },
     /// Size information about the servers canvas
     Size {
         /// Width of the canvas in number of pixels
@@ -114,34 +880,79 @@ pub enum Response {
         /// The color of the pixel
         color: Color,
     },
+    /// Confirmation that all following requests and responses on this connection use the given encoding
+    Protocol(ProtocolVariant),
+    /// Confirmation that all data which the server sends after this response is compressed
+    Compression(Compression),
+    /// Confirmation that this connection is authenticated and may draw on the canvas
+    Authenticated,
+    /// Information about the capabilities of the server
+    Info(ServerInfo),
+    /// Live statistics about the server
+    Stats(ServerStats),
+    /// The limits which the server enforces on this connection
+    Limits(ServerLimits),
+    /// Confirmation whether responses to requests which draw on the canvas are suppressed on this connection
+    NoReply(bool),
+    /// Confirmation that the requested region is claimed by this connection
+    Claimed,
+    /// Confirmation that this connection no longer holds a claim
+    Released,
+    /// Confirmation that this connection is labeled with the requested name
+    NickSet,
+    /// The colors of all pixels in a region, row by row
+    ///
+    /// On the wire, the colors are encoded as base64 of their rgb values.
+    PxBlock {
+        /// The region whose pixels are contained
+        region: Region,
+        /// The colors of the pixels
+        colors: Vec<Color>,
+    },
+    /// Confirmation that the following requests of this connection which draw on the canvas are queued
+    TransactionStarted,
+    /// Confirmation that the given number of queued requests was applied
+    Executed(usize),
 }
 
 impl Response {
     /// Write the binary representation of this response into the given writer
     pub fn write(&self, writer: &mut impl Write) -> std::io::Result<()> {
         match self {
-            Response::Help(topic) => match topic {
-                HelpTopic::General => writer.write_all(texts::HELP_GENERAL.as_bytes()),
-                HelpTopic::Size => writer.write_all(texts::HELP_SIZE.as_bytes()),
-                HelpTopic::Px => writer.write_all(texts::HELP_PX.as_bytes()),
-            },
+            Response::Help(topic) => writer.write_all(texts::help_text(*topic).as_bytes()),
+            Response::HelpJson(_) => writer.write_all(format!("{}\n", self).as_bytes()),
             Response::Size { width, height } => {
                 writer.write_all(format!("SIZE {} {}\n", width, height).as_bytes())
             }
             Response::PxData { x, y, color } => {
                 writer.write_all(format!("PX {} {} {:X}\n", x, y, color).as_bytes())
             }
+            Response::Protocol(variant) => {
+                writer.write_all(format!("PROTOCOL {}\n", variant.as_str()).as_bytes())
+            }
+            Response::Compression(compression) => {
+                writer.write_all(format!("COMPRESS {}\n", compression.as_str()).as_bytes())
+            }
+            Response::Authenticated => writer.write_all("AUTH OK\n".as_bytes()),
+            Response::Hello { .. } => writer.write_all(format!("{}\n", self).as_bytes()),
+            Response::Info(info) => writer.write_all(format!("{}\n", info).as_bytes()),
+            Response::Stats(stats) => writer.write_all(format!("{}\n", stats).as_bytes()),
+            Response::Limits(limits) => writer.write_all(format!("{}\n", limits).as_bytes()),
+            Response::NoReply(_) => writer.write_all(format!("{}\n", self).as_bytes()),
+            Response::Claimed => writer.write_all("CLAIM OK\n".as_bytes()),
+            Response::Released => writer.write_all("RELEASE OK\n".as_bytes()),
+            Response::NickSet => writer.write_all("NICK OK\n".as_bytes()),
+            Response::PxBlock { .. } => writer.write_all(format!("{}\n", self).as_bytes()),
+            Response::TransactionStarted => writer.write_all("MULTI OK\n".as_bytes()),
+            Response::Executed(count) => writer.write_all(format!("EXEC {}\n", count).as_bytes()),
         }
     }
 
     /// Write the binary representation of this response into the given async writer
     pub async fn write_async(&self, writer: &mut (impl AsyncWrite + Unpin)) -> std::io::Result<()> {
         match self {
-            Response::Help(topic) => match topic {
-                HelpTopic::General => writer.write_all(texts::HELP_GENERAL.as_bytes()).await,
-                HelpTopic::Size => writer.write_all(texts::HELP_SIZE.as_bytes()).await,
-                HelpTopic::Px => writer.write_all(texts::HELP_PX.as_bytes()).await,
-            },
+            Response::Help(topic) => writer.write_all(texts::help_text(*topic).as_bytes()).await,
+            Response::HelpJson(_) => writer.write_all(format!("{}\n", self).as_bytes()).await,
             Response::Size { width, height } => {
                 writer
                     .write_all(format!("SIZE {} {}\n", width, height).as_bytes())
@@ -152,6 +963,28 @@ impl Response {
                     .write_all(format!("PX {} {} {:X}\n", x, y, color).as_bytes())
                     .await
             }
+            Response::Protocol(variant) => {
+                writer
+                    .write_all(format!("PROTOCOL {}\n", variant.as_str()).as_bytes())
+                    .await
+            }
+            Response::Compression(compression) => {
+                writer
+                    .write_all(format!("COMPRESS {}\n", compression.as_str()).as_bytes())
+                    .await
+            }
+            Response::Authenticated => writer.write_all("AUTH OK\n".as_bytes()).await,
+            Response::Hello { .. } => writer.write_all(format!("{}\n", self).as_bytes()).await,
+            Response::Info(info) => writer.write_all(format!("{}\n", info).as_bytes()).await,
+            Response::Stats(stats) => writer.write_all(format!("{}\n", stats).as_bytes()).await,
+            Response::Limits(limits) => writer.write_all(format!("{}\n", limits).as_bytes()).await,
+            Response::NoReply(_) => writer.write_all(format!("{}\n", self).as_bytes()).await,
+            Response::Claimed => writer.write_all("CLAIM OK\n".as_bytes()).await,
+            Response::Released => writer.write_all("RELEASE OK\n".as_bytes()).await,
+            Response::NickSet => writer.write_all("NICK OK\n".as_bytes()).await,
+            Response::PxBlock { .. } => writer.write_all(format!("{}\n", self).as_bytes()).await,
+            Response::TransactionStarted => writer.write_all("MULTI OK\n".as_bytes()).await,
+            Response::Executed(count) => writer.write_all(format!("EXEC {}\n", count).as_bytes()).await,
         }
     }
 }
@@ -159,13 +992,118 @@ impl Response {
 impl Display for Response {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            Response::Help(topic) => match topic {
-                HelpTopic::General => f.write_str(texts::HELP_GENERAL),
-                HelpTopic::Size => f.write_str(texts::HELP_SIZE),
-                HelpTopic::Px => f.write_str(texts::HELP_PX),
-            },
+            Response::Help(topic) => f.write_str(&texts::help_text(*topic)),
+            Response::HelpJson(json) => f.write_fmt(format_args!("HELP JSON {}", json)),
             Response::Size { width, height } => f.write_fmt(format_args!("SIZE {} {}", width, height)),
             Response::PxData { x, y, color } => f.write_fmt(format_args!("PX {} {} {:X}", x, y, color)),
+            Response::Protocol(variant) => f.write_fmt(format_args!("PROTOCOL {}", variant.as_str())),
+            Response::Compression(compression) => {
+                f.write_fmt(format_args!("COMPRESS {}", compression.as_str()))
+            }
+            Response::Authenticated => f.write_str("AUTH OK"),
+            Response::Hello { version, features } => write_hello(f, *version, *features),
+            Response::Info(info) => info.fmt(f),
+            Response::Stats(stats) => stats.fmt(f),
+            Response::Limits(limits) => limits.fmt(f),
+            Response::NoReply(enabled) => f.write_fmt(format_args!("NOREPLY {}", switch_name(*enabled))),
+            Response::Claimed => f.write_str("CLAIM OK"),
+            Response::Released => f.write_str("RELEASE OK"),
+            Response::NickSet => f.write_str("NICK OK"),
+            Response::PxBlock { region, colors } => {
+                let rgb: Vec<u8> = colors.iter().flat_map(|color| <[u8; 3]>::from(*color)).collect();
+                f.write_fmt(format_args!("PXGET {} {}", region, BASE64_STANDARD.encode(rgb)))
+            }
+            Response::TransactionStarted => f.write_str("MULTI OK"),
+            Response::Executed(count) => f.write_fmt(format_args!("EXEC {}", count)),
         }
     }
 }
+
+/// The name of a switch like NOREPLY on the wire
+fn switch_name(enabled: bool) -> &'static str {
+    match enabled {
+        true => "on",
+        false => "off",
+    }
+}
+
+/// The reason why a server could not handle a request
+///
+/// Errors are sent to clients as `ERR <code> <message>` so that clients can react to the code programmatically
+/// while the message is meant for humans.
+#[derive(Debug, Error, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ResponseError {
+    /// The request addresses pixels outside of the canvas
+    #[error("ERR OUT_OF_BOUNDS {0}")]
+    OutOfBounds(String),
+    /// The request could not be parsed
+    #[error("ERR PARSE_ERROR {0}")]
+    ParseError(String),
+    /// The connection must authenticate via AUTH before it may send the request
+    #[error("ERR UNAUTHORIZED {0}")]
+    Unauthorized(String),
+    /// The client sent more than the server allows
+    #[error("ERR RATE_LIMITED {0}")]
+    RateLimited(String),
+    /// The request is valid but not supported by the server or on this connection
+    #[error("ERR UNSUPPORTED {0}")]
+    Unsupported(String),
+    /// The request draws into a region which another connection claimed via CLAIM
+    #[error("ERR CLAIMED {0}")]
+    Claimed(String),
+    /// The server is shutting down and closes the connection
+    #[error("ERR SHUTTING_DOWN {0}")]
+    ShuttingDown(String),
+}
+
+impl ResponseError {
+    /// The machine-readable code of this error
+    pub fn code(&self) -> &'static str {
+        match self {
+            ResponseError::OutOfBounds(_) => "OUT_OF_BOUNDS",
+            ResponseError::ParseError(_) => "PARSE_ERROR",
+            ResponseError::Unauthorized(_) => "UNAUTHORIZED",
+            ResponseError::RateLimited(_) => "RATE_LIMITED",
+            ResponseError::Unsupported(_) => "UNSUPPORTED",
+            ResponseError::Claimed(_) => "CLAIMED",
+            ResponseError::ShuttingDown(_) => "SHUTTING_DOWN",
+        }
+    }
+
+    /// The human-readable message of this error
+    pub fn message(&self) -> &str {
+        match self {
+            ResponseError::OutOfBounds(message)
+            | ResponseError::ParseError(message)
+            | ResponseError::Unauthorized(message)
+            | ResponseError::RateLimited(message)
+            | ResponseError::Unsupported(message)
+            | ResponseError::Claimed(message)
+            | ResponseError::ShuttingDown(message) => message,
+        }
+    }
+
+    /// Create the error with the given code, if it is known
+    pub fn from_code(code: &str, message: String) -> Option<Self> {
+        match code {
+            "OUT_OF_BOUNDS" => Some(ResponseError::OutOfBounds(message)),
+            "PARSE_ERROR" => Some(ResponseError::ParseError(message)),
+            "UNAUTHORIZED" => Some(ResponseError::Unauthorized(message)),
+            "RATE_LIMITED" => Some(ResponseError::RateLimited(message)),
+            "UNSUPPORTED" => Some(ResponseError::Unsupported(message)),
+            "CLAIMED" => Some(ResponseError::Claimed(message)),
+            "SHUTTING_DOWN" => Some(ResponseError::ShuttingDown(message)),
+            _ => None,
+        }
+    }
+}
+
+/// Format a HELLO request or response which both list a version followed by feature names
+fn write_hello(f: &mut Formatter<'_>, version: u32, features: Features) -> std::fmt::Result {
+    f.write_fmt(format_args!("HELLO {}", version))?;
+    for name in features.names() {
+        f.write_fmt(format_args!(" {}", name))?;
+    }
+    Ok(())
+}
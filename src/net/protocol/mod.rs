@@ -1,9 +1,20 @@
 //! Definitions for the network protocol
 
+mod binary;
+mod commands;
 mod compliant_parser;
 mod dtypes;
 
 pub use dtypes::*;
 
-pub use compliant_parser::{parse_request_bin, parse_request_str};
-pub use compliant_parser::{parse_response_bin, parse_response_str};
+pub use commands::{ArgumentType, CommandDescription, COMMANDS};
+
+pub use binary::{
+    has_binary_encoding, parse_request_binary, parse_response_binary, write_error_binary,
+    write_request_binary, write_response_binary, write_state_binary,
+};
+pub use binary::{parse_request_pb, write_request_pb, PB_PREFIX, PB_RECORD_LEN};
+pub(crate) use compliant_parser::parse_image_header_bytes;
+pub use compliant_parser::ParseErr;
+pub use compliant_parser::{parse_error_response, parse_response_bin, parse_response_str};
+pub use compliant_parser::{parse_image_header, parse_request_bin, parse_request_str, split_tag};
@@ -0,0 +1,133 @@
+//! Restriction of the networks from which a listener accepts clients
+//!
+//! Besides the static lists of every listener, networks can be banned from all listeners while the server runs via
+//! the admin interface.
+
+use ipnet::IpNet;
+use std::net::IpAddr;
+use std::sync::RwLock;
+
+/// The networks which are currently banned from all listeners
+static BANNED: RwLock<Vec<IpNet>> = RwLock::new(Vec::new());
+
+/// The source networks from which clients may use a listener
+///
+/// Addresses in one of the `deny` networks or in a [banned](ban) network are always rejected.
+/// If `allow` is not empty, all other addresses are only accepted if they are in one of its networks, otherwise
+/// they are all accepted.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct AccessControlOptions {
+    /// The networks from which clients are accepted, or an empty list to accept all which are not denied
+    pub allow: Vec<IpNet>,
+    /// The networks from which clients are never accepted, e.g. those of known abusers
+    pub deny: Vec<IpNet>,
+}
+
+impl AccessControlOptions {
+    /// Whether any client is rejected at all
+    pub fn is_restricted(&self) -> bool {
+        !self.allow.is_empty() || !self.deny.is_empty()
+    }
+
+    /// Whether a client from `ip` is accepted
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|net| net.contains(&ip)) || is_banned(ip) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip))
+    }
+}
+
+/// Reject clients from `net` on all listeners until it is unbanned
+///
+/// Returns whether the network was not banned before.
+pub(crate) fn ban(net: IpNet) -> bool {
+    let mut banned = BANNED.write().unwrap();
+    if banned.contains(&net) {
+        return false;
+    }
+    banned.push(net);
+    true
+}
+
+/// Accept clients from `net` again
+///
+/// Returns whether the network was banned before.
+pub(crate) fn unban(net: IpNet) -> bool {
+    let mut banned = BANNED.write().unwrap();
+    let len = banned.len();
+    banned.retain(|candidate| *candidate != net);
+    banned.len() != len
+}
+
+/// The networks which are currently banned
+pub(crate) fn banned() -> Vec<IpNet> {
+    BANNED.read().unwrap().clone()
+}
+
+/// The address under which a client is throttled and limited
+///
+/// IPv6 hosts usually get a whole /64 network and can use any address in it, so all of its addresses are treated as
+/// the same client and mapped to the first one. IPv4 addresses are used as they are.
+pub(crate) fn client_key(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(ip) => IpAddr::V6((u128::from(ip) & !(u64::MAX as u128)).into()),
+        ip => ip,
+    }
+}
+
+/// Whether `ip` is in one of the banned networks
+fn is_banned(ip: IpAddr) -> bool {
+    BANNED.read().unwrap().iter().any(|net| net.contains(&ip))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_permits() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert!(AccessControlOptions::default().permits(ip("192.0.2.1")));
+
+        let options = AccessControlOptions {
+            allow: vec!["10.0.0.0/8".parse().unwrap(), "2001:db8::/32".parse().unwrap()],
+            deny: vec!["10.1.0.0/16".parse().unwrap()],
+        };
+        assert!(options.is_restricted());
+        assert!(options.permits(ip("10.0.0.1")));
+        assert!(options.permits(ip("2001:db8::1")));
+        assert!(!options.permits(ip("10.1.2.3")));
+        assert!(!options.permits(ip("192.0.2.1")));
+
+        let options = AccessControlOptions {
+            allow: Vec::new(),
+            deny: vec!["192.0.2.0/24".parse().unwrap()],
+        };
+        assert!(!options.permits(ip("192.0.2.1")));
+        assert!(options.permits(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn test_ban() {
+        // a network which no other test uses since bans apply to the whole process
+        let ip = "198.51.100.7".parse::<IpAddr>().unwrap();
+        let net = "198.51.100.0/24".parse::<IpNet>().unwrap();
+        assert!(ban(net));
+        assert!(!ban(net));
+        assert!(banned().contains(&net));
+        assert!(!AccessControlOptions::default().permits(ip));
+        assert!(unban(net));
+        assert!(!unban(net));
+        assert!(AccessControlOptions::default().permits(ip));
+    }
+
+    #[test]
+    fn test_client_key() {
+        let key = |ip: &str| client_key(ip.parse().unwrap()).to_string();
+        assert_eq!(key("10.0.0.1"), "10.0.0.1");
+        assert_eq!(key("::ffff:10.0.0.1"), "10.0.0.1");
+        assert_eq!(key("2001:db8:1:2:3:4:5:6"), "2001:db8:1:2::");
+        assert_eq!(key("2001:db8:1:2::ffff"), "2001:db8:1:2::");
+    }
+}
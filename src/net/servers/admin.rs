@@ -0,0 +1,401 @@
+//! An interface for operators which is kept apart from the listeners that players use
+//!
+//! It only listens on a unix socket or on a loopback address and speaks its own small line protocol so that none of
+//! its commands can be reached via the public protocol. Every command is answered with a single line that starts
+//! with `OK` or `ERR`:
+//!
+//! - `HELP` lists the commands.
+//! - `CLEAR [<rrggbb>]` fills the whole canvas with one color, black by default.
+//! - `RESIZE <width> <height>` is refused since the canvas size is fixed while the server runs.
+//! - `BAN <ip or network>` rejects new connections and datagrams from a network on all listeners,
+//!   `UNBAN <ip or network>` lifts that again and `BANS` lists all banned networks.
+//! - `READONLY on|off` rejects all requests which draw on the canvas, or accepts them again.
+//! - `STATS` returns the live statistics which clients get via STATS, e.g. how many connection storms were detected.
+//! - `CLIENTS` lists the traffic and pixels of every open connection and of every IP address, the most active clients
+//!   first, as entries like `CONNECTION id=3 remote=10.0.0.1:4567 bytes-received=8192 bytes-sent=16 pixels=512` and
+//!   `IP 10.0.0.1 connections=1 bytes-received=8192 bytes-sent=16 pixels=512` which are separated by `; `.
+//! - `SNAPSHOT` makes the snapshot file sink write a snapshot right away.
+//! - `RELOAD` asks whoever started the server to reload its configuration, see [`reload_requests`].
+//! - `UPGRADE` asks whoever started the server to hand all listeners and the canvas over to a new server process,
+//!   see [`upgrade_requests`].
+
+use crate::net::servers::{access_control, socket_activation, statistics, write_protection, GenServer};
+use crate::pixmap::{Color, SharedPixmap};
+use crate::sinks::pixmap_file;
+use crate::DaemonResult;
+use anyhow::anyhow;
+use async_trait::async_trait;
+use ipnet::IpNet;
+use socket2::Type;
+use std::ffi::OsString;
+use std::fs::{DirBuilder, Permissions};
+use std::net::{IpAddr, SocketAddr};
+use std::os::fd::AsFd;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, UnixListener};
+use tokio::sync::watch;
+use tokio::task::{AbortHandle, JoinSet};
+
+/// The longest command line which is accepted
+const MAX_COMMAND_LEN: u64 = 1024;
+
+/// How often a reload was requested via the `RELOAD` command
+static RELOAD_REQUESTS: LazyLock<watch::Sender<u64>> = LazyLock::new(|| watch::Sender::new(0));
+
+/// Get notified whenever a reload of the configuration is requested via the `RELOAD` command
+///
+/// The command fails while nobody holds a receiver.
+pub fn reload_requests() -> watch::Receiver<u64> {
+    RELOAD_REQUESTS.subscribe()
+}
+
+/// How often a handover to a new server process was requested via the `UPGRADE` command
+static UPGRADE_REQUESTS: LazyLock<watch::Sender<u64>> = LazyLock::new(|| watch::Sender::new(0));
+
+/// Get notified whenever a handover of all listeners and the canvas to a new server process is requested via the
+/// `UPGRADE` command
+///
+/// The command fails while nobody holds a receiver.
+pub fn upgrade_requests() -> watch::Receiver<u64> {
+    UPGRADE_REQUESTS.subscribe()
+}
+
+/// Where the admin interface listens
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum AdminEndpoint {
+    /// A unix socket
+    Unix {
+        /// The path at which the socket is created
+        path: PathBuf,
+        /// The file mode of the socket, which only gives the user of the server access by default
+        permissions: Option<u32>,
+    },
+    /// A TCP socket which must be bound to a loopback address
+    Tcp(SocketAddr),
+}
+
+/// Options with which the `AdminServer` is configured
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct AdminServerOptions {
+    /// Where the admin interface listens
+    pub endpoint: AdminEndpoint,
+}
+
+/// A server for privileged commands of operators, see the [module documentation](self)
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct AdminServer {
+    options: AdminServerOptions,
+}
+
+impl AdminServer {
+    async fn handle_connection(
+        stream: impl AsyncRead + AsyncWrite + Unpin,
+        pixmap: SharedPixmap,
+    ) -> anyhow::Result<()> {
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut reader = BufReader::new(reader);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let n = (&mut reader).take(MAX_COMMAND_LEN).read_line(&mut line).await?;
+            if n == 0 {
+                return Ok(());
+            }
+            let response = match line.ends_with('\n') || n < MAX_COMMAND_LEN as usize {
+                true => execute(line.trim(), &pixmap),
+                false => Err(anyhow!("Command is too long")),
+            };
+            let response = match response {
+                Ok(message) => format!("OK {}\n", message),
+                Err(e) => format!("ERR {}\n", e),
+            };
+            writer.write_all(response.as_bytes()).await?;
+            if !line.ends_with('\n') && n == MAX_COMMAND_LEN as usize {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Execute an admin command and return a description of what was done
+fn execute(command: &str, pixmap: &SharedPixmap) -> anyhow::Result<String> {
+    let mut args = command.split_whitespace();
+    let name = args.next().unwrap_or_default().to_ascii_uppercase();
+    let args: Vec<&str> = args.collect();
+    match (name.as_str(), args.as_slice()) {
+        ("HELP", []) => Ok(
+            "HELP, CLEAR [<rrggbb>], RESIZE <width> <height>, BAN <ip or network>, \
+            UNBAN <ip or network>, BANS, READONLY on|off, STATS, CLIENTS, SNAPSHOT, RELOAD, UPGRADE"
+                .to_string(),
+        ),
+        ("CLEAR", color) if color.len() <= 1 => {
+            let color = match color.first() {
+                Some(color) => color
+                    .parse::<Color>()
+                    .map_err(|e| anyhow!("Invalid color: {}", e))?,
+                None => Color::from((0, 0, 0)),
+            };
+            let (width, height) = pixmap.get_size();
+            pixmap.fill_rect(0, 0, width, height, color)?;
+            tracing::info!("Cleared the canvas with color {:X}", color);
+            Ok(format!("Cleared the canvas with color {:X}", color))
+        }
+        ("RESIZE", [_, _]) => Err(anyhow!(
+            "The canvas size is fixed while the server runs; restart it with --width and --height instead"
+        )),
+        ("BAN", [net]) => {
+            let net = parse_net(net)?;
+            match access_control::ban(net) {
+                true => {
+                    tracing::info!("Banned {}", net);
+                    Ok(format!("Banned {}", net))
+                }
+                false => Ok(format!("{} was already banned", net)),
+            }
+        }
+        ("UNBAN", [net]) => {
+            let net = parse_net(net)?;
+            match access_control::unban(net) {
+                true => {
+                    tracing::info!("Unbanned {}", net);
+                    Ok(format!("Unbanned {}", net))
+                }
+                false => Err(anyhow!("{} is not banned", net)),
+            }
+        }
+        ("BANS", []) => Ok(access_control::banned()
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(" ")),
+        ("READONLY", [value]) => {
+            let read_only = match *value {
+                "on" => true,
+                "off" => false,
+                _ => return Err(anyhow!("Invalid value {:?}; expected on or off", value)),
+            };
+            write_protection::set_read_only(read_only);
+            tracing::info!(
+                "Made the canvas {}",
+                if read_only { "read-only" } else { "writable" }
+            );
+            Ok(format!("read-only {}", value))
+        }
+        ("STATS", []) => {
+            let stats = statistics::current().to_string();
+            Ok(stats.trim_start_matches("STATS ").to_string())
+        }
+        ("CLIENTS", []) => {
+            let clients = statistics::clients();
+            Ok(clients
+                .connections
+                .iter()
+                .map(ToString::to_string)
+                .chain(clients.ips.iter().map(ToString::to_string))
+                .collect::<Vec<_>>()
+                .join("; "))
+        }
+        ("SNAPSHOT", []) => match pixmap_file::request_snapshot() {
+            true => Ok("Requested a snapshot".to_string()),
+            false => Err(anyhow!("No snapshot file is configured")),
+        },
+        ("RELOAD", []) => {
+            RELOAD_REQUESTS.send_modify(|requests| *requests += 1);
+            match RELOAD_REQUESTS.receiver_count() > 0 {
+                true => Ok("Requested a reload".to_string()),
+                false => Err(anyhow!("There is no configuration which could be reloaded")),
+            }
+        }
+        ("UPGRADE", []) => {
+            UPGRADE_REQUESTS.send_modify(|requests| *requests += 1);
+            match UPGRADE_REQUESTS.receiver_count() > 0 {
+                true => Ok("Requested a handover to a new server process".to_string()),
+                false => Err(anyhow!("This server cannot hand over to a new process")),
+            }
+        }
+        ("", []) => Err(anyhow!("Empty command")),
+        _ => Err(anyhow!(
+            "Unknown command or wrong arguments; send HELP for a list of commands"
+        )),
+    }
+}
+
+/// Parse a network like `10.0.0.0/8` or a single address like `10.0.0.1`
+fn parse_net(s: &str) -> anyhow::Result<IpNet> {
+    s.parse::<IpNet>()
+        .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| anyhow!("Invalid ip address or network {:?}", s))
+}
+
+/// Bind a unix socket at `path` which nobody can connect to before it has the given mode
+///
+/// The socket is bound in a directory which only the user of the server can access and moved to `path` once its
+/// mode is set.
+fn bind_unix(path: &Path, mode: u32) -> anyhow::Result<UnixListener> {
+    if path.symlink_metadata().is_ok() {
+        return Err(anyhow!(
+            "Could not bind to {} since it already exists",
+            path.display()
+        ));
+    }
+    let mut dir_name = OsString::from(".");
+    dir_name.push(
+        path.file_name()
+            .ok_or_else(|| anyhow!("Invalid unix socket path {}", path.display()))?,
+    );
+    dir_name.push(format!(".{}", std::process::id()));
+    let dir = path.with_file_name(dir_name);
+    DirBuilder::new().mode(0o700).create(&dir)?;
+    let bound = (|| {
+        let private_path = dir.join("socket");
+        let listener = UnixListener::bind(&private_path)?;
+        std::fs::set_permissions(&private_path, Permissions::from_mode(mode))?;
+        std::fs::rename(&private_path, path)?;
+        anyhow::Ok(listener)
+    })();
+    if let Err(e) = std::fs::remove_dir_all(&dir) {
+        tracing::warn!("Could not remove {}: {}", dir.display(), e);
+    }
+    bound
+}
+
+#[async_trait]
+impl GenServer for AdminServer {
+    type Options = AdminServerOptions;
+
+    fn new(options: Self::Options) -> Self {
+        Self { options }
+    }
+
+    async fn start(
+        self,
+        pixmap: SharedPixmap,
+        join_set: &mut JoinSet<DaemonResult>,
+    ) -> anyhow::Result<AbortHandle> {
+        let handle = match self.options.endpoint {
+            AdminEndpoint::Unix { path, permissions } => {
+                let listener = match socket_activation::take_unix(&path) {
+                    Some(listener) => {
+                        listener.set_nonblocking(true)?;
+                        UnixListener::from_std(listener)?
+                    }
+                    // only the user of the server may use the socket unless another mode is configured
+                    None => bind_unix(&path, permissions.unwrap_or(0o600))?,
+                };
+                socket_activation::remember(listener.as_fd());
+                tracing::info!("Started admin interface on {}", path.display());
+                join_set.build_task().name("admin_server").spawn(async move {
+                    loop {
+                        let (stream, _) = listener.accept().await?;
+                        let pixmap = pixmap.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = AdminServer::handle_connection(stream, pixmap).await {
+                                tracing::warn!("Got error while handling admin connection: {e}");
+                            }
+                        });
+                    }
+                })?
+            }
+            AdminEndpoint::Tcp(addr) => {
+                if !addr.ip().is_loopback() {
+                    return Err(anyhow!(
+                        "The admin interface must only listen on a loopback address, not on {}",
+                        addr.ip()
+                    ));
+                }
+                let listener = match socket_activation::take_inet(addr, Type::STREAM) {
+                    Some(socket) => {
+                        socket.set_nonblocking(true)?;
+                        TcpListener::from_std(socket.into())?
+                    }
+                    None => TcpListener::bind(addr).await?,
+                };
+                socket_activation::remember(listener.as_fd());
+                tracing::info!("Started admin interface on {}", addr);
+                join_set.build_task().name("admin_server").spawn(async move {
+                    loop {
+                        let (stream, _) = listener.accept().await?;
+                        let pixmap = pixmap.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = AdminServer::handle_connection(stream, pixmap).await {
+                                tracing::warn!("Got error while handling admin connection: {e}");
+                            }
+                        });
+                    }
+                })?
+            }
+        };
+        Ok(handle)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pixmap::Pixmap;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_execute() {
+        let pixmap = Arc::new(Pixmap::new(4, 2).unwrap());
+        assert!(execute("CLEAR FF0080", &pixmap).is_ok());
+        assert_eq!(pixmap.get_pixel(3, 1).unwrap(), Color::from((0xFF, 0x00, 0x80)));
+        assert!(execute("clear", &pixmap).is_ok());
+        assert_eq!(pixmap.get_pixel(3, 1).unwrap(), Color::from((0, 0, 0)));
+        assert!(execute("CLEAR nope", &pixmap).is_err());
+        assert!(execute("RESIZE 8 8", &pixmap).is_err());
+        assert!(execute("READONLY maybe", &pixmap).is_err());
+        assert!(execute("BAN 10.0.0.0/33", &pixmap).is_err());
+        assert!(execute("UNBAN 203.0.113.0/24", &pixmap).is_err());
+        assert!(execute("PX 1 1 FFFFFF", &pixmap).is_err());
+        assert!(execute("", &pixmap).is_err());
+        assert!(execute("HELP", &pixmap).unwrap().contains("CLIENTS"));
+        assert!(execute("CLIENTS", &pixmap).is_ok());
+        assert!(execute("STATS", &pixmap).unwrap().contains(" storms-detected="));
+        assert!(execute("CLIENTS 10.0.0.1", &pixmap).is_err());
+        assert!(execute("RELOAD", &pixmap).is_err());
+        let requests = reload_requests();
+        assert!(execute("RELOAD", &pixmap).is_ok());
+        assert!(requests.has_changed().unwrap());
+        assert!(execute("UPGRADE", &pixmap).is_err());
+        let requests = upgrade_requests();
+        assert!(execute("UPGRADE", &pixmap).is_ok());
+        assert!(requests.has_changed().unwrap());
+    }
+
+    #[test]
+    fn test_parse_net() {
+        assert_eq!(
+            parse_net("10.0.0.1").unwrap(),
+            "10.0.0.1/32".parse::<IpNet>().unwrap()
+        );
+        assert_eq!(
+            parse_net("2001:db8::/32").unwrap(),
+            "2001:db8::/32".parse::<IpNet>().unwrap()
+        );
+        assert!(parse_net("10.0.0.0/33").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bind_unix() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("admin.sock");
+        let _listener = bind_unix(&path, 0o660).unwrap();
+        assert_eq!(path.metadata().unwrap().permissions().mode() & 0o777, 0o660);
+        // the private directory in which the socket was bound is gone again
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+        assert!(bind_unix(&path, 0o600).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tcp_endpoint_must_be_loopback() {
+        let server = AdminServer::new(AdminServerOptions {
+            endpoint: AdminEndpoint::Tcp("0.0.0.0:0".parse().unwrap()),
+        });
+        let pixmap = Arc::new(Pixmap::new(1, 1).unwrap());
+        assert!(server.start(pixmap, &mut JoinSet::new()).await.is_err());
+    }
+}
@@ -11,7 +11,7 @@ fn bench_1000_requests(b: &mut Bencher) {
         #[allow(clippy::needless_range_loop)]
         for i in 0..COMMANDS.len() {
             let line = black_box(COMMANDS[i]);
-            let result = super::handle_request(line, &pixmap);
+            let result = super::handle_request(line, &pixmap, &Default::default());
             assert_eq!(result, Ok(None));
         }
     })
@@ -0,0 +1,339 @@
+//! Cooperative reservations of canvas regions via CLAIM and RELEASE
+//!
+//! Claims are shared by all listeners so that it does not matter through which one a client draws.
+//! They expire on their own or when the connection which holds them is closed and are checked before requests which
+//! draw on the canvas are handled.
+//! Since every write is checked against all claims, their number is limited in total and per client, and a single
+//! claim may only cover a small part of the canvas.
+
+use crate::net::protocol::{Region, Request, ResponseError};
+use crate::net::servers::access_control;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// A single claim may cover at most this fraction of the canvas
+const MAX_CLAIM_SHARE: usize = 16;
+
+/// How many claims the connections of a single client may hold at the same time
+const MAX_CLAIMS_PER_CLIENT: usize = 4;
+
+/// How many claims may exist at the same time
+const MAX_CLAIMS: usize = 1024;
+
+/// How requests which draw into a region that is claimed by another connection are treated
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ClaimMode {
+    /// The pixels are drawn but the client is told that the region is claimed
+    Advisory,
+    /// The request is rejected
+    Enforced,
+}
+
+/// An error which indicates that a string could not be parsed into a [`ClaimMode`]
+#[derive(Debug, Error, Clone, Eq, PartialEq)]
+#[error("Invalid claim mode {0:?}; expected advisory or enforced")]
+pub struct InvalidClaimModeError(String);
+
+impl FromStr for ClaimMode {
+    type Err = InvalidClaimModeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "advisory" => Ok(ClaimMode::Advisory),
+            "enforced" => Ok(ClaimMode::Enforced),
+            _ => Err(InvalidClaimModeError(s.to_string())),
+        }
+    }
+}
+
+/// Identifies the connection which holds a claim and releases it once the connection is closed
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) struct ClaimOwner {
+    id: u64,
+    /// The address of the client, by which its claims are limited, or `None` if it is unknown
+    client: Option<IpAddr>,
+}
+
+impl ClaimOwner {
+    /// An owner which is distinct from all previously created ones
+    pub fn unique(client: Option<IpAddr>) -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        Self {
+            id: NEXT.fetch_add(1, Ordering::Relaxed),
+            client: client.map(access_control::client_key),
+        }
+    }
+}
+
+impl Drop for ClaimOwner {
+    fn drop(&mut self) {
+        release(self);
+    }
+}
+
+/// A region which is reserved for one connection until it expires
+#[derive(Debug, Copy, Clone)]
+struct Claim {
+    owner: u64,
+    client: Option<IpAddr>,
+    region: Region,
+    expires: Instant,
+}
+
+impl Claim {
+    /// The error for a request which draws into this claim
+    fn conflict(&self, mode: ClaimMode, now: Instant) -> ResponseError {
+        let Region { x, y, width, height } = self.region;
+        ResponseError::Claimed(format!(
+            "The region of size {}x{} at {},{} is claimed by another client for another {} seconds{}",
+            width,
+            height,
+            x,
+            y,
+            self.expires.saturating_duration_since(now).as_secs_f64().ceil(),
+            match mode {
+                ClaimMode::Advisory => ", your pixels were drawn anyway",
+                ClaimMode::Enforced => "",
+            }
+        ))
+    }
+}
+
+/// All claims of all listeners, including expired ones which were not cleaned up yet
+static CLAIMS: RwLock<Vec<Claim>> = RwLock::new(Vec::new());
+
+/// Claim `region` of a canvas with `canvas_pixels` pixels for `owner` for the given duration, replacing its
+/// previous claim
+///
+/// Regions which overlap the claim of another owner cannot be claimed.
+pub(crate) fn claim(
+    owner: &ClaimOwner,
+    region: Region,
+    duration: Duration,
+    canvas_pixels: usize,
+) -> Result<(), ResponseError> {
+    let max_area = canvas_pixels / MAX_CLAIM_SHARE;
+    if region.width.saturating_mul(region.height) > max_area {
+        return Err(ResponseError::RateLimited(format!(
+            "A claim may cover at most {} pixels",
+            max_area
+        )));
+    }
+    let now = Instant::now();
+    let mut claims = CLAIMS.write().unwrap();
+    claims.retain(|claim| claim.expires > now);
+    let others = || claims.iter().filter(|claim| claim.owner != owner.id);
+    if let Some(other) = others().find(|claim| claim.region.intersects(&region)) {
+        return Err(other.conflict(ClaimMode::Enforced, now));
+    }
+    if owner.client.is_some()
+        && others().filter(|claim| claim.client == owner.client).count() >= MAX_CLAIMS_PER_CLIENT
+    {
+        return Err(ResponseError::RateLimited(format!(
+            "Your address may hold at most {} claims at the same time",
+            MAX_CLAIMS_PER_CLIENT
+        )));
+    }
+    if others().count() >= MAX_CLAIMS {
+        return Err(ResponseError::RateLimited(
+            "Too many regions are claimed, retry later".to_string(),
+        ));
+    }
+    claims.retain(|claim| claim.owner != owner.id);
+    claims.push(Claim {
+        owner: owner.id,
+        client: owner.client,
+        region,
+        expires: now + duration,
+    });
+    Ok(())
+}
+
+/// Release the claim of `owner`, if it holds one
+pub(crate) fn release(owner: &ClaimOwner) {
+    CLAIMS.write().unwrap().retain(|claim| claim.owner != owner.id);
+}
+
+/// Check whether `request` draws into a region which is claimed by someone else than `owner`
+pub(crate) fn check_write(
+    request: &Request,
+    owner: Option<&ClaimOwner>,
+    mode: ClaimMode,
+) -> Result<(), ResponseError> {
+    let claims = CLAIMS.read().unwrap();
+    if claims.is_empty() {
+        return Ok(());
+    }
+    let now = Instant::now();
+    match claims
+        .iter()
+        .filter(|claim| claim.expires > now && Some(claim.owner) != owner.map(|owner| owner.id))
+        .find(|claim| touches(request, &claim.region))
+    {
+        Some(claim) => Err(claim.conflict(mode, now)),
+        None => Ok(()),
+    }
+}
+
+/// Whether `request` draws into `region`
+///
+/// Lines are checked by their bounding box.
+fn touches(request: &Request, region: &Region) -> bool {
+    match request {
+        Request::SetPixel { x, y, .. }
+        | Request::BlendPixel { x, y, .. }
+        | Request::ComposePixel { x, y, .. } => region.contains(*x, *y),
+        Request::SetPixelBatch(pixels) => pixels.iter().any(|(x, y, _)| region.contains(*x, *y)),
+        Request::FillRect {
+            x, y, width, height, ..
+        } => region.intersects(&Region {
+            x: *x,
+            y: *y,
+            width: *width,
+            height: *height,
+        }),
+        Request::DrawLine { x1, y1, x2, y2, .. } => region.intersects(&Region {
+            x: *x1.min(x2),
+            y: *y1.min(y2),
+            width: x1.abs_diff(*x2) + 1,
+            height: y1.abs_diff(*y2) + 1,
+        }),
+        Request::PutImage { x, y, data } => image_size(data).is_some_and(|(width, height)| {
+            region.intersects(&Region {
+                x: *x,
+                y: *y,
+                width,
+                height,
+            })
+        }),
+        _ => false,
+    }
+}
+
+/// The size of an encoded image, read from its header without decoding it
+#[cfg(feature = "images")]
+fn image_size(data: &[u8]) -> Option<(usize, usize)> {
+    let (width, height) = image::ImageReader::new(std::io::Cursor::new(data))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()?;
+    Some((width as usize, height as usize))
+}
+
+/// Images cannot be drawn at all without the `images` feature
+#[cfg(not(feature = "images"))]
+fn image_size(_data: &[u8]) -> Option<(usize, usize)> {
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pixmap::Color;
+
+    const CANVAS: usize = 2000 * 2000;
+
+    #[test]
+    fn test_claims() {
+        let (alice, bob) = (ClaimOwner::unique(None), ClaimOwner::unique(None));
+        let region = Region {
+            x: 1000,
+            y: 1000,
+            width: 10,
+            height: 10,
+        };
+        let pixel = |x, y| Request::SetPixel {
+            x,
+            y,
+            color: Color::from((0xFF, 0, 0)),
+        };
+        claim(&alice, region, Duration::from_secs(60), CANVAS).unwrap();
+
+        // only other connections are affected by a claim
+        assert_eq!(
+            check_write(&pixel(1005, 1005), Some(&alice), ClaimMode::Enforced),
+            Ok(())
+        );
+        assert_eq!(
+            check_write(&pixel(1010, 1005), Some(&bob), ClaimMode::Enforced),
+            Ok(())
+        );
+        let error = check_write(&pixel(1005, 1005), Some(&bob), ClaimMode::Enforced).unwrap_err();
+        assert_eq!(error.code(), "CLAIMED");
+        assert!(check_write(&pixel(1009, 1009), None, ClaimMode::Advisory)
+            .unwrap_err()
+            .message()
+            .ends_with("drawn anyway"));
+        let line = Request::DrawLine {
+            x1: 990,
+            y1: 1020,
+            x2: 1020,
+            y2: 990,
+            color: Color::from((0xFF, 0, 0)),
+        };
+        assert!(check_write(&line, Some(&bob), ClaimMode::Enforced).is_err());
+
+        // overlapping regions cannot be claimed by someone else
+        let overlapping = Region {
+            x: 1009,
+            y: 990,
+            width: 5,
+            height: 20,
+        };
+        assert!(claim(&bob, overlapping, Duration::from_secs(60), CANVAS).is_err());
+
+        release(&alice);
+        assert_eq!(
+            check_write(&pixel(1005, 1005), Some(&bob), ClaimMode::Enforced),
+            Ok(())
+        );
+        claim(&bob, overlapping, Duration::from_secs(60), CANVAS).unwrap();
+        release(&bob);
+    }
+
+    #[test]
+    fn test_claim_limits() {
+        let pixel = |x| Region {
+            x,
+            y: 1500,
+            width: 1,
+            height: 1,
+        };
+        let secs = Duration::from_secs(60);
+        let huge = Region {
+            x: 0,
+            y: 0,
+            width: 1000,
+            height: 1000,
+        };
+        let error = claim(&ClaimOwner::unique(None), huge, secs, CANVAS).unwrap_err();
+        assert_eq!(error.message(), "A claim may cover at most 250000 pixels");
+
+        // the connections of a client share their limit, even across an IPv6 /64
+        let owners: Vec<_> = (0..5)
+            .map(|i| ClaimOwner::unique(Some(format!("2001:db8::{}", i).parse().unwrap())))
+            .collect();
+        for (i, owner) in owners[..4].iter().enumerate() {
+            claim(owner, pixel(1500 + i), secs, CANVAS).unwrap();
+        }
+        assert_eq!(
+            claim(&owners[4], pixel(1504), secs, CANVAS).unwrap_err().code(),
+            "RATE_LIMITED"
+        );
+        // claiming again replaces the previous claim
+        claim(&owners[0], pixel(1510), secs, CANVAS).unwrap();
+
+        // closed connections release their claims
+        let mut owners = owners.into_iter();
+        drop(owners.next());
+        let last = owners.next_back().unwrap();
+        claim(&last, pixel(1504), secs, CANVAS).unwrap();
+        let other = ClaimOwner::unique(None);
+        assert!(claim(&other, pixel(1510), secs, CANVAS).is_ok());
+    }
+}
@@ -0,0 +1,124 @@
+//! Compression of the data which servers send on a connection after a client requested it via COMPRESS
+
+use crate::net::protocol::Compression;
+use bytes::BytesMut;
+use flate2::write::GzEncoder;
+use std::borrow::Cow;
+use std::io::Write;
+use std::mem;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// The per-connection state of the compression that is applied to all data which is sent to a client
+///
+/// All data of one connection is compressed as one continuous stream which is flushed whenever data is sent so
+/// that clients can decompress everything they have received so far.
+#[derive(Default)]
+pub(crate) enum ResponseEncoder {
+    /// Data is sent uncompressed
+    #[default]
+    None,
+    /// Data is compressed as a gzip stream
+    Gzip(GzEncoder<Vec<u8>>),
+    /// Data is compressed as a zstd stream
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+}
+
+impl ResponseEncoder {
+    /// Create an encoder which applies the given compression
+    pub fn new(compression: Compression) -> std::io::Result<Self> {
+        Ok(match compression {
+            Compression::None => Self::None,
+            Compression::Gzip => Self::Gzip(GzEncoder::new(Vec::new(), flate2::Compression::fast())),
+            Compression::Zstd => Self::Zstd(zstd::stream::write::Encoder::new(Vec::new(), 0)?),
+        })
+    }
+
+    /// The compression which this encoder applies
+    pub fn compression(&self) -> Compression {
+        match self {
+            Self::None => Compression::None,
+            Self::Gzip(_) => Compression::Gzip,
+            Self::Zstd(_) => Compression::Zstd,
+        }
+    }
+
+    /// Compress `data` and return everything that should be sent to the client
+    pub fn encode<'a>(&mut self, data: &'a [u8]) -> std::io::Result<Cow<'a, [u8]>> {
+        match self {
+            Self::None => Ok(Cow::Borrowed(data)),
+            Self::Gzip(encoder) => {
+                encoder.write_all(data)?;
+                encoder.flush()?;
+                Ok(Cow::Owned(mem::take(encoder.get_mut())))
+            }
+            Self::Zstd(encoder) => {
+                encoder.write_all(data)?;
+                encoder.flush()?;
+                Ok(Cow::Owned(mem::take(encoder.get_mut())))
+            }
+        }
+    }
+
+    /// Compress all data in `buf` and send it to the client
+    ///
+    /// Returns the number of bytes which were sent after compression.
+    pub async fn send(
+        &mut self,
+        writer: &mut (impl AsyncWrite + Unpin),
+        buf: &mut BytesMut,
+    ) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let data = self.encode(buf)?;
+        writer.write_all(&data).await?;
+        let sent = data.len();
+        buf.clear();
+        Ok(sent)
+    }
+
+    /// Switch to a different compression
+    ///
+    /// The remaining data of the previous compression stream is returned and must be sent to the client before any
+    /// data of the new one.
+    pub fn switch(&mut self, compression: Compression) -> std::io::Result<Vec<u8>> {
+        let previous = mem::replace(self, Self::new(compression)?);
+        match previous {
+            Self::None => Ok(Vec::new()),
+            Self::Gzip(encoder) => encoder.finish(),
+            Self::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_continuous_compression() {
+        for compression in [Compression::Gzip, Compression::Zstd] {
+            let mut encoder = ResponseEncoder::new(compression).unwrap();
+            let mut sent = encoder.encode(b"SIZE 800 600\n").unwrap().into_owned();
+            sent.extend_from_slice(&encoder.encode(b"PX 1 2 AABBCC\n").unwrap());
+            sent.extend_from_slice(&encoder.switch(Compression::None).unwrap());
+            assert_eq!(
+                encoder.encode(b"PX 3 4 000000\n").unwrap(),
+                &b"PX 3 4 000000\n"[..]
+            );
+
+            let mut received = String::new();
+            match compression {
+                Compression::Gzip => flate2::read::GzDecoder::new(&sent[..])
+                    .read_to_string(&mut received)
+                    .unwrap(),
+                _ => zstd::stream::read::Decoder::new(&sent[..])
+                    .unwrap()
+                    .read_to_string(&mut received)
+                    .unwrap(),
+            };
+            assert_eq!(received, "SIZE 800 600\nPX 1 2 AABBCC\n");
+        }
+    }
+}
@@ -0,0 +1,182 @@
+//! Limits on the number of concurrent connections from single IP addresses
+
+use crate::net::protocol::ResponseError;
+use crate::net::servers::access_control;
+use crate::net::servers::storm_guard::StormGuard;
+use crate::net::servers::{AccessControlOptions, Reloadable};
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpStream;
+
+/// The checks which every new connection of a listener must pass before it is handled
+///
+/// This is shared between all acceptors of a listener and the tasks of their connections.
+#[derive(Debug, Clone)]
+pub(crate) struct Gatekeeper {
+    pub access_control: Reloadable<AccessControlOptions>,
+    pub storm_guard: Option<Arc<Mutex<StormGuard>>>,
+    pub connection_limiter: Option<Arc<ConnectionLimiter>>,
+}
+
+/// Accept-side bookkeeping of how many connections every IP address currently has open on a listener
+///
+/// All addresses of an IPv6 /64 network count as one address.
+#[derive(Debug)]
+pub(crate) struct ConnectionLimiter {
+    max_per_ip: usize,
+    open: Mutex<HashMap<IpAddr, usize>>,
+}
+
+/// A connection which was admitted by a [`ConnectionLimiter`] and which is counted until this is dropped
+#[derive(Debug)]
+pub(crate) struct ConnectionSlot {
+    limiter: Arc<ConnectionLimiter>,
+    ip: IpAddr,
+}
+
+impl ConnectionLimiter {
+    pub fn new(max_per_ip: usize) -> Arc<Self> {
+        Arc::new(Self {
+            max_per_ip,
+            open: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// How many connections a single IP address may have open at the same time
+    pub fn max_per_ip(&self) -> usize {
+        self.max_per_ip
+    }
+
+    /// Register a new connection from `ip` if that address has not yet reached its limit
+    ///
+    /// The returned slot should be held for as long as the connection is open.
+    pub fn acquire(self: &Arc<Self>, ip: IpAddr) -> Option<ConnectionSlot> {
+        let ip = access_control::client_key(ip);
+        let mut open = self.open.lock().unwrap();
+        if open.get(&ip).copied().unwrap_or(0) >= self.max_per_ip {
+            return None;
+        }
+        *open.entry(ip).or_insert(0) += 1;
+        Some(ConnectionSlot {
+            limiter: self.clone(),
+            ip,
+        })
+    }
+}
+
+impl Gatekeeper {
+    /// Decide whether a new connection from `remote_addr` is admitted
+    ///
+    /// The returned slot should be held for as long as the connection is open.
+    pub fn admit(&self, remote_addr: SocketAddr) -> Result<Option<ConnectionSlot>, ResponseError> {
+        if !self.access_control.get().permits(remote_addr.ip()) {
+            tracing::debug!(
+                "Rejecting connection from {} which is not allowed on this listener",
+                remote_addr
+            );
+            return Err(ResponseError::Unauthorized(
+                "Connections from your address are not allowed".to_string(),
+            ));
+        }
+        if let Some(storm_guard) = &self.storm_guard {
+            let admission = storm_guard.lock().unwrap().admit(remote_addr.ip());
+            if let Err(remaining) = admission {
+                tracing::trace!(
+                    "Rejecting connection from {} which is throttled for another {:?}",
+                    remote_addr,
+                    remaining
+                );
+                return Err(ResponseError::RateLimited(format!(
+                    "Too many connections, retry in {}ms",
+                    remaining.as_millis()
+                )));
+            }
+        }
+        let Some(limiter) = &self.connection_limiter else {
+            return Ok(None);
+        };
+        match limiter.acquire(remote_addr.ip()) {
+            Some(slot) => Ok(Some(slot)),
+            None => {
+                tracing::debug!(
+                    "Rejecting connection from {} which already has {} connections open",
+                    remote_addr,
+                    limiter.max_per_ip()
+                );
+                Err(ResponseError::RateLimited(format!(
+                    "Too many concurrent connections from your address, at most {} are allowed",
+                    limiter.max_per_ip()
+                )))
+            }
+        }
+    }
+}
+
+/// Send `message` to a client whose connection is rejected and close it
+///
+/// This is best effort only since rejected clients must not be able to stall the listener.
+/// The message is written without waiting for the socket to become writable because a freshly accepted socket
+/// has not been polled by the runtime yet but always has room in its send buffer.
+pub(crate) fn reject(stream: TcpStream, message: &[u8]) {
+    if let Ok(mut stream) = stream.into_std() {
+        let _ = stream.write(message);
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut open = self.limiter.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            // forget addresses without connections so that the map does not grow forever
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_connections_are_limited_per_ip() {
+        let limiter = ConnectionLimiter::new(2);
+        let peer = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let other = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+        let first = limiter.acquire(peer).unwrap();
+        let second = limiter.acquire(peer).unwrap();
+        assert!(limiter.acquire(peer).is_none());
+        let third = limiter.acquire(other).unwrap();
+        assert_eq!(limiter.open.lock().unwrap().get(&peer), Some(&2));
+
+        // closing a connection frees its slot
+        drop(first);
+        let fourth = limiter.acquire(peer).unwrap();
+        assert!(limiter.acquire(peer).is_none());
+
+        drop((second, third, fourth));
+        assert!(limiter.open.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_ipv6_networks_and_rejections() {
+        let limiter = ConnectionLimiter::new(1);
+        let addr = |ip: &str| ip.parse::<IpAddr>().unwrap();
+
+        // all addresses of a /64 share the limit
+        let _slot = limiter.acquire(addr("2001:db8::1")).unwrap();
+        assert!(limiter.acquire(addr("2001:db8::2")).is_none());
+        assert!(limiter.acquire(addr("2001:db8:0:1::1")).is_some());
+
+        // rejected addresses are not remembered
+        let none = ConnectionLimiter::new(0);
+        assert!(none.acquire(addr("10.0.0.1")).is_none());
+        assert!(none.open.lock().unwrap().is_empty());
+    }
+}
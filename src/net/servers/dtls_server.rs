@@ -0,0 +1,560 @@
+//! A UDP listener which encrypts its datagrams with DTLS
+//!
+//! Every client address gets a DTLS session of its own. Each record which a client sends in it may contain any number
+//! of newline separated commands, just like a plain UDP datagram, but settings which were negotiated via e.g. AUTH or
+//! HELLO apply to all following records of the session.
+//!
+//! If the listener is configured with the CA which issues client certificates, clients which present a certificate
+//! of that CA are authenticated for their whole session and all others may only read the canvas, unless they
+//! authenticate via AUTH with one of the `--write-token`s.
+//! Sessions are cached so that clients which come back resume them with an abbreviated handshake.
+
+use crate::net::protocol::Features;
+use crate::net::servers::gen_server::GenServer;
+use crate::net::servers::udp_server::{response_datagrams, MAX_UDP_PAYLOAD};
+use crate::net::servers::{sockets, statistics};
+use crate::net::servers::{
+    AccessControlOptions, BufferOptions, ClaimMode, ConnectionPreferences, ListenerCapabilities,
+    RateLimitOptions, Reloadable, SocketOptions, WriteProtectionOptions, MAX_LINE_LEN,
+};
+use crate::pixmap::SharedPixmap;
+use crate::DaemonResult;
+use anyhow::Context;
+use async_trait::async_trait;
+use bytes::BytesMut;
+use openssl::error::ErrorStack;
+use openssl::ex_data::Index;
+use openssl::ssl::{
+    ErrorCode, Ssl, SslContext, SslContextBuilder, SslFiletype, SslMethod, SslOptions, SslRef,
+    SslSessionCacheMode, SslStream, SslVerifyMode, SslVersion,
+};
+use openssl::x509::{X509Name, X509VerifyResult};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::task::{AbortHandle, JoinSet};
+
+/// Options with which the `DtlsServer` is configured
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DtlsServerOptions {
+    /// The address to which the server binds
+    pub bind_addr: SocketAddr,
+    /// Tuning of the socket, of which only the buffer sizes apply to UDP
+    pub socket: SocketOptions,
+    /// The networks from which datagrams are accepted, all others are silently dropped
+    pub access_control: Reloadable<AccessControlOptions>,
+    /// A PEM file containing the certificate chain, starting with the certificate of the server itself
+    pub cert_path: PathBuf,
+    /// A PEM file containing the private key of the certificate
+    pub key_path: PathBuf,
+    /// A PEM file containing the certificates of the CAs whose client certificates authenticate a session, if any
+    ///
+    /// Clients don't need a certificate to connect, but the canvas is write protected as soon as this is set.
+    pub client_ca_path: Option<PathBuf>,
+    /// Whether sessions must authenticate before they may draw on the canvas
+    pub write_protection: Option<WriteProtectionOptions>,
+    /// How drawing into regions which other connections claimed is treated, if regions can be claimed via CLAIM
+    pub claims: Option<ClaimMode>,
+    /// The optional protocol features which clients may use, or `None` to enable all which the server supports
+    pub features: Option<Features>,
+}
+
+/// The largest datagram which is sent while a handshake is fragmented
+///
+/// It cannot be queried from the socket since all sessions share it, so it is chosen to fit common links.
+const MTU: u32 = 1400;
+
+/// The size of the records into which responses are split so that each fits into a single datagram of [`MTU`]
+const MAX_RECORD_PAYLOAD: usize = 1200;
+
+/// The largest record which a client may send
+const MAX_RECORD_LEN: usize = 16 * 1024;
+
+/// How many sessions may exist at the same time, beyond which datagrams of new clients are dropped
+const MAX_SESSIONS: usize = 10_000;
+
+/// After how long without datagrams an established session is dropped
+const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How long a client may take to complete its handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The length of the cookies with which clients prove that they receive datagrams at their address
+const COOKIE_LEN: usize = 16;
+
+/// Where the cookie of a session is stored on its `Ssl`
+static COOKIE_INDEX: LazyLock<Index<Ssl, [u8; COOKIE_LEN]>> =
+    LazyLock::new(|| Ssl::new_ex_index().expect("Could not allocate an index for DTLS cookies"));
+
+/// The datagrams which are exchanged with a single client and through which OpenSSL reads and writes its records
+#[derive(Debug, Default)]
+struct Channel {
+    /// Datagrams which were received from the client but not read by OpenSSL yet
+    incoming: VecDeque<Vec<u8>>,
+    /// Datagrams which OpenSSL wrote and that still need to be sent to the client
+    outgoing: Vec<Vec<u8>>,
+}
+
+impl Read for Channel {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // every read returns a single datagram, just like a read from a UDP socket
+        match self.incoming.pop_front() {
+            Some(datagram) => {
+                let n = datagram.len().min(buf.len());
+                buf[..n].copy_from_slice(&datagram[..n]);
+                Ok(n)
+            }
+            None => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl Write for Channel {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing.push(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The DTLS session of a single client
+struct Session {
+    stream: SslStream<Channel>,
+    /// Whether the handshake is complete
+    established: bool,
+    /// The settings which the client negotiated in this session
+    preferences: ConnectionPreferences,
+    /// When the first datagram of the client was received
+    started: Instant,
+    /// When the latest datagram of the client was received
+    last_active: Instant,
+}
+
+impl Session {
+    fn new(context: &SslContext) -> Result<Self, ErrorStack> {
+        let mut ssl = Ssl::new(context)?;
+        ssl.set_mtu(MTU)?;
+        let mut cookie = [0; COOKIE_LEN];
+        openssl::rand::rand_bytes(&mut cookie)?;
+        ssl.set_ex_data(*COOKIE_INDEX, cookie);
+        let now = Instant::now();
+        Ok(Self {
+            stream: SslStream::new(ssl, Channel::default())?,
+            established: false,
+            preferences: ConnectionPreferences::default(),
+            started: now,
+            last_active: now,
+        })
+    }
+
+    /// Whether the session should be dropped because the client stalled during the handshake or went away
+    fn is_expired(&self, now: Instant) -> bool {
+        match self.established {
+            true => now - self.last_active > SESSION_IDLE_TIMEOUT,
+            false => now - self.started > HANDSHAKE_TIMEOUT,
+        }
+    }
+
+    /// Continue the session with a datagram of the client and handle all requests which it contains
+    ///
+    /// The datagrams which need to be sent back are left in the channel of the session.
+    /// Returns whether the session is still open.
+    fn receive(
+        &mut self,
+        datagram: Vec<u8>,
+        record: &mut [u8],
+        pixmap: &SharedPixmap,
+        capabilities: &ListenerCapabilities,
+    ) -> Result<bool, openssl::ssl::Error> {
+        self.last_active = Instant::now();
+        self.stream.get_mut().incoming.push_back(datagram);
+        if !self.established {
+            match self.stream.accept() {
+                Ok(()) => {
+                    self.established = true;
+                    self.preferences.authenticated = has_client_certificate(self.stream.ssl());
+                    tracing::debug!(
+                        "Established DTLS session{}{}",
+                        if self.stream.ssl().session_reused() {
+                            " (resumed)"
+                        } else {
+                            ""
+                        },
+                        if self.preferences.authenticated {
+                            " with client certificate"
+                        } else {
+                            ""
+                        }
+                    );
+                }
+                Err(e) if e.code() == ErrorCode::WANT_READ => return Ok(true),
+                Err(e) => return Err(e),
+            }
+        }
+        loop {
+            match self.stream.ssl_read(record) {
+                Ok(n) => {
+                    tracing::trace!("Received {}B DTLS record: {:?}", n, &record[..n]);
+                    let responses = super::handle_session_message(
+                        BytesMut::from(&record[..n]),
+                        pixmap,
+                        capabilities,
+                        &mut self.preferences,
+                    );
+                    for response in response_datagrams(&responses, true, MAX_RECORD_PAYLOAD) {
+                        self.stream.ssl_write(response)?;
+                    }
+                }
+                Err(e) if e.code() == ErrorCode::WANT_READ => return Ok(true),
+                Err(e) if e.code() == ErrorCode::ZERO_RETURN => {
+                    // answer the close_notify of the client with our own
+                    let _ = self.stream.shutdown();
+                    return Ok(false);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Whether the client of a session presented a client certificate which was successfully verified
+fn has_client_certificate(ssl: &SslRef) -> bool {
+    ssl.peer_certificate().is_some() && ssl.verify_result() == X509VerifyResult::OK
+}
+
+/// A server implementation which receives pixelflut messages in DTLS sessions over UDP, see the
+/// [module documentation](self)
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DtlsServer {
+    options: DtlsServerOptions,
+}
+
+impl DtlsServer {
+    /// Load the certificates into the context from which the sessions of all clients are created
+    fn context(&self) -> anyhow::Result<SslContext> {
+        let mut context = SslContextBuilder::new(SslMethod::dtls())?;
+        context.set_min_proto_version(Some(SslVersion::DTLS1_2))?;
+        context
+            .set_certificate_chain_file(&self.options.cert_path)
+            .with_context(|| {
+                format!(
+                    "Could not read certificates from {}",
+                    self.options.cert_path.display()
+                )
+            })?;
+        context
+            .set_private_key_file(&self.options.key_path, SslFiletype::PEM)
+            .with_context(|| {
+                format!(
+                    "Could not read private key from {}",
+                    self.options.key_path.display()
+                )
+            })?;
+        context.check_private_key()?;
+        // all sessions share one socket whose path MTU is unknown, so each session uses a fixed one
+        context.set_options(SslOptions::NO_QUERY_MTU | SslOptions::COOKIE_EXCHANGE);
+        context.set_cookie_generate_cb(|ssl, buf| {
+            let cookie = ssl.ex_data(*COOKIE_INDEX).ok_or_else(ErrorStack::get)?;
+            buf[..COOKIE_LEN].copy_from_slice(cookie);
+            Ok(COOKIE_LEN)
+        });
+        context.set_cookie_verify_cb(|ssl, cookie| {
+            ssl.ex_data(*COOKIE_INDEX)
+                .is_some_and(|expected| cookie.len() == COOKIE_LEN && openssl::memcmp::eq(expected, cookie))
+        });
+        context.set_session_id_context(b"pixeldike-dtls")?;
+        context.set_session_cache_mode(SslSessionCacheMode::SERVER);
+        context.set_session_cache_size(MAX_SESSIONS as i32);
+        match &self.options.client_ca_path {
+            Some(path) => {
+                context
+                    .set_ca_file(path)
+                    .with_context(|| format!("Could not read CA certificates from {}", path.display()))?;
+                context.set_client_ca_list(X509Name::load_client_ca_file(path)?);
+                // clients without a certificate may still read the canvas
+                context.set_verify(SslVerifyMode::PEER);
+            }
+            None => context.set_verify(SslVerifyMode::NONE),
+        }
+        Ok(context.build())
+    }
+
+    fn capabilities(&self) -> ListenerCapabilities {
+        // a client CA protects the canvas even if no tokens are configured for AUTH
+        let write_protection = self.options.write_protection.clone().or_else(|| {
+            self.options
+                .client_ca_path
+                .is_some()
+                .then(|| WriteProtectionOptions { tokens: Vec::new() })
+        });
+        ListenerCapabilities {
+            binary_protocol: true,
+            image_upload: cfg!(feature = "images"),
+            state_streaming: false,
+            compression: false,
+            max_line_len: Some(MAX_LINE_LEN),
+            buffers: BufferOptions::default(),
+            storm_protection: None,
+            max_connections_per_ip: None,
+            rate_limit: RateLimitOptions::default().into(),
+            idle_timeout: Some(SESSION_IDLE_TIMEOUT),
+            write_protection: write_protection.map(Arc::new),
+            claims: self.options.claims,
+            enabled_features: self.options.features,
+        }
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn listen(
+        pixmap: SharedPixmap,
+        socket: UdpSocket,
+        context: SslContext,
+        capabilities: ListenerCapabilities,
+        access_control: Reloadable<AccessControlOptions>,
+    ) -> anyhow::Result<!> {
+        let mut sessions: HashMap<SocketAddr, Session> = HashMap::new();
+        let mut recv_buf = vec![0; MAX_UDP_PAYLOAD];
+        let mut record = vec![0; MAX_RECORD_LEN];
+        let mut expiry = tokio::time::interval(HANDSHAKE_TIMEOUT);
+        loop {
+            let (n, sender) = tokio::select! {
+                result = socket.recv_from(&mut recv_buf) => result?,
+                _ = expiry.tick() => {
+                    let now = Instant::now();
+                    sessions.retain(|_, session| !session.is_expired(now));
+                    continue;
+                }
+            };
+            if !access_control.get().permits(sockets::peer_addr(sender).ip()) {
+                tracing::trace!(
+                    "Dropping datagram from {} which is not allowed on this listener",
+                    sender
+                );
+                continue;
+            }
+
+            let is_full = sessions.len() >= MAX_SESSIONS;
+            let session = match sessions.entry(sender) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(_) if is_full => {
+                    tracing::warn!(
+                        "Dropping datagram from {} because there are already {} DTLS sessions",
+                        sender,
+                        MAX_SESSIONS
+                    );
+                    continue;
+                }
+                Entry::Vacant(entry) => entry.insert(Session::new(&context)?),
+            };
+            let open = session
+                .receive(recv_buf[..n].to_vec(), &mut record, &pixmap, &capabilities)
+                .unwrap_or_else(|e| {
+                    tracing::debug!("Closing DTLS session with {}: {}", sender, e);
+                    false
+                });
+            for datagram in session.stream.get_mut().outgoing.drain(..) {
+                if let Err(e) = socket.send_to(&datagram, sender).await {
+                    tracing::error!("Error while writing response to {}: {}", sender, e);
+                    break;
+                }
+            }
+            if !open {
+                sessions.remove(&sender);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl GenServer for DtlsServer {
+    type Options = DtlsServerOptions;
+
+    fn new(options: Self::Options) -> Self {
+        Self { options }
+    }
+
+    async fn start(
+        self,
+        pixmap: SharedPixmap,
+        join_set: &mut JoinSet<DaemonResult>,
+    ) -> anyhow::Result<AbortHandle> {
+        let context = self.context()?;
+        let capabilities = self.capabilities();
+        let socket = UdpSocket::from_std(sockets::udp_socket(self.options.bind_addr, &self.options.socket)?)?;
+        statistics::start();
+        tracing::info!("Started DTLS Server on {}", self.options.bind_addr);
+
+        let handle = join_set.build_task().name("dtls_server").spawn(async move {
+            DtlsServer::listen(pixmap, socket, context, capabilities, self.options.access_control).await
+        })?;
+        Ok(handle)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pixmap::{Color, Pixmap};
+    use openssl::asn1::Asn1Time;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::{PKey, Private};
+    use openssl::x509::extension::BasicConstraints;
+    use openssl::x509::{X509NameBuilder, X509};
+    use std::path::Path;
+
+    /// Create a key and a certificate for `name` which is signed by `issuer` or by itself
+    fn certificate(name: &str, issuer: Option<&(X509, PKey<Private>)>) -> (X509, PKey<Private>) {
+        let key = PKey::from_ec_key(
+            EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap()).unwrap(),
+        )
+        .unwrap();
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject.append_entry_by_nid(Nid::COMMONNAME, name).unwrap();
+        let subject = subject.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&subject).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        match issuer {
+            Some((issuer_cert, issuer_key)) => {
+                cert.set_issuer_name(issuer_cert.subject_name()).unwrap();
+                cert.sign(issuer_key, MessageDigest::sha256()).unwrap();
+            }
+            None => {
+                cert.append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+                    .unwrap();
+                cert.set_issuer_name(&subject).unwrap();
+                cert.sign(&key, MessageDigest::sha256()).unwrap();
+            }
+        }
+        (cert.build(), key)
+    }
+
+    fn write_pem(dir: &Path, name: &str, (cert, key): &(X509, PKey<Private>)) -> (PathBuf, PathBuf) {
+        let cert_path = dir.join(format!("{}.crt", name));
+        let key_path = dir.join(format!("{}.key", name));
+        std::fs::write(&cert_path, cert.to_pem().unwrap()).unwrap();
+        std::fs::write(&key_path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        (cert_path, key_path)
+    }
+
+    /// Exchange datagrams between a client and a session until neither has anything left to send
+    fn pump(
+        client: &mut SslStream<Channel>,
+        session: &mut Session,
+        record: &mut [u8],
+        pixmap: &SharedPixmap,
+        capabilities: &ListenerCapabilities,
+    ) {
+        while !client.get_ref().outgoing.is_empty() {
+            for datagram in std::mem::take(&mut client.get_mut().outgoing) {
+                assert!(session.receive(datagram, record, pixmap, capabilities).unwrap());
+            }
+            let responses = std::mem::take(&mut session.stream.get_mut().outgoing);
+            client.get_mut().incoming.extend(responses);
+            match client.connect() {
+                Ok(()) => {}
+                Err(e) => assert_eq!(e.code(), ErrorCode::WANT_READ, "{}", e),
+            }
+        }
+    }
+
+    /// Connect a client with an optional client certificate, draw a pixel and return the responses
+    fn draw(
+        server: &DtlsServer,
+        client_cert: Option<&(PathBuf, PathBuf)>,
+        pixmap: &SharedPixmap,
+    ) -> (bool, String) {
+        let context = server.context().unwrap();
+        let capabilities = server.capabilities();
+        let mut session = Session::new(&context).unwrap();
+        let mut record = vec![0; MAX_RECORD_LEN];
+
+        let mut client_context = SslContextBuilder::new(SslMethod::dtls()).unwrap();
+        client_context.set_options(SslOptions::NO_QUERY_MTU);
+        client_context.set_verify(SslVerifyMode::NONE);
+        if let Some((cert_path, key_path)) = client_cert {
+            client_context.set_certificate_chain_file(cert_path).unwrap();
+            client_context
+                .set_private_key_file(key_path, SslFiletype::PEM)
+                .unwrap();
+        }
+        let mut ssl = Ssl::new(&client_context.build()).unwrap();
+        ssl.set_mtu(MTU).unwrap();
+        let mut client = SslStream::new(ssl, Channel::default()).unwrap();
+        assert_eq!(client.connect().unwrap_err().code(), ErrorCode::WANT_READ);
+        pump(&mut client, &mut session, &mut record, pixmap, &capabilities);
+        assert!(session.established);
+
+        client.ssl_write(b"PX 1 1 FF0000\nSIZE\n").unwrap();
+        pump(&mut client, &mut session, &mut record, pixmap, &capabilities);
+        let n = client.ssl_read(&mut record).unwrap();
+        (
+            session.preferences.authenticated,
+            String::from_utf8_lossy(&record[..n]).to_string(),
+        )
+    }
+
+    #[test]
+    fn test_client_certificates() {
+        let dir = std::env::temp_dir().join(format!("pixeldike-dtls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ca = certificate("ca", None);
+        let (ca_path, _) = write_pem(&dir, "ca", &ca);
+        let (cert_path, key_path) = write_pem(&dir, "server", &certificate("server", Some(&ca)));
+        let client = write_pem(&dir, "client", &certificate("client", Some(&ca)));
+        let server = DtlsServer::new(DtlsServerOptions {
+            bind_addr: "127.0.0.1:0".parse().unwrap(),
+            socket: SocketOptions::default(),
+            access_control: AccessControlOptions::default().into(),
+            cert_path,
+            key_path,
+            client_ca_path: Some(ca_path),
+            write_protection: None,
+            claims: None,
+            features: None,
+        });
+        let pixmap: SharedPixmap = Arc::new(Pixmap::new(4, 2).unwrap());
+
+        // without a certificate, the canvas can only be read
+        let (authenticated, responses) = draw(&server, None, &pixmap);
+        assert!(!authenticated);
+        assert!(responses.contains("SIZE 4 2"), "{}", responses);
+        assert_eq!(pixmap.get_pixel(1, 1).unwrap(), Color::from((0, 0, 0)));
+
+        let (authenticated, responses) = draw(&server, Some(&client), &pixmap);
+        assert!(authenticated);
+        assert!(responses.contains("SIZE 4 2"), "{}", responses);
+        assert_eq!(pixmap.get_pixel(1, 1).unwrap(), Color::from((0xFF, 0, 0)));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_channel_keeps_datagrams_apart() {
+        let mut channel = Channel::default();
+        channel.incoming.extend([b"first".to_vec(), b"second".to_vec()]);
+        let mut buf = [0; 16];
+        assert_eq!(channel.read(&mut buf).unwrap(), 5);
+        assert_eq!(channel.read(&mut buf).unwrap(), 6);
+        assert_eq!(
+            channel.read(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        channel.write_all(b"a").unwrap();
+        channel.write_all(b"b").unwrap();
+        assert_eq!(channel.outgoing, vec![b"a".to_vec(), b"b".to_vec()]);
+    }
+}
@@ -0,0 +1,41 @@
+//! The web frontend which is bundled into the server and served by the HTTP API of WebSocket listeners
+//!
+//! It is a small viewer which shows the canvas in a browser at `/` so that visitors have something to look at
+//! without deploying a separate frontend. Its files live in the `web` directory of the repository.
+
+use include_dir::{include_dir, Dir};
+
+/// The files of the frontend
+static FILES: Dir = include_dir!("$CARGO_MANIFEST_DIR/web");
+
+/// The content type and content of the frontend file at an HTTP request path, if there is one
+pub(super) fn file(path: &str) -> Option<(&'static str, &'static str)> {
+    let name = match path.trim_start_matches('/') {
+        "" => "index.html",
+        name => name,
+    };
+    let file = FILES.get_file(name)?;
+    let content_type = match file.path().extension()?.to_str()? {
+        "html" => "text/html; charset=utf-8",
+        "js" => "text/javascript; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "svg" => "image/svg+xml",
+        _ => "text/plain; charset=utf-8",
+    };
+    Some((content_type, file.contents_utf8()?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_file() {
+        let (content_type, content) = file("/").unwrap();
+        assert_eq!(content_type, "text/html; charset=utf-8");
+        assert!(content.contains("viewer.js"));
+        assert_eq!(file("/viewer.js").unwrap().0, "text/javascript; charset=utf-8");
+        assert_eq!(file("/nothing.html"), None);
+        assert_eq!(file("/../Cargo.toml"), None);
+    }
+}
@@ -0,0 +1,337 @@
+//! A gRPC server which exposes the canvas as the `pixeldike.Canvas` service defined in `proto/pixeldike.proto`
+
+use crate::net::protocol::{self, MAX_STREAM_FPS};
+use crate::net::servers::sockets;
+use crate::net::servers::state_stream::{canvas_rgb, changed_pixels};
+use crate::net::servers::statistics;
+use crate::net::servers::{claims, write_protection, ClaimMode, GenServer, WriteProtectionOptions};
+use crate::pixmap::{Color, SharedPixmap};
+use crate::DaemonResult;
+use async_trait::async_trait;
+use proto::canvas_server::{Canvas, CanvasServer};
+use proto::{Empty, Pixel, PixelPosition, SetPixelsResponse, State, SubscribeRequest, Update};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::{AbortHandle, JoinSet};
+use tokio::time::MissedTickBehavior;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::server::TcpIncoming;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status, Streaming};
+
+/// The messages and service definitions of `proto/pixeldike.proto`
+#[allow(missing_docs, unused_qualifications)]
+mod proto {
+    /// A message without content
+    #[derive(Clone, Copy, PartialEq, prost::Message)]
+    pub struct Empty {}
+
+    /// The coordinates of a pixel
+    #[derive(Clone, Copy, PartialEq, prost::Message)]
+    pub struct PixelPosition {
+        #[prost(uint32, tag = "1")]
+        pub x: u32,
+        #[prost(uint32, tag = "2")]
+        pub y: u32,
+    }
+
+    /// A pixel with its color encoded as `0xRRGGBB`
+    #[derive(Clone, Copy, PartialEq, prost::Message)]
+    pub struct Pixel {
+        #[prost(uint32, tag = "1")]
+        pub x: u32,
+        #[prost(uint32, tag = "2")]
+        pub y: u32,
+        #[prost(uint32, tag = "3")]
+        pub color: u32,
+    }
+
+    /// How many pixels were set by a `SetPixels` call
+    #[derive(Clone, Copy, PartialEq, prost::Message)]
+    pub struct SetPixelsResponse {
+        #[prost(uint64, tag = "1")]
+        pub pixels_set: u64,
+    }
+
+    /// The size and all pixels of the canvas
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct State {
+        #[prost(uint32, tag = "1")]
+        pub width: u32,
+        #[prost(uint32, tag = "2")]
+        pub height: u32,
+        /// The rgb values of all pixels, row by row
+        #[prost(bytes = "vec", tag = "3")]
+        pub rgb: Vec<u8>,
+    }
+
+    /// A request to receive the changed pixels `fps` times per second
+    #[derive(Clone, Copy, PartialEq, prost::Message)]
+    pub struct SubscribeRequest {
+        #[prost(uint32, tag = "1")]
+        pub fps: u32,
+    }
+
+    /// The pixels which changed since the previous update
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Update {
+        #[prost(message, repeated, tag = "1")]
+        pub pixels: Vec<Pixel>,
+    }
+
+    include!(concat!(env!("OUT_DIR"), "/pixeldike.Canvas.rs"));
+}
+
+/// Options with which the `GrpcServer` is configured
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct GrpcServerOptions {
+    /// The address to which the server binds
+    pub bind_addr: SocketAddr,
+    /// Whether requests must carry an `authorization: Bearer <token>` metadata entry to draw on the canvas
+    pub write_protection: Option<WriteProtectionOptions>,
+    /// How drawing into regions which connections of other listeners claimed is treated, if claims are enabled
+    ///
+    /// gRPC clients cannot claim regions themselves and are only told about conflicts if claims are enforced.
+    pub claims: Option<ClaimMode>,
+}
+
+/// A server implementation exposing the canvas via gRPC
+#[derive(Debug, Clone)]
+pub struct GrpcServer {
+    options: GrpcServerOptions,
+}
+
+/// The implementation of the `pixeldike.Canvas` service
+#[derive(Debug)]
+struct CanvasService {
+    pixmap: SharedPixmap,
+    write_protection: Option<Arc<WriteProtectionOptions>>,
+    claims: Option<ClaimMode>,
+}
+
+// errors are returned to clients as the large but unavoidable `Status` anyway
+#[allow(clippy::result_large_err)]
+impl CanvasService {
+    /// Reject requests which draw on a write protected canvas without a valid token or on a read-only canvas
+    fn check_write_access<T>(&self, request: &Request<T>) -> Result<(), Status> {
+        if write_protection::is_read_only() {
+            return Err(Status::permission_denied("The canvas is read-only at the moment"));
+        }
+        let Some(write_protection) = &self.write_protection else {
+            return Ok(());
+        };
+        let token = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match token {
+            Some(token) if write_protection.accepts(token) => Ok(()),
+            Some(_) => Err(Status::unauthenticated("Invalid authentication token")),
+            None => Err(Status::unauthenticated(
+                "Drawing on this canvas requires an authorization: Bearer <token> metadata entry",
+            )),
+        }
+    }
+
+    /// Set a single pixel of the canvas
+    ///
+    /// Pixels in regions which are claimed by a connection of another listener are rejected if claims are enforced.
+    fn draw_pixel(&self, pixel: Pixel) -> Result<(), Status> {
+        let (x, y, color) = (
+            pixel.x as usize,
+            pixel.y as usize,
+            Color::from(pixel.color & 0xFFFFFF),
+        );
+        if self.claims == Some(ClaimMode::Enforced) {
+            claims::check_write(
+                &protocol::Request::SetPixel { x, y, color },
+                None,
+                ClaimMode::Enforced,
+            )
+            .map_err(|e| Status::failed_precondition(e.message()))?;
+        }
+        self.pixmap
+            .set_pixel(x, y, color)
+            .map_err(|e| Status::out_of_range(e.to_string()))
+    }
+}
+
+#[async_trait]
+impl Canvas for CanvasService {
+    async fn get_pixel(&self, request: Request<PixelPosition>) -> Result<Response<Pixel>, Status> {
+        let PixelPosition { x, y } = request.into_inner();
+        let color = self
+            .pixmap
+            .get_pixel(x as usize, y as usize)
+            .map_err(|e| Status::out_of_range(e.to_string()))?;
+        Ok(Response::new(Pixel {
+            x,
+            y,
+            color: color.into(),
+        }))
+    }
+
+    async fn set_pixel(&self, request: Request<Pixel>) -> Result<Response<Empty>, Status> {
+        self.check_write_access(&request)?;
+        self.draw_pixel(request.into_inner())?;
+        statistics::record(statistics::Event::PixelsSet(None, 1));
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn set_pixels(
+        &self,
+        request: Request<Streaming<Pixel>>,
+    ) -> Result<Response<SetPixelsResponse>, Status> {
+        self.check_write_access(&request)?;
+        let mut pixels = request.into_inner();
+        let mut pixels_set = 0;
+        let result = async {
+            while let Some(pixel) = pixels.message().await? {
+                self.draw_pixel(pixel)?;
+                pixels_set += 1;
+            }
+            Ok(())
+        }
+        .await;
+
+        // pixels which were set before an error stay on the canvas and are counted nonetheless
+        if pixels_set > 0 {
+            statistics::record(statistics::Event::PixelsSet(None, pixels_set));
+        }
+        result.map(|_| Response::new(SetPixelsResponse { pixels_set }))
+    }
+
+    async fn get_state(&self, _request: Request<Empty>) -> Result<Response<State>, Status> {
+        let (width, height) = self.pixmap.get_size();
+        Ok(Response::new(State {
+            width: width as u32,
+            height: height as u32,
+            rgb: canvas_rgb(&self.pixmap),
+        }))
+    }
+
+    type SubscribeUpdatesStream = ReceiverStream<Result<Update, Status>>;
+
+    async fn subscribe_updates(
+        &self,
+        request: Request<SubscribeRequest>,
+    ) -> Result<Response<Self::SubscribeUpdatesStream>, Status> {
+        let SubscribeRequest { fps } = request.into_inner();
+        if fps == 0 || fps > MAX_STREAM_FPS {
+            return Err(Status::invalid_argument(format!(
+                "fps must be between 1 and {}",
+                MAX_STREAM_FPS
+            )));
+        }
+
+        let (sender, receiver) = mpsc::channel(1);
+        let pixmap = self.pixmap.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs_f64(1.0 / fps as f64));
+            // slow clients should get fewer updates instead of a burst of outdated ones
+            interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+            let mut snapshot: Option<Vec<u8>> = None;
+            loop {
+                interval.tick().await;
+                let (width, _) = pixmap.get_size();
+                let current = canvas_rgb(&pixmap);
+                let pixels: Vec<_> = changed_pixels(snapshot.as_deref(), &current)
+                    .map(|(i, rgb)| Pixel {
+                        x: (i % width) as u32,
+                        y: (i / width) as u32,
+                        color: Color::from(<[u8; 3]>::try_from(rgb).unwrap()).into(),
+                    })
+                    .collect();
+                let is_first = snapshot.is_none();
+                snapshot = Some(current);
+                if pixels.is_empty() && !is_first {
+                    continue;
+                }
+                // the subscription ends when the client goes away
+                if sender.send(Ok(Update { pixels })).await.is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+}
+
+#[async_trait]
+impl GenServer for GrpcServer {
+    type Options = GrpcServerOptions;
+
+    fn new(options: Self::Options) -> Self {
+        Self { options }
+    }
+
+    async fn start(
+        self,
+        pixmap: SharedPixmap,
+        join_set: &mut JoinSet<DaemonResult>,
+    ) -> anyhow::Result<AbortHandle> {
+        let listener = sockets::tcp_listener(self.options.bind_addr, &Default::default())?;
+        let incoming = TcpIncoming::from_listener(listener, true, None).map_err(|e| anyhow::anyhow!(e))?;
+        statistics::start();
+        tracing::info!("Started gRPC Server on {}", self.options.bind_addr);
+
+        let service = CanvasService {
+            pixmap,
+            write_protection: self.options.write_protection.map(Arc::new),
+            claims: self.options.claims,
+        };
+        let handle = join_set.build_task().name("grpc_server").spawn(async move {
+            Server::builder()
+                .add_service(CanvasServer::new(service))
+                .serve_with_incoming(incoming)
+                .await?;
+            Err(anyhow::anyhow!("gRPC server stopped unexpectedly"))
+        })?;
+        Ok(handle)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pixmap::Pixmap;
+    use tonic::Code;
+
+    #[tokio::test]
+    async fn test_write_protection() {
+        let service = CanvasService {
+            pixmap: Arc::new(Pixmap::new(4, 4).unwrap()),
+            write_protection: Some(Arc::new(WriteProtectionOptions {
+                tokens: vec!["s3cr3t".to_string()],
+            })),
+            claims: None,
+        };
+        let pixel = Pixel {
+            x: 1,
+            y: 2,
+            color: 0xAABBCC,
+        };
+        let request = |authorization: Option<&str>| {
+            let mut request = Request::new(pixel);
+            if let Some(authorization) = authorization {
+                request
+                    .metadata_mut()
+                    .insert("authorization", authorization.parse().unwrap());
+            }
+            request
+        };
+
+        for authorization in [None, Some("Bearer wrong"), Some("s3cr3t")] {
+            let status = service.set_pixel(request(authorization)).await.unwrap_err();
+            assert_eq!(status.code(), Code::Unauthenticated);
+        }
+        service.set_pixel(request(Some("Bearer s3cr3t"))).await.unwrap();
+
+        let position = PixelPosition { x: 1, y: 2 };
+        let response = service.get_pixel(Request::new(position)).await.unwrap();
+        assert_eq!(response.into_inner(), pixel);
+    }
+}
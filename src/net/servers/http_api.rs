@@ -0,0 +1,994 @@
+//! A small HTTP API which is served on WebSocket listeners
+//!
+//! It allows tools like `curl` or `fetch()` to draw on the canvas without implementing the line protocol:
+//!
+//! - `GET /size` returns the canvas size as `{"width":<width>,"height":<height>}`.
+//! - `GET /pixel?x=<x>&y=<y>` returns the color of a pixel as `{"x":<x>,"y":<y>,"color":"<RRGGBB>"}`.
+//! - `PUT /pixel?x=<x>&y=<y>&color=<RRGGBB>` sets the color of a pixel. The parameters may also be sent as a
+//!   form encoded body.
+//! - `GET /state/<algorithm>` returns the canvas state as a single `STATE` line like the frames of STREAM.
+//! - `GET /canvas.png` returns the canvas as a PNG image which is encoded at most once per second. Its `ETag`
+//!   changes whenever the image does, so that clients can revalidate cheaply with `If-None-Match`.
+//! - `GET /events?fps=<fps>&snapshot=<secs>` streams the canvas as Server-Sent Events until the client disconnects.
+//!   `state` events contain an `rgb64` `STATE` line and are sent right away and every `snapshot` seconds
+//!   (10 by default). In between, `px` events contain one `PX` line for every pixel which changed, at most `fps`
+//!   times per second (10 by default).
+//! - `GET /stream.mjpeg?fps=<fps>` streams the canvas as `multipart/x-mixed-replace` JPEG images, `fps` times per
+//!   second (10 by default, at most 15), which browsers and tools like OBS show as a video.
+//! - If the server is built with the `web` feature, `GET /` and other paths return the bundled web frontend.
+//!
+//! On write protected canvases, a token must be sent as `Authorization: Bearer <token>` to draw.
+//! Errors are answered with a matching status code and the `ERR` line of the line protocol.
+//! Every connection serves exactly one request.
+
+use crate::net::protocol::{Request, Response, ResponseError, StateAlgorithm, MAX_STREAM_FPS};
+use crate::net::servers::state_stream::{canvas_rgb, StateStream, StreamSettings};
+use crate::net::servers::statistics::{self, Event};
+use crate::net::servers::{ConnectionPreferences, ListenerCapabilities};
+use crate::pixmap::{Color, Pixmap, SharedPixmap};
+use crate::shutdown::{self, Phase};
+use crate::texts;
+use anyhow::anyhow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::watch;
+use tokio::time::{Instant, MissedTickBehavior};
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use url::form_urlencoded;
+
+/// The maximum size of a request head and of a request body
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// How long the PNG image of the canvas is served before the canvas is encoded again
+const PNG_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many `px` events are sent per second on event streams which don't specify it
+const DEFAULT_EVENT_FPS: u32 = 10;
+
+/// After how many seconds the full canvas is sent again on event streams which don't specify it
+const DEFAULT_SNAPSHOT_SECS: u64 = 10;
+
+/// How many images are sent per second on MJPEG streams which don't specify it
+const DEFAULT_MJPEG_FPS: u32 = 10;
+
+/// The maximum number of images which are sent per second on MJPEG streams
+///
+/// All streams of a canvas share one encoder which encodes it at this rate.
+const MAX_MJPEG_FPS: u32 = 15;
+
+/// The boundary between the images of MJPEG streams
+const MJPEG_BOUNDARY: &str = "frame";
+
+/// The latest PNG image of a canvas together with its ETag
+struct CachedPng {
+    /// The canvas which the image shows
+    canvas: Weak<Pixmap>,
+    /// When the canvas was last encoded, or found to be unchanged
+    refreshed: Instant,
+    /// A hash of the canvas which identifies the image
+    etag: String,
+    /// The encoded image
+    png: Arc<Vec<u8>>,
+}
+
+/// The latest PNG image, which concurrent requests wait for instead of encoding the canvas themselves
+static PNG_CACHE: tokio::sync::Mutex<Option<CachedPng>> = tokio::sync::Mutex::const_new(None);
+
+/// The latest JPEG image of a canvas, or `None` until it was encoded for the first time
+type LatestJpeg = Option<Arc<Vec<u8>>>;
+
+/// The encoders which publish the latest JPEG image of every canvas to its MJPEG streams
+///
+/// An encoder is started by the first stream of a canvas and stops once all of its streams are closed.
+static MJPEG_ENCODERS: Mutex<Vec<(Weak<Pixmap>, watch::Sender<LatestJpeg>)>> = Mutex::new(Vec::new());
+
+/// The parts of an HTTP request head which are relevant to the API and to WebSocket handshakes
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub(super) struct RequestHead {
+    /// The request method, e.g. `GET`
+    pub method: String,
+    /// The path of the request target
+    pub path: String,
+    /// The query string of the request target without the leading `?`
+    pub query: String,
+    /// The length of the request body
+    pub content_length: usize,
+    /// The value of the `Authorization` header
+    pub authorization: Option<String>,
+    /// The value of the `If-None-Match` header
+    pub if_none_match: Option<String>,
+    /// The key of a WebSocket handshake, if the client requested an upgrade to WebSocket
+    pub websocket_key: Option<String>,
+    /// The WebSocket extensions which the client offered, from all `Sec-WebSocket-Extensions` headers
+    pub websocket_extensions: String,
+}
+
+/// Read the head of an HTTP request from the stream
+///
+/// Bytes which were read beyond the end of the head are returned as well.
+pub(super) async fn read_head(
+    stream: &mut (impl AsyncRead + Unpin),
+) -> anyhow::Result<(RequestHead, Vec<u8>)> {
+    let mut buf = Vec::new();
+    loop {
+        if let Some((head, len)) = parse_head(&buf)? {
+            return Ok((head, buf.split_off(len)));
+        }
+        if buf.len() >= MAX_REQUEST_SIZE {
+            return Err(anyhow!("HTTP request head is too large"));
+        }
+        let mut chunk = [0u8; 1024];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(anyhow!(
+                "Connection was closed before the HTTP request head was complete"
+            ));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+/// Parse an HTTP request head from the start of `buf`
+///
+/// The head is returned together with its length or `None` if `buf` does not yet contain all of it.
+fn parse_head(buf: &[u8]) -> anyhow::Result<Option<(RequestHead, usize)>> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut request = httparse::Request::new(&mut headers);
+    let len = match request.parse(buf)? {
+        httparse::Status::Complete(len) => len,
+        httparse::Status::Partial => return Ok(None),
+    };
+
+    let target = request.path.unwrap_or("/");
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let mut head = RequestHead {
+        method: request.method.unwrap_or_default().to_string(),
+        path: path.to_string(),
+        query: query.to_string(),
+        ..RequestHead::default()
+    };
+    let mut upgrade = false;
+    let mut websocket_key = None;
+    for header in request.headers.iter() {
+        let value = String::from_utf8_lossy(header.value).trim().to_string();
+        match header.name.to_ascii_lowercase().as_str() {
+            "content-length" => head.content_length = value.parse()?,
+            "authorization" => head.authorization = Some(value),
+            "if-none-match" => head.if_none_match = Some(value),
+            "upgrade" => upgrade = value.eq_ignore_ascii_case("websocket"),
+            "sec-websocket-key" => websocket_key = Some(value),
+            "sec-websocket-extensions" => {
+                if !head.websocket_extensions.is_empty() {
+                    head.websocket_extensions.push_str(", ");
+                }
+                head.websocket_extensions.push_str(&value);
+            }
+            _ => {}
+        }
+    }
+    head.websocket_key = websocket_key.filter(|_| upgrade);
+    Ok(Some((head, len)))
+}
+
+/// The response which accepts the WebSocket handshake with the given key and the negotiated extensions, if any
+pub(super) fn upgrade_response(websocket_key: &str, extensions: Option<&str>) -> String {
+    let extensions = extensions
+        .map(|extensions| format!("Sec-WebSocket-Extensions: {}\r\n", extensions))
+        .unwrap_or_default();
+    format!(
+        "HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Accept: {}\r\n{}\r\n",
+        derive_accept_key(websocket_key.as_bytes()),
+        extensions
+    )
+}
+
+/// Read the body of a request, answer it and close the connection
+///
+/// `body` contains the part of the body which was already read together with the head.
+/// Requests for an event stream are answered until the client disconnects or the server shuts down.
+pub(super) async fn serve(
+    mut stream: impl AsyncRead + AsyncWrite + Unpin,
+    head: RequestHead,
+    mut body: Vec<u8>,
+    pixmap: &SharedPixmap,
+    capabilities: &ListenerCapabilities,
+) -> anyhow::Result<()> {
+    if head.method == "GET" && head.path == "/events" {
+        return events(stream, &head, pixmap, capabilities).await;
+    }
+    if head.method == "GET" && head.path == "/stream.mjpeg" {
+        return mjpeg(stream, &head, pixmap, capabilities).await;
+    }
+    if head.method == "GET" && head.path == "/canvas.png" {
+        stream
+            .write_all(&canvas_png(&head, pixmap, capabilities).await)
+            .await?;
+        stream.shutdown().await?;
+        return Ok(());
+    }
+    let (status, content_type, content) = if head.content_length > MAX_REQUEST_SIZE {
+        (413, "text/plain", "Request body is too large\n".to_string())
+    } else {
+        while body.len() < head.content_length {
+            let mut chunk = [0u8; 1024];
+            let n = stream.read(&mut chunk).await?;
+            if n == 0 {
+                return Err(anyhow!(
+                    "Connection was closed before the HTTP request body was complete"
+                ));
+            }
+            body.extend_from_slice(&chunk[..n]);
+        }
+        body.truncate(head.content_length);
+        handle(&head, &body, pixmap, capabilities)
+    };
+
+    stream
+        .write_all(response(status, content_type, &content).as_bytes())
+        .await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Encode an HTTP response which tells a client why its connection is rejected
+pub(super) fn rejection(e: ResponseError) -> String {
+    let (status, content_type, content) = error(e);
+    response(status, content_type, &content)
+}
+
+/// Encode a complete HTTP response after which the connection is closed
+fn response(status: u16, content_type: &str, content: &str) -> String {
+    response_head(status, content_type, content.len(), "") + content
+}
+
+/// Encode the head of a response with `content_length` bytes of content and any number of extra header lines
+fn response_head(status: u16, content_type: &str, content_length: usize, extra_headers: &str) -> String {
+    format!(
+        "HTTP/1.1 {} {}\r\n\
+         Content-Type: {}\r\n\
+         Content-Length: {}\r\n\
+         Access-Control-Allow-Origin: *\r\n\
+         Access-Control-Allow-Methods: GET, PUT, OPTIONS\r\n\
+         Access-Control-Allow-Headers: Authorization, Content-Type\r\n\
+         {}\
+         Connection: close\r\n\r\n",
+        status,
+        reason_phrase(status),
+        content_type,
+        content_length,
+        extra_headers
+    )
+}
+
+/// Encode the response to a request for the canvas as a PNG image
+///
+/// The image is tagged with a hash of the canvas and not sent at all if the client already has the current one.
+async fn canvas_png(
+    head: &RequestHead,
+    pixmap: &SharedPixmap,
+    capabilities: &ListenerCapabilities,
+) -> Vec<u8> {
+    let request = Request::StreamState {
+        algorithm: StateAlgorithm::Rgb64,
+        fps: 0,
+        region: None,
+    };
+    let checked = authorized_preferences(head, capabilities)
+        .and_then(|preferences| super::check_features(&request, capabilities, &preferences));
+    if let Err(e) = checked {
+        let (status, content_type, content) = error(e);
+        return response(status, content_type, &content).into_bytes();
+    }
+
+    let (etag, png) = match latest_png(pixmap).await {
+        Ok(latest) => latest,
+        Err(e) => {
+            let (status, content_type, content) = error(e);
+            return response(status, content_type, &content).into_bytes();
+        }
+    };
+    // browsers and chat previews must revalidate since the canvas changes all the time
+    let cache_headers = format!("ETag: {}\r\nCache-Control: no-cache\r\n", etag);
+    if head.if_none_match.as_deref() == Some(&etag) {
+        return response_head(304, "image/png", 0, &cache_headers).into_bytes();
+    }
+    let mut response = response_head(200, "image/png", png.len(), &cache_headers).into_bytes();
+    response.extend_from_slice(&png);
+    response
+}
+
+/// Get the ETag and the PNG image of the canvas, which is encoded again at most once per refresh interval
+///
+/// The canvas is only encoded again if its hash changed, and never on the threads which handle connections.
+async fn latest_png(pixmap: &SharedPixmap) -> Result<(String, Arc<Vec<u8>>), ResponseError> {
+    let mut cache = PNG_CACHE.lock().await;
+    let previous = cache
+        .take()
+        .filter(|cached| cached.canvas.as_ptr() == Arc::as_ptr(pixmap));
+    if let Some(cached) = previous
+        .as_ref()
+        .filter(|cached| cached.refreshed.elapsed() < PNG_REFRESH_INTERVAL)
+    {
+        let latest = (cached.etag.clone(), cached.png.clone());
+        *cache = previous;
+        return Ok(latest);
+    }
+    let canvas = pixmap.clone();
+    let refreshed = tokio::task::spawn_blocking(move || -> Result<CachedPng, ResponseError> {
+        let (width, height) = canvas.get_size();
+        let rgb = canvas_rgb(&canvas);
+        let mut hasher = DefaultHasher::new();
+        (width, height, &rgb).hash(&mut hasher);
+        let etag = format!("\"{:016x}\"", hasher.finish());
+        let png = match previous {
+            Some(previous) if previous.etag == etag => previous.png,
+            _ => Arc::new(encode_png(width, height, &rgb)?),
+        };
+        Ok(CachedPng {
+            canvas: Arc::downgrade(&canvas),
+            refreshed: Instant::now(),
+            etag,
+            png,
+        })
+    })
+    .await
+    .map_err(|e| ResponseError::Unsupported(format!("Could not encode canvas: {}", e)))??;
+    let latest = (refreshed.etag.clone(), refreshed.png.clone());
+    *cache = Some(refreshed);
+    Ok(latest)
+}
+
+/// Encode rgb values as a PNG image
+#[cfg(feature = "images")]
+fn encode_png(width: usize, height: usize, rgb: &[u8]) -> Result<Vec<u8>, ResponseError> {
+    use image::codecs::png::{CompressionType, FilterType, PngEncoder};
+    use image::{ExtendedColorType, ImageEncoder};
+
+    let mut png = Vec::new();
+    // fast compression since the canvas is encoded again on every request which comes after a change
+    PngEncoder::new_with_quality(&mut png, CompressionType::Fast, FilterType::Adaptive)
+        .write_image(rgb, width as u32, height as u32, ExtendedColorType::Rgb8)
+        .map_err(|e| ResponseError::Unsupported(format!("Could not encode canvas: {}", e)))?;
+    Ok(png)
+}
+
+/// Encode rgb values as a JPEG image
+#[cfg(feature = "images")]
+fn encode_jpeg(width: usize, height: usize, rgb: &[u8]) -> anyhow::Result<Vec<u8>> {
+    use image::codecs::jpeg::JpegEncoder;
+    use image::ExtendedColorType;
+
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, 80).encode(
+        rgb,
+        width as u32,
+        height as u32,
+        ExtendedColorType::Rgb8,
+    )?;
+    Ok(jpeg)
+}
+
+#[cfg(not(feature = "images"))]
+fn encode_jpeg(_width: usize, _height: usize, _rgb: &[u8]) -> anyhow::Result<Vec<u8>> {
+    Err(anyhow!("The server was built without support for images"))
+}
+
+#[cfg(not(feature = "images"))]
+fn encode_png(_width: usize, _height: usize, _rgb: &[u8]) -> Result<Vec<u8>, ResponseError> {
+    Err(ResponseError::Unsupported(
+        "The server was built without support for images".to_string(),
+    ))
+}
+
+/// Stream pixel updates and periodic snapshots of the canvas as Server-Sent Events
+async fn events(
+    mut stream: impl AsyncWrite + Unpin,
+    head: &RequestHead,
+    pixmap: &SharedPixmap,
+    capabilities: &ListenerCapabilities,
+) -> anyhow::Result<()> {
+    let (settings, snapshot_interval) = match event_settings(head, capabilities) {
+        Ok(settings) => settings,
+        Err(e) => {
+            let (status, content_type, content) = error(e);
+            stream
+                .write_all(response(status, content_type, &content).as_bytes())
+                .await?;
+            stream.shutdown().await?;
+            return Ok(());
+        }
+    };
+    stream
+        .write_all(
+            b"HTTP/1.1 200 OK\r\n\
+              Content-Type: text/event-stream\r\n\
+              Cache-Control: no-cache\r\n\
+              Access-Control-Allow-Origin: *\r\n\
+              Connection: close\r\n\r\n",
+        )
+        .await?;
+
+    let shutdown = shutdown::Guard::new(Phase::Draining);
+    let mut px_stream = StateStream::default();
+    let mut snapshots = tokio::time::interval(snapshot_interval);
+    let snapshot_settings = StreamSettings {
+        algorithm: StateAlgorithm::Rgb64,
+        ..settings
+    };
+    loop {
+        let mut frame = Vec::new();
+        let event = tokio::select! {
+            _ = snapshots.tick() => {
+                StateStream::default().write_frame(pixmap, snapshot_settings, &mut frame)?;
+                "state"
+            }
+            settings = px_stream.tick(Some(settings)) => {
+                px_stream.write_frame(pixmap, settings, &mut frame)?;
+                "px"
+            }
+            _ = shutdown.requested() => {
+                tracing::debug!("Closing event stream since the server shuts down");
+                stream.shutdown().await?;
+                return Ok(());
+            }
+        };
+        if !frame.is_empty() {
+            stream.write_all(&encode_event(event, &frame)).await?;
+        }
+    }
+}
+
+/// Determine how pixel updates are streamed to a client which requested an event stream and after which interval
+/// it is sent the whole canvas again
+fn event_settings(
+    head: &RequestHead,
+    capabilities: &ListenerCapabilities,
+) -> Result<(StreamSettings, Duration), ResponseError> {
+    let preferences = authorized_preferences(head, capabilities)?;
+    let params: HashMap<String, String> = form_urlencoded::parse(head.query.as_bytes())
+        .map(|(name, value)| (name.into_owned(), value.into_owned()))
+        .collect();
+    let fps = match params.get("fps") {
+        None => DEFAULT_EVENT_FPS,
+        Some(fps) => fps
+            .parse::<u32>()
+            .ok()
+            .filter(|fps| (1..=MAX_STREAM_FPS).contains(fps))
+            .ok_or_else(|| {
+                ResponseError::ParseError(format!("Parameter fps must be between 1 and {}", MAX_STREAM_FPS))
+            })?,
+    };
+    let snapshot_secs = match params.get("snapshot") {
+        None => DEFAULT_SNAPSHOT_SECS,
+        Some(secs) => secs.parse::<u64>().ok().filter(|secs| *secs > 0).ok_or_else(|| {
+            ResponseError::ParseError("Parameter snapshot must be a positive number".to_string())
+        })?,
+    };
+
+    let request = Request::StreamState {
+        algorithm: StateAlgorithm::Px,
+        fps,
+        region: None,
+    };
+    super::check_features(&request, capabilities, &preferences)?;
+    Ok((
+        StreamSettings {
+            algorithm: StateAlgorithm::Px,
+            fps,
+            region: None,
+        },
+        Duration::from_secs(snapshot_secs),
+    ))
+}
+
+/// Encode the lines of a state stream frame as a Server-Sent Event with the given name
+fn encode_event(name: &str, frame: &[u8]) -> Vec<u8> {
+    let mut event = format!("event: {}\n", name).into_bytes();
+    for line in frame.split(|&b| b == b'\n').filter(|line| !line.is_empty()) {
+        event.extend_from_slice(b"data: ");
+        event.extend_from_slice(line);
+        event.push(b'\n');
+    }
+    event.push(b'\n');
+    event
+}
+
+/// Stream the canvas as JPEG images which replace each other until the client disconnects
+async fn mjpeg(
+    mut stream: impl AsyncWrite + Unpin,
+    head: &RequestHead,
+    pixmap: &SharedPixmap,
+    capabilities: &ListenerCapabilities,
+) -> anyhow::Result<()> {
+    let fps = match mjpeg_fps(head, capabilities) {
+        Ok(fps) => fps,
+        Err(e) => {
+            let (status, content_type, content) = error(e);
+            stream
+                .write_all(response(status, content_type, &content).as_bytes())
+                .await?;
+            stream.shutdown().await?;
+            return Ok(());
+        }
+    };
+    stream
+        .write_all(
+            format!(
+                "HTTP/1.1 200 OK\r\n\
+                 Content-Type: multipart/x-mixed-replace; boundary={}\r\n\
+                 Cache-Control: no-cache\r\n\
+                 Access-Control-Allow-Origin: *\r\n\
+                 Connection: close\r\n\r\n",
+                MJPEG_BOUNDARY
+            )
+            .as_bytes(),
+        )
+        .await?;
+
+    let shutdown = shutdown::Guard::new(Phase::Draining);
+    let mut images = subscribe_jpeg(pixmap);
+    let mut frames = tokio::time::interval(Duration::from_secs(1) / fps);
+    frames.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    loop {
+        tokio::select! {
+            _ = frames.tick() => {}
+            _ = shutdown.requested() => {
+                tracing::debug!("Closing MJPEG stream since the server shuts down");
+                stream.shutdown().await?;
+                return Ok(());
+            }
+        }
+        // images of an unchanged canvas are sent again without encoding them again
+        let jpeg = images.borrow_and_update().clone();
+        if let Some(jpeg) = jpeg {
+            stream.write_all(&encode_mjpeg_part(&jpeg)).await?;
+        }
+    }
+}
+
+/// Receive the latest JPEG images of a canvas and start encoding them if no other MJPEG stream does yet
+fn subscribe_jpeg(pixmap: &SharedPixmap) -> watch::Receiver<LatestJpeg> {
+    let mut encoders = MJPEG_ENCODERS.lock().unwrap();
+    if let Some((_, sender)) = encoders
+        .iter()
+        .find(|(canvas, _)| canvas.as_ptr() == Arc::as_ptr(pixmap))
+    {
+        return sender.subscribe();
+    }
+    let (sender, receiver) = watch::channel(None);
+    encoders.push((Arc::downgrade(pixmap), sender.clone()));
+    tokio::spawn(encode_jpegs(pixmap.clone(), sender));
+    receiver
+}
+
+/// Encode the canvas as JPEG images at the maximum MJPEG rate until no stream receives them anymore
+///
+/// Images are only encoded when the canvas changed, and never on the threads which handle connections.
+async fn encode_jpegs(pixmap: SharedPixmap, sender: watch::Sender<LatestJpeg>) {
+    let mut frames = tokio::time::interval(Duration::from_secs(1) / MAX_MJPEG_FPS);
+    frames.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let mut previous_rgb: Option<Vec<u8>> = None;
+    loop {
+        frames.tick().await;
+        {
+            // streams subscribe while holding the lock, so none can subscribe to an encoder that stops
+            let mut encoders = MJPEG_ENCODERS.lock().unwrap();
+            if sender.receiver_count() == 0 {
+                encoders.retain(|(canvas, _)| canvas.as_ptr() != Arc::as_ptr(&pixmap));
+                return;
+            }
+        }
+        let canvas = pixmap.clone();
+        let previous = previous_rgb.take();
+        let encoded = tokio::task::spawn_blocking(move || {
+            let rgb = canvas_rgb(&canvas);
+            if previous.as_ref() == Some(&rgb) {
+                return Ok((rgb, None));
+            }
+            let (width, height) = canvas.get_size();
+            encode_jpeg(width, height, &rgb).map(|jpeg| (rgb, Some(jpeg)))
+        })
+        .await;
+        match encoded {
+            Ok(Ok((rgb, jpeg))) => {
+                previous_rgb = Some(rgb);
+                if let Some(jpeg) = jpeg {
+                    sender.send_replace(Some(Arc::new(jpeg)));
+                }
+            }
+            Ok(Err(e)) => tracing::warn!("Could not encode the canvas for MJPEG streams: {}", e),
+            Err(e) => tracing::warn!("Could not encode the canvas for MJPEG streams: {}", e),
+        }
+    }
+}
+
+/// Determine how many images per second are sent to a client which requested an MJPEG stream
+fn mjpeg_fps(head: &RequestHead, capabilities: &ListenerCapabilities) -> Result<u32, ResponseError> {
+    if !cfg!(feature = "images") {
+        return Err(ResponseError::Unsupported(
+            "The server was built without support for images".to_string(),
+        ));
+    }
+    let preferences = authorized_preferences(head, capabilities)?;
+    let fps = match form_urlencoded::parse(head.query.as_bytes()).find(|(name, _)| name == "fps") {
+        None => DEFAULT_MJPEG_FPS,
+        Some((_, fps)) => fps
+            .parse::<u32>()
+            .ok()
+            .filter(|fps| (1..=MAX_MJPEG_FPS).contains(fps))
+            .ok_or_else(|| {
+                ResponseError::ParseError(format!("Parameter fps must be between 1 and {}", MAX_MJPEG_FPS))
+            })?,
+    };
+    let request = Request::StreamState {
+        algorithm: StateAlgorithm::Rgb64,
+        fps,
+        region: None,
+    };
+    super::check_features(&request, capabilities, &preferences)?;
+    Ok(fps)
+}
+
+/// Encode a JPEG image as one part of an MJPEG stream
+fn encode_mjpeg_part(jpeg: &[u8]) -> Vec<u8> {
+    let mut part = format!(
+        "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+        MJPEG_BOUNDARY,
+        jpeg.len()
+    )
+    .into_bytes();
+    part.extend_from_slice(jpeg);
+    part.extend_from_slice(b"\r\n");
+    part
+}
+
+/// The preferences of a request which authenticated itself via an `Authorization` header, if it sent one
+fn authorized_preferences(
+    head: &RequestHead,
+    capabilities: &ListenerCapabilities,
+) -> Result<ConnectionPreferences, ResponseError> {
+    let mut preferences = ConnectionPreferences::default();
+    if let Some(token) = head
+        .authorization
+        .as_deref()
+        .and_then(|value| value.strip_prefix("Bearer "))
+    {
+        super::authenticate(token, capabilities, &mut preferences)?;
+    }
+    Ok(preferences)
+}
+
+/// Answer a request with its status code, content type and content
+fn handle(
+    head: &RequestHead,
+    body: &[u8],
+    pixmap: &SharedPixmap,
+    capabilities: &ListenerCapabilities,
+) -> (u16, &'static str, String) {
+    let preferences = match authorized_preferences(head, capabilities) {
+        Ok(preferences) => preferences,
+        Err(e) => return error(e),
+    };
+    let params: HashMap<String, String> = form_urlencoded::parse(head.query.as_bytes())
+        .chain(form_urlencoded::parse(body))
+        .map(|(name, value)| (name.into_owned(), value.into_owned()))
+        .collect();
+
+    let request = match (head.method.as_str(), head.path.as_str()) {
+        // preflight requests of browsers only need the headers which are sent with every response
+        ("OPTIONS", _) => return (204, "text/plain", String::new()),
+        ("GET", "/size") => Ok(Request::GetSize),
+        ("GET", "/pixel") => coordinates(&params).map(|(x, y)| Request::GetPixel { x, y }),
+        ("PUT", "/pixel") => coordinates(&params).and_then(|(x, y)| {
+            let color = params
+                .get("color")
+                .ok_or_else(|| ResponseError::ParseError("Missing parameter color".to_string()))?
+                .parse::<Color>()
+                .map_err(|e| ResponseError::ParseError(e.to_string()))?;
+            Ok(Request::SetPixel { x, y, color })
+        }),
+        ("GET", path) if path.starts_with("/state/") => {
+            return state(&path["/state/".len()..], pixmap, capabilities, &preferences)
+        }
+        #[cfg(feature = "web")]
+        ("GET", path) => {
+            return match super::frontend::file(path) {
+                Some((content_type, content)) => (200, content_type, content.to_string()),
+                None => (404, "text/plain", "Not Found\n".to_string()),
+            }
+        }
+        _ => return (404, "text/plain", "Not Found\n".to_string()),
+    };
+
+    let result = request.and_then(|request| {
+        super::check_features(&request, capabilities, &preferences)?;
+        let pixels = statistics::pixels_drawn(&request);
+        let result = super::handle_parsed_request(request, pixmap, capabilities, &preferences);
+        if super::pixels_were_drawn(&result, capabilities) && pixels > 0 {
+            statistics::record(Event::PixelsSet(None, pixels));
+        }
+        result
+    });
+    match result {
+        Ok(Some(Response::Size { width, height })) => (
+            200,
+            "application/json",
+            format!("{{\"width\":{},\"height\":{}}}", width, height),
+        ),
+        Ok(Some(Response::PxData { x, y, color })) => (
+            200,
+            "application/json",
+            format!("{{\"x\":{},\"y\":{},\"color\":\"{:X}\"}}", x, y, color),
+        ),
+        Ok(_) => (204, "text/plain", String::new()),
+        Err(e) => error(e),
+    }
+}
+
+/// Answer a request for the canvas state with a single frame that contains all pixels
+fn state(
+    algorithm: &str,
+    pixmap: &SharedPixmap,
+    capabilities: &ListenerCapabilities,
+    preferences: &ConnectionPreferences,
+) -> (u16, &'static str, String) {
+    let Some(algorithm) = [StateAlgorithm::Rgb64, StateAlgorithm::Delta]
+        .into_iter()
+        .find(|candidate| candidate.as_str().eq_ignore_ascii_case(algorithm))
+    else {
+        return (404, "text/plain", "Not Found\n".to_string());
+    };
+    let request = Request::StreamState {
+        algorithm,
+        fps: 0,
+        region: None,
+    };
+    if let Err(e) = super::check_features(&request, capabilities, preferences) {
+        return error(e);
+    }
+
+    let settings = StreamSettings {
+        algorithm,
+        fps: 0,
+        region: None,
+    };
+    let mut frame = Vec::new();
+    StateStream::default()
+        .write_frame(pixmap, settings, &mut frame)
+        .expect("Could not write state frame into memory");
+    (200, "text/plain", String::from_utf8_lossy(&frame).into_owned())
+}
+
+/// Extract the `x` and `y` parameters of a request
+fn coordinates(params: &HashMap<String, String>) -> Result<(usize, usize), ResponseError> {
+    let coordinate = |name: &str| {
+        params
+            .get(name)
+            .ok_or_else(|| ResponseError::ParseError(format!("Missing parameter {}", name)))?
+            .parse::<usize>()
+            .map_err(|e| ResponseError::ParseError(format!("Invalid parameter {}: {}", name, e)))
+    };
+    Ok((coordinate("x")?, coordinate("y")?))
+}
+
+/// Answer a request with an error of the line protocol
+fn error(e: ResponseError) -> (u16, &'static str, String) {
+    let status = match e {
+        ResponseError::OutOfBounds(_) | ResponseError::ParseError(_) => 400,
+        ResponseError::Unauthorized(_) => 401,
+        ResponseError::Claimed(_) => 409,
+        ResponseError::ShuttingDown(_) => 503,
+        ResponseError::RateLimited(_) => 429,
+        ResponseError::Unsupported(_) => 501,
+    };
+    (status, "text/plain", format!("{}\n", texts::error_text(&e)))
+}
+
+/// The reason phrase of the status codes which the API uses
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        409 => "Conflict",
+        413 => "Content Too Large",
+        429 => "Too Many Requests",
+        503 => "Service Unavailable",
+        _ => "Not Implemented",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pixmap::Pixmap;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_parse_head() {
+        let buf = b"GET /pixel?x=1&y=2 HTTP/1.1\r\nHost: localhost\r\nContent-Length: 3\r\n\r\nabc";
+        let (head, len) = parse_head(buf).unwrap().unwrap();
+        assert_eq!(&buf[len..], b"abc");
+        assert_eq!(head.method, "GET");
+        assert_eq!(head.path, "/pixel");
+        assert_eq!(head.query, "x=1&y=2");
+        assert_eq!(head.content_length, 3);
+        assert_eq!(head.websocket_key, None);
+
+        let buf =
+            b"GET / HTTP/1.1\r\nUpgrade: websocket\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Extensions: permessage-deflate\r\nSec-WebSocket-Extensions: foo\r\n\r\n";
+        let (head, _) = parse_head(buf).unwrap().unwrap();
+        assert_eq!(head.websocket_key.as_deref(), Some("dGhlIHNhbXBsZSBub25jZQ=="));
+        assert_eq!(head.websocket_extensions, "permessage-deflate, foo");
+
+        assert_eq!(parse_head(b"GET /size HTTP/1.1\r\n").unwrap(), None);
+    }
+
+    #[test]
+    fn test_handle() {
+        let pixmap = Arc::new(Pixmap::new(4, 2).unwrap());
+        let capabilities = ListenerCapabilities::default();
+        let request = |method: &str, target: &str, body: &[u8]| {
+            let buf = format!("{} {} HTTP/1.1\r\n\r\n", method, target);
+            let (head, _) = parse_head(buf.as_bytes()).unwrap().unwrap();
+            handle(&head, body, &pixmap, &capabilities)
+        };
+
+        assert_eq!(
+            request("GET", "/size", b""),
+            (200, "application/json", r#"{"width":4,"height":2}"#.to_string())
+        );
+        assert_eq!(request("PUT", "/pixel?x=1", b"y=1&color=FF0080").0, 204);
+        assert_eq!(
+            request("GET", "/pixel?x=1&y=1", b""),
+            (
+                200,
+                "application/json",
+                r#"{"x":1,"y":1,"color":"FF0080"}"#.to_string()
+            )
+        );
+        assert_eq!(request("GET", "/pixel?x=9&y=1", b"").0, 400);
+        assert_eq!(request("PUT", "/pixel?x=1&y=1", b"").0, 400);
+        assert_eq!(
+            request("GET", "/state/RGB64", b"").2,
+            "STATE rgb64 AAAAAAAAAAAAAAAAAAAA/wCAAAAAAAAA\n"
+        );
+        // the addresses of clients are only listed via the admin interface
+        assert_eq!(request("GET", "/clients", b"").0, 404);
+        assert_eq!(request("GET", "/nothing", b"").0, 404);
+        #[cfg(feature = "web")]
+        assert_eq!(request("GET", "/", b"").1, "text/html; charset=utf-8");
+    }
+
+    #[cfg(feature = "images")]
+    #[tokio::test(start_paused = true)]
+    async fn test_canvas_png() {
+        let pixmap = Arc::new(Pixmap::new(4, 2).unwrap());
+        pixmap.set_pixel(1, 1, Color::from((0xFF, 0x00, 0x80))).unwrap();
+        let capabilities = ListenerCapabilities::default();
+        let request = |headers: &str| {
+            let buf = format!("GET /canvas.png HTTP/1.1\r\n{}\r\n", headers);
+            let (head, _) = parse_head(buf.as_bytes()).unwrap().unwrap();
+            let (pixmap, capabilities) = (&pixmap, &capabilities);
+            async move {
+                let response = canvas_png(&head, pixmap, capabilities).await;
+                let split = response
+                    .windows(4)
+                    .position(|window| window == b"\r\n\r\n")
+                    .unwrap();
+                let (head, content) = response.split_at(split + 4);
+                (String::from_utf8(head.to_vec()).unwrap(), content.to_vec())
+            }
+        };
+
+        let (head, content) = request("").await;
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\nContent-Type: image/png\r\n"));
+        let image = image::load_from_memory(&content).unwrap().into_rgb8();
+        assert_eq!(image.dimensions(), (4, 2));
+        assert_eq!(image.get_pixel(1, 1).0, [0xFF, 0x00, 0x80]);
+
+        let etag = head
+            .lines()
+            .find_map(|line| line.strip_prefix("ETag: "))
+            .unwrap()
+            .to_string();
+        let (head, content) = request(&format!("If-None-Match: {}\r\n", etag)).await;
+        assert!(head.starts_with("HTTP/1.1 304 Not Modified\r\n"));
+        assert!(content.is_empty());
+
+        // changes are only encoded once the image was served for the refresh interval
+        pixmap.set_pixel(0, 0, Color::from((0x00, 0x00, 0xFF))).unwrap();
+        let (head, _) = request(&format!("If-None-Match: {}\r\n", etag)).await;
+        assert!(head.starts_with("HTTP/1.1 304 Not Modified\r\n"));
+        tokio::time::advance(PNG_REFRESH_INTERVAL).await;
+        let (head, _) = request(&format!("If-None-Match: {}\r\n", etag)).await;
+        assert!(head.starts_with("HTTP/1.1 200 OK"));
+    }
+
+    #[test]
+    fn test_event_settings() {
+        let capabilities = ListenerCapabilities::default();
+        let settings = |target: &str| {
+            let buf = format!("GET {} HTTP/1.1\r\n\r\n", target);
+            let (head, _) = parse_head(buf.as_bytes()).unwrap().unwrap();
+            event_settings(&head, &capabilities)
+        };
+
+        let (stream_settings, snapshot_interval) = settings("/events").unwrap();
+        assert_eq!(stream_settings.algorithm, StateAlgorithm::Px);
+        assert_eq!(stream_settings.fps, DEFAULT_EVENT_FPS);
+        assert_eq!(snapshot_interval, Duration::from_secs(DEFAULT_SNAPSHOT_SECS));
+
+        let (stream_settings, snapshot_interval) = settings("/events?fps=30&snapshot=2").unwrap();
+        assert_eq!(stream_settings.fps, 30);
+        assert_eq!(snapshot_interval, Duration::from_secs(2));
+
+        assert!(settings("/events?fps=0").is_err());
+        assert!(settings("/events?fps=1000").is_err());
+        assert!(settings("/events?snapshot=0").is_err());
+    }
+
+    #[cfg(feature = "images")]
+    #[test]
+    fn test_mjpeg() {
+        let capabilities = ListenerCapabilities::default();
+        let fps = |target: &str| {
+            let buf = format!("GET {} HTTP/1.1\r\n\r\n", target);
+            let (head, _) = parse_head(buf.as_bytes()).unwrap().unwrap();
+            mjpeg_fps(&head, &capabilities)
+        };
+        assert_eq!(fps("/stream.mjpeg"), Ok(DEFAULT_MJPEG_FPS));
+        assert_eq!(fps("/stream.mjpeg?fps=2"), Ok(2));
+        assert!(fps("/stream.mjpeg?fps=0").is_err());
+        assert!(fps("/stream.mjpeg?fps=60").is_err());
+
+        let jpeg = encode_jpeg(4, 2, &[0x80; 4 * 2 * 3]).unwrap();
+        assert_eq!(image::load_from_memory(&jpeg).unwrap().width(), 4);
+        let part = encode_mjpeg_part(&jpeg);
+        let head = format!(
+            "--frame\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+            jpeg.len()
+        );
+        assert!(part.starts_with(head.as_bytes()));
+        assert!(part.ends_with(b"\r\n"));
+    }
+
+    #[cfg(feature = "images")]
+    #[tokio::test]
+    async fn test_shared_jpeg_encoder() {
+        let pixmap = Arc::new(Pixmap::new(4, 2).unwrap());
+        let mut first = subscribe_jpeg(&pixmap);
+        let second = subscribe_jpeg(&pixmap);
+        assert!(first.same_channel(&second));
+        first.changed().await.unwrap();
+        let jpeg = first.borrow().clone().unwrap();
+        assert_eq!(image::load_from_memory(&jpeg).unwrap().width(), 4);
+
+        // the encoder stops on its next tick once all streams are closed
+        drop((first, second));
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let encoders = MJPEG_ENCODERS.lock().unwrap();
+        assert!(!encoders
+            .iter()
+            .any(|(canvas, _)| canvas.as_ptr() == Arc::as_ptr(&pixmap)));
+    }
+
+    #[test]
+    fn test_encode_event() {
+        assert_eq!(
+            encode_event("px", b"PX 1 0 FF0080\nPX 0 1 123456\n"),
+            b"event: px\ndata: PX 1 0 FF0080\ndata: PX 0 1 123456\n\n"
+        );
+        assert_eq!(
+            encode_event("state", b"STATE rgb64 AAAA/wCA\n"),
+            b"event: state\ndata: STATE rgb64 AAAA/wCA\n\n"
+        );
+    }
+}
@@ -1,38 +1,247 @@
 //! Server implementations for different transport protocols
 
+mod access_control;
+mod admin;
+mod claims;
+mod compression;
+mod connection_limit;
+#[cfg(feature = "dtls")]
+mod dtls_server;
 mod gen_server;
+#[cfg(any(feature = "tcp", feature = "ws"))]
+mod proxy_protocol;
+pub(crate) mod rate_limit;
+mod reloadable;
+mod socket_activation;
+#[cfg(any(feature = "tcp", feature = "udp", feature = "ws", feature = "grpc"))]
+mod sockets;
+mod state_stream;
+pub(crate) mod statistics;
+mod storm_guard;
+mod transactions;
+mod write_protection;
 
 #[cfg(test)]
 mod benchmark;
 
+pub use access_control::AccessControlOptions;
+pub use admin::{reload_requests, upgrade_requests, AdminEndpoint, AdminServer, AdminServerOptions};
+pub use claims::{ClaimMode, InvalidClaimModeError};
 pub use gen_server::GenServer;
+pub use rate_limit::RateLimitOptions;
+pub use reloadable::Reloadable;
+pub use socket_activation::{close_unused_sockets, take_handed_over_canvas, Successor};
+#[cfg(any(feature = "tcp", feature = "udp", feature = "ws", feature = "grpc"))]
+pub use sockets::SocketOptions;
+pub use storm_guard::StormProtectionOptions;
+pub use write_protection::WriteProtectionOptions;
 
+#[cfg(feature = "web")]
+mod frontend;
+#[cfg(feature = "grpc")]
+mod grpc_server;
+#[cfg(feature = "ws")]
+mod http_api;
+#[cfg(feature = "mqtt")]
+mod mqtt_server;
+#[cfg(feature = "quic")]
+mod quic_server;
 #[cfg(feature = "tcp")]
 mod tcp_server;
+#[cfg(feature = "io-uring")]
+mod tcp_uring;
+#[cfg(feature = "tls")]
+mod tls;
 #[cfg(feature = "udp")]
 mod udp_server;
 mod unix_sock_server;
 #[cfg(feature = "ws")]
+mod ws_deflate;
+#[cfg(feature = "ws")]
+pub(crate) mod ws_json;
+#[cfg(feature = "ws")]
 mod ws_server;
 
-use crate::net::protocol::{parse_request_bin, Request, Response};
+use crate::net::framing::{Frame, FrameBuffer};
+use crate::net::protocol::{
+    parse_request_bin, write_error_binary, write_response_binary, Compression, Features, ParseErr,
+    ProtocolVariant, Region, Request, Response, ResponseError, ServerInfo, ServerLimits, StateAlgorithm,
+    MAX_BATCH_SIZE, MAX_BLOCK_PIXELS, MAX_CLAIM_SECS, MAX_IMAGE_SIZE, MAX_STREAM_FPS, MAX_TRANSACTION_LEN,
+    MAX_TRANSACTION_PIXELS, PROTOCOL_VERSION,
+};
 use crate::pixmap::SharedPixmap;
+use crate::texts;
+use bytes::buf::Writer;
+use bytes::BytesMut;
+use claims::ClaimOwner;
+use rate_limit::Usage;
+use state_stream::StreamSettings;
+use statistics::ClientId;
+use std::future::Future;
+use std::io::Write;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
 
+#[cfg(feature = "dtls")]
+pub use dtls_server::{DtlsServer, DtlsServerOptions};
+#[cfg(feature = "grpc")]
+pub use grpc_server::{GrpcServer, GrpcServerOptions};
+#[cfg(feature = "mqtt")]
+pub use mqtt_server::{MqttServer, MqttServerOptions};
+#[cfg(feature = "quic")]
+pub use quic_server::{QuicServer, QuicServerOptions, QUIC_ALPN};
 #[cfg(feature = "tcp")]
 pub use tcp_server::{TcpServer, TcpServerOptions};
+#[cfg(feature = "tls")]
+pub use tls::TlsOptions;
 #[cfg(feature = "udp")]
 pub use udp_server::{UdpServer, UdpServerOptions};
 pub use unix_sock_server::{UnixSocketOptions, UnixSocketServer};
 #[cfg(feature = "ws")]
 pub use ws_server::{WsServer, WsServerOptions};
 
+/// The maximum length of a request line on stream and datagram based listeners
+///
+/// It is long enough for a PXB request with the maximum number of pixels.
+pub(crate) const MAX_LINE_LEN: usize = 4096;
+
+/// After how many bytes of collected responses or handled requests the responses to pipelined requests are sent
+///
+/// This bounds how long a connection which keeps sending requests can go without the rate limiter or a shutdown
+/// getting a chance to intervene.
+const PIPELINE_FLUSH_LEN: usize = 64 * 1024;
+
+/// Options for the buffers of the connections of stream based listeners
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct BufferOptions {
+    /// The initial size of the buffer into which requests are read
+    pub read_buffer_size: usize,
+    /// The initial size of the buffer in which responses are collected before they are sent
+    pub write_buffer_size: usize,
+    /// How long a request line may be before it is rejected with an error
+    ///
+    /// The data of image uploads does not count towards this limit.
+    pub max_line_len: usize,
+}
+
+impl Default for BufferOptions {
+    fn default() -> Self {
+        Self {
+            read_buffer_size: 8 * 1024,
+            write_buffer_size: 2 * 1024,
+            max_line_len: MAX_LINE_LEN,
+        }
+    }
+}
+
+/// Settings which a client has negotiated for its connection
+#[derive(Debug, Default)]
+pub(crate) struct ConnectionPreferences {
+    /// The encoding in which requests and responses are exchanged
+    pub protocol: ProtocolVariant,
+    /// How the canvas state is streamed to the client, if requested
+    pub stream: Option<StreamSettings>,
+    /// The compression which is applied to all data that is sent to the client
+    pub compression: Compression,
+    /// Whether the client has authenticated via AUTH and may draw on a write protected canvas
+    pub authenticated: bool,
+    /// The optional features which were granted via HELLO
+    ///
+    /// Clients which never sent HELLO may use all features of the listener.
+    pub features: Option<Features>,
+    /// Whether responses to requests which draw on the canvas are suppressed via NOREPLY
+    pub no_reply: bool,
+    /// The identity under which the client claims regions, assigned by its first CLAIM
+    ///
+    /// Its claim is released once the preferences are dropped together with the connection.
+    pub claim_owner: Option<ClaimOwner>,
+    /// The requests which draw on the canvas that were queued since MULTI, if a transaction was started
+    pub transaction: Option<Vec<Request>>,
+    /// The id under which the traffic of the connection is accounted, if it is a continuous connection
+    pub client: Option<ClientId>,
+    /// The address of the client of a continuous connection, which is unknown for unix sockets
+    pub remote: Option<IpAddr>,
+}
+
+/// Properties of the listener through which a request was received which are reported to clients via INFO
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub(crate) struct ListenerCapabilities {
+    /// Whether clients can switch to the binary protocol
+    pub binary_protocol: bool,
+    /// Whether clients can upload images, which requires the transport to carry arbitrary binary data
+    pub image_upload: bool,
+    /// Whether the canvas state can be streamed, which requires the server to push data to connections
+    pub state_streaming: bool,
+    /// Whether responses can be compressed, which requires a continuous connection
+    pub compression: bool,
+    /// The maximum length of a request line, if requests are sent as lines instead of separate messages
+    pub max_line_len: Option<usize>,
+    /// The sizes of the buffers of connections, if requests are sent as a continuous stream
+    pub buffers: BufferOptions,
+    /// How connection storms of single IP addresses are mitigated, if they are
+    pub storm_protection: Option<Arc<StormProtectionOptions>>,
+    /// How many connections a single IP address may have open at the same time, if limited
+    pub max_connections_per_ip: Option<usize>,
+    /// How many requests and pixels a single connection may send and draw per second
+    pub rate_limit: Reloadable<RateLimitOptions>,
+    /// After which duration without requests connections are closed, if ever
+    pub idle_timeout: Option<Duration>,
+    /// The tokens with which connections must authenticate before they may draw on the canvas, if protected
+    pub write_protection: Option<Arc<WriteProtectionOptions>>,
+    /// How drawing into regions which other connections claimed is treated, if regions can be claimed
+    pub claims: Option<ClaimMode>,
+    /// The optional features which the operator enabled on this listener, or `None` if all supported ones are
+    pub enabled_features: Option<Features>,
+}
+
+impl ListenerCapabilities {
+    /// How many connections a single IP address may open per second, if limited
+    fn max_connects_per_sec(&self) -> Option<u32> {
+        self.storm_protection
+            .as_ref()
+            .map(|options| options.max_connects_per_sec)
+    }
+
+    /// The optional features which clients can enable via HELLO
+    fn features(&self) -> Features {
+        let supported: Features = [
+            (Features::BINARY, self.binary_protocol),
+            (Features::RGBA, true),
+            (Features::PXB, true),
+            (Features::RECT, true),
+            (Features::LINE, true),
+            (Features::IMG, self.image_upload),
+            (Features::STREAM, self.state_streaming),
+            (Features::COMPRESS, self.compression),
+            (Features::STATS, true),
+            (Features::CLAIM, self.claims.is_some()),
+            (Features::PXGET, true),
+            (Features::MULTI, true),
+            (Features::BLEND, true),
+        ]
+        .into_iter()
+        .filter_map(|(feature, supported)| supported.then_some(feature))
+        .collect();
+        match self.enabled_features {
+            Some(enabled) => supported.intersection(enabled),
+            None => supported,
+        }
+    }
+}
+
 /// Handle a single request
 ///
 /// This is the core request handling method that is run by all servers.
 /// It parses requests, handles them and generates responses.
 /// The actual IO is left to the specific server though.
 #[allow(unused)]
-fn handle_request(line: &[u8], pixmap: &SharedPixmap) -> Result<Option<Response>, String> {
+fn handle_request(
+    line: &[u8],
+    pixmap: &SharedPixmap,
+    capabilities: &ListenerCapabilities,
+) -> Result<Option<Response>, ResponseError> {
     tracing::trace!(
         "Handling single request {:?}",
         match line.is_ascii() {
@@ -41,23 +250,838 @@ fn handle_request(line: &[u8], pixmap: &SharedPixmap) -> Result<Option<Response>
         }
     );
 
-    let parse_result = parse_request_bin(line);
-    match parse_result {
-        Err(e) => Err(e.to_string()),
-        Ok(request) => match request {
-            Request::Help(topic) => Ok(Some(Response::Help(topic))),
-            Request::GetSize => {
-                let (width, height) = pixmap.get_size();
-                Ok(Some(Response::Size { width, height }))
+    let request = parse_request_bin(line)?;
+    let preferences = ConnectionPreferences::default();
+    check_features(&request, capabilities, &preferences)?;
+    handle_parsed_request(request, pixmap, capabilities, &preferences)
+}
+
+/// Handle a single request that has already been parsed
+///
+/// Requests which draw on a write protected canvas are rejected unless the connection is authenticated, and all of
+/// them are rejected while the canvas is read-only.
+/// Requests which draw into a region that another connection claimed are answered with an error and, depending on
+/// the [`ClaimMode`], also rejected.
+fn handle_parsed_request(
+    request: Request,
+    pixmap: &SharedPixmap,
+    capabilities: &ListenerCapabilities,
+    preferences: &ConnectionPreferences,
+) -> Result<Option<Response>, ResponseError> {
+    if request.is_write() && capabilities.write_protection.is_some() && !preferences.authenticated {
+        return Err(ResponseError::Unauthorized(
+            "Drawing on this canvas requires authentication via AUTH <token>".to_string(),
+        ));
+    }
+    if request.is_write() && write_protection::is_read_only() {
+        return Err(ResponseError::Unauthorized(
+            "The canvas is read-only at the moment".to_string(),
+        ));
+    }
+    let claim_check = match capabilities.claims {
+        Some(mode) if request.is_write() => {
+            claims::check_write(&request, preferences.claim_owner.as_ref(), mode)
+        }
+        _ => Ok(()),
+    };
+    if capabilities.claims == Some(ClaimMode::Enforced) {
+        claim_check.clone()?;
+    }
+
+    let result = match request {
+        Request::Help(topic) => Ok(Some(Response::Help(topic))),
+        Request::HelpJson => Ok(Some(Response::HelpJson(texts::help_json(
+            capabilities.features(),
+        )))),
+        Request::GetSize => {
+            let (width, height) = pixmap.get_size();
+            Ok(Some(Response::Size { width, height }))
+        }
+        Request::GetInfo => {
+            let (width, height) = pixmap.get_size();
+            let features = capabilities.features();
+            Ok(Some(Response::Info(ServerInfo {
+                width,
+                height,
+                binary_protocol: features.contains(Features::BINARY),
+                alpha_blending: features.contains(Features::RGBA),
+                pixel_batches: features.contains(Features::PXB),
+                rect_fill: features.contains(Features::RECT),
+                line_drawing: features.contains(Features::LINE),
+                image_upload: features.contains(Features::IMG),
+                state_streaming: features.contains(Features::STREAM),
+                compression: features.contains(Features::COMPRESS),
+                write_protection: capabilities.write_protection.is_some(),
+                statistics: features.contains(Features::STATS),
+                region_claims: features.contains(Features::CLAIM),
+                pixel_blocks: features.contains(Features::PXGET),
+                transactions: features.contains(Features::MULTI),
+                blend_modes: features.contains(Features::BLEND),
+                max_connects_per_sec: capabilities.max_connects_per_sec(),
+            })))
+        }
+        Request::GetStats => Ok(Some(Response::Stats(statistics::current()))),
+        Request::GetLimits => {
+            let rate_limit = capabilities.rate_limit.get();
+            Ok(Some(Response::Limits(ServerLimits {
+                max_line_length: capabilities.max_line_len,
+                max_batch_size: MAX_BATCH_SIZE,
+                max_image_size: MAX_IMAGE_SIZE,
+                max_block_pixels: MAX_BLOCK_PIXELS,
+                max_transaction_len: MAX_TRANSACTION_LEN,
+                max_stream_fps: MAX_STREAM_FPS,
+                max_claim_secs: MAX_CLAIM_SECS,
+                max_connects_per_sec: capabilities.max_connects_per_sec(),
+                max_connections_per_ip: capabilities.max_connections_per_ip,
+                max_commands_per_sec: rate_limit.max_commands_per_sec,
+                max_pixels_per_sec: rate_limit.max_pixels_per_sec,
+                idle_timeout_secs: capabilities.idle_timeout.map(|timeout| timeout.as_secs()),
+            })))
+        }
+        Request::GetPixel { x, y } => {
+            let color = pixmap.get_pixel(x, y).map_err(out_of_bounds)?;
+            Ok(Some(Response::PxData { x, y, color }))
+        }
+        Request::GetPixelBlock(region) => {
+            check_region(&region, pixmap)?;
+            let colors = transactions::read_consistent(|| {
+                (region.y..region.y + region.height)
+                    .flat_map(|y| (region.x..region.x + region.width).map(move |x| (x, y)))
+                    .map(|(x, y)| pixmap.get_pixel(x, y).map_err(out_of_bounds))
+                    .collect::<Result<_, _>>()
+            })?;
+            Ok(Some(Response::PxBlock { region, colors }))
+        }
+        Request::SetPixel { x, y, color } => {
+            pixmap.set_pixel(x, y, color).map_err(out_of_bounds)?;
+            Ok(None)
+        }
+        Request::SetPixelBatch(pixels) => {
+            pixmap.set_pixels(pixels).map_err(out_of_bounds)?;
+            Ok(None)
+        }
+        Request::FillRect {
+            x,
+            y,
+            width,
+            height,
+            color,
+        } => {
+            pixmap
+                .fill_rect(x, y, width, height, color)
+                .map_err(out_of_bounds)?;
+            Ok(None)
+        }
+        Request::DrawLine {
+            x1,
+            y1,
+            x2,
+            y2,
+            color,
+        } => {
+            pixmap
+                .draw_line((x1, y1), (x2, y2), color)
+                .map_err(out_of_bounds)?;
+            Ok(None)
+        }
+        Request::BlendPixel { x, y, color, alpha } => {
+            pixmap.blend_pixel(x, y, color, alpha).map_err(out_of_bounds)?;
+            Ok(None)
+        }
+        Request::ComposePixel { x, y, color, mode } => {
+            pixmap.compose_pixel(x, y, color, mode).map_err(out_of_bounds)?;
+            Ok(None)
+        }
+        Request::PutImage { x, y, data } => match capabilities.image_upload {
+            true => put_image(pixmap, x, y, &data).map(|_| None),
+            false => Err(unsupported("Uploading images")),
+        },
+        Request::StreamState { .. } => Err(unsupported("Streaming the canvas state")),
+        Request::SetCompression(_) => Err(unsupported("Compressing responses")),
+        Request::SetProtocol(_) => Err(unsupported("Switching protocols")),
+        Request::Authenticate(_) => Err(unsupported("Authentication")),
+        Request::Hello { .. } => Err(unsupported("Negotiating features")),
+        Request::SetNoReply(_) => Err(unsupported("Suppressing responses")),
+        Request::Claim { .. } | Request::Release => Err(unsupported("Claiming regions")),
+        Request::Multi | Request::Exec => Err(unsupported("Transactions")),
+        Request::SetNick(name) => {
+            // connections are handled within a span which carries the name from now on
+            tracing::Span::current().record("nick", &name);
+            tracing::info!("Client is now known as {}", name);
+            if let Some(client) = preferences.client {
+                statistics::record(statistics::Event::NickSet(client, name));
             }
-            Request::GetPixel { x, y } => {
-                let color = pixmap.get_pixel(x, y).map_err(|e| format!("{}", e))?;
-                Ok(Some(Response::PxData { x, y, color }))
+            Ok(Some(Response::NickSet))
+        }
+    };
+    result.and_then(|response| claim_check.map(|_| response))
+}
+
+/// The error for a request which is not supported by the server
+fn unsupported(what: &str) -> ResponseError {
+    ResponseError::Unsupported(format!("{} is not supported by this server", what))
+}
+
+/// The error for a request which addresses pixels outside of the canvas
+fn out_of_bounds(e: impl ToString) -> ResponseError {
+    ResponseError::OutOfBounds(e.to_string())
+}
+
+/// Reject regions which do not lie completely within the canvas
+fn check_region(region: &Region, pixmap: &SharedPixmap) -> Result<(), ResponseError> {
+    let (width, height) = pixmap.get_size();
+    match region.fits_into(width, height) {
+        true => Ok(()),
+        false => Err(out_of_bounds(format!(
+            "Region of size {}x{} at {},{} is outside the canvas of size {}x{}",
+            region.width, region.height, region.x, region.y, width, height
+        ))),
+    }
+}
+
+/// Whether the pixels of a request were drawn, which is also the case for advisory claim conflicts
+fn pixels_were_drawn(
+    result: &Result<Option<Response>, ResponseError>,
+    capabilities: &ListenerCapabilities,
+) -> bool {
+    match result {
+        Ok(_) => true,
+        Err(ResponseError::Claimed(_)) => capabilities.claims == Some(ClaimMode::Advisory),
+        Err(_) => false,
+    }
+}
+
+/// Negotiate the protocol version and the optional features of a connection
+///
+/// Only the features which both the client and the listener support are granted.
+fn hello(
+    version: u32,
+    features: Features,
+    capabilities: &ListenerCapabilities,
+    preferences: &mut ConnectionPreferences,
+) -> Result<Option<Response>, ResponseError> {
+    if version == 0 {
+        return Err(ResponseError::Unsupported(
+            "Protocol version 0 does not exist".to_string(),
+        ));
+    }
+    let granted = features.intersection(capabilities.features());
+    preferences.features = Some(granted);
+    Ok(Some(Response::Hello {
+        version: version.min(PROTOCOL_VERSION),
+        features: granted,
+    }))
+}
+
+/// Reject requests which need an optional feature that is disabled on the listener or was not granted via HELLO
+fn check_features(
+    request: &Request,
+    capabilities: &ListenerCapabilities,
+    preferences: &ConnectionPreferences,
+) -> Result<(), ResponseError> {
+    if let (Some(feature), Some(enabled)) = (request.required_feature(), capabilities.enabled_features) {
+        if !enabled.contains(feature) {
+            return Err(ResponseError::Unsupported(format!(
+                "The {} feature is disabled on this listener",
+                feature.names().next().unwrap_or_default()
+            )));
+        }
+    }
+    match (request.required_feature(), preferences.features) {
+        (Some(feature), Some(granted)) if !granted.contains(feature) => {
+            Err(ResponseError::Unsupported(format!(
+                "The {} feature was not enabled via HELLO",
+                feature.names().next().unwrap_or_default()
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Start, change or stop streaming the canvas state to a connection
+///
+/// Streamed frames only contain the pixels which changed since the previous frame if requested, so that repeated
+/// writes to the same pixel are coalesced and clients receive at most `fps` frames per second, no matter how many
+/// pixels are drawn.
+fn stream_state(
+    algorithm: StateAlgorithm,
+    fps: u32,
+    region: Option<Region>,
+    pixmap: &SharedPixmap,
+    preferences: &mut ConnectionPreferences,
+) -> Result<Option<Response>, ResponseError> {
+    if let Some(region) = region {
+        check_region(&region, pixmap)?;
+    }
+    preferences.stream = (fps > 0).then_some(StreamSettings {
+        algorithm,
+        fps,
+        region,
+    });
+    Ok(None)
+}
+
+/// Claim a region of the canvas for a connection, replacing its previous claim
+fn claim(
+    region: Region,
+    secs: u32,
+    pixmap: &SharedPixmap,
+    capabilities: &ListenerCapabilities,
+    preferences: &mut ConnectionPreferences,
+) -> Result<Option<Response>, ResponseError> {
+    if capabilities.claims.is_none() {
+        return Err(unsupported("Claiming regions"));
+    }
+    // claims of self-contained messages could neither be renewed nor released
+    if preferences.client.is_none() {
+        return Err(ResponseError::Unsupported(
+            "Regions can only be claimed over continuous connections like TCP or WebSocket".to_string(),
+        ));
+    }
+    check_region(&region, pixmap)?;
+    let (width, height) = pixmap.get_size();
+    let remote = preferences.remote;
+    let owner = preferences
+        .claim_owner
+        .get_or_insert_with(|| ClaimOwner::unique(remote));
+    claims::claim(owner, region, Duration::from_secs(secs.into()), width * height)?;
+    Ok(Some(Response::Claimed))
+}
+
+/// Release the claim of a connection, if it holds one
+fn release(
+    capabilities: &ListenerCapabilities,
+    preferences: &ConnectionPreferences,
+) -> Result<Option<Response>, ResponseError> {
+    if capabilities.claims.is_none() {
+        return Err(unsupported("Claiming regions"));
+    }
+    if let Some(owner) = &preferences.claim_owner {
+        claims::release(owner);
+    }
+    Ok(Some(Response::Released))
+}
+
+/// Start queueing the requests of a connection which draw on the canvas until they are applied via EXEC
+fn multi(preferences: &mut ConnectionPreferences) -> Result<Option<Response>, ResponseError> {
+    if preferences.transaction.is_some() {
+        return Err(ResponseError::Unsupported(
+            "A transaction was already started via MULTI".to_string(),
+        ));
+    }
+    preferences.transaction = Some(Vec::new());
+    Ok(Some(Response::TransactionStarted))
+}
+
+/// Queue a request which draws on the canvas until the transaction of a connection is applied
+///
+/// Transactions which grow too large are discarded so that the queued requests are never applied partially.
+/// Images are rejected since decoding them while the transaction is applied would block all readers of the canvas.
+fn queue(
+    request: Request,
+    preferences: &mut ConnectionPreferences,
+) -> Result<Option<Response>, ResponseError> {
+    if matches!(request, Request::PutImage { .. }) {
+        return Err(ResponseError::Unsupported(
+            "Images cannot be uploaded inside a transaction".to_string(),
+        ));
+    }
+    let transaction = preferences.transaction.get_or_insert_with(Vec::new);
+    if transaction.len() >= MAX_TRANSACTION_LEN {
+        preferences.transaction = None;
+        return Err(ResponseError::RateLimited(format!(
+            "Transactions can contain at most {} requests, all queued requests were discarded",
+            MAX_TRANSACTION_LEN
+        )));
+    }
+    let pixels = transaction.iter().chain([&request]).fold(0u64, |sum, request| {
+        sum.saturating_add(statistics::pixels_drawn(request))
+    });
+    if pixels > MAX_TRANSACTION_PIXELS {
+        preferences.transaction = None;
+        return Err(ResponseError::RateLimited(format!(
+            "Transactions can draw at most {} pixels, all queued requests were discarded",
+            MAX_TRANSACTION_PIXELS
+        )));
+    }
+    transaction.push(request);
+    Ok(None)
+}
+
+/// Apply all requests which a connection queued since MULTI at once
+///
+/// Requests which fail are skipped and the error of the first one is returned instead of the confirmation.
+fn exec(
+    pixmap: &SharedPixmap,
+    capabilities: &ListenerCapabilities,
+    preferences: &mut ConnectionPreferences,
+) -> Result<Option<Response>, ResponseError> {
+    let Some(requests) = preferences.transaction.take() else {
+        return Err(ResponseError::Unsupported(
+            "There is no transaction to execute, start one via MULTI".to_string(),
+        ));
+    };
+    let count = requests.len();
+    let mut pixels_set = 0;
+    let mut first_error = None;
+    transactions::commit(|| {
+        for request in requests {
+            let pixels = statistics::pixels_drawn(&request);
+            let result = handle_parsed_request(request, pixmap, capabilities, preferences);
+            if pixels_were_drawn(&result, capabilities) {
+                pixels_set += pixels;
             }
-            Request::SetPixel { x, y, color } => {
-                pixmap.set_pixel(x, y, color).map_err(|e| format!("{}", e))?;
-                Ok(None)
+            if let Err(e) = result {
+                first_error.get_or_insert(e);
             }
-        },
+        }
+    });
+    if pixels_set > 0 {
+        statistics::record(statistics::Event::PixelsSet(preferences.client, pixels_set));
+    }
+    match first_error {
+        Some(e) => Err(e),
+        None => Ok(Some(Response::Executed(count))),
+    }
+}
+
+/// Authenticate a connection with the token of an AUTH request
+///
+/// Listeners without write protection accept every token since all connections may draw anyway.
+fn authenticate(
+    token: &str,
+    capabilities: &ListenerCapabilities,
+    preferences: &mut ConnectionPreferences,
+) -> Result<Option<Response>, ResponseError> {
+    match &capabilities.write_protection {
+        Some(write_protection) if !write_protection.accepts(token) => Err(ResponseError::Unauthorized(
+            "Invalid authentication token".to_string(),
+        )),
+        _ => {
+            preferences.authenticated = true;
+            Ok(Some(Response::Authenticated))
+        }
+    }
+}
+
+/// Decode an uploaded image and draw it onto the pixmap with its top-left corner at (x,y)
+///
+/// Transparent parts of the image are blended onto the existing canvas.
+#[cfg(feature = "images")]
+fn put_image(pixmap: &SharedPixmap, x: usize, y: usize, data: &[u8]) -> Result<(), ResponseError> {
+    use crate::pixmap::Color;
+
+    let (width, height) = pixmap.get_size();
+    if x >= width || y >= height {
+        return Err(out_of_bounds(format!(
+            "Image position {},{} is outside the canvas of size {}x{}",
+            x, y, width, height
+        )));
+    }
+
+    // refuse to decode images that would not fit onto the canvas anyway
+    let mut limits = image::Limits::default();
+    limits.max_image_width = Some(u32::try_from(width - x).unwrap_or(u32::MAX));
+    limits.max_image_height = Some(u32::try_from(height - y).unwrap_or(u32::MAX));
+    let mut reader = image::ImageReader::new(std::io::Cursor::new(data))
+        .with_guessed_format()
+        .map_err(|e| ResponseError::ParseError(format!("Could not read image: {}", e)))?;
+    reader.limits(limits);
+    let image = reader
+        .decode()
+        .map_err(|e| ResponseError::ParseError(format!("Could not decode image: {}", e)))?
+        .into_rgba8();
+
+    for (image_x, image_y, pixel) in image.enumerate_pixels() {
+        let [r, g, b, alpha] = pixel.0;
+        let (x, y) = (x + image_x as usize, y + image_y as usize);
+        match alpha {
+            0 => Ok(()),
+            u8::MAX => pixmap.set_pixel(x, y, Color::from((r, g, b))),
+            alpha => pixmap.blend_pixel(x, y, Color::from((r, g, b)), alpha),
+        }
+        .map_err(out_of_bounds)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "images"))]
+fn put_image(_pixmap: &SharedPixmap, _x: usize, _y: usize, _data: &[u8]) -> Result<(), ResponseError> {
+    Err(ResponseError::Unsupported(
+        "This server was built without support for decoding images".to_string(),
+    ))
+}
+
+/// Echo the tag of a request in front of its text response
+///
+/// Only the first line of responses which span multiple lines, like HELP, is tagged.
+fn write_tag(tag: Option<u32>, resp_buf: &mut Writer<BytesMut>) {
+    if let Some(tag) = tag {
+        resp_buf.write_fmt(format_args!("#{} ", tag)).unwrap();
+    }
+}
+
+/// Complete once a connection did not send anything for the idle timeout of its listener
+///
+/// Connections which stream the canvas state are never idle since they are not expected to send requests.
+async fn idle(timeout: Option<Duration>, streaming: bool) {
+    match timeout {
+        Some(timeout) if !streaming => tokio::time::sleep(timeout).await,
+        _ => std::future::pending().await,
+    }
+}
+
+/// Poll `future` once and return its output if it completes right away
+///
+/// Stream based servers use this to handle requests which a client already sent before they send the responses
+/// to the previous ones.
+fn now_or_never<F: Future>(future: F) -> Option<F::Output> {
+    let future = std::pin::pin!(future);
+    match future.poll(&mut Context::from_waker(Waker::noop())) {
+        Poll::Ready(output) => Some(output),
+        Poll::Pending => None,
+    }
+}
+
+/// Write the notice which tells a client that its connection is closed because the server shuts down
+fn write_shutdown_notice(protocol: ProtocolVariant, resp_buf: &mut Writer<BytesMut>) {
+    let error = ResponseError::ShuttingDown("The server is shutting down".to_string());
+    match protocol {
+        ProtocolVariant::Text => resp_buf
+            .write_fmt(format_args!("{}\n", texts::error_text(&error)))
+            .unwrap(),
+        ProtocolVariant::Binary => write_error_binary(&texts::error_text(&error), resp_buf).unwrap(),
+    }
+}
+
+/// Discard the buffered data of a connection if it exceeds the maximum line length and tell the client about it
+fn discard_overlong_frame(
+    req_buf: &mut FrameBuffer,
+    resp_buf: &mut Writer<BytesMut>,
+    protocol: ProtocolVariant,
+) {
+    let len = req_buf.len();
+    if !req_buf.discard_overlong_frame(protocol) {
+        return;
+    }
+    tracing::warn!(
+        "Request buffer has {}B but no lines left in it. Client is probably misbehaving.",
+        len
+    );
+    let error = ResponseError::from(ParseErr::LineTooLong);
+    match protocol {
+        ProtocolVariant::Text => resp_buf
+            .write_fmt(format_args!("{}\n", texts::error_text(&error)))
+            .unwrap(),
+        ProtocolVariant::Binary => write_error_binary(&texts::error_text(&error), resp_buf).unwrap(),
+    }
+}
+
+/// Handle all requests of a self-contained message like a UDP datagram and return the responses to them
+///
+/// Since messages are independent of each other, negotiated preferences only apply to the current one and its last
+/// request does not need to be terminated.
+#[cfg(any(feature = "udp", feature = "mqtt"))]
+fn handle_message(buf: BytesMut, pixmap: &SharedPixmap, capabilities: &ListenerCapabilities) -> BytesMut {
+    handle_session_message(buf, pixmap, capabilities, &mut ConnectionPreferences::default())
+}
+
+/// Handle all requests of a self-contained message which belongs to a longer lived session like a DTLS session and
+/// return the responses to them
+///
+/// Unlike with [`handle_message`], preferences which are negotiated in one message apply to the following ones.
+#[cfg(any(feature = "udp", feature = "mqtt"))]
+fn handle_session_message(
+    buf: BytesMut,
+    pixmap: &SharedPixmap,
+    capabilities: &ListenerCapabilities,
+    preferences: &mut ConnectionPreferences,
+) -> BytesMut {
+    use bytes::BufMut;
+
+    let mut req_buf = FrameBuffer::message(buf);
+    let mut resp_buf = BytesMut::with_capacity(2 * 1024).writer();
+    handle_frames(&mut req_buf, &mut resp_buf, pixmap, preferences, capabilities);
+    resp_buf.into_inner()
+}
+
+/// Handle all complete frames that are contained in `req_buf` and write their responses into `resp_buf`
+///
+/// This is used by all servers which transport a continuous stream of requests and allows clients to negotiate
+/// connection specific settings.
+/// Handling stops early after the compression was changed so that the caller can send all responses up to the
+/// confirmation with the previous compression.
+fn handle_frames(
+    req_buf: &mut FrameBuffer,
+    resp_buf: &mut Writer<BytesMut>,
+    pixmap: &SharedPixmap,
+    preferences: &mut ConnectionPreferences,
+    capabilities: &ListenerCapabilities,
+) -> Usage {
+    let mut pixels_set = 0;
+    let mut usage = Usage::default();
+    loop {
+        // responses are always encoded with the protocol that was used for the request
+        let protocol = preferences.protocol;
+        let compression = preferences.compression;
+        let (tag, request) = match req_buf.next_frame(protocol) {
+            Ok(None) => break,
+            Ok(Some(Frame::Text(tag, request))) => (tag, request.map_err(ResponseError::from)),
+            Ok(Some(Frame::Decoded(tag, request))) => {
+                tracing::trace!("Handling single decoded request {:?}", request);
+                (tag, Ok(request))
+            }
+            Err(e) => {
+                tracing::warn!(
+                    "Could not decode binary request frame, discarding buffered data: {}",
+                    e
+                );
+                req_buf.clear();
+                (None, Err(ResponseError::from(e)))
+            }
+        };
+
+        usage += Usage::of(&request);
+
+        // queued requests are counted once they are applied via EXEC
+        let pixels = match preferences.transaction {
+            Some(_) => 0,
+            None => request.as_ref().map_or(0, statistics::pixels_drawn),
+        };
+        let quiet = preferences.no_reply && request.as_ref().is_ok_and(Request::is_write);
+        let result = request.and_then(|request| {
+            check_features(&request, capabilities, preferences)?;
+            match request {
+                Request::SetProtocol(variant) => {
+                    preferences.protocol = variant;
+                    Ok(Some(Response::Protocol(variant)))
+                }
+                Request::StreamState {
+                    algorithm,
+                    fps,
+                    region,
+                } if capabilities.state_streaming => {
+                    stream_state(algorithm, fps, region, pixmap, preferences)
+                }
+                Request::SetCompression(compression) if capabilities.compression => {
+                    preferences.compression = compression;
+                    Ok(Some(Response::Compression(compression)))
+                }
+                Request::Authenticate(token) => authenticate(&token, capabilities, preferences),
+                Request::Hello { version, features } => hello(version, features, capabilities, preferences),
+                Request::SetNoReply(enabled) => {
+                    preferences.no_reply = enabled;
+                    Ok(Some(Response::NoReply(enabled)))
+                }
+                Request::Claim { region, secs } => claim(region, secs, pixmap, capabilities, preferences),
+                Request::Release => release(capabilities, preferences),
+                Request::Multi => multi(preferences),
+                Request::Exec => exec(pixmap, capabilities, preferences),
+                request if request.is_write() && preferences.transaction.is_some() => {
+                    queue(request, preferences)
+                }
+                request => handle_parsed_request(request, pixmap, capabilities, preferences),
+            }
+        });
+        if pixels_were_drawn(&result, capabilities) {
+            pixels_set += pixels;
+        }
+
+        match (protocol, result) {
+            (_, Ok(None)) => {}
+            (_, _) if quiet => {}
+            (ProtocolVariant::Text, Ok(Some(response))) => {
+                write_tag(tag, resp_buf);
+                response.write(resp_buf).unwrap()
+            }
+            (ProtocolVariant::Text, Err(e)) => {
+                write_tag(tag, resp_buf);
+                resp_buf
+                    .write_fmt(format_args!("{}\n", texts::error_text(&e)))
+                    .unwrap()
+            }
+            (ProtocolVariant::Binary, Ok(Some(response))) => {
+                write_response_binary(&response, resp_buf).unwrap()
+            }
+            (ProtocolVariant::Binary, Err(e)) => {
+                write_error_binary(&texts::error_text(&e), resp_buf).unwrap()
+            }
+        }
+
+        if preferences.compression != compression {
+            break;
+        }
+    }
+
+    // statistics are only fed once per buffer to keep the overhead per request low
+    if pixels_set > 0 {
+        statistics::record(statistics::Event::PixelsSet(preferences.client, pixels_set));
+    }
+    usage
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pixmap::Pixmap;
+    use bytes::BufMut;
+
+    #[cfg(feature = "tcp")]
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_timeout() {
+        use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+        use tokio::time::{sleep, Instant};
+
+        let capabilities = ListenerCapabilities {
+            idle_timeout: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+        let (client, server) = tokio::io::duplex(1024);
+        let connection = tokio::spawn(TcpServer::handle_connection(
+            server,
+            "10.0.0.1:1234".parse().unwrap(),
+            Arc::new(Pixmap::new(4, 2).unwrap()),
+            capabilities,
+        ));
+        let mut client = BufReader::new(client);
+
+        // every request restarts the timeout
+        let start = Instant::now();
+        for _ in 0..3 {
+            sleep(Duration::from_secs(8)).await;
+            client.write_all(b"SIZE\n").await.unwrap();
+            let mut line = String::new();
+            client.read_line(&mut line).await.unwrap();
+            assert_eq!(line, "SIZE 4 2\n");
+        }
+        assert!(!connection.is_finished());
+
+        // the connection is closed once it was idle for the whole timeout
+        let active = Instant::now();
+        assert_eq!(client.read(&mut [0; 16]).await.unwrap(), 0);
+        assert_eq!(active - start, Duration::from_secs(24));
+        assert_eq!(active.elapsed(), Duration::from_secs(10));
+        connection.await.unwrap().unwrap();
+    }
+
+    #[cfg(feature = "tcp")]
+    #[tokio::test]
+    async fn test_pipelined_requests() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let pixmap = Arc::new(Pixmap::new(4, 2).unwrap());
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let connection = tokio::spawn(TcpServer::handle_connection(
+            server,
+            "10.0.0.1:1234".parse().unwrap(),
+            pixmap,
+            ListenerCapabilities::default(),
+        ));
+
+        client
+            .write_all(b"#1 SIZE\nPX 1 0 FF0000\nPX 1 0\n#2 PX 9 9\n#3 PX 1 0\nSIZE\n")
+            .await
+            .unwrap();
+        client.shutdown().await.unwrap();
+        let mut responses = String::new();
+        client.read_to_string(&mut responses).await.unwrap();
+        // the responses keep the order of the requests and drawing a pixel is not answered
+        assert_eq!(
+            responses,
+            concat!(
+                "#1 SIZE 4 2\n",
+                "PX 1 0 FF0000\n",
+                "#2 ERR OUT_OF_BOUNDS Could not access invalid coordinates 9x9 on pixmap of size 4x2\n",
+                "#3 PX 1 0 FF0000\n",
+                "SIZE 4 2\n",
+            )
+        );
+        connection.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_disabled_features() {
+        let pixmap = Arc::new(Pixmap::new(4, 2).unwrap());
+        let capabilities = ListenerCapabilities {
+            enabled_features: Some([Features::RECT, Features::STATS].into_iter().collect()),
+            ..Default::default()
+        };
+        let mut preferences = ConnectionPreferences::default();
+        let mut handle = |requests: &[u8]| {
+            let mut req_buf = FrameBuffer::from(BytesMut::from(requests));
+            let mut resp_buf = BytesMut::new().writer();
+            handle_frames(
+                &mut req_buf,
+                &mut resp_buf,
+                &pixmap,
+                &mut preferences,
+                &capabilities,
+            );
+            String::from_utf8(resp_buf.into_inner().to_vec()).unwrap()
+        };
+
+        // disabled features are neither granted nor usable
+        assert_eq!(handle(b"HELLO 2 RECT LINE STATS\n"), "HELLO 2 RECT STATS\n");
+        assert_eq!(
+            handle(b"LINE 0 0 1 1 FF0000\n"),
+            "ERR UNSUPPORTED The LINE feature is disabled on this listener\n"
+        );
+    }
+
+    #[test]
+    fn test_claims_need_a_connection() {
+        let pixmap = Arc::new(Pixmap::new(4, 2).unwrap());
+        let capabilities = ListenerCapabilities {
+            claims: Some(ClaimMode::Enforced),
+            ..Default::default()
+        };
+        let mut req_buf = FrameBuffer::from(BytesMut::from(&b"CLAIM 0 0 1 1 60\n"[..]));
+        let mut resp_buf = BytesMut::new().writer();
+        let mut preferences = ConnectionPreferences::default();
+        handle_frames(
+            &mut req_buf,
+            &mut resp_buf,
+            &pixmap,
+            &mut preferences,
+            &capabilities,
+        );
+        assert_eq!(
+            resp_buf.into_inner(),
+            "ERR UNSUPPORTED Regions can only be claimed over continuous connections like TCP or WebSocket\n"
+        );
+    }
+
+    #[test]
+    fn test_transaction_limits() {
+        let pixmap = Arc::new(Pixmap::new(2048, 2048).unwrap());
+        let capabilities = ListenerCapabilities {
+            image_upload: true,
+            ..Default::default()
+        };
+        let mut preferences = ConnectionPreferences::default();
+        let mut handle = |request: &[u8]| {
+            let mut req_buf = FrameBuffer::from(BytesMut::from(request));
+            let mut resp_buf = BytesMut::new().writer();
+            handle_frames(
+                &mut req_buf,
+                &mut resp_buf,
+                &pixmap,
+                &mut preferences,
+                &capabilities,
+            );
+            String::from_utf8(resp_buf.into_inner().to_vec()).unwrap()
+        };
+
+        assert_eq!(handle(b"MULTI\n"), "MULTI OK\n");
+        assert_eq!(
+            handle(b"IMG 0 0 4\nabcd"),
+            "ERR UNSUPPORTED Images cannot be uploaded inside a transaction\n"
+        );
+        assert_eq!(handle(b"RECT 0 0 1024 1000 FF0000\n"), "");
+        assert_eq!(
+            handle(b"RECT 0 1000 1024 25 FF0000\n"),
+            "ERR RATE_LIMITED Transactions can draw at most 1048576 pixels, all queued requests were discarded\n"
+        );
+        assert!(handle(b"EXEC\n").starts_with("ERR UNSUPPORTED"));
     }
 }
@@ -0,0 +1,272 @@
+use crate::net::protocol::{Features, StateAlgorithm};
+use crate::net::servers::gen_server::GenServer;
+use crate::net::servers::state_stream::{StateStream, StreamSettings};
+use crate::net::servers::statistics;
+use crate::net::servers::{
+    BufferOptions, ClaimMode, ListenerCapabilities, RateLimitOptions, WriteProtectionOptions, MAX_LINE_LEN,
+};
+use crate::pixmap::SharedPixmap;
+use crate::DaemonResult;
+use async_trait::async_trait;
+use bytes::BytesMut;
+use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Packet, QoS};
+use std::future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::{AbortHandle, JoinSet};
+use tokio::time::MissedTickBehavior;
+
+/// Options with which the `MqttServer` is configured
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MqttServerOptions {
+    /// The host name of the MQTT broker to which the server connects
+    pub broker_host: String,
+    /// The port of the MQTT broker
+    pub broker_port: u16,
+    /// The username and password with which the server logs into the broker, if it requires them
+    pub credentials: Option<(String, String)>,
+    /// The prefix of all topics which the server uses, e.g. `pixelflut`
+    pub topic_prefix: String,
+    /// How often the pixels which changed are published per second, or 0 to publish no updates
+    pub updates_fps: u32,
+    /// How often the whole canvas is published, or `None` to never publish it
+    pub state_interval: Option<Duration>,
+    /// Whether messages must authenticate before they may draw on the canvas
+    ///
+    /// Since messages are independent of each other, every message which draws on the canvas must start with
+    /// its own AUTH request.
+    pub write_protection: Option<WriteProtectionOptions>,
+    /// How drawing into regions which other clients claimed is treated, if regions can be claimed via CLAIM
+    pub claims: Option<ClaimMode>,
+    /// The optional protocol features which clients may use, or `None` to enable all which the server supports
+    pub features: Option<Features>,
+}
+
+/// The largest message which the server accepts from the broker
+const MAX_INCOMING_MESSAGE: usize = 64 * 1024;
+
+/// The largest message which the server publishes, which is the limit of the MQTT protocol
+const MAX_OUTGOING_MESSAGE: usize = 256 * 1024 * 1024;
+
+/// How long the server waits before it reconnects to a broker after the connection was lost
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// A bridge which connects to an MQTT broker to receive pixelflut messages and publish canvas updates.
+///
+/// Topics are named after the configured prefix:
+///
+/// - `<prefix>/requests` is subscribed to. Every message may contain any number of newline separated commands, just
+///   like a UDP datagram.
+/// - `<prefix>/responses` receives the responses to the commands, e.g. the colors requested via `PX <x> <y>`.
+/// - `<prefix>/updates` receives one `PX <x> <y> <rrggbb>` line for every pixel which changed since the previous
+///   message, at most `updates_fps` times per second.
+/// - `<prefix>/state` receives the whole canvas as a `STATE rgb64` line every `state_interval`, which is retained by
+///   the broker so that new subscribers receive it right away.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MqttServer {
+    options: MqttServerOptions,
+}
+
+/// The topics which a server uses
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct Topics {
+    requests: String,
+    responses: String,
+    updates: String,
+    state: String,
+}
+
+impl Topics {
+    fn new(prefix: &str) -> Self {
+        let prefix = prefix.trim_end_matches('/');
+        Self {
+            requests: format!("{}/requests", prefix),
+            responses: format!("{}/responses", prefix),
+            updates: format!("{}/updates", prefix),
+            state: format!("{}/state", prefix),
+        }
+    }
+}
+
+impl MqttServer {
+    fn capabilities(options: &MqttServerOptions) -> ListenerCapabilities {
+        ListenerCapabilities {
+            binary_protocol: true,
+            image_upload: false,
+            state_streaming: false,
+            compression: false,
+            max_line_len: Some(MAX_LINE_LEN),
+            buffers: BufferOptions::default(),
+            storm_protection: None,
+            max_connections_per_ip: None,
+            rate_limit: RateLimitOptions::default().into(),
+            idle_timeout: None,
+            write_protection: options.write_protection.clone().map(Arc::new),
+            claims: options.claims,
+            enabled_features: options.features,
+        }
+    }
+
+    #[tracing::instrument(skip_all, fields(broker = options.broker_host))]
+    async fn run(pixmap: SharedPixmap, options: MqttServerOptions) -> anyhow::Result<!> {
+        let mut mqtt_options = MqttOptions::new(
+            format!("pixeldike-{}", std::process::id()),
+            &options.broker_host,
+            options.broker_port,
+        );
+        mqtt_options.set_max_packet_size(MAX_INCOMING_MESSAGE, MAX_OUTGOING_MESSAGE);
+        if let Some((username, password)) = &options.credentials {
+            mqtt_options.set_credentials(username, password);
+        }
+        let (client, eventloop) = AsyncClient::new(mqtt_options, 64);
+        let topics = Topics::new(&options.topic_prefix);
+        let capabilities = Self::capabilities(&options);
+        let updates = (options.updates_fps > 0).then_some(StreamSettings {
+            algorithm: StateAlgorithm::Px,
+            fps: options.updates_fps,
+            region: None,
+        });
+
+        tokio::select! {
+            result = Self::receive(eventloop, &client, &topics, &pixmap, &capabilities) => result,
+            result = Self::publish(&client, &topics, &pixmap, updates, options.state_interval) => result,
+        }
+    }
+
+    /// Drive the connection to the broker and handle all messages which are received on the requests topic
+    async fn receive(
+        mut eventloop: EventLoop,
+        client: &AsyncClient,
+        topics: &Topics,
+        pixmap: &SharedPixmap,
+        capabilities: &ListenerCapabilities,
+    ) -> anyhow::Result<!> {
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    tracing::info!("Connected to MQTT broker; subscribing to {}", topics.requests);
+                    // subscriptions don't survive reconnects since the server always starts a clean session
+                    client.try_subscribe(&topics.requests, QoS::AtMostOnce)?;
+                }
+                Ok(Event::Incoming(Packet::Publish(message))) if message.topic == topics.requests => {
+                    tracing::trace!(
+                        "Received {}KiB MQTT message: {:?}",
+                        message.payload.len() / 1024,
+                        message.payload
+                    );
+                    // the payload is only copied if the event loop still references its buffer
+                    let responses =
+                        super::handle_message(BytesMut::from(message.payload), pixmap, capabilities);
+                    if !responses.is_empty() {
+                        // publishing must not wait for the event loop which is only polled by this task
+                        if let Err(e) =
+                            client.try_publish(&topics.responses, QoS::AtMostOnce, false, responses)
+                        {
+                            tracing::warn!("Could not publish responses: {}", e);
+                        }
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    tracing::warn!(
+                        "Lost connection to MQTT broker, reconnecting in {:?}: {}",
+                        RECONNECT_DELAY,
+                        e
+                    );
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                }
+            }
+        }
+    }
+
+    /// Publish the pixels which changed and snapshots of the whole canvas
+    async fn publish(
+        client: &AsyncClient,
+        topics: &Topics,
+        pixmap: &SharedPixmap,
+        updates: Option<StreamSettings>,
+        state_interval: Option<Duration>,
+    ) -> anyhow::Result<!> {
+        let mut updates_stream = StateStream::default();
+        let mut snapshots = state_interval.map(|state_interval| {
+            let mut interval = tokio::time::interval(state_interval);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+            interval
+        });
+        let snapshot_settings = StreamSettings {
+            algorithm: StateAlgorithm::Rgb64,
+            fps: 0,
+            region: None,
+        };
+
+        loop {
+            let mut message = Vec::new();
+            let topic = tokio::select! {
+                settings = updates_stream.tick(updates) => {
+                    updates_stream.write_frame(pixmap, settings, &mut message)?;
+                    &topics.updates
+                }
+                _ = async {
+                    match &mut snapshots {
+                        Some(snapshots) => snapshots.tick().await,
+                        None => future::pending().await,
+                    }
+                } => {
+                    StateStream::default().write_frame(pixmap, snapshot_settings, &mut message)?;
+                    &topics.state
+                }
+            };
+            if !message.is_empty() {
+                let retain = *topic == topics.state;
+                client.publish(topic, QoS::AtMostOnce, retain, message).await?;
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl GenServer for MqttServer {
+    type Options = MqttServerOptions;
+
+    fn new(options: Self::Options) -> Self {
+        Self { options }
+    }
+
+    async fn start(
+        self,
+        pixmap: SharedPixmap,
+        join_set: &mut JoinSet<DaemonResult>,
+    ) -> anyhow::Result<AbortHandle> {
+        statistics::start();
+        tracing::info!(
+            "Started MQTT bridge to {}:{} with topic prefix {}",
+            self.options.broker_host,
+            self.options.broker_port,
+            self.options.topic_prefix
+        );
+
+        let handle = join_set
+            .build_task()
+            .name("mqtt_server")
+            .spawn(async move { MqttServer::run(pixmap, self.options).await })?;
+        Ok(handle)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_topics() {
+        assert_eq!(
+            Topics::new("venue/wall/"),
+            Topics {
+                requests: "venue/wall/requests".to_string(),
+                responses: "venue/wall/responses".to_string(),
+                updates: "venue/wall/updates".to_string(),
+                state: "venue/wall/state".to_string(),
+            }
+        );
+    }
+}
@@ -0,0 +1,172 @@
+//! Parsing of the PROXY protocol header with which proxies like HAProxy or nginx forward the address of a client
+//!
+//! Both the human-readable version 1 and the binary version 2 of the header are supported, as described in
+//! <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>.
+
+use anyhow::{anyhow, bail};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// The signature with which every version 2 header starts
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The maximum length of a version 1 header including the terminating CRLF
+const V1_MAX_LEN: usize = 107;
+
+/// How long a proxy may take to send the header after it connected
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Read the PROXY protocol header from the start of a connection and return the address of the client
+///
+/// `None` is returned if the proxy connected on its own behalf, e.g. for health checks, or if it does not know
+/// the address of the client.
+/// Nothing after the header is consumed so that the connection can be handled as usual afterwards.
+pub(crate) async fn read_header(stream: &mut (impl AsyncRead + Unpin)) -> anyhow::Result<Option<SocketAddr>> {
+    tokio::time::timeout(HEADER_TIMEOUT, read_header_unbounded(stream))
+        .await
+        .map_err(|_| {
+            anyhow!(
+                "Proxy did not send a PROXY protocol header within {:?}",
+                HEADER_TIMEOUT
+            )
+        })?
+}
+
+async fn read_header_unbounded(stream: &mut (impl AsyncRead + Unpin)) -> anyhow::Result<Option<SocketAddr>> {
+    // both versions are longer than the signature of version 2
+    let mut start = [0u8; V2_SIGNATURE.len()];
+    stream.read_exact(&mut start).await?;
+    if start == V2_SIGNATURE {
+        let mut header = [0u8; 4];
+        stream.read_exact(&mut header).await?;
+        let mut addresses = vec![0u8; u16::from_be_bytes([header[2], header[3]]) as usize];
+        stream.read_exact(&mut addresses).await?;
+        parse_v2(header[0], header[1], &addresses)
+    } else if start.starts_with(b"PROXY ") {
+        // read byte by byte since the header has no length prefix and no data after it may be consumed
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LEN {
+                bail!("PROXY protocol header is longer than {} bytes", V1_MAX_LEN);
+            }
+            line.push(stream.read_u8().await?);
+        }
+        let line = std::str::from_utf8(&line)?;
+        parse_v1(line.trim_end())
+    } else {
+        bail!("Connection did not start with a PROXY protocol header")
+    }
+}
+
+/// Parse a version 1 header like `PROXY TCP4 192.168.0.1 192.168.0.11 56324 443`
+fn parse_v1(line: &str) -> anyhow::Result<Option<SocketAddr>> {
+    let fields = line.split(' ').collect::<Vec<_>>();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", protocol @ ("TCP4" | "TCP6"), src_addr, _dst_addr, src_port, _dst_port] => {
+            let ip = src_addr.parse::<IpAddr>()?;
+            if ip.is_ipv4() != (*protocol == "TCP4") {
+                bail!("PROXY protocol header specifies {} with address {}", protocol, ip);
+            }
+            Ok(Some(SocketAddr::new(ip, src_port.parse()?)))
+        }
+        _ => bail!("Invalid PROXY protocol header {:?}", line),
+    }
+}
+
+/// Parse the version, command and address family bytes of a version 2 header followed by its address block
+fn parse_v2(version_command: u8, family: u8, addresses: &[u8]) -> anyhow::Result<Option<SocketAddr>> {
+    if version_command >> 4 != 2 {
+        bail!("Unsupported PROXY protocol version {}", version_command >> 4);
+    }
+    match version_command & 0x0F {
+        // LOCAL
+        0x0 => return Ok(None),
+        // PROXY
+        0x1 => {}
+        command => bail!("Unsupported PROXY protocol command {}", command),
+    }
+    let (ip, port_offset) = match family >> 4 {
+        0x1 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&addresses[..4]).unwrap());
+            (IpAddr::V4(ip), 8)
+        }
+        0x2 if addresses.len() >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&addresses[..16]).unwrap());
+            (IpAddr::V6(ip), 32)
+        }
+        0x1 | 0x2 => bail!("PROXY protocol header contains a truncated address block"),
+        // unix sockets and unspecified families carry no address which could be used
+        _ => return Ok(None),
+    };
+    let port = u16::from_be_bytes([addresses[port_offset], addresses[port_offset + 1]]);
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn read(data: &[u8]) -> (anyhow::Result<Option<SocketAddr>>, &[u8]) {
+        let mut rest = data;
+        let result = read_header(&mut rest).await;
+        (result, rest)
+    }
+
+    #[tokio::test]
+    async fn test_v1() {
+        let (result, rest) = read(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 1234\r\nSIZE\n").await;
+        assert_eq!(result.unwrap(), Some("192.168.0.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"SIZE\n");
+
+        let (result, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 1234\r\n").await;
+        assert_eq!(result.unwrap(), Some("[2001:db8::1]:4000".parse().unwrap()));
+
+        let (result, rest) = read(b"PROXY UNKNOWN\r\nSIZE\n").await;
+        assert_eq!(result.unwrap(), None);
+        assert_eq!(rest, b"SIZE\n");
+
+        assert!(read(b"PROXY TCP4 2001:db8::1 2001:db8::2 4000 1234\r\n")
+            .await
+            .0
+            .is_err());
+        assert!(read(b"PROXY TCP4 192.168.0.1\r\n").await.0.is_err());
+        assert!(read(&[b"PROXY TCP4 ".as_slice(), &[b'1'; 200]].concat())
+            .await
+            .0
+            .is_err());
+        assert!(read(b"PX 10 10 ff0000\nSIZE\n").await.0.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_v2() {
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[
+            0x21, 0x11, 0, 12, 10, 0, 0, 1, 10, 0, 0, 2, 0x1F, 0x90, 0x04, 0xD2,
+        ]);
+        data.extend_from_slice(b"SIZE\n");
+        let (result, rest) = read(&data).await;
+        assert_eq!(result.unwrap(), Some("10.0.0.1:8080".parse().unwrap()));
+        assert_eq!(rest, b"SIZE\n");
+
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[0x21, 0x21, 0, 36]);
+        data.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        data.extend_from_slice(&[0; 16]);
+        data.extend_from_slice(&[0x1F, 0x90, 0x04, 0xD2]);
+        let (result, _) = read(&data).await;
+        assert_eq!(result.unwrap(), Some("[2001:db8::1]:8080".parse().unwrap()));
+
+        // health checks of the proxy itself
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        let (result, rest) = read(&data).await;
+        assert_eq!(result.unwrap(), None);
+        assert!(rest.is_empty());
+
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[0x21, 0x11, 0, 4, 10, 0, 0, 1]);
+        assert!(read(&data).await.0.is_err());
+    }
+}
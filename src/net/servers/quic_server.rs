@@ -0,0 +1,173 @@
+use crate::net::protocol::Features;
+use crate::net::servers::sockets;
+use crate::net::servers::statistics;
+use crate::net::servers::storm_guard::StormGuard;
+use crate::net::servers::{
+    AccessControlOptions, BufferOptions, ClaimMode, GenServer, ListenerCapabilities, RateLimitOptions,
+    Reloadable, SocketOptions, StormProtectionOptions, TcpServer, TlsOptions, WriteProtectionOptions,
+};
+use crate::pixmap::SharedPixmap;
+use crate::DaemonResult;
+use anyhow::anyhow;
+use async_trait::async_trait;
+use quinn::crypto::rustls::QuicServerConfig;
+use quinn::{ConnectionError, Endpoint, EndpointConfig, Incoming, ServerConfig, TokioRuntime};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::task::{AbortHandle, JoinSet};
+
+/// The ALPN protocol identifier which QUIC clients should negotiate
+pub const QUIC_ALPN: &[u8] = b"pixelflut";
+
+/// Options with which the `QuicServer` is configured
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct QuicServerOptions {
+    /// The address to which the server binds
+    pub bind_addr: SocketAddr,
+    /// The certificate with which connections are encrypted
+    pub tls: TlsOptions,
+    /// Tuning of the socket, of which only the buffer sizes apply to QUIC
+    pub socket: SocketOptions,
+    /// The networks from which connections are accepted
+    pub access_control: Reloadable<AccessControlOptions>,
+    /// Whether and how connection storms from single IP addresses should be throttled
+    pub storm_protection: Option<StormProtectionOptions>,
+    /// How many requests and pixels a single stream may send and draw per second
+    pub rate_limit: Reloadable<RateLimitOptions>,
+    /// The sizes of the buffers of streams and how long their request lines may be
+    pub buffers: BufferOptions,
+    /// Whether connections must authenticate before they may draw on the canvas
+    pub write_protection: Option<WriteProtectionOptions>,
+    /// How drawing into regions which other connections claimed is treated, if regions can be claimed via CLAIM
+    pub claims: Option<ClaimMode>,
+    /// The optional protocol features which clients may use, or `None` to enable all which the server supports
+    pub features: Option<Features>,
+}
+
+/// A server implementation using QUIC to transport pixelflut messages
+///
+/// Every bidirectional stream which a client opens carries the same protocol as a TCP connection and is handled
+/// independently, so that packet loss on one stream does not stall the others.
+#[derive(Debug, Clone)]
+pub struct QuicServer {
+    options: QuicServerOptions,
+}
+
+impl QuicServer {
+    #[tracing::instrument(skip_all)]
+    async fn handle_listener(
+        endpoint: Endpoint,
+        pixmap: SharedPixmap,
+        options: QuicServerOptions,
+    ) -> anyhow::Result<!> {
+        let capabilities = ListenerCapabilities {
+            binary_protocol: true,
+            image_upload: cfg!(feature = "images"),
+            state_streaming: true,
+            compression: true,
+            max_line_len: Some(options.buffers.max_line_len),
+            buffers: options.buffers,
+            storm_protection: options.storm_protection.clone().map(Arc::new),
+            max_connections_per_ip: None,
+            rate_limit: options.rate_limit,
+            idle_timeout: None,
+            write_protection: options.write_protection.map(Arc::new),
+            claims: options.claims,
+            enabled_features: options.features,
+        };
+        let mut storm_guard = options.storm_protection.map(StormGuard::new);
+        while let Some(incoming) = endpoint.accept().await {
+            let remote_addr = sockets::peer_addr(incoming.remote_address());
+            if !options.access_control.get().permits(remote_addr.ip()) {
+                tracing::debug!(
+                    "Refusing connection from {} which is not allowed on this listener",
+                    remote_addr
+                );
+                incoming.refuse();
+                continue;
+            }
+            if let Some(storm_guard) = &mut storm_guard {
+                if let Err(remaining) = storm_guard.admit(remote_addr.ip()) {
+                    tracing::trace!(
+                        "Rejecting connection from {} which is throttled for another {:?}",
+                        remote_addr,
+                        remaining
+                    );
+                    incoming.refuse();
+                    continue;
+                }
+            }
+            let pixmap = pixmap.clone();
+            let capabilities = capabilities.clone();
+            tokio::spawn(async move {
+                if let Err(e) = QuicServer::handle_connection(incoming, pixmap, capabilities).await {
+                    tracing::warn!("Got error while handling quic connection: {e}");
+                }
+            });
+        }
+        Err(anyhow!("QUIC endpoint was closed"))
+    }
+
+    /// Accept the streams of a connection and handle each of them like a TCP connection
+    async fn handle_connection(
+        incoming: Incoming,
+        pixmap: SharedPixmap,
+        capabilities: ListenerCapabilities,
+    ) -> anyhow::Result<()> {
+        let connection = incoming.await?;
+        let remote_addr = sockets::peer_addr(connection.remote_address());
+        tracing::debug!("QUIC client {} connected", remote_addr);
+        loop {
+            let (send, recv) = match connection.accept_bi().await {
+                Ok(stream) => stream,
+                Err(ConnectionError::ApplicationClosed(_) | ConnectionError::LocallyClosed) => {
+                    tracing::debug!("QUIC client {} disconnected", remote_addr);
+                    return Ok(());
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let pixmap = pixmap.clone();
+            let capabilities = capabilities.clone();
+            tokio::spawn(async move {
+                let stream = tokio::io::join(recv, send);
+                if let Err(e) = TcpServer::handle_connection(stream, remote_addr, pixmap, capabilities).await
+                {
+                    tracing::warn!("Got error while handling quic stream: {e}");
+                }
+            });
+        }
+    }
+}
+
+#[async_trait]
+impl GenServer for QuicServer {
+    type Options = QuicServerOptions;
+
+    fn new(options: Self::Options) -> Self {
+        Self { options }
+    }
+
+    async fn start(
+        self,
+        pixmap: SharedPixmap,
+        join_set: &mut JoinSet<DaemonResult>,
+    ) -> anyhow::Result<AbortHandle> {
+        let mut tls = self.options.tls.server_config()?;
+        tls.alpn_protocols = vec![QUIC_ALPN.to_vec()];
+        let config = ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(tls)?));
+        let endpoint = Endpoint::new(
+            EndpointConfig::default(),
+            Some(config),
+            sockets::udp_socket(self.options.bind_addr, &self.options.socket)?,
+            Arc::new(TokioRuntime),
+        )?;
+        statistics::start();
+        tracing::info!("Started QUIC Server on {}", self.options.bind_addr);
+
+        let handle = join_set
+            .build_task()
+            .name("quic_server")
+            .spawn(async move { QuicServer::handle_listener(endpoint, pixmap, self.options).await })?;
+        Ok(handle)
+    }
+}
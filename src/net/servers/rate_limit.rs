@@ -0,0 +1,266 @@
+//! Limits on the rate at which single connections may send requests and draw pixels
+
+use crate::net::protocol::{Request, ResponseError};
+use crate::net::servers::{statistics, Reloadable, StormProtectionOptions};
+use std::net::IpAddr;
+use std::ops::AddAssign;
+use std::time::{Duration, Instant};
+
+/// Options for limiting the request rate of every connection of a listener
+///
+/// Each limit is enforced with a token bucket which holds one second worth of tokens so that clients may send
+/// short bursts.
+/// Connections which exceed a limit are not disconnected but their requests are not read until they are within
+/// their limits again, which slows them down via the flow control of the transport.
+/// If the listener is protected against connection storms, connections may send the burst of the storm protection
+/// as additional requests and connections from its exempt networks are not limited at all.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct RateLimitOptions {
+    /// How many requests a single connection may send per second, or `None` for no limit
+    pub max_commands_per_sec: Option<u32>,
+    /// How many pixels a single connection may draw per second, or `None` for no limit
+    pub max_pixels_per_sec: Option<u32>,
+}
+
+/// How much of its rate limits a connection used up with some requests
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub(crate) struct Usage {
+    /// The number of requests, including invalid ones
+    pub commands: u64,
+    /// The number of pixels which the requests draw if they succeed
+    pub pixels: u64,
+}
+
+impl Usage {
+    /// The usage of a single request
+    pub fn of(request: &Result<Request, ResponseError>) -> Self {
+        Self {
+            commands: 1,
+            pixels: request.as_ref().map_or(0, statistics::pixels_drawn),
+        }
+    }
+}
+
+impl AddAssign for Usage {
+    fn add_assign(&mut self, rhs: Self) {
+        self.commands += rhs.commands;
+        self.pixels += rhs.pixels;
+    }
+}
+
+/// A bucket of tokens which is refilled at a constant rate and which can be overdrawn
+///
+/// It holds one second worth of tokens plus an optional burst. Clients use it as well to pace what they send.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u32, now: Instant) -> Self {
+        Self::with_burst(rate, 0, now)
+    }
+
+    /// Create a full bucket which holds `burst` tokens on top of one second worth of them
+    pub fn with_burst(rate: u32, burst: u32, now: Instant) -> Self {
+        let capacity = rate as f64 + burst as f64;
+        Self {
+            rate: rate as f64,
+            capacity,
+            tokens: capacity,
+            last_refill: now,
+        }
+    }
+
+    /// Remove `n` tokens and determine for how long the bucket must be refilled until it is no longer overdrawn
+    pub fn take(&mut self, n: u64, now: Instant) -> Duration {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity) - n as f64;
+        self.last_refill = now;
+        match self.tokens < 0.0 {
+            true => Duration::from_secs_f64(-self.tokens / self.rate),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+/// Per-connection bookkeeping which decides how long a connection must pause to stay within its limits
+///
+/// When the limits of the listener are reloaded, the buckets are replaced with full ones of the new limits.
+#[derive(Debug, Clone)]
+pub(crate) struct RateLimiter {
+    options: Reloadable<RateLimitOptions>,
+    current: RateLimitOptions,
+    /// How many requests the connection may send on top of one second worth of them
+    burst: u32,
+    /// Whether the connection is not limited at all
+    exempt: bool,
+    commands: Option<TokenBucket>,
+    pixels: Option<TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(options: Reloadable<RateLimitOptions>) -> Self {
+        let current = *options.get();
+        let mut limiter = Self {
+            options,
+            current,
+            burst: 0,
+            exempt: false,
+            commands: None,
+            pixels: None,
+        };
+        limiter.fill_buckets(Instant::now());
+        limiter
+    }
+
+    /// Apply the burst and exemptions of the storm protection of the listener to a connection from `ip`
+    pub fn with_storm_protection(
+        mut self,
+        storm_protection: Option<&StormProtectionOptions>,
+        ip: IpAddr,
+    ) -> Self {
+        if let Some(storm_protection) = storm_protection {
+            self.burst = storm_protection.burst;
+            self.exempt = storm_protection.exempts(ip);
+            self.fill_buckets(Instant::now());
+        }
+        self
+    }
+
+    fn fill_buckets(&mut self, now: Instant) {
+        self.commands = self
+            .current
+            .max_commands_per_sec
+            .map(|rate| TokenBucket::with_burst(rate, self.burst, now));
+        self.pixels = self
+            .current
+            .max_pixels_per_sec
+            .map(|rate| TokenBucket::new(rate, now));
+    }
+
+    /// Account for requests which a connection sent and wait until it is within its limits again
+    pub async fn throttle(&mut self, usage: Usage) {
+        let delay = self.take_at(usage, Instant::now());
+        if !delay.is_zero() {
+            tracing::trace!(
+                "Throttling connection for {:?} since it exceeded its rate limit",
+                delay
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    fn take_at(&mut self, usage: Usage, now: Instant) -> Duration {
+        if self.exempt {
+            return Duration::ZERO;
+        }
+        let latest = *self.options.get();
+        if latest != self.current {
+            self.current = latest;
+            self.fill_buckets(now);
+        }
+        let commands = self
+            .commands
+            .as_mut()
+            .map_or(Duration::ZERO, |bucket| bucket.take(usage.commands, now));
+        let pixels = self
+            .pixels
+            .as_mut()
+            .map_or(Duration::ZERO, |bucket| bucket.take(usage.pixels, now));
+        commands.max(pixels)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_limits_are_enforced_independently() {
+        let mut limiter = RateLimiter::new(
+            RateLimitOptions {
+                max_commands_per_sec: Some(10),
+                max_pixels_per_sec: Some(1000),
+            }
+            .into(),
+        );
+        let start = Instant::now();
+        let usage = |commands, pixels| Usage { commands, pixels };
+
+        // a burst of one second worth of requests is allowed
+        assert_eq!(limiter.take_at(usage(10, 100), start), Duration::ZERO);
+        assert_eq!(limiter.take_at(usage(5, 0), start), Duration::from_millis(500));
+
+        // the buckets are refilled over time and the longer delay wins
+        let later = start + Duration::from_secs(1);
+        assert_eq!(limiter.take_at(usage(1, 3000), later), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let mut limiter = RateLimiter::new(RateLimitOptions::default().into());
+        let usage = Usage {
+            commands: u64::MAX,
+            pixels: u64::MAX,
+        };
+        assert_eq!(limiter.take_at(usage, Instant::now()), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_storm_protection() {
+        let options = RateLimitOptions {
+            max_commands_per_sec: Some(10),
+            max_pixels_per_sec: Some(10),
+        };
+        let storm_protection = StormProtectionOptions {
+            burst: 5,
+            exemptions: vec!["10.0.0.0/8".parse().unwrap()],
+            ..Default::default()
+        };
+        let limiter = |ip: &str| {
+            RateLimiter::new(options.into())
+                .with_storm_protection(Some(&storm_protection), ip.parse().unwrap())
+        };
+        let start = Instant::now();
+        let usage = |commands, pixels| Usage { commands, pixels };
+
+        // exempt peers may exceed all limits
+        let mut exempt = limiter("10.1.2.3");
+        assert_eq!(exempt.take_at(usage(1000, 1000), start), Duration::ZERO);
+        assert_eq!(exempt.take_at(usage(1000, 1000), start), Duration::ZERO);
+
+        // other peers may only send the burst on top of their requests
+        let mut other = limiter("192.0.2.1");
+        assert_eq!(other.take_at(usage(15, 0), start), Duration::ZERO);
+        assert_eq!(other.take_at(usage(1, 0), start), Duration::from_millis(100));
+        assert_eq!(other.take_at(usage(0, 15), start), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_reload() {
+        let options = Reloadable::new(RateLimitOptions {
+            max_commands_per_sec: Some(10),
+            max_pixels_per_sec: None,
+        });
+        let mut limiter = RateLimiter::new(options.clone());
+        let start = Instant::now();
+        let usage = Usage {
+            commands: 20,
+            pixels: 0,
+        };
+        assert_eq!(limiter.take_at(usage, start), Duration::from_secs(1));
+
+        // new limits take effect on the next request with full buckets
+        options.set(RateLimitOptions {
+            max_commands_per_sec: Some(20),
+            max_pixels_per_sec: None,
+        });
+        assert_eq!(limiter.take_at(usage, start), Duration::ZERO);
+        options.set(RateLimitOptions::default());
+        assert_eq!(limiter.take_at(usage, start), Duration::ZERO);
+    }
+}
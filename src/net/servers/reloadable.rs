@@ -0,0 +1,53 @@
+//! Options of a listener which can be changed while it runs
+
+use std::sync::{Arc, RwLock};
+
+/// A value which is shared between whoever started a listener and the listener's connections so that it can be
+/// replaced without restarting the listener, e.g. when the configuration is reloaded
+///
+/// Cloning it is cheap and all clones observe the same value.
+#[derive(Debug, Default)]
+pub struct Reloadable<T> {
+    value: Arc<RwLock<Arc<T>>>,
+}
+
+impl<T> Reloadable<T> {
+    /// Share `value` so that it can be replaced later
+    pub fn new(value: T) -> Self {
+        Self {
+            value: Arc::new(RwLock::new(Arc::new(value))),
+        }
+    }
+
+    /// The current value
+    pub fn get(&self) -> Arc<T> {
+        self.value.read().unwrap().clone()
+    }
+
+    /// Replace the value for all clones of this handle
+    pub fn set(&self, value: T) {
+        *self.value.write().unwrap() = Arc::new(value);
+    }
+}
+
+impl<T> Clone for Reloadable<T> {
+    fn clone(&self) -> Self {
+        Self {
+            value: self.value.clone(),
+        }
+    }
+}
+
+impl<T> From<T> for Reloadable<T> {
+    fn from(value: T) -> Self {
+        Self::new(value)
+    }
+}
+
+impl<T: PartialEq> PartialEq for Reloadable<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.get() == other.get()
+    }
+}
+
+impl<T: Eq> Eq for Reloadable<T> {}
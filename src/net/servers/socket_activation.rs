@@ -0,0 +1,359 @@
+//! Sockets which a service manager like systemd (socket activation) or a previous server process (handover) passed
+//! to the server
+//!
+//! The service manager binds the sockets itself and passes them as file descriptors starting at 3, which it announces
+//! via the `LISTEN_FDS` and `LISTEN_PID` environment variables. A listener takes the passed socket which is bound to
+//! its own address instead of binding a new one. This lets the server listen on privileged ports without running as
+//! root and keeps the sockets open while it restarts, so clients which connect in between wait in the backlog instead
+//! of being refused.
+//!
+//! A running server hands its sockets over to a new process of the same executable via a [`Successor`], e.g. after
+//! the executable was upgraded. They are passed the same way together with a snapshot of the canvas, but marked with
+//! `PIXELDIKE_HANDOVER_FROM` instead of `LISTEN_PID` since the PID of the new process is unknown before it starts.
+//! The previous process waits until the new one has started all of its listeners before it exits.
+
+use socket2::{SockRef, Socket, Type};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::unix::pipe;
+
+/// The file descriptor of the first passed socket
+const LISTEN_FDS_START: RawFd = 3;
+
+/// The variable with which a previous server process marks the file descriptors which it handed over
+const HANDOVER_FROM: &str = "PIXELDIKE_HANDOVER_FROM";
+
+/// The name of the passed file descriptor which contains a snapshot of the canvas
+const CANVAS_NAME: &str = "pixeldike-canvas";
+
+/// The name of the passed pipe which tells the previous server process that this one took over
+const READY_NAME: &str = "pixeldike-ready";
+
+/// The file descriptors which were passed to this process
+#[derive(Debug, Default)]
+struct Passed {
+    /// The passed sockets which no listener has taken yet
+    sockets: Vec<OwnedFd>,
+    /// A snapshot of the canvas which a previous server process handed over
+    canvas: Option<OwnedFd>,
+    /// A pipe on which a previous server process waits until this one has started all of its listeners
+    ready: Option<OwnedFd>,
+}
+
+static PASSED: LazyLock<Mutex<Passed>> = LazyLock::new(|| Mutex::new(from_env()));
+
+/// Duplicates of the sockets of all listeners of this process, which are handed over to a [`Successor`]
+static BOUND: Mutex<Vec<OwnedFd>> = Mutex::new(Vec::new());
+
+/// Take ownership of the file descriptors which the service manager or a previous server process passed
+fn from_env() -> Passed {
+    let pid = |name: &str| std::env::var(name).ok().and_then(|pid| pid.parse::<u32>().ok());
+    let handover = pid(HANDOVER_FROM) == Some(std::os::unix::process::parent_id());
+    let for_us = handover || pid("LISTEN_PID") == Some(std::process::id());
+    let n = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse::<RawFd>().ok())
+        .unwrap_or(0);
+    if !for_us || n <= 0 {
+        return Passed::default();
+    }
+    tracing::debug!(
+        "Received {} file descriptors from the {}",
+        n,
+        if handover {
+            "previous server process"
+        } else {
+            "service manager"
+        }
+    );
+    let names = std::env::var("LISTEN_FDNAMES").unwrap_or_default();
+    let mut names = names.split(':');
+    let mut passed = Passed::default();
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + n {
+        let name = names.next().unwrap_or_default();
+        // passed file descriptors are inherited across exec and must not have been opened by this process itself,
+        // which opens all file descriptors with close-on-exec
+        // SAFETY: querying the flags of a file descriptor has no effect on it, even if it is not open
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+        let is_inherited = flags >= 0 && flags & libc::FD_CLOEXEC == 0;
+        let is_socket = is_inherited && {
+            // SAFETY: the file descriptor is open and stays so while it is borrowed
+            let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+            SockRef::from(&fd).r#type().is_ok()
+        };
+        let is_handover_fd = handover && matches!(name, CANVAS_NAME | READY_NAME);
+        if !is_socket && !(is_inherited && is_handover_fd) {
+            tracing::warn!("Ignoring file descriptor {} which was not passed as a socket", fd);
+            continue;
+        }
+        // SAFETY: the file descriptor was handed over to this process which owns it from now on; child processes
+        // like ffmpeg must not inherit it
+        let fd = unsafe {
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+            OwnedFd::from_raw_fd(fd)
+        };
+        match name {
+            CANVAS_NAME if handover => passed.canvas = Some(fd),
+            READY_NAME if handover => passed.ready = Some(fd),
+            _ => passed.sockets.push(fd),
+        }
+    }
+    passed
+}
+
+/// Remove the first passed socket which `matches` from the passed sockets
+fn take(matches: impl Fn(&OwnedFd) -> bool) -> Option<OwnedFd> {
+    let mut passed = PASSED.lock().unwrap();
+    let index = passed.sockets.iter().position(matches)?;
+    Some(passed.sockets.remove(index))
+}
+
+/// Take the passed IP socket of type `ty` which is bound to `addr`, if there is one
+pub(super) fn take_inet(addr: SocketAddr, ty: Type) -> Option<Socket> {
+    take(|fd| {
+        let socket = SockRef::from(fd);
+        socket.r#type().is_ok_and(|candidate| candidate == ty)
+            && socket.local_addr().ok().and_then(|local| local.as_socket()) == Some(addr)
+    })
+    .map(Socket::from)
+}
+
+/// Take the passed unix stream socket which is bound to `path`, if there is one
+pub(super) fn take_unix(path: &Path) -> Option<UnixListener> {
+    take(|fd| {
+        SockRef::from(fd).r#type().is_ok_and(|ty| ty == Type::STREAM)
+            && fd
+                .try_clone()
+                .and_then(|fd| UnixListener::from(fd).local_addr())
+                .is_ok_and(|local| local.as_pathname() == Some(path))
+    })
+    .map(UnixListener::from)
+}
+
+/// Take the snapshot of the canvas which a previous server process handed over, if there is one
+///
+/// It is in the format of snapshot files.
+pub fn take_handed_over_canvas() -> Option<OwnedFd> {
+    PASSED.lock().unwrap().canvas.take()
+}
+
+/// Remember the socket of a listener so that it can be handed over to a [`Successor`]
+pub(super) fn remember(socket: BorrowedFd<'_>) {
+    match socket.try_clone_to_owned() {
+        Ok(socket) => BOUND.lock().unwrap().push(socket),
+        Err(e) => tracing::warn!(
+            "Could not keep the socket of a listener for handing it over to a new process: {}",
+            e
+        ),
+    }
+}
+
+/// Close all passed sockets which no listener has taken and tell the previous server process, if any, that this one
+/// took over
+///
+/// This should be called once all listeners are started since such sockets are probably misconfigured and would
+/// otherwise accept connections which are never handled.
+pub fn close_unused_sockets() {
+    let mut passed = PASSED.lock().unwrap();
+    for fd in passed.sockets.drain(..) {
+        let local = SockRef::from(&fd).local_addr().ok();
+        let description = match local.as_ref().and_then(|local| local.as_socket()) {
+            Some(addr) => addr.to_string(),
+            None => "a non-IP socket".to_string(),
+        };
+        tracing::warn!(
+            "Closing {} which was passed to the server but which no listener is bound to",
+            description
+        );
+    }
+    if let Some(ready) = passed.ready.take() {
+        if let Err(e) = std::fs::File::from(ready).write_all(b"\n") {
+            tracing::warn!(
+                "Could not tell the previous server process that it can exit: {}",
+                e
+            );
+        }
+    }
+}
+
+/// A new server process which takes over the sockets of all listeners of this one
+#[derive(Debug)]
+pub struct Successor {
+    /// The executable from which the new process is started
+    executable: PathBuf,
+    /// Duplicates of the sockets of all listeners
+    sockets: Vec<OwnedFd>,
+}
+
+impl Successor {
+    /// Prepare handing the sockets of all listeners over to a new process of the executable of this one
+    ///
+    /// The sockets stay open from now on, even after the listeners of this process are stopped, so that clients which
+    /// connect in between wait in the backlog until the new process accepts them.
+    pub fn prepare() -> io::Result<Self> {
+        let executable = upgraded_executable(std::env::current_exe()?);
+        if !executable.is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("The executable {} does not exist", executable.display()),
+            ));
+        }
+        let sockets = BOUND
+            .lock()
+            .unwrap()
+            .iter()
+            .map(OwnedFd::try_clone)
+            .collect::<io::Result<_>>()?;
+        Ok(Self { executable, sockets })
+    }
+
+    /// Start the new process with the arguments of this one, hand it the sockets and optionally a snapshot of the
+    /// canvas and wait until it has started all of its listeners
+    ///
+    /// Returns the PID of the new process.
+    pub async fn start(self, canvas: Option<OwnedFd>, timeout: Duration) -> anyhow::Result<u32> {
+        let (ready_reader, ready_writer) = io::pipe()?;
+        let mut names = vec!["listener"; self.sockets.len()];
+        let mut fds = self.sockets;
+        if let Some(canvas) = canvas {
+            fds.push(canvas);
+            names.push(CANVAS_NAME);
+        }
+        fds.push(ready_writer.into());
+        names.push(READY_NAME);
+
+        let mut command = Command::new(&self.executable);
+        command
+            .args(std::env::args_os().skip(1))
+            .env(HANDOVER_FROM, std::process::id().to_string())
+            .env("LISTEN_FDS", fds.len().to_string())
+            .env("LISTEN_FDNAMES", names.join(":"))
+            .env_remove("LISTEN_PID");
+        let handed_over: Vec<RawFd> = fds.iter().map(AsRawFd::as_raw_fd).collect();
+        let mut moved = vec![0; handed_over.len()];
+        // SAFETY: only async-signal-safe functions are called between fork and exec and nothing is allocated
+        unsafe {
+            command.pre_exec(move || {
+                // move the file descriptors out of the way first since some may already be where others belong
+                let first_free = LISTEN_FDS_START + handed_over.len() as RawFd;
+                for (moved, &fd) in moved.iter_mut().zip(&handed_over) {
+                    *moved = libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, first_free);
+                    if *moved < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                // the new process inherits the duplicates since they don't have close-on-exec set
+                for (target, &fd) in (LISTEN_FDS_START..).zip(&moved) {
+                    if libc::dup2(fd, target) < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            })
+        };
+        let child = command.spawn()?;
+        // the pipe is only closed without a message if the new process exits before it took over
+        drop(fds);
+
+        let mut ready = pipe::Receiver::from_owned_fd(ready_reader.into())?;
+        let mut message = [0; 1];
+        match tokio::time::timeout(timeout, ready.read(&mut message)).await {
+            Ok(Ok(1)) => Ok(child.id()),
+            Ok(Ok(_)) => Err(anyhow::anyhow!(
+                "The new server process {} exited before it took over",
+                child.id()
+            )),
+            Ok(Err(e)) => Err(e.into()),
+            Err(_) => Err(anyhow::anyhow!(
+                "The new server process {} did not take over within {:?}",
+                child.id(),
+                timeout
+            )),
+        }
+    }
+}
+
+/// The path of the executable which is running as `current`, even if it was replaced by an upgrade since
+///
+/// The kernel marks the path of an executable which was replaced while it runs as deleted.
+fn upgraded_executable(current: PathBuf) -> PathBuf {
+    match current.to_str().and_then(|path| path.strip_suffix(" (deleted)")) {
+        Some(path) => PathBuf::from(path),
+        None => current,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_take_inet() {
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let tcp_addr = tcp.local_addr().unwrap();
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let udp_addr = udp.local_addr().unwrap();
+        PASSED
+            .lock()
+            .unwrap()
+            .sockets
+            .extend([OwnedFd::from(tcp), OwnedFd::from(udp)]);
+
+        assert!(take_inet(tcp_addr, Type::DGRAM).is_none());
+        assert!(take_inet(tcp_addr, Type::STREAM).is_some());
+        assert!(take_inet(tcp_addr, Type::STREAM).is_none());
+        assert!(take_inet(udp_addr, Type::DGRAM).is_some());
+    }
+
+    #[test]
+    fn test_take_unix() {
+        let path = std::env::temp_dir().join(format!("pixeldike-activation-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let unix = UnixListener::bind(&path).unwrap();
+        PASSED.lock().unwrap().sockets.push(OwnedFd::from(unix));
+
+        assert!(take_unix(Path::new("/nothing.sock")).is_none());
+        assert!(take_unix(&path).is_some());
+        assert!(take_unix(&path).is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_upgraded_executable() {
+        assert_eq!(
+            upgraded_executable(PathBuf::from("/usr/bin/pixeldike (deleted)")),
+            PathBuf::from("/usr/bin/pixeldike")
+        );
+        assert_eq!(
+            upgraded_executable(PathBuf::from("/usr/bin/pixeldike")),
+            PathBuf::from("/usr/bin/pixeldike")
+        );
+    }
+
+    #[test]
+    fn test_remembered_sockets_are_handed_over() {
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap();
+        remember(std::os::fd::AsFd::as_fd(&tcp));
+        drop(tcp);
+
+        // the socket stays open for the successor after the listener is gone
+        let successor = Successor::prepare().unwrap();
+        assert!(successor.sockets.iter().any(|fd| {
+            SockRef::from(fd)
+                .local_addr()
+                .ok()
+                .and_then(|local| local.as_socket())
+                == Some(addr)
+        }));
+        assert!(std::net::TcpStream::connect(addr).is_ok());
+    }
+}
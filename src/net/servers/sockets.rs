@@ -0,0 +1,188 @@
+//! Creation of the sockets on which listeners accept connections and datagrams
+//!
+//! Listeners which are bound to the unspecified IPv6 address `[::]` are always dual-stack so that they also accept
+//! IPv4 clients, independent of the `net.ipv6.bindv6only` setting of the system.
+//! Such clients are reported with IPv4-mapped addresses like `::ffff:10.0.0.1`, which [`peer_addr`] turns back into
+//! plain IPv4 addresses so that they are logged, limited and exempted like clients of IPv4 listeners.
+//!
+//! Sockets which a service manager or a previous server process passed for the address of a listener are used instead
+//! of binding new ones, and all sockets are remembered so that they can be handed over to a new process, see
+//! [`socket_activation`](super::socket_activation).
+
+use crate::net::servers::socket_activation;
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use std::io;
+use std::net::SocketAddr;
+use std::os::fd::AsFd;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+
+/// Tuning of the sockets of a listener
+///
+/// Options which are `None` keep the defaults of the system.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct SocketOptions {
+    /// Whether small writes to TCP connections are sent right away instead of being coalesced (`TCP_NODELAY`)
+    ///
+    /// Interactive clients want this enabled while it wastes bandwidth on connections of bulk flooders.
+    pub nodelay: Option<bool>,
+    /// The size of the receive buffer of the kernel for every socket (`SO_RCVBUF`)
+    ///
+    /// TCP connections inherit the buffer sizes of their listener so that they apply from the handshake on.
+    pub recv_buffer_size: Option<usize>,
+    /// The size of the send buffer of the kernel for every socket (`SO_SNDBUF`)
+    pub send_buffer_size: Option<usize>,
+    /// After which duration without any traffic TCP connections are probed whether their peer is still alive
+    pub keepalive_time: Option<Duration>,
+    /// The interval between keepalive probes once a connection is probed
+    pub keepalive_interval: Option<Duration>,
+}
+
+impl SocketOptions {
+    /// Apply the buffer sizes to a socket before it is bound
+    fn apply_buffer_sizes(&self, socket: SockRef) -> io::Result<()> {
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        Ok(())
+    }
+
+    /// Apply the options which only affect established connections to an accepted TCP stream
+    pub(super) fn apply_to_stream(&self, stream: &TcpStream) -> io::Result<()> {
+        if let Some(nodelay) = self.nodelay {
+            stream.set_nodelay(nodelay)?;
+        }
+        if self.keepalive_time.is_some() || self.keepalive_interval.is_some() {
+            let mut keepalive = TcpKeepalive::new();
+            if let Some(time) = self.keepalive_time {
+                keepalive = keepalive.with_time(time);
+            }
+            if let Some(interval) = self.keepalive_interval {
+                keepalive = keepalive.with_interval(interval);
+            }
+            SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether a socket bound to `addr` should accept connections of both IP versions
+fn is_dual_stack(addr: SocketAddr) -> bool {
+    addr.is_ipv6() && addr.ip().is_unspecified()
+}
+
+/// Create an unbound TCP socket for listening on `addr`
+pub(super) fn tcp_socket(addr: SocketAddr, options: &SocketOptions) -> io::Result<TcpSocket> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    if is_dual_stack(addr) {
+        SockRef::from(&socket).set_only_v6(false)?;
+    }
+    options.apply_buffer_sizes(SockRef::from(&socket))?;
+    Ok(socket)
+}
+
+/// Bind a TCP listener to `addr`, or take the one which the service manager passed for it
+pub(super) fn tcp_listener(addr: SocketAddr, options: &SocketOptions) -> io::Result<TcpListener> {
+    if let Some(listener) = passed_tcp_listener(addr, options)? {
+        return Ok(listener);
+    }
+    let socket = tcp_socket(addr, options)?;
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    let listener = socket.listen(1024)?;
+    socket_activation::remember(listener.as_fd());
+    Ok(listener)
+}
+
+/// The TCP listener bound to `addr` which the service manager passed, if there is one
+pub(super) fn passed_tcp_listener(
+    addr: SocketAddr,
+    options: &SocketOptions,
+) -> io::Result<Option<TcpListener>> {
+    let Some(socket) = socket_activation::take_inet(addr, Type::STREAM) else {
+        return Ok(None);
+    };
+    tracing::info!("Using the socket on {} which was passed to the server", addr);
+    socket_activation::remember(socket.as_fd());
+    options.apply_buffer_sizes(SockRef::from(&socket))?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into()).map(Some)
+}
+
+/// Bind a non-blocking UDP socket to `addr`, or take the one which the service manager passed for it
+pub(super) fn udp_socket(addr: SocketAddr, options: &SocketOptions) -> io::Result<std::net::UdpSocket> {
+    if let Some(socket) = socket_activation::take_inet(addr, Type::DGRAM) {
+        tracing::info!("Using the socket on {} which was passed to the server", addr);
+        socket_activation::remember(socket.as_fd());
+        options.apply_buffer_sizes(SockRef::from(&socket))?;
+        socket.set_nonblocking(true)?;
+        return Ok(socket.into());
+    }
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if is_dual_stack(addr) {
+        socket.set_only_v6(false)?;
+    }
+    options.apply_buffer_sizes(SockRef::from(&socket))?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket_activation::remember(socket.as_fd());
+    Ok(socket.into())
+}
+
+/// The address of a client as it should be logged and limited
+///
+/// IPv4-mapped IPv6 addresses are turned into plain IPv4 addresses.
+pub(super) fn peer_addr(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_peer_addr() {
+        let mapped: SocketAddr = "[::ffff:10.0.0.1]:1234".parse().unwrap();
+        assert_eq!(peer_addr(mapped), "10.0.0.1:1234".parse().unwrap());
+        let v6: SocketAddr = "[2001:db8::1]:1234".parse().unwrap();
+        assert_eq!(peer_addr(v6), v6);
+    }
+
+    #[tokio::test]
+    async fn test_dual_stack_listener() {
+        let listener = match tcp_listener("[::]:0".parse().unwrap(), &SocketOptions::default()) {
+            Ok(listener) => listener,
+            // the system may not support IPv6 at all
+            Err(_) => return,
+        };
+        let port = listener.local_addr().unwrap().port();
+        let client = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let (_, remote_addr) = listener.accept().await.unwrap();
+        assert_eq!(peer_addr(remote_addr), client.local_addr().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_socket_options() {
+        let options = SocketOptions {
+            nodelay: Some(true),
+            recv_buffer_size: Some(64 * 1024),
+            send_buffer_size: None,
+            keepalive_time: Some(Duration::from_secs(30)),
+            keepalive_interval: Some(Duration::from_secs(5)),
+        };
+        let listener = tcp_listener("127.0.0.1:0".parse().unwrap(), &options).unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        options.apply_to_stream(&stream).unwrap();
+        assert!(stream.nodelay().unwrap());
+        assert!(SockRef::from(&stream).keepalive().unwrap());
+        // the kernel may round the requested size but never goes below it
+        assert!(SockRef::from(&stream).recv_buffer_size().unwrap() >= 64 * 1024);
+    }
+}
@@ -0,0 +1,391 @@
+//! Periodic pushes of the canvas state to clients which requested them via STREAM
+
+#[cfg(feature = "ws")]
+use crate::net::protocol::write_state_binary;
+use crate::net::protocol::{Region, Response, StateAlgorithm};
+use crate::net::servers::transactions;
+use crate::pixmap::{Color, Pixmap};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use std::future;
+use std::io::Write;
+use std::time::Duration;
+use tokio::time::{Interval, MissedTickBehavior};
+
+/// How the canvas state should be streamed to a connection
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) struct StreamSettings {
+    /// The algorithm with which each frame is encoded
+    pub algorithm: StateAlgorithm,
+    /// How many frames are sent per second
+    pub fps: u32,
+    /// The part of the canvas which is streamed or `None` for the whole canvas
+    pub region: Option<Region>,
+}
+
+/// The state of a connections stream which decides when the next frame is due and how it is encoded
+#[derive(Debug, Default)]
+pub(crate) struct StateStream {
+    settings: Option<StreamSettings>,
+    interval: Option<Interval>,
+    /// The token of the last frame that was sent
+    token: u64,
+    /// The canvas as it was sent in the last frame, which delta and px frames are based on
+    snapshot: Option<Vec<u8>>,
+}
+
+impl StateStream {
+    /// Wait until the next frame should be sent according to `settings`
+    ///
+    /// If `settings` is `None`, this never completes.
+    /// The returned future is cancel safe so that it can be used in `tokio::select!` alongside reading requests.
+    pub async fn tick(&mut self, settings: Option<StreamSettings>) -> StreamSettings {
+        if settings != self.settings {
+            self.settings = settings;
+            // a new stream always starts with all pixels
+            self.snapshot = None;
+            self.interval = settings.map(|settings| {
+                let mut interval = tokio::time::interval(Duration::from_secs_f64(1.0 / settings.fps as f64));
+                // slow clients should get fewer frames instead of a burst of outdated ones
+                interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+                interval
+            });
+        }
+        match (&mut self.interval, self.settings) {
+            (Some(interval), Some(settings)) => {
+                interval.tick().await;
+                settings
+            }
+            _ => future::pending().await,
+        }
+    }
+
+    /// Write the next frame of the canvas state according to the given settings
+    ///
+    /// Frames are always sent as a text line, independent of the connections protocol:
+    ///
+    /// - `STATE rgb64 <data>` contains all pixels of the streamed region.
+    /// - `STATE delta <token> <base> <data>` contains only the pixels which changed since the frame with the token
+    ///   `base` was sent on this connection. A `base` of 0 means that all pixels are included.
+    ///   Since connections deliver frames reliably and in order, a client has always received the base frame.
+    ///   Nothing is written if no pixel changed.
+    ///   Pixels outside of the streamed region are never included and coordinates are relative to the canvas.
+    /// - `PX <x> <y> <rrggbb>` lines for every pixel which changed since the previous frame, just like the responses
+    ///   to PX requests. The first frame only records the canvas state, so nothing is written for it.
+    pub fn write_frame(
+        &mut self,
+        pixmap: &Pixmap,
+        settings: StreamSettings,
+        writer: &mut impl Write,
+    ) -> std::io::Result<()> {
+        let algorithm = settings.algorithm;
+        match self.next_frame(pixmap, settings) {
+            None => Ok(()),
+            Some(StateFrame { data, .. }) if algorithm == StateAlgorithm::Rgb64 => writer.write_fmt(
+                format_args!("STATE {} {}\n", algorithm.as_str(), BASE64_STANDARD.encode(data)),
+            ),
+            Some(StateFrame { data, .. }) if algorithm == StateAlgorithm::Px => {
+                for record in data.chunks_exact(11) {
+                    let response = Response::PxData {
+                        x: u32::from_be_bytes(record[0..4].try_into().unwrap()) as usize,
+                        y: u32::from_be_bytes(record[4..8].try_into().unwrap()) as usize,
+                        color: Color::from((record[8], record[9], record[10])),
+                    };
+                    writer.write_fmt(format_args!("{}\n", response))?;
+                }
+                Ok(())
+            }
+            Some(StateFrame { token, base, data }) => writer.write_fmt(format_args!(
+                "STATE {} {} {} {}\n",
+                algorithm.as_str(),
+                token,
+                base,
+                BASE64_STANDARD.encode(data)
+            )),
+        }
+    }
+
+    /// Write the next frame of the canvas state according to the given settings in its binary representation
+    ///
+    /// The frames contain the same data as the ones written by [`write_frame`](Self::write_frame) but without base64
+    /// encoding, which is used for the binary messages of WebSocket connections.
+    #[cfg(feature = "ws")]
+    pub fn write_binary_frame(
+        &mut self,
+        pixmap: &Pixmap,
+        settings: StreamSettings,
+        writer: &mut impl Write,
+    ) -> std::io::Result<()> {
+        match self.next_frame(pixmap, settings) {
+            None => Ok(()),
+            Some(StateFrame { token, base, data }) => {
+                write_state_binary(settings.algorithm, token, base, &data, writer)
+            }
+        }
+    }
+
+    /// Compute the next frame according to the given settings or `None` if no frame needs to be sent
+    fn next_frame(&mut self, pixmap: &Pixmap, settings: StreamSettings) -> Option<StateFrame> {
+        let (width, height) = pixmap.get_size();
+        let region = settings.region.unwrap_or(Region {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        });
+        match settings.algorithm {
+            StateAlgorithm::Rgb64 => Some(StateFrame {
+                token: 0,
+                base: 0,
+                data: region_rgb(pixmap, region),
+            }),
+            StateAlgorithm::Delta => {
+                let current = region_rgb(pixmap, region);
+                let base = match &self.snapshot {
+                    Some(_) => self.token,
+                    None => 0,
+                };
+                let changes = encode_changes(self.snapshot.as_deref(), &current, region);
+                if base != 0 && changes.is_empty() {
+                    return None;
+                }
+
+                self.token += 1;
+                self.snapshot = Some(current);
+                Some(StateFrame {
+                    token: self.token,
+                    base,
+                    data: changes,
+                })
+            }
+            StateAlgorithm::Px => {
+                let current = region_rgb(pixmap, region);
+                let previous = self.snapshot.replace(current);
+                let changes = encode_changes(Some(previous?.as_slice()), self.snapshot.as_deref()?, region);
+                (!changes.is_empty()).then_some(StateFrame {
+                    token: 0,
+                    base: 0,
+                    data: changes,
+                })
+            }
+        }
+    }
+}
+
+/// A single frame of a state stream before it is encoded
+struct StateFrame {
+    /// The token of a delta frame or 0
+    token: u64,
+    /// The token of the frame which a delta frame is based on or 0
+    base: u64,
+    /// The raw rgb data or pixel records
+    data: Vec<u8>,
+}
+
+/// Encode all pixels which differ between `previous` and `current` rgb data of `region` as records of
+/// `x: u32, y: u32, r: u8, g: u8, b: u8` with big-endian coordinates
+///
+/// If there is no previous data, all pixels are encoded.
+fn encode_changes(previous: Option<&[u8]>, current: &[u8], region: Region) -> Vec<u8> {
+    let mut changes = Vec::new();
+    for (i, rgb) in changed_pixels(previous, current) {
+        changes.extend_from_slice(&((region.x + i % region.width) as u32).to_be_bytes());
+        changes.extend_from_slice(&((region.y + i / region.width) as u32).to_be_bytes());
+        changes.extend_from_slice(rgb);
+    }
+    changes
+}
+
+/// The index and rgb value of all pixels which differ between `previous` and `current` rgb data
+///
+/// If there is no previous data, all pixels are returned.
+pub(super) fn changed_pixels<'a>(
+    previous: Option<&'a [u8]>,
+    current: &'a [u8],
+) -> impl Iterator<Item = (usize, &'a [u8])> {
+    current
+        .chunks_exact(3)
+        .enumerate()
+        .filter(move |(i, rgb)| !previous.is_some_and(|previous| previous[i * 3..i * 3 + 3] == **rgb))
+}
+
+/// Copy the rgb values of all pixels on the canvas, row by row
+///
+/// Transactions are never copied partially.
+pub(super) fn canvas_rgb(pixmap: &Pixmap) -> Vec<u8> {
+    let (width, height) = pixmap.get_size();
+    transactions::read_consistent(|| {
+        if pixmap.get_transform().is_identity() {
+            unsafe { pixmap.get_color_data() }
+                .iter()
+                .flat_map(|c| <[u8; 3]>::from(*c))
+                .collect()
+        } else {
+            (0..height)
+                .flat_map(|y| (0..width).map(move |x| (x, y)))
+                .flat_map(|(x, y)| <[u8; 3]>::from(pixmap.get_pixel(x, y).unwrap_or_default()))
+                .collect()
+        }
+    })
+}
+
+/// Copy the rgb values of all pixels in `region`, row by row
+///
+/// The region must lie within the canvas and transactions are never copied partially.
+fn region_rgb(pixmap: &Pixmap, region: Region) -> Vec<u8> {
+    let (width, height) = pixmap.get_size();
+    if region.x == 0 && region.y == 0 && region.width == width && region.height == height {
+        return canvas_rgb(pixmap);
+    }
+    transactions::read_consistent(|| {
+        (region.y..region.y + region.height)
+            .flat_map(|y| (region.x..region.x + region.width).map(move |x| (x, y)))
+            .flat_map(|(x, y)| <[u8; 3]>::from(pixmap.get_pixel(x, y).unwrap_or_default()))
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rgb64_frame() {
+        let pixmap = Pixmap::new(2, 1).unwrap();
+        pixmap.set_pixel(1, 0, Color::from((0xFF, 0x00, 0x80))).unwrap();
+        let mut buf = Vec::new();
+        let settings = StreamSettings {
+            algorithm: StateAlgorithm::Rgb64,
+            fps: 1,
+            region: None,
+        };
+        StateStream::default()
+            .write_frame(&pixmap, settings, &mut buf)
+            .unwrap();
+        assert_eq!(buf, b"STATE rgb64 AAAA/wCA\n");
+    }
+
+    #[test]
+    fn test_delta_frames() {
+        let pixmap = Pixmap::new(2, 2).unwrap();
+        let mut stream = StateStream::default();
+        let settings = StreamSettings {
+            algorithm: StateAlgorithm::Delta,
+            fps: 1,
+            region: None,
+        };
+        let mut frame = || {
+            let mut buf = Vec::new();
+            stream.write_frame(&pixmap, settings, &mut buf).unwrap();
+            String::from_utf8(buf).unwrap()
+        };
+
+        // the first frame contains all pixels
+        let first = frame();
+        assert!(first.starts_with("STATE delta 1 0 "));
+        let data = first.trim_end().split(' ').nth(4).unwrap();
+        assert_eq!(BASE64_STANDARD.decode(data).unwrap().len(), 4 * 11);
+
+        // unchanged canvases are not sent at all
+        assert_eq!(frame(), "");
+
+        pixmap.set_pixel(1, 1, Color::from((0xFF, 0x00, 0x80))).unwrap();
+        let changes = [0, 0, 0, 1, 0, 0, 0, 1, 0xFF, 0x00, 0x80];
+        assert_eq!(
+            frame(),
+            format!("STATE delta 2 1 {}\n", BASE64_STANDARD.encode(changes))
+        );
+    }
+
+    #[test]
+    fn test_px_frames() {
+        let pixmap = Pixmap::new(2, 2).unwrap();
+        let mut stream = StateStream::default();
+        let settings = StreamSettings {
+            algorithm: StateAlgorithm::Px,
+            fps: 1,
+            region: None,
+        };
+        let mut frame = || {
+            let mut buf = Vec::new();
+            stream.write_frame(&pixmap, settings, &mut buf).unwrap();
+            String::from_utf8(buf).unwrap()
+        };
+
+        // only updates are sent
+        assert_eq!(frame(), "");
+        assert_eq!(frame(), "");
+
+        pixmap.set_pixel(1, 0, Color::from((0x00, 0x00, 0x01))).unwrap();
+        pixmap.set_pixel(1, 0, Color::from((0xFF, 0x00, 0x80))).unwrap();
+        pixmap.set_pixel(0, 1, Color::from((0x12, 0x34, 0x56))).unwrap();
+        assert_eq!(frame(), "PX 1 0 FF0080\nPX 0 1 123456\n");
+        assert_eq!(frame(), "");
+    }
+
+    #[test]
+    #[cfg(feature = "ws")]
+    fn test_binary_frames() {
+        let pixmap = Pixmap::new(2, 1).unwrap();
+        pixmap.set_pixel(1, 0, Color::from((0xFF, 0x00, 0x80))).unwrap();
+        let mut stream = StateStream::default();
+        let mut frame = |algorithm| {
+            let settings = StreamSettings {
+                algorithm,
+                fps: 1,
+                region: None,
+            };
+            let mut buf = Vec::new();
+            stream.write_binary_frame(&pixmap, settings, &mut buf).unwrap();
+            buf
+        };
+
+        let mut expected = vec![0x04, 0];
+        expected.extend_from_slice(&[0; 16]);
+        expected.extend_from_slice(&[0, 0, 0, 6, 0, 0, 0, 0xFF, 0x00, 0x80]);
+        assert_eq!(frame(StateAlgorithm::Rgb64), expected);
+
+        let frame = frame(StateAlgorithm::Delta);
+        assert_eq!(frame[..2], [0x04, 1]);
+        assert_eq!(frame[2..10], 1u64.to_be_bytes());
+        assert_eq!(frame[10..18], 0u64.to_be_bytes());
+        assert_eq!(frame[18..22], 22u32.to_be_bytes());
+        assert_eq!(frame.len(), 22 + 2 * 11);
+    }
+
+    #[test]
+    fn test_region_frames() {
+        let pixmap = Pixmap::new(4, 4).unwrap();
+        let mut stream = StateStream::default();
+        let settings = StreamSettings {
+            algorithm: StateAlgorithm::Delta,
+            fps: 1,
+            region: Some(Region {
+                x: 2,
+                y: 1,
+                width: 2,
+                height: 2,
+            }),
+        };
+        let mut frame = || {
+            let mut buf = Vec::new();
+            stream.write_frame(&pixmap, settings, &mut buf).unwrap();
+            String::from_utf8(buf).unwrap()
+        };
+
+        // the first frame contains all pixels of the region
+        let first = frame();
+        let data = first.trim_end().split(' ').nth(4).unwrap();
+        assert_eq!(BASE64_STANDARD.decode(data).unwrap().len(), 4 * 11);
+
+        // changes outside of the region are not sent
+        pixmap.set_pixel(0, 0, Color::from((0xFF, 0x00, 0x80))).unwrap();
+        assert_eq!(frame(), "");
+
+        pixmap.set_pixel(3, 2, Color::from((0xFF, 0x00, 0x80))).unwrap();
+        let changes = [0, 0, 0, 3, 0, 0, 0, 2, 0xFF, 0x00, 0x80];
+        assert_eq!(
+            frame(),
+            format!("STATE delta 2 1 {}\n", BASE64_STANDARD.encode(changes))
+        );
+    }
+}
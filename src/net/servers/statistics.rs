@@ -0,0 +1,604 @@
+//! Live counters about all servers which are reported to clients via STATS
+//!
+//! The counters are aggregated by a small actor running on its own thread.
+//! Connection handlers feed it with [`Event`]s and it regularly publishes a snapshot which can be read without
+//! waiting for the actor.
+//!
+//! The mailbox of the actor is bounded so that memory stays bounded when the server is flooded faster than the
+//! actor keeps up.
+//! Once [`MAX_PENDING_EVENTS`] events are queued, further counters are dropped and only the number of dropped events
+//! is tracked, while connects and disconnects are always delivered so that the number of clients stays exact.
+//!
+//! Besides the totals, the actor accounts the traffic and pixels of every connection and of every IP address so
+//! that operators can build leaderboards and spot clients which hog the server.
+
+use crate::net::protocol::{Request, ServerStats};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+/// Over which period the pixel rate is measured
+const RATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many events may be queued for the actor before counters are dropped
+const MAX_PENDING_EVENTS: usize = 64 * 1024;
+
+/// For how many IP addresses traffic is accounted at most
+///
+/// Addresses without open connections are forgotten in favor of new ones once this is reached, starting with the
+/// one which drew the fewest pixels.
+const MAX_TRACKED_IPS: usize = 65536;
+
+/// Identifies a connection in the accounting of the statistics
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub(crate) struct ClientId(u64);
+
+/// Something that happened on one of the servers
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) enum Event {
+    /// A client connected from the given address, which is unknown for unix sockets
+    Connected(ClientId, Option<SocketAddr>),
+    /// A previously connected client disconnected
+    Disconnected(ClientId),
+    /// The given number of pixels was drawn on the canvas, by the given client if it is connected
+    PixelsSet(Option<ClientId>, u64),
+    /// A client sent and received the given numbers of bytes
+    Transferred {
+        client: ClientId,
+        received: u64,
+        sent: u64,
+    },
+    /// A client chose a name via NICK
+    NickSet(ClientId, String),
+    /// A client answered a ping after the given round-trip time, which proves that it is still alive
+    #[cfg(feature = "ws")]
+    Pong(ClientId, Duration),
+    /// A connection was closed because it did not send anything for too long
+    IdleTimeout,
+    /// An IP address opened too many connections and is throttled from now on
+    StormDetected,
+    /// A connection was rejected because its IP address was throttled
+    ConnectionRejected,
+}
+
+/// How much a client or a group of clients transferred and drew
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub(crate) struct Traffic {
+    /// How many bytes were received from the client
+    pub bytes_received: u64,
+    /// How many bytes were sent to the client, after compression
+    pub bytes_sent: u64,
+    /// How many pixels the client drew on the canvas
+    pub pixels_set: u64,
+}
+
+impl Display for Traffic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "bytes-received={} bytes-sent={} pixels={}",
+            self.bytes_received, self.bytes_sent, self.pixels_set
+        )
+    }
+}
+
+/// The accounting of one open connection
+///
+/// It is displayed as a line like `CONNECTION id=3 remote=10.0.0.1:4567 nick=alice rtt=12ms bytes-received=8192
+/// bytes-sent=16 pixels=512`, where `nick` is left out for clients without a name, `rtt` for clients which never
+/// answered a ping and `remote` is `local` for unix sockets.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct ConnectionStats {
+    pub id: ClientId,
+    pub remote: Option<SocketAddr>,
+    pub nick: Option<String>,
+    /// The round-trip time of the most recently answered ping
+    pub rtt: Option<Duration>,
+    pub traffic: Traffic,
+}
+
+impl Display for ConnectionStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CONNECTION id={}", self.id.0)?;
+        match self.remote {
+            Some(remote) => write!(f, " remote={}", remote)?,
+            None => write!(f, " remote=local")?,
+        }
+        if let Some(nick) = &self.nick {
+            write!(f, " nick={}", nick)?;
+        }
+        if let Some(rtt) = self.rtt {
+            write!(f, " rtt={}ms", rtt.as_millis())?;
+        }
+        write!(f, " {}", self.traffic)
+    }
+}
+
+/// The accounting of all connections which were opened from one IP address since the server started
+///
+/// It is displayed as a line like `IP 10.0.0.1 connections=2 bytes-received=8192 bytes-sent=16 pixels=512`, where
+/// `connections` is the number of currently open connections.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct IpStats {
+    pub ip: IpAddr,
+    pub connections: u64,
+    pub traffic: Traffic,
+}
+
+impl Display for IpStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "IP {} connections={} {}",
+            self.ip, self.connections, self.traffic
+        )
+    }
+}
+
+/// The accounting of all clients, each sorted by the number of pixels drawn with the most active clients first
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub(crate) struct ClientStats {
+    pub connections: Vec<ConnectionStats>,
+    pub ips: Vec<IpStats>,
+}
+
+/// Handle to the statistics actor
+#[derive(Debug)]
+struct Statistics {
+    events: Sender<Event>,
+    mailbox: Arc<Mailbox>,
+    snapshot: Arc<Mutex<ServerStats>>,
+    clients: Arc<Mutex<ClientStats>>,
+}
+
+/// The statistics actor which is shared by all servers
+static STATISTICS: LazyLock<Statistics> = LazyLock::new(|| {
+    let (events, receiver) = mpsc::channel();
+    let mailbox = Arc::new(Mailbox::default());
+    let snapshot = Arc::new(Mutex::new(ServerStats::default()));
+    let clients = Arc::new(Mutex::new(ClientStats::default()));
+    std::thread::Builder::new()
+        .name("statistics".to_string())
+        .spawn({
+            let mailbox = mailbox.clone();
+            let snapshot = snapshot.clone();
+            let clients = clients.clone();
+            move || aggregate(receiver, &mailbox, &snapshot, &clients)
+        })
+        .expect("Could not start statistics thread");
+    Statistics {
+        events,
+        mailbox,
+        snapshot,
+        clients,
+    }
+});
+
+/// Start the statistics actor so that the reported uptime is measured from now on
+pub(crate) fn start() {
+    LazyLock::force(&STATISTICS);
+}
+
+/// Feed an event into the statistics
+///
+/// Counters are dropped instead if the actor is overloaded.
+pub(crate) fn record(event: Event) {
+    if !STATISTICS.mailbox.admit(&event) {
+        return;
+    }
+    // the actor only stops when the process exits
+    let _ = STATISTICS.events.send(event);
+}
+
+/// Bookkeeping of how full the mailbox of the actor is
+#[derive(Debug, Default)]
+struct Mailbox {
+    /// How many events were sent but not yet received by the actor
+    pending: AtomicUsize,
+    /// How many events were dropped because too many were pending
+    dropped: AtomicU64,
+}
+
+impl Mailbox {
+    /// Decide whether an event is queued and count it as pending if it is
+    fn admit(&self, event: &Event) -> bool {
+        let essential = matches!(event, Event::Connected(..) | Event::Disconnected(_));
+        if !essential && self.pending.load(Ordering::Relaxed) >= MAX_PENDING_EVENTS {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        self.pending.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Count an event as received by the actor
+    fn received(&self) {
+        self.pending.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The most recently published statistics
+pub(crate) fn current() -> ServerStats {
+    *STATISTICS.snapshot.lock().unwrap()
+}
+
+/// The most recently published accounting of all clients, which is updated once per second
+pub(crate) fn clients() -> ClientStats {
+    STATISTICS.clients.lock().unwrap().clone()
+}
+
+/// The traffic of all clients, which is only kept by the statistics actor
+#[derive(Debug, Default)]
+struct Accounting {
+    connections: HashMap<ClientId, ConnectionStats>,
+    ips: HashMap<IpAddr, IpStats>,
+}
+
+impl Accounting {
+    fn connect(&mut self, id: ClientId, remote: Option<SocketAddr>) {
+        self.connections.insert(
+            id,
+            ConnectionStats {
+                id,
+                remote,
+                nick: None,
+                rtt: None,
+                traffic: Traffic::default(),
+            },
+        );
+        let Some(ip) = remote.map(|remote| remote.ip()) else {
+            return;
+        };
+        if !self.ips.contains_key(&ip) && self.ips.len() >= MAX_TRACKED_IPS {
+            let forgotten = self
+                .ips
+                .values()
+                .filter(|stats| stats.connections == 0)
+                .min_by_key(|stats| stats.traffic.pixels_set)
+                .map(|stats| stats.ip);
+            match forgotten {
+                Some(forgotten) => self.ips.remove(&forgotten),
+                None => return,
+            };
+        }
+        self.ips
+            .entry(ip)
+            .or_insert_with(|| IpStats {
+                ip,
+                connections: 0,
+                traffic: Traffic::default(),
+            })
+            .connections += 1;
+    }
+
+    fn disconnect(&mut self, id: ClientId) {
+        let Some(ip) = self.connections.remove(&id).and_then(|stats| stats.remote) else {
+            return;
+        };
+        if let Some(stats) = self.ips.get_mut(&ip.ip()) {
+            stats.connections = stats.connections.saturating_sub(1);
+        }
+    }
+
+    /// Add to the traffic of a connection and of its IP address
+    fn account(&mut self, id: ClientId, add: impl Fn(&mut Traffic)) {
+        let Some(connection) = self.connections.get_mut(&id) else {
+            return;
+        };
+        add(&mut connection.traffic);
+        if let Some(stats) = connection
+            .remote
+            .and_then(|remote| self.ips.get_mut(&remote.ip()))
+        {
+            add(&mut stats.traffic);
+        }
+    }
+
+    fn snapshot(&self) -> ClientStats {
+        let mut connections: Vec<_> = self.connections.values().cloned().collect();
+        connections.sort_by_key(|stats| (u64::MAX - stats.traffic.pixels_set, stats.id));
+        let mut ips: Vec<_> = self.ips.values().cloned().collect();
+        ips.sort_by_key(|stats| (u64::MAX - stats.traffic.pixels_set, stats.ip));
+        ClientStats { connections, ips }
+    }
+}
+
+/// Aggregate all events into counters and publish them as `snapshot` and `clients` until all senders are gone
+fn aggregate(
+    events: Receiver<Event>,
+    mailbox: &Mailbox,
+    snapshot: &Mutex<ServerStats>,
+    clients: &Mutex<ClientStats>,
+) {
+    let started = Instant::now();
+    let mut stats = ServerStats::default();
+    let mut accounting = Accounting::default();
+    let mut rate_start = (started, 0);
+    let mut reported_drops = 0;
+    loop {
+        let timeout = (rate_start.0 + RATE_INTERVAL).saturating_duration_since(Instant::now());
+        let event = events.recv_timeout(timeout);
+        if event.is_ok() {
+            mailbox.received();
+        }
+        match event {
+            Ok(Event::Connected(id, remote)) => {
+                stats.clients += 1;
+                accounting.connect(id, remote);
+            }
+            Ok(Event::Disconnected(id)) => {
+                stats.clients = stats.clients.saturating_sub(1);
+                accounting.disconnect(id);
+            }
+            Ok(Event::PixelsSet(id, n)) => {
+                stats.pixels_set += n;
+                if let Some(id) = id {
+                    accounting.account(id, |traffic| traffic.pixels_set += n);
+                }
+            }
+            Ok(Event::Transferred {
+                client,
+                received,
+                sent,
+            }) => accounting.account(client, |traffic| {
+                traffic.bytes_received += received;
+                traffic.bytes_sent += sent;
+            }),
+            Ok(Event::NickSet(id, nick)) => {
+                if let Some(connection) = accounting.connections.get_mut(&id) {
+                    connection.nick = Some(nick);
+                }
+            }
+            #[cfg(feature = "ws")]
+            Ok(Event::Pong(id, rtt)) => {
+                if let Some(connection) = accounting.connections.get_mut(&id) {
+                    connection.rtt = Some(rtt);
+                }
+            }
+            Ok(Event::IdleTimeout) => stats.idle_timeouts += 1,
+            Ok(Event::StormDetected) => stats.storms_detected += 1,
+            Ok(Event::ConnectionRejected) => stats.rejected_connections += 1,
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                *clients.lock().unwrap() = accounting.snapshot();
+                return;
+            }
+        }
+
+        let now = Instant::now();
+        if now >= rate_start.0 + RATE_INTERVAL {
+            let elapsed = now.duration_since(rate_start.0).as_secs_f64();
+            stats.pixels_per_sec = ((stats.pixels_set - rate_start.1) as f64 / elapsed) as u64;
+            rate_start = (now, stats.pixels_set);
+            // copying the accounting of all clients is too expensive to do for every event
+            *clients.lock().unwrap() = accounting.snapshot();
+            let dropped = mailbox.dropped.load(Ordering::Relaxed);
+            if dropped > reported_drops {
+                tracing::warn!(
+                    "Dropped {} statistics events since the server is overloaded ({} in total)",
+                    dropped - reported_drops,
+                    dropped
+                );
+                reported_drops = dropped;
+            }
+        }
+        stats.uptime_secs = now.duration_since(started).as_secs();
+        *snapshot.lock().unwrap() = stats;
+    }
+}
+
+/// Records a client as connected for as long as this guard exists and accounts its traffic
+#[derive(Debug)]
+pub(crate) struct ConnectionGuard(ClientId);
+
+impl ConnectionGuard {
+    pub fn new(remote: Option<SocketAddr>) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        let id = ClientId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+        record(Event::Connected(id, remote));
+        Self(id)
+    }
+
+    /// The id under which the traffic of the connection is accounted
+    pub fn id(&self) -> ClientId {
+        self.0
+    }
+
+    /// Record that the client answered a ping after `rtt`
+    #[cfg(feature = "ws")]
+    pub fn pong(&self, rtt: Duration) {
+        record(Event::Pong(self.0, rtt));
+    }
+
+    /// Account bytes which were received from and sent to the client
+    pub fn transferred(&self, received: usize, sent: usize) {
+        if received > 0 || sent > 0 {
+            record(Event::Transferred {
+                client: self.0,
+                received: received as u64,
+                sent: sent as u64,
+            });
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        record(Event::Disconnected(self.0));
+    }
+}
+
+/// How many pixels a request draws on the canvas if it succeeds
+pub(crate) fn pixels_drawn(request: &Request) -> u64 {
+    match request {
+        Request::SetPixel { .. } | Request::BlendPixel { .. } | Request::ComposePixel { .. } => 1,
+        Request::SetPixelBatch(pixels) => pixels.len() as u64,
+        Request::FillRect { width, height, .. } => (width * height) as u64,
+        Request::DrawLine { x1, y1, x2, y2, .. } => (x1.abs_diff(*x2).max(y1.abs_diff(*y2)) + 1) as u64,
+        Request::PutImage { data, .. } => image_pixels(data),
+        _ => 0,
+    }
+}
+
+/// The number of pixels of an encoded image which is determined without decoding all of it
+#[cfg(feature = "images")]
+fn image_pixels(data: &[u8]) -> u64 {
+    image::ImageReader::new(std::io::Cursor::new(data))
+        .with_guessed_format()
+        .ok()
+        .and_then(|reader| reader.into_dimensions().ok())
+        .map_or(0, |(width, height)| width as u64 * height as u64)
+}
+
+#[cfg(not(feature = "images"))]
+fn image_pixels(_data: &[u8]) -> u64 {
+    0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pixmap::Color;
+
+    #[test]
+    fn test_pixels_drawn() {
+        let color = Color::from((0xFF, 0xFF, 0xFF));
+        assert_eq!(pixels_drawn(&Request::SetPixel { x: 1, y: 2, color }), 1);
+        let rect = Request::FillRect {
+            x: 0,
+            y: 0,
+            width: 3,
+            height: 4,
+            color,
+        };
+        assert_eq!(pixels_drawn(&rect), 12);
+        let line = Request::DrawLine {
+            x1: 5,
+            y1: 1,
+            x2: 0,
+            y2: 3,
+            color,
+        };
+        assert_eq!(pixels_drawn(&line), 6);
+        assert_eq!(pixels_drawn(&Request::GetSize), 0);
+    }
+
+    #[test]
+    fn test_aggregate_events() {
+        let (events, receiver) = mpsc::channel();
+        let snapshot = Mutex::new(ServerStats::default());
+        let clients = Mutex::new(ClientStats::default());
+        let remote: SocketAddr = "10.0.0.1:1234".parse().unwrap();
+        events.send(Event::Connected(ClientId(1), Some(remote))).unwrap();
+        events.send(Event::Connected(ClientId(2), None)).unwrap();
+        events.send(Event::PixelsSet(Some(ClientId(1)), 10)).unwrap();
+        events.send(Event::Disconnected(ClientId(2))).unwrap();
+        events.send(Event::PixelsSet(None, 5)).unwrap();
+        events.send(Event::StormDetected).unwrap();
+        events.send(Event::ConnectionRejected).unwrap();
+        events.send(Event::ConnectionRejected).unwrap();
+        drop(events);
+        aggregate(receiver, &Mailbox::default(), &snapshot, &clients);
+
+        let stats = *snapshot.lock().unwrap();
+        assert_eq!(stats.clients, 1);
+        assert_eq!(stats.pixels_set, 15);
+        assert_eq!(stats.storms_detected, 1);
+        assert_eq!(stats.rejected_connections, 2);
+        let clients = clients.lock().unwrap();
+        assert_eq!(clients.connections.len(), 1);
+        assert_eq!(clients.connections[0].traffic.pixels_set, 10);
+    }
+
+    #[test]
+    #[cfg(feature = "ws")]
+    fn test_aggregate_pong() {
+        let (events, receiver) = mpsc::channel();
+        let snapshot = Mutex::new(ServerStats::default());
+        let clients = Mutex::new(ClientStats::default());
+        events.send(Event::Connected(ClientId(1), None)).unwrap();
+        events
+            .send(Event::Pong(ClientId(1), Duration::from_millis(12)))
+            .unwrap();
+        // pongs of clients which already disconnected are ignored
+        events
+            .send(Event::Pong(ClientId(2), Duration::from_millis(5)))
+            .unwrap();
+        drop(events);
+        aggregate(receiver, &Mailbox::default(), &snapshot, &clients);
+
+        let clients = clients.lock().unwrap();
+        assert_eq!(clients.connections.len(), 1);
+        assert_eq!(clients.connections[0].rtt, Some(Duration::from_millis(12)));
+    }
+
+    #[test]
+    fn test_mailbox_is_bounded() {
+        let mailbox = Mailbox::default();
+        for _ in 0..MAX_PENDING_EVENTS {
+            assert!(mailbox.admit(&Event::PixelsSet(None, 1)));
+        }
+        assert!(!mailbox.admit(&Event::PixelsSet(None, 1)));
+        assert!(!mailbox.admit(&Event::IdleTimeout));
+        assert_eq!(mailbox.dropped.load(Ordering::Relaxed), 2);
+        // connects and disconnects are never dropped so that the number of clients stays exact
+        assert!(mailbox.admit(&Event::Disconnected(ClientId(1))));
+
+        mailbox.received();
+        mailbox.received();
+        assert!(mailbox.admit(&Event::PixelsSet(None, 1)));
+    }
+
+    #[test]
+    fn test_accounting() {
+        let mut accounting = Accounting::default();
+        let first: SocketAddr = "10.0.0.1:1234".parse().unwrap();
+        let second: SocketAddr = "10.0.0.1:1235".parse().unwrap();
+        accounting.connect(ClientId(1), Some(first));
+        accounting.connect(ClientId(2), Some(second));
+        accounting.connect(ClientId(3), None);
+        accounting.account(ClientId(1), |traffic| traffic.bytes_received += 100);
+        accounting.account(ClientId(2), |traffic| traffic.pixels_set += 7);
+        accounting.account(ClientId(3), |traffic| traffic.pixels_set += 3);
+        accounting.disconnect(ClientId(2));
+        accounting.connections.get_mut(&ClientId(1)).unwrap().rtt = Some(Duration::from_millis(12));
+
+        let clients = accounting.snapshot();
+        assert_eq!(
+            clients
+                .connections
+                .iter()
+                .map(|stats| stats.id)
+                .collect::<Vec<_>>(),
+            vec![ClientId(3), ClientId(1)]
+        );
+        assert_eq!(
+            clients.ips,
+            vec![IpStats {
+                ip: first.ip(),
+                connections: 1,
+                traffic: Traffic {
+                    bytes_received: 100,
+                    bytes_sent: 0,
+                    pixels_set: 7,
+                },
+            }]
+        );
+        assert_eq!(
+            clients.ips[0].to_string(),
+            "IP 10.0.0.1 connections=1 bytes-received=100 bytes-sent=0 pixels=7"
+        );
+        assert_eq!(
+            clients.connections[0].to_string(),
+            "CONNECTION id=3 remote=local bytes-received=0 bytes-sent=0 pixels=3"
+        );
+        assert_eq!(
+            clients.connections[1].to_string(),
+            "CONNECTION id=1 remote=10.0.0.1:1234 rtt=12ms bytes-received=100 bytes-sent=0 pixels=0"
+        );
+    }
+}
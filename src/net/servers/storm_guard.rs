@@ -0,0 +1,264 @@
+//! Detection and mitigation of connection storms originating from single IP addresses
+
+use crate::net::servers::access_control;
+use crate::net::servers::statistics::{self, Event};
+use ipnet::IpNet;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// Options for protecting a listener against connection storms
+///
+/// Every IP address has an allowance of `max_connects_per_sec + burst` connections which is used up by opening
+/// connections and which recovers at a rate of `max_connects_per_sec`. All addresses of an IPv6 /64 network share one
+/// allowance.
+/// A connection storm is detected when an address has used up its allowance.
+/// All further connections from that address are then rejected for a backoff duration which starts at
+/// `initial_backoff` and doubles with every repeated storm up to `max_backoff`.
+/// The burst and exemptions apply to the request rate limits of the connections of the listener as well.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct StormProtectionOptions {
+    /// How many connections a single IP address may open per second before it is throttled
+    pub max_connects_per_sec: u32,
+    /// How many connections a single IP address may open in a short burst on top of `max_connects_per_sec`
+    pub burst: u32,
+    /// For how long an IP address is throttled after its first storm
+    pub initial_backoff: Duration,
+    /// The maximum duration for which an IP address is throttled
+    pub max_backoff: Duration,
+    /// Networks whose connections are never throttled, e.g. those of official display bots or organizers
+    pub exemptions: Vec<IpNet>,
+}
+
+impl StormProtectionOptions {
+    /// Whether `ip` is in one of the exempt networks
+    pub(crate) fn exempts(&self, ip: IpAddr) -> bool {
+        self.exemptions.iter().any(|net| net.contains(&ip))
+    }
+}
+
+impl Default for StormProtectionOptions {
+    fn default() -> Self {
+        Self {
+            max_connects_per_sec: 100,
+            burst: 0,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(5 * 60),
+            exemptions: Vec::new(),
+        }
+    }
+}
+
+/// How many IP addresses are tracked at most, after which the least recently seen one is forgotten
+const MAX_PEERS: usize = 64 * 1024;
+
+/// Tracking state of a single remote IP address
+#[derive(Debug, Copy, Clone)]
+struct PeerState {
+    /// How many more connections this peer may currently open
+    allowance: f64,
+    /// When this peer last opened a connection
+    last_connect: Instant,
+    /// How many storms this peer has caused without cooling down in between
+    offenses: u32,
+    /// Until when connections from this peer are rejected
+    throttled_until: Option<Instant>,
+}
+
+/// Accept-side bookkeeping which decides whether a new connection should be admitted
+///
+/// Detected storms and rejected connections are counted by the [statistics](statistics) and reported via STATS.
+#[derive(Debug)]
+pub(crate) struct StormGuard {
+    options: StormProtectionOptions,
+    peers: HashMap<IpAddr, PeerState>,
+    last_cleanup: Instant,
+}
+
+impl StormGuard {
+    pub fn new(options: StormProtectionOptions) -> Self {
+        Self {
+            options,
+            peers: HashMap::new(),
+            last_cleanup: Instant::now(),
+        }
+    }
+
+    /// Register a new connection from `ip` and decide whether it should be admitted
+    ///
+    /// If the connection should be rejected, the remaining duration for which `ip` is throttled is returned.
+    pub fn admit(&mut self, ip: IpAddr) -> Result<(), Duration> {
+        self.admit_at(ip, Instant::now())
+    }
+
+    fn admit_at(&mut self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        if self.options.exempts(ip) {
+            return Ok(());
+        }
+        self.cleanup(now);
+        let ip = access_control::client_key(ip);
+        if self.peers.len() >= MAX_PEERS && !self.peers.contains_key(&ip) {
+            self.forget_least_recent();
+        }
+
+        let rate = self.options.max_connects_per_sec as f64;
+        let capacity = rate + self.options.burst as f64;
+        let peer = self.peers.entry(ip).or_insert(PeerState {
+            allowance: capacity,
+            last_connect: now,
+            offenses: 0,
+            throttled_until: None,
+        });
+
+        // reject connections while the peer is still throttled
+        if let Some(throttled_until) = peer.throttled_until {
+            if throttled_until > now {
+                statistics::record(Event::ConnectionRejected);
+                return Err(throttled_until - now);
+            }
+            peer.throttled_until = None;
+            peer.allowance = capacity;
+            peer.last_connect = now;
+        }
+
+        // forgive previous offenses if the peer behaved for long enough
+        let idle = now.duration_since(peer.last_connect);
+        if idle >= self.options.max_backoff {
+            peer.offenses = 0;
+        }
+
+        // recover the allowance since the last connection and use it up for this one
+        peer.allowance = (peer.allowance + idle.as_secs_f64() * rate).min(capacity);
+        peer.last_connect = now;
+        if peer.allowance >= 1.0 {
+            peer.allowance -= 1.0;
+            return Ok(());
+        }
+
+        // start throttling since the peer opened too many connections
+        let backoff = self
+            .options
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(peer.offenses))
+            .min(self.options.max_backoff);
+        peer.offenses = peer.offenses.saturating_add(1);
+        peer.throttled_until = Some(now + backoff);
+        statistics::record(Event::StormDetected);
+        statistics::record(Event::ConnectionRejected);
+        tracing::warn!(
+            "Detected connection storm from {} (more than {} connections per second), throttling it for {:?}",
+            ip,
+            self.options.max_connects_per_sec,
+            backoff
+        );
+        Err(backoff)
+    }
+
+    /// Forget about peers which have not been seen or throttled for a long time
+    fn cleanup(&mut self, now: Instant) {
+        if now.duration_since(self.last_cleanup) < Duration::from_secs(10) {
+            return;
+        }
+        self.last_cleanup = now;
+
+        let max_backoff = self.options.max_backoff;
+        self.peers.retain(|_, peer| match peer.throttled_until {
+            Some(throttled_until) => throttled_until > now,
+            None => now.duration_since(peer.last_connect) < max_backoff,
+        });
+    }
+
+    /// Make room for a new peer by forgetting the one which connected least recently
+    fn forget_least_recent(&mut self) {
+        let oldest = self
+            .peers
+            .iter()
+            .min_by_key(|(_, peer)| peer.last_connect)
+            .map(|(ip, _)| *ip);
+        if let Some(ip) = oldest {
+            self.peers.remove(&ip);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_storm_is_throttled_with_backoff() {
+        let mut guard = StormGuard::new(StormProtectionOptions {
+            max_connects_per_sec: 3,
+            burst: 0,
+            initial_backoff: Duration::from_secs(2),
+            max_backoff: Duration::from_secs(60),
+            exemptions: Vec::new(),
+        });
+        let storming = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let other = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert_eq!(guard.admit_at(storming, start), Ok(()));
+        }
+        assert_eq!(guard.admit_at(storming, start), Err(Duration::from_secs(2)));
+        assert!(guard.admit_at(storming, start).is_err());
+        assert_eq!(guard.admit_at(other, start), Ok(()));
+
+        // the second storm is throttled for twice as long
+        let later = start + Duration::from_secs(2);
+        for _ in 0..3 {
+            assert_eq!(guard.admit_at(storming, later), Ok(()));
+        }
+        assert_eq!(guard.admit_at(storming, later), Err(Duration::from_secs(4)));
+    }
+
+    #[test]
+    fn test_burst_and_exemptions() {
+        let mut guard = StormGuard::new(StormProtectionOptions {
+            max_connects_per_sec: 2,
+            burst: 2,
+            exemptions: vec!["10.1.0.0/16".parse().unwrap()],
+            ..Default::default()
+        });
+        let peer = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let exempt = IpAddr::V4(Ipv4Addr::new(10, 1, 2, 3));
+        let start = Instant::now();
+
+        // a burst may exceed the rate once
+        for _ in 0..4 {
+            assert_eq!(guard.admit_at(peer, start), Ok(()));
+        }
+        // the allowance recovers at the configured rate
+        let later = start + Duration::from_millis(500);
+        assert_eq!(guard.admit_at(peer, later), Ok(()));
+        assert!(guard.admit_at(peer, later).is_err());
+
+        for _ in 0..100 {
+            assert_eq!(guard.admit_at(exempt, start), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_ipv6_networks() {
+        let mut guard = StormGuard::new(StormProtectionOptions {
+            max_connects_per_sec: 2,
+            ..Default::default()
+        });
+        let start = Instant::now();
+        let addr = |ip: &str| ip.parse::<IpAddr>().unwrap();
+
+        // rotating addresses within a /64 does not escape the throttling
+        assert_eq!(guard.admit_at(addr("2001:db8::1"), start), Ok(()));
+        assert_eq!(guard.admit_at(addr("2001:db8::2"), start), Ok(()));
+        assert!(guard.admit_at(addr("2001:db8::3"), start).is_err());
+        assert_eq!(guard.admit_at(addr("2001:db8:0:1::1"), start), Ok(()));
+
+        // the number of tracked peers is bounded
+        for i in 0..MAX_PEERS as u32 {
+            let _ = guard.admit_at(IpAddr::V4(Ipv4Addr::from(i)), start);
+        }
+        assert_eq!(guard.peers.len(), MAX_PEERS);
+    }
+}
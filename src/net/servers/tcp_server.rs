@@ -1,82 +1,397 @@
-use crate::net::servers::GenServer;
+use crate::net::framing::FrameBuffer;
+use crate::net::protocol::{Features, ResponseError};
+use crate::net::servers::compression::ResponseEncoder;
+use crate::net::servers::connection_limit::{self, ConnectionLimiter, ConnectionSlot, Gatekeeper};
+use crate::net::servers::proxy_protocol;
+use crate::net::servers::rate_limit::{RateLimiter, Usage};
+use crate::net::servers::socket_activation;
+use crate::net::servers::sockets;
+use crate::net::servers::state_stream::StateStream;
+use crate::net::servers::statistics::{self, ConnectionGuard, Event};
+use crate::net::servers::storm_guard::StormGuard;
+#[cfg(feature = "io-uring")]
+use crate::net::servers::tcp_uring::{Connection, UringWorkers};
+#[cfg(feature = "tls")]
+use crate::net::servers::TlsOptions;
+use crate::net::servers::{
+    AccessControlOptions, BufferOptions, ClaimMode, ConnectionPreferences, GenServer, ListenerCapabilities,
+    RateLimitOptions, Reloadable, SocketOptions, StormProtectionOptions, WriteProtectionOptions,
+    PIPELINE_FLUSH_LEN,
+};
 use crate::pixmap::SharedPixmap;
+use crate::shutdown::{self, Phase};
+use crate::texts;
 use crate::DaemonResult;
 use async_trait::async_trait;
 use bytes::{BufMut, BytesMut};
-use std::io::Write;
 use std::net::SocketAddr;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::os::fd::AsFd;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::task::{AbortHandle, JoinSet};
+#[cfg(feature = "tls")]
+use tokio_rustls::TlsAcceptor;
+
+/// How long a client may take to complete the TLS handshake after it connected
+#[cfg(feature = "tls")]
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// Options with which the `TcpServer` is configured
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct TcpServerOptions {
     /// The address to which the server binds
     pub bind_addr: SocketAddr,
+    /// Tuning of the listening socket and of the sockets of connections
+    pub socket: SocketOptions,
+    /// The networks from which connections are accepted
+    ///
+    /// Proxied connections are checked against the address of the client from the PROXY protocol header.
+    pub access_control: Reloadable<AccessControlOptions>,
+    /// Whether and how connection storms from single IP addresses should be throttled
+    pub storm_protection: Option<StormProtectionOptions>,
+    /// How many connections a single IP address may have open at the same time, or `None` for no limit
+    pub max_connections_per_ip: Option<usize>,
+    /// How many requests and pixels a single connection may send and draw per second
+    pub rate_limit: Reloadable<RateLimitOptions>,
+    /// After which duration without requests connections are closed, or `None` to keep them open forever
+    pub idle_timeout: Option<Duration>,
+    /// The sizes of the buffers of connections and how long their request lines may be
+    pub buffers: BufferOptions,
+    /// Whether connections must authenticate before they may draw on the canvas
+    pub write_protection: Option<WriteProtectionOptions>,
+    /// How drawing into regions which other connections claimed is treated, if regions can be claimed via CLAIM
+    pub claims: Option<ClaimMode>,
+    /// The optional protocol features which clients may use, or `None` to enable all which the server supports
+    pub features: Option<Features>,
+    /// Whether connections start with a PROXY protocol header which carries the actual address of the client
+    ///
+    /// This must only be enabled when the listener is exclusively reachable via a proxy which sends the header
+    /// since clients could otherwise claim arbitrary addresses.
+    pub proxy_protocol: bool,
+    /// How many threads handle connections with io_uring instead of the tokio runtime, or `None` to not use
+    /// io_uring
+    ///
+    /// Only unencrypted connections can be handled this way.
+    #[cfg(feature = "io-uring")]
+    pub io_uring_workers: Option<usize>,
+    /// The certificate with which connections are encrypted, or `None` to accept unencrypted connections
+    #[cfg(feature = "tls")]
+    pub tls: Option<TlsOptions>,
+}
+
+/// Everything which the acceptors of a listener and the tasks of their connections share
+#[derive(Clone)]
+struct ListenerContext {
+    pixmap: SharedPixmap,
+    capabilities: ListenerCapabilities,
+    gatekeeper: Gatekeeper,
+    socket: SocketOptions,
+    proxy_protocol: bool,
+    #[cfg(feature = "io-uring")]
+    uring_workers: Option<UringWorkers>,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsAcceptor>,
+}
+
+impl ListenerContext {
+    /// Whether connections are encrypted so that nothing can be sent to them before the TLS handshake
+    fn encrypted(&self) -> bool {
+        #[cfg(feature = "tls")]
+        return self.tls.is_some();
+        #[cfg(not(feature = "tls"))]
+        return false;
+    }
 }
 
 /// A server implementation using TCP to transport pixelflut messages.
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub struct TcpServer {
     options: TcpServerOptions,
 }
 
 impl TcpServer {
+    /// Start `n` listeners on the same address between which the kernel balances new connections
+    ///
+    /// The listeners are bound with `SO_REUSEPORT` and accept connections in their own tasks so that connection
+    /// storms are spread across all runtime workers instead of pegging a single one.
+    /// Storm protection and per-IP connection limits are shared between the listeners.
+    pub async fn start_many(
+        self,
+        pixmap: SharedPixmap,
+        n: usize,
+        join_set: &mut JoinSet<DaemonResult>,
+    ) -> anyhow::Result<Vec<AbortHandle>> {
+        let context = self.context(pixmap).await?;
+        if let Some(listener) = sockets::passed_tcp_listener(self.options.bind_addr, &self.options.socket)? {
+            tracing::warn!(
+                "Accepting connections on {} with a single acceptor since its socket was passed to the server",
+                self.options.bind_addr
+            );
+            statistics::start();
+            let handle = join_set
+                .build_task()
+                .name("tcp_server")
+                .spawn(async move { TcpServer::handle_listener(listener, context).await })?;
+            return Ok(vec![handle]);
+        }
+        let listeners = (0..n)
+            .map(|_| {
+                let socket = sockets::tcp_socket(self.options.bind_addr, &self.options.socket)?;
+                socket.set_reuseaddr(true)?;
+                socket.set_reuseport(true)?;
+                socket.bind(self.options.bind_addr)?;
+                socket.listen(1024)
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+        // a new process which takes over the listeners accepts all connections on a single one of them
+        if let Some(listener) = listeners.first() {
+            socket_activation::remember(listener.as_fd());
+        }
+        statistics::start();
+        tracing::info!(
+            "Started TCP Server on {} with {} acceptors",
+            self.options.bind_addr,
+            n
+        );
+
+        listeners
+            .into_iter()
+            .enumerate()
+            .map(|(i, listener)| {
+                let context = context.clone();
+                let handle = join_set
+                    .build_task()
+                    .name(&format!("tcp_server{}", i))
+                    .spawn(async move { TcpServer::handle_listener(listener, context).await })?;
+                Ok(handle)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()
+    }
+
+    async fn context(&self, pixmap: SharedPixmap) -> anyhow::Result<ListenerContext> {
+        #[cfg(all(feature = "io-uring", feature = "tls"))]
+        if self.options.io_uring_workers.is_some() && self.options.tls.is_some() {
+            anyhow::bail!("TLS connections cannot be handled by io_uring workers");
+        }
+        Ok(ListenerContext {
+            pixmap,
+            capabilities: self.capabilities(),
+            gatekeeper: Gatekeeper {
+                access_control: self.options.access_control.clone(),
+                storm_guard: self.storm_guard(),
+                connection_limiter: self.options.max_connections_per_ip.map(ConnectionLimiter::new),
+            },
+            socket: self.options.socket,
+            proxy_protocol: self.options.proxy_protocol,
+            #[cfg(feature = "io-uring")]
+            uring_workers: match self.options.io_uring_workers {
+                None => None,
+                Some(n) => Some(UringWorkers::start(n).await?),
+            },
+            #[cfg(feature = "tls")]
+            tls: self.options.tls.as_ref().map(TlsOptions::acceptor).transpose()?,
+        })
+    }
+
+    fn capabilities(&self) -> ListenerCapabilities {
+        ListenerCapabilities {
+            binary_protocol: true,
+            image_upload: cfg!(feature = "images"),
+            state_streaming: true,
+            compression: true,
+            max_line_len: Some(self.options.buffers.max_line_len),
+            buffers: self.options.buffers,
+            storm_protection: self.options.storm_protection.clone().map(Arc::new),
+            max_connections_per_ip: self.options.max_connections_per_ip,
+            rate_limit: self.options.rate_limit.clone(),
+            idle_timeout: self.options.idle_timeout,
+            write_protection: self.options.write_protection.clone().map(Arc::new),
+            claims: self.options.claims,
+            enabled_features: self.options.features,
+        }
+    }
+
+    fn storm_guard(&self) -> Option<Arc<Mutex<StormGuard>>> {
+        self.options
+            .storm_protection
+            .clone()
+            .map(|options| Arc::new(Mutex::new(StormGuard::new(options))))
+    }
+
     #[tracing::instrument(skip_all)]
-    async fn handle_listener(listener: TcpListener, pixmap: SharedPixmap) -> anyhow::Result<!> {
+    async fn handle_listener(listener: TcpListener, context: ListenerContext) -> anyhow::Result<!> {
         loop {
             let (stream, remote_addr) = listener.accept().await?;
-            let pixmap = pixmap.clone();
+            let remote_addr = sockets::peer_addr(remote_addr);
+            // without the PROXY protocol, connections are admitted right away so that rejected ones cost nothing
+            let slot = match context.proxy_protocol {
+                true => None,
+                false => match context.gatekeeper.admit(remote_addr) {
+                    Ok(slot) => slot,
+                    Err(error) => {
+                        Self::reject(stream, &error, context.encrypted());
+                        continue;
+                    }
+                },
+            };
+            let context = context.clone();
             tokio::spawn(async move {
-                if let Err(e) = TcpServer::handle_connection(stream, remote_addr, pixmap).await {
+                if let Err(e) = TcpServer::accept(stream, remote_addr, slot, context).await {
                     tracing::warn!("Got error while handling tcp connection: {e}");
                 }
             });
         }
     }
 
-    #[tracing::instrument(skip_all, fields(remote = _remote_addr.to_string()))]
-    async fn handle_connection(
+    /// Perform everything which precedes the pixelflut protocol on a new connection and then handle it
+    ///
+    /// Connections which are proxied are only admitted once the PROXY protocol header revealed the address of
+    /// the client.
+    async fn accept(
         mut stream: TcpStream,
-        _remote_addr: SocketAddr,
+        mut remote_addr: SocketAddr,
+        mut slot: Option<ConnectionSlot>,
+        context: ListenerContext,
+    ) -> anyhow::Result<()> {
+        context.socket.apply_to_stream(&stream)?;
+        if context.proxy_protocol {
+            if let Some(client_addr) = proxy_protocol::read_header(&mut stream).await? {
+                remote_addr = sockets::peer_addr(client_addr);
+            }
+            slot = match context.gatekeeper.admit(remote_addr) {
+                Ok(slot) => slot,
+                Err(error) => {
+                    Self::reject(stream, &error, context.encrypted());
+                    return Ok(());
+                }
+            };
+        }
+        #[cfg(feature = "io-uring")]
+        if let Some(workers) = &context.uring_workers {
+            return workers.dispatch(Connection {
+                stream: stream.into_std()?,
+                remote_addr,
+                slot,
+                pixmap: context.pixmap,
+                capabilities: context.capabilities,
+            });
+        }
+        let _slot = slot;
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &context.tls {
+            let stream = tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tls.accept(stream))
+                .await
+                .map_err(|_| {
+                    anyhow::anyhow!(
+                        "Client did not complete the TLS handshake within {:?}",
+                        TLS_HANDSHAKE_TIMEOUT
+                    )
+                })??;
+            return Self::handle_connection(stream, remote_addr, context.pixmap, context.capabilities).await;
+        }
+        Self::handle_connection(stream, remote_addr, context.pixmap, context.capabilities).await
+    }
+
+    /// Tell a client why its connection is rejected before it is closed
+    fn reject(stream: TcpStream, error: &ResponseError, encrypted: bool) {
+        if !encrypted {
+            connection_limit::reject(stream, format!("{}\n", texts::error_text(error)).as_bytes());
+        }
+    }
+
+    /// Handle all requests of a connection until it is closed
+    ///
+    /// This is also used for the streams of QUIC connections, which carry the same protocol.
+    #[tracing::instrument(skip_all, fields(remote = remote_addr.to_string(), nick = tracing::field::Empty))]
+    pub(super) async fn handle_connection(
+        mut stream: impl AsyncRead + AsyncWrite + Unpin,
+        remote_addr: SocketAddr,
         pixmap: SharedPixmap,
+        capabilities: ListenerCapabilities,
     ) -> anyhow::Result<()> {
-        const MAX_LINE_LEN: usize = 32;
         tracing::debug!("Client connected");
+        let connection = ConnectionGuard::new(Some(remote_addr));
+        let shutdown = shutdown::Guard::new(Phase::Draining);
 
-        let mut req_buf = BytesMut::with_capacity(8 * 1024);
-        let mut resp_buf = BytesMut::with_capacity(2 * 1024).writer();
+        let mut req_buf = FrameBuffer::with_capacity(capabilities.buffers.read_buffer_size)
+            .with_max_line_len(capabilities.max_line_len);
+        let mut resp_buf = BytesMut::with_capacity(capabilities.buffers.write_buffer_size).writer();
+        let mut preferences = ConnectionPreferences {
+            client: Some(connection.id()),
+            remote: Some(remote_addr.ip()),
+            ..Default::default()
+        };
+        let mut state_stream = StateStream::default();
+        let mut response_encoder = ResponseEncoder::default();
+        let mut rate_limiter = RateLimiter::new(capabilities.rate_limit.clone())
+            .with_storm_protection(capabilities.storm_protection.as_deref(), remote_addr.ip());
         loop {
-            // fill the line buffer from the network
-            let n = stream.read_buf(&mut req_buf).await?;
+            // fill the line buffer from the network or send the next frame of a requested state stream
+            let n = tokio::select! {
+                n = stream.read_buf(req_buf.data_mut()) => n?,
+                settings = state_stream.tick(preferences.stream) => {
+                    state_stream.write_frame(&pixmap, settings, &mut resp_buf)?;
+                    let sent = response_encoder.send(&mut stream, resp_buf.get_mut()).await?;
+                    connection.transferred(0, sent);
+                    continue;
+                }
+                _ = super::idle(capabilities.idle_timeout, preferences.stream.is_some()) => {
+                    tracing::debug!("Closing connection which did not send anything for {:?}", capabilities.idle_timeout);
+                    statistics::record(Event::IdleTimeout);
+                    return Ok(());
+                }
+                _ = shutdown.requested() => {
+                    tracing::debug!("Closing connection since the server shuts down");
+                    super::write_shutdown_notice(preferences.protocol, &mut resp_buf);
+                    response_encoder.send(&mut stream, resp_buf.get_mut()).await?;
+                    stream.shutdown().await?;
+                    return Ok(());
+                }
+            };
             if n == 0 {
                 tracing::debug!("Client stream exhausted, likely disconnected");
                 return Ok(());
             }
             tracing::trace!("Received {}KiB stream data: {:?}", n / 1024, req_buf);
 
-            // handle all lines contained in the buffer
-            while let Some((i, _)) = req_buf.iter().enumerate().find(|(_, &b)| b == b'\n') {
-                let line = req_buf.split_to(i + 1);
-                let result = super::handle_request(&line, &pixmap);
-                match result {
-                    Err(e) => {
-                        resp_buf.write_fmt(format_args!("{}\n", e)).unwrap();
+            // handle all frames contained in the buffer as well as those of requests which the client already sent
+            // meanwhile so that pipelined requests are answered with few large writes
+            let mut usage = Usage::default();
+            let mut received = n;
+            let mut sent = 0;
+            let mut closed = false;
+            loop {
+                loop {
+                    usage += super::handle_frames(
+                        &mut req_buf,
+                        &mut resp_buf,
+                        &pixmap,
+                        &mut preferences,
+                        &capabilities,
+                    );
+                    if preferences.compression == response_encoder.compression() {
+                        break;
                     }
-                    Ok(Some(response)) => response.write(&mut resp_buf).unwrap(),
-                    Ok(None) => {}
+                    // responses up to the confirmation of a new compression still use the previous one
+                    sent += response_encoder.send(&mut stream, resp_buf.get_mut()).await?;
+                    let rest = response_encoder.switch(preferences.compression)?;
+                    stream.write_all(&rest).await?;
+                    sent += rest.len();
+                }
+                super::discard_overlong_frame(&mut req_buf, &mut resp_buf, preferences.protocol);
+                if resp_buf.get_ref().len() >= PIPELINE_FLUSH_LEN || received >= PIPELINE_FLUSH_LEN {
+                    break;
+                }
+                match super::now_or_never(stream.read_buf(req_buf.data_mut())).transpose()? {
+                    None => break,
+                    Some(0) => {
+                        closed = true;
+                        break;
+                    }
+                    Some(n) => received += n,
                 }
-            }
-
-            // clear the buffer if someone is deliberately not sending a newline
-            if req_buf.len() > MAX_LINE_LEN {
-                tracing::warn!(
-                    "Request buffer has {}B but no lines left in it. Client is probably misbehaving.",
-                    req_buf.len()
-                );
-                req_buf.clear();
-                resp_buf.write_all("line too long\n".as_bytes()).unwrap();
             }
 
             // write accumulated responses back to the sender
@@ -86,8 +401,16 @@ impl TcpServer {
                     resp_buf.get_ref().len() / 1024,
                     resp_buf.get_ref()
                 );
-                stream.write_all_buf(resp_buf.get_mut()).await?;
+                sent += response_encoder.send(&mut stream, resp_buf.get_mut()).await?;
             }
+            connection.transferred(received, sent);
+            if closed {
+                tracing::debug!("Client stream exhausted, likely disconnected");
+                return Ok(());
+            }
+
+            // stop reading further requests while the connection exceeds its rate limits
+            rate_limiter.throttle(usage).await;
         }
     }
 }
@@ -105,13 +428,73 @@ impl GenServer for TcpServer {
         pixmap: SharedPixmap,
         join_set: &mut JoinSet<DaemonResult>,
     ) -> anyhow::Result<AbortHandle> {
-        let listener = TcpListener::bind(self.options.bind_addr).await?;
+        let context = self.context(pixmap).await?;
+        let listener = sockets::tcp_listener(self.options.bind_addr, &self.options.socket)?;
+        statistics::start();
         tracing::info!("Started TCP Server on {}", self.options.bind_addr);
 
         let handle = join_set
             .build_task()
             .name("tcp_server")
-            .spawn(async move { TcpServer::handle_listener(listener, pixmap).await })?;
+            .spawn(async move { TcpServer::handle_listener(listener, context).await })?;
         Ok(handle)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pixmap::Pixmap;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    #[tokio::test]
+    async fn test_acceptors_share_connection_limits() {
+        // all acceptors must be bound to the same port, which is only known after binding the first one
+        let bind_addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let server = TcpServer::new(TcpServerOptions {
+            bind_addr,
+            socket: SocketOptions::default(),
+            access_control: Reloadable::default(),
+            storm_protection: None,
+            max_connections_per_ip: Some(3),
+            rate_limit: Reloadable::default(),
+            idle_timeout: None,
+            buffers: BufferOptions::default(),
+            write_protection: None,
+            claims: None,
+            features: None,
+            proxy_protocol: false,
+            #[cfg(feature = "io-uring")]
+            io_uring_workers: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+        });
+        let mut join_set = JoinSet::new();
+        let handles = server
+            .start_many(Arc::new(Pixmap::new(4, 2).unwrap()), 4, &mut join_set)
+            .await
+            .unwrap();
+        assert_eq!(handles.len(), 4);
+
+        let response = |client: TcpStream| async move {
+            let mut client = BufReader::new(client);
+            client.write_all(b"SIZE\n").await.unwrap();
+            let mut line = String::new();
+            client.read_line(&mut line).await.unwrap();
+            (client, line)
+        };
+        let mut clients = Vec::new();
+        for _ in 0..3 {
+            let (client, line) = response(TcpStream::connect(bind_addr).await.unwrap()).await;
+            assert_eq!(line, "SIZE 4 2\n");
+            clients.push(client);
+        }
+
+        // the connections are counted together no matter which acceptor they were balanced to
+        let (_, line) = response(TcpStream::connect(bind_addr).await.unwrap()).await;
+        assert!(line.starts_with("ERR "), "{}", line);
+    }
+}
@@ -0,0 +1,298 @@
+//! A completion-based fast path for plain TCP connections which uses io_uring on Linux
+//!
+//! Connections are still accepted and admitted by the regular [`TcpServer`](super::TcpServer) but are then handed
+//! to a pool of worker threads which each drive their connections with their own io_uring instance.
+//! This saves the readiness notifications and most of the syscalls which the epoll based runtime needs for every
+//! read and write.
+//! Every connection keeps its buffers for its whole lifetime so that the kernel always reads into and writes
+//! from the same memory.
+//! The released versions of `tokio-uring` do not support registered buffers yet, which would additionally save
+//! mapping these buffers for every operation.
+
+use crate::net::framing::FrameBuffer;
+use crate::net::servers::compression::ResponseEncoder;
+use crate::net::servers::connection_limit::ConnectionSlot;
+use crate::net::servers::rate_limit::{RateLimiter, Usage};
+use crate::net::servers::state_stream::StateStream;
+use crate::net::servers::statistics::{self, ConnectionGuard, Event};
+use crate::net::servers::{ConnectionPreferences, ListenerCapabilities};
+use crate::pixmap::SharedPixmap;
+use crate::shutdown::{self, Phase};
+use bytes::{BufMut, Bytes, BytesMut};
+use std::borrow::Cow;
+use std::net::{Shutdown, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+use tokio_uring::buf::IoBuf;
+use tokio_uring::net::TcpStream;
+
+/// A connection which was accepted and admitted by a listener and is handed to a worker
+#[derive(Debug)]
+pub(crate) struct Connection {
+    pub stream: std::net::TcpStream,
+    pub remote_addr: SocketAddr,
+    pub slot: Option<ConnectionSlot>,
+    pub pixmap: SharedPixmap,
+    pub capabilities: ListenerCapabilities,
+}
+
+/// A pool of threads which each run an io_uring driven runtime and handle the connections which are dispatched
+/// to them
+#[derive(Debug, Clone)]
+pub(crate) struct UringWorkers {
+    workers: Arc<[mpsc::UnboundedSender<Connection>]>,
+    next: Arc<AtomicUsize>,
+}
+
+impl UringWorkers {
+    /// Start `n` worker threads
+    ///
+    /// This fails if the kernel does not support io_uring or its use is forbidden, e.g. by a seccomp filter.
+    /// The workers stop once the pool and all of its clones are dropped and their connections are closed.
+    pub async fn start(n: usize) -> anyhow::Result<Self> {
+        let mut workers = Vec::with_capacity(n);
+        for i in 0..n {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            let (started, result) = oneshot::channel();
+            std::thread::Builder::new()
+                .name(format!("uring_worker{}", i))
+                .spawn(move || Self::run(receiver, started))?;
+            result.await??;
+            workers.push(sender);
+        }
+        tracing::info!("Started {} io_uring workers", n);
+        Ok(Self {
+            workers: workers.into(),
+            next: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    fn run(
+        mut connections: mpsc::UnboundedReceiver<Connection>,
+        started: oneshot::Sender<std::io::Result<()>>,
+    ) {
+        let runtime = match tokio_uring::Runtime::new(&tokio_uring::builder()) {
+            Ok(runtime) => runtime,
+            Err(e) => {
+                let _ = started.send(Err(e));
+                return;
+            }
+        };
+        let _ = started.send(Ok(()));
+        runtime.block_on(async move {
+            // every connection holds a sender so that the worker can wait for all of them to close
+            let (open, mut all_closed) = mpsc::channel::<()>(1);
+            while let Some(connection) = connections.recv().await {
+                let open = open.clone();
+                tokio_uring::spawn(async move {
+                    let _open = open;
+                    let _slot = connection.slot;
+                    let stream = TcpStream::from_std(connection.stream);
+                    let result = handle_connection(
+                        stream,
+                        connection.remote_addr,
+                        connection.pixmap,
+                        connection.capabilities,
+                    )
+                    .await;
+                    if let Err(e) = result {
+                        tracing::warn!("Got error while handling tcp connection: {e}");
+                    }
+                });
+            }
+            // the listener stopped but its connections must still be drained if the server shuts down
+            drop(open);
+            let _ = all_closed.recv().await;
+        });
+    }
+
+    /// Hand a connection to the next worker
+    pub fn dispatch(&self, connection: Connection) -> anyhow::Result<()> {
+        // io_uring waits for sockets to become ready by itself while the runtime used them in non-blocking mode
+        connection.stream.set_nonblocking(false)?;
+        let i = self.next.fetch_add(1, Ordering::Relaxed) % self.workers.len();
+        self.workers[i]
+            .send(connection)
+            .map_err(|_| anyhow::anyhow!("io_uring worker {} has stopped", i))
+    }
+}
+
+/// Handle all requests of a connection until it is closed
+///
+/// This is the same as [`TcpServer::handle_connection`](super::TcpServer) except that reads are kept in flight
+/// while state stream frames are sent since cancelling them would lose the data which they read.
+#[tracing::instrument(skip_all, fields(remote = remote_addr.to_string(), nick = tracing::field::Empty))]
+async fn handle_connection(
+    stream: TcpStream,
+    remote_addr: SocketAddr,
+    pixmap: SharedPixmap,
+    capabilities: ListenerCapabilities,
+) -> anyhow::Result<()> {
+    tracing::debug!("Client connected");
+    let connection = ConnectionGuard::new(Some(remote_addr));
+    let shutdown = shutdown::Guard::new(Phase::Draining);
+
+    let read_size = capabilities.buffers.read_buffer_size;
+    let mut req_buf = FrameBuffer::with_capacity(read_size).with_max_line_len(capabilities.max_line_len);
+    let mut resp_buf = BytesMut::with_capacity(capabilities.buffers.write_buffer_size).writer();
+    let mut preferences = ConnectionPreferences {
+        client: Some(connection.id()),
+        remote: Some(remote_addr.ip()),
+        ..Default::default()
+    };
+    let mut state_stream = StateStream::default();
+    let mut response_encoder = ResponseEncoder::default();
+    let mut rate_limiter = RateLimiter::new(capabilities.rate_limit.clone())
+        .with_storm_protection(capabilities.storm_protection.as_deref(), remote_addr.ip());
+    let mut pending_read = Box::pin(read(&stream, std::mem::take(req_buf.data_mut()), read_size));
+    loop {
+        // wait for the pending read to complete or send the next frame of a requested state stream
+        let n = tokio::select! {
+            (n, data) = &mut pending_read => {
+                *req_buf.data_mut() = data;
+                n?
+            }
+            settings = state_stream.tick(preferences.stream) => {
+                state_stream.write_frame(&pixmap, settings, &mut resp_buf)?;
+                let sent = send(&stream, &mut response_encoder, resp_buf.get_mut()).await?;
+                connection.transferred(0, sent);
+                continue;
+            }
+            _ = super::idle(capabilities.idle_timeout, preferences.stream.is_some()) => {
+                tracing::debug!("Closing connection which did not send anything for {:?}", capabilities.idle_timeout);
+                statistics::record(Event::IdleTimeout);
+                // the pending read keeps the socket open until it completes, which this forces
+                stream.shutdown(Shutdown::Both)?;
+                return Ok(());
+            }
+            _ = shutdown.requested() => {
+                tracing::debug!("Closing connection since the server shuts down");
+                super::write_shutdown_notice(preferences.protocol, &mut resp_buf);
+                send(&stream, &mut response_encoder, resp_buf.get_mut()).await?;
+                stream.shutdown(Shutdown::Write)?;
+                return Ok(());
+            }
+        };
+        if n == 0 {
+            tracing::debug!("Client stream exhausted, likely disconnected");
+            return Ok(());
+        }
+        tracing::trace!("Received {}KiB stream data: {:?}", n / 1024, req_buf);
+
+        // handle all frames contained in the buffer
+        let mut usage = Usage::default();
+        let mut sent = 0;
+        loop {
+            usage += super::handle_frames(
+                &mut req_buf,
+                &mut resp_buf,
+                &pixmap,
+                &mut preferences,
+                &capabilities,
+            );
+            if preferences.compression == response_encoder.compression() {
+                break;
+            }
+            // responses up to the confirmation of a new compression still use the previous one
+            sent += send(&stream, &mut response_encoder, resp_buf.get_mut()).await?;
+            let rest = response_encoder.switch(preferences.compression)?;
+            sent += rest.len();
+            stream.write_all(rest).await.0?;
+        }
+        super::discard_overlong_frame(&mut req_buf, &mut resp_buf, preferences.protocol);
+
+        // write accumulated responses back to the sender
+        if !resp_buf.get_ref().is_empty() {
+            tracing::trace!(
+                "Sending back {}KiB response: {:?}",
+                resp_buf.get_ref().len() / 1024,
+                resp_buf.get_ref()
+            );
+            sent += send(&stream, &mut response_encoder, resp_buf.get_mut()).await?;
+        }
+        connection.transferred(n, sent);
+
+        // stop reading further requests while the connection exceeds its rate limits
+        rate_limiter.throttle(usage).await;
+        pending_read.set(read(&stream, std::mem::take(req_buf.data_mut()), read_size));
+    }
+}
+
+/// Read data from the network into the spare capacity of `buf` and return it together with the buffer
+///
+/// The buffer is grown beforehand if less than half of `read_size` is left.
+async fn read(stream: &TcpStream, mut buf: BytesMut, read_size: usize) -> (std::io::Result<usize>, BytesMut) {
+    if buf.capacity() - buf.len() < read_size / 2 {
+        buf.reserve(read_size);
+    }
+    let len = buf.len();
+    let (n, slice) = stream.read(buf.slice(len..)).await;
+    (n, slice.into_inner())
+}
+
+/// Compress all data in `buf` and send it to the client
+///
+/// Returns the number of bytes which were sent after compression.
+async fn send(
+    stream: &TcpStream,
+    encoder: &mut ResponseEncoder,
+    buf: &mut BytesMut,
+) -> std::io::Result<usize> {
+    if buf.is_empty() {
+        return Ok(0);
+    }
+    let compressed = match encoder.encode(buf)? {
+        Cow::Borrowed(_) => None,
+        Cow::Owned(data) => Some(data),
+    };
+    // uncompressed data is sent straight from the response buffer whose memory is reclaimed afterward
+    let data = match compressed {
+        None => buf.split().freeze(),
+        Some(data) => {
+            buf.clear();
+            Bytes::from(data)
+        }
+    };
+    let sent = data.len();
+    stream.write_all(data).await.0?;
+    Ok(sent)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pixmap::{Color, Pixmap};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_dispatched_connection() {
+        let workers = match UringWorkers::start(2).await {
+            Ok(workers) => workers,
+            // the system may not support io_uring at all
+            Err(_) => return,
+        };
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = tokio::net::TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, remote_addr) = listener.accept().unwrap();
+        let pixmap = Arc::new(Pixmap::new(4, 2).unwrap());
+        workers
+            .dispatch(Connection {
+                stream,
+                remote_addr,
+                slot: None,
+                pixmap: pixmap.clone(),
+                capabilities: ListenerCapabilities::default(),
+            })
+            .unwrap();
+
+        client.write_all(b"PX 1 0 FF0000\nSIZE\nPX 1 0\n").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut responses = String::new();
+        client.read_to_string(&mut responses).await.unwrap();
+        assert_eq!(responses, "SIZE 4 2\nPX 1 0 FF0000\n");
+        assert_eq!(pixmap.get_pixel(1, 0).unwrap(), Color::from((0xFF, 0, 0)));
+    }
+}
@@ -0,0 +1,40 @@
+//! Encryption of TCP connections with TLS and of QUIC connections
+
+use anyhow::Context;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+/// The certificate with which a TCP or QUIC listener encrypts its connections
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TlsOptions {
+    /// A PEM file containing the certificate chain, starting with the certificate of the server itself
+    pub cert_path: PathBuf,
+    /// A PEM file containing the private key of the certificate
+    pub key_path: PathBuf,
+}
+
+impl TlsOptions {
+    /// Load the certificate and its key into an acceptor which performs the TLS handshake of new connections
+    pub(crate) fn acceptor(&self) -> anyhow::Result<TlsAcceptor> {
+        Ok(TlsAcceptor::from(Arc::new(self.server_config()?)))
+    }
+
+    /// Load the certificate and its key into a server configuration
+    pub(crate) fn server_config(&self) -> anyhow::Result<ServerConfig> {
+        let certs = CertificateDer::pem_file_iter(&self.cert_path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .with_context(|| format!("Could not read certificates from {}", self.cert_path.display()))?;
+        let key = PrivateKeyDer::from_pem_file(&self.key_path)
+            .with_context(|| format!("Could not read private key from {}", self.key_path.display()))?;
+        let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+        Ok(config)
+    }
+}
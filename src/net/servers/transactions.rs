@@ -0,0 +1,21 @@
+//! Groups of drawing requests which are applied at once via MULTI and EXEC
+//!
+//! Pixels are written without any synchronization, so transactions are only atomic relative to readers which copy
+//! the canvas via [`read_consistent`], like the encoding of state frames.
+
+use std::sync::RwLock;
+
+/// Held exclusively while a transaction is applied and shared while the canvas is copied
+static COMMIT_LOCK: RwLock<()> = RwLock::new(());
+
+/// Apply a transaction without any reader observing it partially
+pub(crate) fn commit<T>(apply: impl FnOnce() -> T) -> T {
+    let _guard = COMMIT_LOCK.write().unwrap();
+    apply()
+}
+
+/// Read the canvas without any transaction being applied in the meantime
+pub(crate) fn read_consistent<T>(read: impl FnOnce() -> T) -> T {
+    let _guard = COMMIT_LOCK.read().unwrap();
+    read()
+}
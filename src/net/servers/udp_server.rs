@@ -1,25 +1,59 @@
+use crate::net::protocol::Features;
 use crate::net::servers::gen_server::GenServer;
+use crate::net::servers::sockets;
+use crate::net::servers::statistics;
+use crate::net::servers::{
+    AccessControlOptions, BufferOptions, ClaimMode, ListenerCapabilities, RateLimitOptions, Reloadable,
+    SocketOptions, WriteProtectionOptions, MAX_LINE_LEN,
+};
 use crate::pixmap::SharedPixmap;
 use crate::DaemonResult;
 use async_trait::async_trait;
-use bytes::{BufMut, Bytes, BytesMut};
-use std::io::Write;
+use bytes::BytesMut;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::net::UdpSocket;
 use tokio::task::{AbortHandle, JoinSet};
 
 /// Options with which the `UdpServer` is configured
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct UdpServerOptions {
     /// The address to which the server binds
     pub bind_addr: SocketAddr,
+    /// Tuning of the socket, of which only the buffer sizes apply to UDP
+    pub socket: SocketOptions,
+    /// The networks from which datagrams are accepted, all others are silently dropped
+    pub access_control: Reloadable<AccessControlOptions>,
+    /// Whether datagrams must authenticate before they may draw on the canvas
+    ///
+    /// Since datagrams are independent of each other, every datagram which draws on the canvas must start with
+    /// its own AUTH request.
+    pub write_protection: Option<WriteProtectionOptions>,
+    /// How drawing into regions which other connections claimed is treated, if regions can be claimed via CLAIM
+    ///
+    /// Since datagrams are independent of each other, a claim made via UDP cannot be released and lasts until it
+    /// expires.
+    pub claims: Option<ClaimMode>,
+    /// The optional protocol features which clients may use, or `None` to enable all which the server supports
+    pub features: Option<Features>,
+    /// Whether the responses to all commands of a datagram are sent back in as few datagrams as possible instead of
+    /// one datagram per response line
+    pub batch_responses: bool,
 }
 
+/// The largest payload that a UDP datagram can carry
+pub(super) const MAX_UDP_PAYLOAD: usize = 65_507;
+
+/// The size of the buffers into which datagrams are received
+///
+/// Every datagram is split off of such a buffer so that many small datagrams share one allocation.
+const RECV_BUFFER_LEN: usize = 16 * MAX_UDP_PAYLOAD;
+
 /// A server implementation using UDP to receive pixelflut messages.
 ///
-/// *Note*: This server **never** sends data back.
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+/// Every datagram may contain any number of newline separated commands, the last of which does not need to be
+/// terminated.
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct UdpServer {
     options: UdpServerOptions,
 }
@@ -32,80 +66,150 @@ impl UdpServer {
         n: usize,
         join_set: &mut JoinSet<DaemonResult>,
     ) -> anyhow::Result<Vec<AbortHandle>> {
-        let socket = Arc::new(UdpSocket::bind(self.options.bind_addr).await?);
+        let socket = Arc::new(UdpSocket::from_std(sockets::udp_socket(
+            self.options.bind_addr,
+            &self.options.socket,
+        )?)?);
+        statistics::start();
         tracing::info!(
             "Started UDP Server on {} with {} tasks",
             self.options.bind_addr,
             n
         );
+        let capabilities = Self::capabilities(
+            self.options.write_protection,
+            self.options.claims,
+            self.options.features,
+        );
+        let access_control = self.options.access_control;
         (0..n)
             .map(|i| {
                 let pixmap = pixmap.clone();
                 let socket = socket.clone();
+                let capabilities = capabilities.clone();
+                let access_control = access_control.clone();
+                let batch_responses = self.options.batch_responses;
                 let handle = join_set
                     .build_task()
                     .name(&format!("udp_server{}", i))
-                    .spawn(async move { UdpServer::listen(pixmap, socket).await })?;
+                    .spawn(async move {
+                        UdpServer::listen(pixmap, socket, capabilities, access_control, batch_responses).await
+                    })?;
                 Ok(handle)
             })
             .collect::<anyhow::Result<Vec<_>>>()
     }
 
+    fn capabilities(
+        write_protection: Option<WriteProtectionOptions>,
+        claims: Option<ClaimMode>,
+        features: Option<Features>,
+    ) -> ListenerCapabilities {
+        ListenerCapabilities {
+            binary_protocol: true,
+            image_upload: cfg!(feature = "images"),
+            state_streaming: false,
+            compression: false,
+            max_line_len: Some(MAX_LINE_LEN),
+            buffers: BufferOptions::default(),
+            storm_protection: None,
+            max_connections_per_ip: None,
+            rate_limit: RateLimitOptions::default().into(),
+            idle_timeout: None,
+            write_protection: write_protection.map(Arc::new),
+            claims,
+            enabled_features: features,
+        }
+    }
+
     #[tracing::instrument(skip_all)]
-    async fn listen(pixmap: SharedPixmap, socket: Arc<UdpSocket>) -> anyhow::Result<!> {
+    async fn listen(
+        pixmap: SharedPixmap,
+        socket: Arc<UdpSocket>,
+        capabilities: ListenerCapabilities,
+        access_control: Reloadable<AccessControlOptions>,
+        batch_responses: bool,
+    ) -> anyhow::Result<!> {
+        let mut recv_buf = BytesMut::new();
         loop {
-            // fill a buffer from the network
-            let mut req_buf = BytesMut::with_capacity(4 * 1024);
-            let (_, sender) = socket.recv_buf_from(&mut req_buf).await?;
+            // fill a buffer from the network which has enough room left for every datagram
+            if recv_buf.capacity() < MAX_UDP_PAYLOAD {
+                recv_buf = BytesMut::with_capacity(RECV_BUFFER_LEN);
+            }
+            let (_, sender) = socket.recv_buf_from(&mut recv_buf).await?;
+            let req_buf = recv_buf.split();
+            if !access_control.get().permits(sockets::peer_addr(sender).ip()) {
+                tracing::trace!(
+                    "Dropping datagram from {} which is not allowed on this listener",
+                    sender
+                );
+                continue;
+            }
 
             // process received commands in the background
             let pixmap = pixmap.clone();
             let socket = socket.clone();
-            tokio::spawn(
-                async move { Self::handle_requests(sender, req_buf.freeze(), pixmap, socket).await },
-            );
+            let capabilities = capabilities.clone();
+            tokio::spawn(async move {
+                Self::handle_requests(sender, req_buf, pixmap, socket, capabilities, batch_responses).await
+            });
         }
     }
 
-    #[tracing::instrument(skip_all, fields(remote = sender.to_string()))]
+    #[tracing::instrument(skip_all, fields(remote = sockets::peer_addr(sender).to_string(), nick = tracing::field::Empty))]
     async fn handle_requests(
         sender: SocketAddr,
-        mut buf: Bytes,
+        buf: BytesMut,
         pixmap: SharedPixmap,
         socket: Arc<UdpSocket>,
+        capabilities: ListenerCapabilities,
+        batch_responses: bool,
     ) {
         tracing::trace!("Received {}KiB UDP datagram: {:?}", buf.len() / 1024, buf);
 
-        let mut resp_buf = BytesMut::with_capacity(2 * 1024).writer();
-
-        // handle all lines contained in the request buffer
-        while let Some((i, _)) = buf.iter().enumerate().find(|(_, &b)| b == b'\n') {
-            let line = buf.split_to(i + 1);
-            let result = super::handle_request(&line, &pixmap);
-            match result {
-                Err(e) => {
-                    resp_buf.write_fmt(format_args!("{}\n", e)).unwrap();
-                }
-                Ok(Some(response)) => response.write(&mut resp_buf).unwrap(),
-                Ok(None) => {}
-            }
-        }
-
-        // write accumulated responses back to the sender
-        let resp_buf = resp_buf.into_inner();
+        // handle all requests contained in the datagram and write accumulated responses back to the sender
+        let resp_buf = super::handle_message(buf, &pixmap, &capabilities);
         if !resp_buf.is_empty() {
             tracing::trace!(
                 "Sending back {}KiB response: {:?}",
                 resp_buf.len() / 1024,
                 &resp_buf
             );
-            if let Err(e) = socket.send_to(&resp_buf, sender).await {
-                tracing::error!("Error while writing response to {}: {}", sender, e);
+            for datagram in response_datagrams(&resp_buf, batch_responses, MAX_UDP_PAYLOAD) {
+                if let Err(e) = socket.send_to(datagram, sender).await {
+                    tracing::error!("Error while writing response to {}: {}", sender, e);
+                    return;
+                }
             }
         }
     }
 }
 
+/// Split the responses to a datagram into the datagrams of at most `max_len` bytes with which they are sent back
+///
+/// Responses are split at line ends. Batched responses are packed into as few datagrams as possible while
+/// unbatched ones are sent as one datagram per line. Lines which don't fit into a single datagram are split.
+pub(super) fn response_datagrams(responses: &[u8], batch: bool, max_len: usize) -> Vec<&[u8]> {
+    let mut datagrams = Vec::new();
+    let mut start = 0;
+    let mut end = 0;
+    for line in responses.split_inclusive(|&b| b == b'\n') {
+        if end > start && (!batch || end - start + line.len() > max_len) {
+            datagrams.push(&responses[start..end]);
+            start = end;
+        }
+        end += line.len();
+        while end - start > max_len {
+            datagrams.push(&responses[start..start + max_len]);
+            start += max_len;
+        }
+    }
+    if end > start {
+        datagrams.push(&responses[start..end]);
+    }
+    datagrams
+}
+
 #[async_trait]
 impl GenServer for UdpServer {
     type Options = UdpServerOptions;
@@ -119,13 +223,58 @@ impl GenServer for UdpServer {
         pixmap: SharedPixmap,
         join_set: &mut JoinSet<DaemonResult>,
     ) -> anyhow::Result<AbortHandle> {
-        let socket = Arc::new(UdpSocket::bind(self.options.bind_addr).await?);
+        let socket = Arc::new(UdpSocket::from_std(sockets::udp_socket(
+            self.options.bind_addr,
+            &self.options.socket,
+        )?)?);
+        statistics::start();
         tracing::info!("Started UDP Server on {}", self.options.bind_addr);
 
-        let handle = join_set
-            .build_task()
-            .name("udp_server")
-            .spawn(async move { UdpServer::listen(pixmap, socket).await })?;
+        let handle = join_set.build_task().name("udp_server").spawn(async move {
+            UdpServer::listen(
+                pixmap,
+                socket,
+                Self::capabilities(
+                    self.options.write_protection,
+                    self.options.claims,
+                    self.options.features,
+                ),
+                self.options.access_control,
+                self.options.batch_responses,
+            )
+            .await
+        })?;
         Ok(handle)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_response_datagrams() {
+        let responses = b"PX 0 0 FF0000\nPX 1 0 00FF00\nSIZE 8 8\n";
+        assert_eq!(
+            response_datagrams(responses, true, MAX_UDP_PAYLOAD),
+            vec![&responses[..]]
+        );
+        assert_eq!(
+            response_datagrams(responses, false, MAX_UDP_PAYLOAD),
+            vec![&b"PX 0 0 FF0000\n"[..], b"PX 1 0 00FF00\n", b"SIZE 8 8\n"]
+        );
+
+        let line = [b'A'; MAX_UDP_PAYLOAD / 2 + 1];
+        let responses = [&line[..], b"\n", &line[..], b"\n"].concat();
+        let datagrams = response_datagrams(&responses, true, MAX_UDP_PAYLOAD);
+        assert_eq!(datagrams.len(), 2);
+        assert!(datagrams.iter().all(|datagram| datagram.len() == line.len() + 1));
+
+        let responses = [b'A'; MAX_UDP_PAYLOAD + 1];
+        let datagrams = response_datagrams(&responses, true, MAX_UDP_PAYLOAD);
+        assert_eq!(
+            datagrams,
+            vec![&responses[..MAX_UDP_PAYLOAD], &responses[MAX_UDP_PAYLOAD..]]
+        );
+    }
+}
@@ -1,10 +1,23 @@
-use crate::net::servers::GenServer;
+use crate::net::framing::FrameBuffer;
+use crate::net::protocol::Features;
+use crate::net::servers::compression::ResponseEncoder;
+use crate::net::servers::state_stream::StateStream;
+use crate::net::servers::statistics::{self, ConnectionGuard, Event};
+use crate::net::servers::{
+    socket_activation, BufferOptions, ClaimMode, ConnectionPreferences, GenServer, ListenerCapabilities,
+    RateLimitOptions, WriteProtectionOptions, PIPELINE_FLUSH_LEN,
+};
 use crate::pixmap::SharedPixmap;
+use crate::shutdown::{self, Phase};
 use crate::DaemonResult;
 use async_trait::async_trait;
 use bytes::{BufMut, BytesMut};
-use std::io::Write;
+use std::fs::Permissions;
+use std::os::fd::AsFd;
+use std::os::unix::fs::PermissionsExt;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{UnixListener, UnixStream};
 use tokio::task::{AbortHandle, JoinSet};
@@ -14,6 +27,21 @@ use tokio::task::{AbortHandle, JoinSet};
 pub struct UnixSocketOptions {
     /// The path at which a socket should be created
     pub path: PathBuf,
+    /// The file mode which the socket is given after it was created, e.g. `0o660`
+    ///
+    /// Only processes which may write to the socket can connect to it.
+    /// If this is `None`, the mode depends on the umask of the server process.
+    pub permissions: Option<u32>,
+    /// After which duration without requests connections are closed, or `None` to keep them open forever
+    pub idle_timeout: Option<Duration>,
+    /// The sizes of the buffers of connections and how long their request lines may be
+    pub buffers: BufferOptions,
+    /// Whether connections must authenticate before they may draw on the canvas
+    pub write_protection: Option<WriteProtectionOptions>,
+    /// How drawing into regions which other connections claimed is treated, if regions can be claimed via CLAIM
+    pub claims: Option<ClaimMode>,
+    /// The optional protocol features which clients may use, or `None` to enable all which the server supports
+    pub features: Option<Features>,
 }
 
 /// A server implementation using unix domain sockets to transport pixelflut messages.
@@ -24,55 +52,121 @@ pub struct UnixSocketServer {
 
 impl UnixSocketServer {
     #[tracing::instrument(skip_all)]
-    async fn handle_listener(listener: UnixListener, pixmap: SharedPixmap) -> anyhow::Result<!> {
+    async fn handle_listener(
+        listener: UnixListener,
+        pixmap: SharedPixmap,
+        options: UnixSocketOptions,
+    ) -> anyhow::Result<!> {
+        let capabilities = ListenerCapabilities {
+            binary_protocol: true,
+            image_upload: cfg!(feature = "images"),
+            state_streaming: true,
+            compression: true,
+            max_line_len: Some(options.buffers.max_line_len),
+            buffers: options.buffers,
+            storm_protection: None,
+            max_connections_per_ip: None,
+            rate_limit: RateLimitOptions::default().into(),
+            idle_timeout: options.idle_timeout,
+            write_protection: options.write_protection.map(Arc::new),
+            claims: options.claims,
+            enabled_features: options.features,
+        };
         loop {
             let (stream, _) = listener.accept().await?;
             let pixmap = pixmap.clone();
+            let capabilities = capabilities.clone();
             tokio::spawn(async move {
-                if let Err(e) = UnixSocketServer::handle_connection(stream, pixmap).await {
+                if let Err(e) = UnixSocketServer::handle_connection(stream, pixmap, capabilities).await {
                     tracing::warn!("Got error while handling unix socket stream: {e}");
                 }
             });
         }
     }
 
-    #[tracing::instrument(skip_all)]
-    async fn handle_connection(mut stream: UnixStream, pixmap: SharedPixmap) -> anyhow::Result<()> {
-        const MAX_LINE_LEN: usize = 32;
+    #[tracing::instrument(skip_all, fields(nick = tracing::field::Empty))]
+    async fn handle_connection(
+        mut stream: UnixStream,
+        pixmap: SharedPixmap,
+        capabilities: ListenerCapabilities,
+    ) -> anyhow::Result<()> {
         tracing::debug!("Client connected");
+        let connection = ConnectionGuard::new(None);
+        let shutdown = shutdown::Guard::new(Phase::Draining);
 
-        let mut req_buf = BytesMut::with_capacity(16 * 1024);
-        let mut resp_buf = BytesMut::with_capacity(2 * 1024).writer();
+        let mut req_buf = FrameBuffer::with_capacity(capabilities.buffers.read_buffer_size)
+            .with_max_line_len(capabilities.max_line_len);
+        let mut resp_buf = BytesMut::with_capacity(capabilities.buffers.write_buffer_size).writer();
+        let mut preferences = ConnectionPreferences {
+            client: Some(connection.id()),
+            ..Default::default()
+        };
+        let mut state_stream = StateStream::default();
+        let mut response_encoder = ResponseEncoder::default();
         loop {
-            // fill the line buffer from the socket
-            let n = stream.read_buf(&mut req_buf).await?;
+            // fill the line buffer from the socket or send the next frame of a requested state stream
+            let n = tokio::select! {
+                n = stream.read_buf(req_buf.data_mut()) => n?,
+                settings = state_stream.tick(preferences.stream) => {
+                    state_stream.write_frame(&pixmap, settings, &mut resp_buf)?;
+                    let sent = response_encoder.send(&mut stream, resp_buf.get_mut()).await?;
+                    connection.transferred(0, sent);
+                    continue;
+                }
+                _ = super::idle(capabilities.idle_timeout, preferences.stream.is_some()) => {
+                    tracing::debug!("Closing connection which did not send anything for {:?}", capabilities.idle_timeout);
+                    statistics::record(Event::IdleTimeout);
+                    return Ok(());
+                }
+                _ = shutdown.requested() => {
+                    tracing::debug!("Closing connection since the server shuts down");
+                    super::write_shutdown_notice(preferences.protocol, &mut resp_buf);
+                    response_encoder.send(&mut stream, resp_buf.get_mut()).await?;
+                    stream.shutdown().await?;
+                    return Ok(());
+                }
+            };
             if n == 0 {
                 tracing::debug!("Client stream exhausted, likely disconnected");
                 return Ok(());
             }
             tracing::trace!("Received {}KiB stream data: {:?}", n / 1024, req_buf);
 
-            // handle all lines contained in the buffer
-            while let Some((i, _)) = req_buf.iter().enumerate().find(|(_, &b)| b == b'\n') {
-                let line = req_buf.split_to(i + 1);
-                let result = super::handle_request(&line, &pixmap);
-                match result {
-                    Err(e) => {
-                        resp_buf.write_fmt(format_args!("{}\n", e)).unwrap();
+            // handle all frames contained in the buffer as well as those of requests which the client already sent
+            // meanwhile so that pipelined requests are answered with few large writes
+            let mut received = n;
+            let mut sent = 0;
+            let mut closed = false;
+            loop {
+                loop {
+                    super::handle_frames(
+                        &mut req_buf,
+                        &mut resp_buf,
+                        &pixmap,
+                        &mut preferences,
+                        &capabilities,
+                    );
+                    if preferences.compression == response_encoder.compression() {
+                        break;
                     }
-                    Ok(Some(response)) => response.write(&mut resp_buf).unwrap(),
-                    Ok(None) => {}
+                    // responses up to the confirmation of a new compression still use the previous one
+                    sent += response_encoder.send(&mut stream, resp_buf.get_mut()).await?;
+                    let rest = response_encoder.switch(preferences.compression)?;
+                    stream.write_all(&rest).await?;
+                    sent += rest.len();
+                }
+                super::discard_overlong_frame(&mut req_buf, &mut resp_buf, preferences.protocol);
+                if resp_buf.get_ref().len() >= PIPELINE_FLUSH_LEN || received >= PIPELINE_FLUSH_LEN {
+                    break;
+                }
+                match super::now_or_never(stream.read_buf(req_buf.data_mut())).transpose()? {
+                    None => break,
+                    Some(0) => {
+                        closed = true;
+                        break;
+                    }
+                    Some(n) => received += n,
                 }
-            }
-
-            // clear the buffer if someone is deliberately not sending a newline
-            if req_buf.len() > MAX_LINE_LEN {
-                tracing::warn!(
-                    "Request buffer has {}B but no lines left in it. Client is probably misbehaving.",
-                    req_buf.len()
-                );
-                req_buf.clear();
-                resp_buf.write_all("line too long\n".as_bytes()).unwrap();
             }
 
             // write accumulated responses back to the sender
@@ -82,7 +176,12 @@ impl UnixSocketServer {
                     resp_buf.get_ref().len() / 1024,
                     resp_buf.get_ref()
                 );
-                stream.write_all_buf(resp_buf.get_mut()).await?;
+                sent += response_encoder.send(&mut stream, resp_buf.get_mut()).await?;
+            }
+            connection.transferred(received, sent);
+            if closed {
+                tracing::debug!("Client stream exhausted, likely disconnected");
+                return Ok(());
             }
         }
     }
@@ -101,13 +200,31 @@ impl GenServer for UnixSocketServer {
         pixmap: SharedPixmap,
         join_set: &mut JoinSet<DaemonResult>,
     ) -> anyhow::Result<AbortHandle> {
-        let listener = UnixListener::bind(&self.options.path)?;
+        let listener = match socket_activation::take_unix(&self.options.path) {
+            Some(listener) => {
+                tracing::info!(
+                    "Using the socket at {} which was passed to the server",
+                    self.options.path.display()
+                );
+                listener.set_nonblocking(true)?;
+                UnixListener::from_std(listener)?
+            }
+            None => {
+                let listener = UnixListener::bind(&self.options.path)?;
+                if let Some(mode) = self.options.permissions {
+                    std::fs::set_permissions(&self.options.path, Permissions::from_mode(mode))?;
+                }
+                listener
+            }
+        };
+        socket_activation::remember(listener.as_fd());
+        statistics::start();
         tracing::info!("Started unix listener on {}", self.options.path.display());
 
         let handle = join_set
             .build_task()
             .name("unix_listener")
-            .spawn(async move { UnixSocketServer::handle_listener(listener, pixmap).await })?;
+            .spawn(async move { UnixSocketServer::handle_listener(listener, pixmap, self.options).await })?;
         Ok(handle)
     }
 }
@@ -0,0 +1,61 @@
+//! Restriction of canvas writes to connections which authenticated via AUTH
+//!
+//! Independent of that, the whole canvas can be made read-only for everyone while the server runs via the admin
+//! interface.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Whether no client may currently draw on the canvas
+static READ_ONLY: AtomicBool = AtomicBool::new(false);
+
+/// Options for protecting the canvas of a listener against writes from unauthenticated connections
+///
+/// Connections can authenticate by sending `AUTH <token>` with one of the configured tokens.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct WriteProtectionOptions {
+    /// The tokens with which connections can authenticate
+    pub tokens: Vec<String>,
+}
+
+impl WriteProtectionOptions {
+    /// Whether `token` is one of the configured tokens
+    ///
+    /// Tokens are compared in constant time so that they cannot be guessed by measuring response times.
+    pub(crate) fn accepts(&self, token: &str) -> bool {
+        self.tokens.iter().fold(false, |found, candidate| {
+            found | constant_time_eq(candidate, token)
+        })
+    }
+}
+
+/// Make the canvas read-only for all clients or writable again
+pub(crate) fn set_read_only(read_only: bool) {
+    READ_ONLY.store(read_only, Ordering::Relaxed);
+}
+
+/// Whether the canvas is currently read-only for all clients
+pub(crate) fn is_read_only() -> bool {
+    READ_ONLY.load(Ordering::Relaxed)
+}
+
+/// Compare two strings in a time that only depends on their length
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_accepts_tokens() {
+        let options = WriteProtectionOptions {
+            tokens: vec!["s3cr3t".to_string(), "orga".to_string()],
+        };
+        assert!(options.accepts("s3cr3t"));
+        assert!(options.accepts("orga"));
+        assert!(!options.accepts("s3cr3"));
+        assert!(!options.accepts("S3CR3T"));
+        assert!(!options.accepts(""));
+    }
+}
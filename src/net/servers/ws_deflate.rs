@@ -0,0 +1,465 @@
+//! The permessage-deflate extension of WebSocket (RFC 7692)
+//!
+//! tungstenite neither negotiates nor understands the extension, so it is implemented around it:
+//!
+//! - [`negotiate`] picks an offer of the client from the `Sec-WebSocket-Extensions` header of the handshake.
+//! - [`DeflateStream`] sits between the socket and tungstenite and inflates compressed messages of the client
+//!   into plain frames before tungstenite parses them.
+//! - [`MessageDeflater`] compresses outgoing messages and sends them as raw frames with the RSV1 bit set.
+//!
+//! The server always uses a window of 32KiB since that is the only one which the deflate backend supports, and
+//! offers which limit it are declined.
+
+use crate::net::protocol::MAX_IMAGE_SIZE;
+use bytes::BytesMut;
+use flate2::{Compress, Decompress, FlushCompress, FlushDecompress, Status};
+use std::io::{self, Cursor};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::{Data, OpCode};
+use tokio_tungstenite::tungstenite::protocol::frame::{Frame, FrameHeader};
+use tokio_tungstenite::tungstenite::Message;
+
+/// The name of the extension in the handshake headers
+const EXTENSION: &str = "permessage-deflate";
+
+/// The bytes with which a sync flush ends, which are left out of messages
+const SYNC_TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// The largest message which a client may send, which leaves room for the request line of an image upload
+pub(super) const MAX_MESSAGE_LEN: usize = MAX_IMAGE_SIZE + 64 * 1024;
+
+/// The largest frame which a client may send, before and after it is inflated
+pub(super) const MAX_FRAME_LEN: usize = MAX_MESSAGE_LEN;
+
+/// How many bytes are read from the socket at once while compressed messages are inflated
+const READ_CHUNK_LEN: usize = 8 * 1024;
+
+/// The parameters with which the extension was negotiated
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub(super) struct DeflateParams {
+    /// Whether the client asked the server to compress every message on its own
+    pub server_no_context_takeover: bool,
+}
+
+impl DeflateParams {
+    /// The value of the `Sec-WebSocket-Extensions` header with which the server accepts the extension
+    pub fn header_value(&self) -> String {
+        match self.server_no_context_takeover {
+            true => format!("{}; server_no_context_takeover", EXTENSION),
+            false => EXTENSION.to_string(),
+        }
+    }
+}
+
+/// Pick the first offer of permessage-deflate from the `Sec-WebSocket-Extensions` header which the server supports
+pub(super) fn negotiate(offers: &str) -> Option<DeflateParams> {
+    offers.split(',').find_map(|offer| {
+        let mut params = offer.split(';').map(str::trim);
+        if params.next()? != EXTENSION {
+            return None;
+        }
+        let mut negotiated = DeflateParams::default();
+        for param in params {
+            let (name, value) = match param.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (param, None),
+            };
+            match (name, value) {
+                ("server_no_context_takeover", None) => negotiated.server_no_context_takeover = true,
+                // the client may keep or reset its context and use any window since the server inflates with the
+                // largest one anyway
+                ("client_no_context_takeover", None) | ("client_max_window_bits", _) => {}
+                ("server_max_window_bits", Some("15")) => {}
+                _ => return None,
+            }
+        }
+        Some(negotiated)
+    })
+}
+
+/// The per-connection state with which messages to the client are compressed
+pub(super) struct MessageDeflater {
+    compress: Compress,
+    params: DeflateParams,
+    threshold: usize,
+}
+
+impl MessageDeflater {
+    /// Create a deflater which compresses all messages with at least `threshold` bytes
+    pub fn new(params: DeflateParams, threshold: usize) -> Self {
+        Self {
+            compress: Compress::new(flate2::Compression::fast(), false),
+            params,
+            threshold,
+        }
+    }
+
+    /// Compress a text or binary message if it is large enough
+    ///
+    /// Compressed messages are returned as raw frames since tungstenite can't set the RSV1 bit otherwise.
+    pub fn deflate(&mut self, message: Message) -> io::Result<Message> {
+        let (data, opcode) = match message {
+            Message::Text(text) if text.len() >= self.threshold => (text.into_bytes(), Data::Text),
+            Message::Binary(data) if data.len() >= self.threshold => (data, Data::Binary),
+            message => return Ok(message),
+        };
+        let mut compressed = self.compress(&data)?;
+        if compressed.ends_with(&SYNC_TRAILER) {
+            compressed.truncate(compressed.len() - SYNC_TRAILER.len());
+        }
+        if self.params.server_no_context_takeover {
+            self.compress.reset();
+        }
+        let mut frame = Frame::message(compressed, OpCode::Data(opcode), true);
+        frame.header_mut().rsv1 = true;
+        Ok(Message::Frame(frame))
+    }
+
+    /// Compress `data` and flush the compressed stream
+    fn compress(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(data.len() / 2 + 64);
+        let start = self.compress.total_in();
+        loop {
+            let consumed = (self.compress.total_in() - start) as usize;
+            self.compress
+                .compress_vec(&data[consumed..], &mut out, FlushCompress::Sync)
+                .map_err(io::Error::other)?;
+            // the flush is complete once all input is consumed without filling the output
+            if (self.compress.total_in() - start) as usize == data.len() && out.len() < out.capacity() {
+                return Ok(out);
+            }
+            out.reserve(out.capacity());
+        }
+    }
+}
+
+/// A stream which inflates the compressed messages of a client before tungstenite reads them
+///
+/// Frames of compressed messages are replaced by frames with the inflated payload while all other frames are passed
+/// on unchanged. Writes go straight to the inner stream.
+pub(super) struct DeflateStream<S> {
+    inner: S,
+    inflater: Option<Box<Inflater>>,
+}
+
+impl<S> DeflateStream<S> {
+    /// Wrap a stream on which the extension was negotiated, if it was
+    pub fn new(inner: S, params: Option<DeflateParams>) -> Self {
+        Self {
+            inner,
+            inflater: params.map(|_| Box::default()),
+        }
+    }
+}
+
+/// The state of inflating the messages which are received on one connection
+struct Inflater {
+    decompress: Decompress,
+    /// Bytes which were read from the socket but do not yet form a complete frame
+    raw: BytesMut,
+    /// Rewritten frames which tungstenite did not read yet
+    frames: BytesMut,
+    /// Whether the fragmented message which is currently received is compressed
+    in_compressed_message: bool,
+    /// How many bytes the frames of the compressed message which is currently received were inflated to so far
+    message_len: usize,
+    chunk: Box<[u8]>,
+}
+
+impl Default for Inflater {
+    fn default() -> Self {
+        Self {
+            decompress: Decompress::new(false),
+            raw: BytesMut::new(),
+            frames: BytesMut::new(),
+            in_compressed_message: false,
+            message_len: 0,
+            chunk: vec![0; READ_CHUNK_LEN].into_boxed_slice(),
+        }
+    }
+}
+
+impl Inflater {
+    /// Move the next complete frame from `raw` to `frames`, inflating it if it belongs to a compressed message
+    ///
+    /// Returns whether there was a complete frame.
+    fn rewrite_frame(&mut self) -> io::Result<bool> {
+        let mut cursor = Cursor::new(&self.raw[..]);
+        let Some((header, len)) = FrameHeader::parse(&mut cursor).map_err(io::Error::other)? else {
+            return Ok(false);
+        };
+        if len > MAX_FRAME_LEN as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "WebSocket frame is too large",
+            ));
+        }
+        let header_len = cursor.position() as usize;
+        if self.raw.len() < header_len + len as usize {
+            self.raw.reserve(header_len + len as usize - self.raw.len());
+            return Ok(false);
+        }
+        let mut frame = self.raw.split_to(header_len + len as usize);
+
+        let compressed = match header.opcode {
+            OpCode::Control(_) => false,
+            OpCode::Data(Data::Continue) => self.in_compressed_message,
+            OpCode::Data(_) => header.rsv1,
+        };
+        if let OpCode::Data(data) = header.opcode {
+            self.in_compressed_message = compressed && !header.is_final;
+            if data != Data::Continue {
+                self.message_len = 0;
+            }
+        }
+        if !compressed {
+            self.frames.extend_from_slice(&frame);
+            return Ok(true);
+        }
+
+        let mut payload = frame.split_off(header_len);
+        if let Some(mask) = header.mask {
+            payload
+                .iter_mut()
+                .zip(mask.iter().cycle())
+                .for_each(|(byte, mask)| *byte ^= mask);
+        }
+        let limit = MAX_FRAME_LEN.min(MAX_MESSAGE_LEN - self.message_len);
+        let mut inflated = Vec::with_capacity((payload.len() * 4 + 64).min(limit + 1));
+        self.inflate(&payload, &mut inflated, limit)?;
+        if header.is_final {
+            self.inflate(&SYNC_TRAILER, &mut inflated, limit)?;
+        }
+        self.message_len += inflated.len();
+        // tungstenite insists on masked frames from clients, which a mask of zeros satisfies without changing them
+        let header = FrameHeader {
+            rsv1: false,
+            mask: Some([0; 4]),
+            ..header
+        };
+        let mut out = Vec::with_capacity(header.len(inflated.len() as u64) + inflated.len());
+        Frame::from_payload(header, inflated)
+            .format(&mut out)
+            .map_err(io::Error::other)?;
+        self.frames.extend_from_slice(&out);
+        Ok(true)
+    }
+
+    /// Inflate all of `input` into `out`, failing if `out` would grow beyond `limit` bytes
+    fn inflate(&mut self, input: &[u8], out: &mut Vec<u8>, limit: usize) -> io::Result<()> {
+        let start = self.decompress.total_in();
+        loop {
+            let consumed = (self.decompress.total_in() - start) as usize;
+            let status = self
+                .decompress
+                .decompress_vec(&input[consumed..], out, FlushDecompress::Sync)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            if out.len() > limit {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Inflated WebSocket message is too large",
+                ));
+            }
+            if status == Status::StreamEnd {
+                // a client which ends its message with a final block starts the next one from scratch
+                self.decompress.reset(false);
+                return Ok(());
+            }
+            if (self.decompress.total_in() - start) as usize == input.len() && out.len() < out.capacity() {
+                return Ok(());
+            }
+            // room for one byte beyond the limit tells output of exactly the limit apart from larger output
+            out.reserve_exact(out.capacity().max(64).min(limit + 1 - out.len()));
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for DeflateStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let Self { inner, inflater } = self.get_mut();
+        let Some(inflater) = inflater else {
+            return Pin::new(inner).poll_read(cx, buf);
+        };
+        loop {
+            if !inflater.frames.is_empty() {
+                let len = inflater.frames.len().min(buf.remaining());
+                buf.put_slice(&inflater.frames.split_to(len));
+                return Poll::Ready(Ok(()));
+            }
+            if inflater.rewrite_frame()? {
+                continue;
+            }
+            let mut chunk = ReadBuf::new(&mut inflater.chunk);
+            ready!(Pin::new(&mut *inner).poll_read(cx, &mut chunk))?;
+            if chunk.filled().is_empty() {
+                // tungstenite reports an incomplete frame at the end of the stream on its own
+                let rest = inflater.raw.split();
+                if rest.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                inflater.frames.extend_from_slice(&rest);
+                continue;
+            }
+            inflater.raw.extend_from_slice(chunk.filled());
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for DeflateStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures_util::StreamExt;
+    use tokio::io::AsyncWriteExt;
+    use tokio_tungstenite::tungstenite::protocol::frame::coding::Control;
+    use tokio_tungstenite::tungstenite::protocol::{Role, WebSocketConfig};
+    use tokio_tungstenite::WebSocketStream;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(
+            negotiate("permessage-deflate; client_max_window_bits"),
+            Some(DeflateParams::default())
+        );
+        assert_eq!(
+            negotiate("permessage-deflate; server_max_window_bits=10, permessage-deflate; server_no_context_takeover"),
+            Some(DeflateParams {
+                server_no_context_takeover: true
+            })
+        );
+        assert_eq!(negotiate("x-webkit-deflate-frame"), None);
+        assert_eq!(negotiate("permessage-deflate; unknown"), None);
+        assert_eq!(
+            DeflateParams {
+                server_no_context_takeover: true
+            }
+            .header_value(),
+            "permessage-deflate; server_no_context_takeover"
+        );
+    }
+
+    /// Encode a frame like a client does
+    fn client_frame(payload: &[u8], opcode: OpCode, is_final: bool, rsv1: bool) -> Vec<u8> {
+        let header = FrameHeader {
+            is_final,
+            rsv1,
+            opcode,
+            mask: Some([1, 2, 3, 4]),
+            ..FrameHeader::default()
+        };
+        let mut out = Vec::new();
+        Frame::from_payload(header, payload.to_vec())
+            .format(&mut out)
+            .unwrap();
+        out
+    }
+
+    #[tokio::test]
+    async fn test_inflate_messages() {
+        // clients compress with the same algorithm, so the deflater of the server stands in for one
+        let mut deflater = MessageDeflater::new(DeflateParams::default(), 0);
+        let mut compress = |text: &str| match deflater.deflate(Message::Text(text.to_string())).unwrap() {
+            Message::Frame(frame) => frame.into_data(),
+            message => panic!("Message was not compressed: {message:?}"),
+        };
+        let first = compress("PX 1 2 FF0080\nPX 1 2 FF0080\n");
+        let second = compress("PX 3 4 000000\n");
+
+        let mut raw = client_frame(&first, OpCode::Data(Data::Text), true, true);
+        raw.extend(client_frame(b"SIZE", OpCode::Data(Data::Text), true, false));
+        let (head, tail) = second.split_at(second.len() / 2);
+        raw.extend(client_frame(head, OpCode::Data(Data::Text), false, true));
+        raw.extend(client_frame(&[], OpCode::Control(Control::Ping), true, false));
+        raw.extend(client_frame(tail, OpCode::Data(Data::Continue), true, false));
+
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        client.write_all(&raw).await.unwrap();
+        let server = DeflateStream::new(server, Some(DeflateParams::default()));
+        let mut stream = WebSocketStream::from_raw_socket(server, Role::Server, None).await;
+        assert_eq!(
+            stream.next().await.unwrap().unwrap(),
+            Message::Text("PX 1 2 FF0080\nPX 1 2 FF0080\n".to_string())
+        );
+        assert_eq!(
+            stream.next().await.unwrap().unwrap(),
+            Message::Text("SIZE".to_string())
+        );
+        assert_eq!(stream.next().await.unwrap().unwrap(), Message::Ping(Vec::new()));
+        assert_eq!(
+            stream.next().await.unwrap().unwrap(),
+            Message::Text("PX 3 4 000000\n".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_inflated_size_is_limited() {
+        let mut deflater = MessageDeflater::new(DeflateParams::default(), 0);
+        let mut compress = |len: usize| match deflater.deflate(Message::Binary(vec![0; len])).unwrap() {
+            Message::Frame(frame) => frame.into_data(),
+            message => panic!("Message was not compressed: {message:?}"),
+        };
+        let fits = compress(MAX_MESSAGE_LEN);
+        // two frames which fit on their own but not as one message
+        let (head, tail) = (compress(MAX_MESSAGE_LEN / 2), compress(MAX_MESSAGE_LEN / 2 + 1));
+
+        let mut raw = client_frame(&fits, OpCode::Data(Data::Binary), true, true);
+        raw.extend(client_frame(&head, OpCode::Data(Data::Binary), false, true));
+        raw.extend(client_frame(&tail, OpCode::Data(Data::Continue), true, false));
+
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move { client.write_all(&raw).await });
+        let server = DeflateStream::new(server, Some(DeflateParams::default()));
+        let config = WebSocketConfig {
+            max_message_size: Some(MAX_MESSAGE_LEN),
+            max_frame_size: Some(MAX_FRAME_LEN),
+            ..Default::default()
+        };
+        let mut stream = WebSocketStream::from_raw_socket(server, Role::Server, Some(config)).await;
+        assert_eq!(
+            stream.next().await.unwrap().unwrap(),
+            Message::Binary(vec![0; MAX_MESSAGE_LEN])
+        );
+        assert!(stream.next().await.unwrap().is_err());
+    }
+
+    #[test]
+    fn test_deflate_threshold() {
+        let mut deflater = MessageDeflater::new(DeflateParams::default(), 16);
+        let small = Message::Text("SIZE 800 600".to_string());
+        assert_eq!(deflater.deflate(small.clone()).unwrap(), small);
+        match deflater.deflate(Message::Binary(vec![0; 4096])).unwrap() {
+            Message::Frame(frame) => {
+                assert!(frame.header().rsv1);
+                assert!(frame.payload().len() < 64);
+            }
+            message => panic!("Message was not compressed: {message:?}"),
+        }
+    }
+}
@@ -0,0 +1,491 @@
+//! The JSON message mode of WebSocket connections
+//!
+//! Connections which request `?format=json` during the handshake exchange every request and response as a JSON
+//! object in a text message, so that browser frontends don't need to implement the line protocol:
+//!
+//! - Requests name their command in `cmd` and pass its arguments by the names which HELP uses for them, e.g.
+//!   `{"cmd":"px","x":1,"y":2,"color":"FF0080"}` or `{"cmd":"stream","algorithm":"px","fps":10}`.
+//!   Boolean switches are given as `true` or `false` and lists like the features of HELLO as arrays.
+//! - Responses carry the command which they answer in `cmd` together with their data, e.g.
+//!   `{"cmd":"px","x":1,"y":2,"color":"FF0080"}`, and errors are sent as `{"error":"<code>","message":"..."}`.
+//! - An `id` of a request is copied into its response so that clients can match them.
+//!
+//! Streamed state frames are still sent as binary messages.
+//!
+//! The [`WebSocketClient`](crate::net::clients::WebSocketClient) speaks the same mode via [`encode_request`] and
+//! [`decode_result`].
+
+use crate::net::protocol::{parse_request_bin, parse_response_str, Request, Response, ResponseError};
+use crate::pixmap::Color;
+use crate::texts;
+use anyhow::anyhow;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use serde_json::{json, Map, Value};
+
+/// The commands which can be sent as JSON together with the names of their arguments in the order in which the
+/// line protocol expects them
+///
+/// Trailing arguments may be left out if the command allows it.
+const COMMANDS: [(&str, &[&str]); 20] = [
+    ("help", &["topic"]),
+    ("hello", &["version", "features"]),
+    ("size", &[]),
+    ("info", &[]),
+    ("stats", &[]),
+    ("limits", &[]),
+    ("px", &["x", "y", "color", "mode"]),
+    ("pxget", &["x", "y", "width", "height"]),
+    ("rect", &["x", "y", "width", "height", "color"]),
+    ("line", &["x1", "y1", "x2", "y2", "color"]),
+    ("stream", &["algorithm", "fps", "x", "y", "width", "height"]),
+    ("compress", &["algorithm"]),
+    ("auth", &["token"]),
+    ("noreply", &["enabled"]),
+    ("claim", &["x", "y", "width", "height", "secs"]),
+    ("release", &[]),
+    ("nick", &["name"]),
+    ("multi", &[]),
+    ("exec", &[]),
+    ("protocol", &["variant"]),
+];
+
+/// Parse a request which was sent as a JSON object and return it together with its `id`, if it has one
+pub(super) fn parse_request(msg: &str) -> (Option<Value>, Result<Request, ResponseError>) {
+    let object = match serde_json::from_str::<Value>(msg) {
+        Ok(Value::Object(object)) => object,
+        Ok(_) => return (None, Err(parse_error("Requests must be JSON objects"))),
+        Err(e) => return (None, Err(parse_error(&format!("Invalid JSON: {}", e)))),
+    };
+    let id = object.get("id").cloned();
+    let request =
+        to_line(&object).and_then(|line| parse_request_bin(line.as_bytes()).map_err(ResponseError::from));
+    (id, request)
+}
+
+/// Translate a request object into the line of the text protocol which has the same meaning
+fn to_line(object: &Map<String, Value>) -> Result<String, ResponseError> {
+    let Some(Value::String(cmd)) = object.get("cmd") else {
+        return Err(parse_error("Requests must contain a cmd string"));
+    };
+    let Some((name, arguments)) = COMMANDS
+        .iter()
+        .find(|(candidate, _)| candidate.eq_ignore_ascii_case(cmd))
+    else {
+        return Err(parse_error(&format!("Unknown command {:?}", cmd)));
+    };
+    let mut line = name.to_ascii_uppercase();
+    for argument in arguments.iter().map_while(|argument| object.get(*argument)) {
+        match argument {
+            Value::Array(items) => {
+                for item in items {
+                    push_argument(&mut line, item)?;
+                }
+            }
+            argument => push_argument(&mut line, argument)?,
+        }
+    }
+    Ok(line)
+}
+
+/// Append a single argument to a request line
+///
+/// Strings must not contain whitespace since it would be interpreted as the start of the next argument.
+fn push_argument(line: &mut String, argument: &Value) -> Result<(), ResponseError> {
+    line.push(' ');
+    match argument {
+        Value::Bool(true) => line.push_str("on"),
+        Value::Bool(false) => line.push_str("off"),
+        Value::Number(number) => line.push_str(&number.to_string()),
+        Value::String(string) if !string.is_empty() && !string.contains(char::is_whitespace) => {
+            line.push_str(string)
+        }
+        argument => return Err(parse_error(&format!("Invalid argument {}", argument))),
+    }
+    Ok(())
+}
+
+fn parse_error(message: &str) -> ResponseError {
+    ResponseError::ParseError(message.to_string())
+}
+
+/// Encode the result of a request as a JSON object or return `None` if nothing should be sent
+pub(super) fn encode_result(
+    id: Option<Value>,
+    result: &Result<Option<Response>, ResponseError>,
+) -> Option<String> {
+    let mut object = match result {
+        Ok(None) => return None,
+        Ok(Some(response)) => encode_response(response),
+        Err(e) => encode_error(e),
+    };
+    if let Some(id) = id {
+        object.insert("id".to_string(), id);
+    }
+    Some(Value::Object(object).to_string())
+}
+
+/// Encode an error like the `ERR` lines of the text protocol
+fn encode_error(error: &ResponseError) -> Map<String, Value> {
+    // the message may have been customized by a template
+    let text = texts::error_text(error);
+    let message = text.splitn(3, ' ').nth(2).unwrap_or_default();
+    object(json!({ "error": error.code(), "message": message }))
+}
+
+fn encode_response(response: &Response) -> Map<String, Value> {
+    object(match response {
+        Response::Help(_) => json!({ "cmd": "help", "text": response.to_string() }),
+        Response::HelpJson(description) => json!({
+            "cmd": "help",
+            "description": serde_json::from_str::<Value>(description).unwrap_or_default(),
+        }),
+        Response::Hello { version, features } => {
+            json!({ "cmd": "hello", "version": version, "features": features.names().collect::<Vec<_>>() })
+        }
+        Response::Size { width, height } => json!({ "cmd": "size", "width": width, "height": height }),
+        Response::PxData { x, y, color } => {
+            json!({ "cmd": "px", "x": x, "y": y, "color": format!("{:X}", color) })
+        }
+        Response::PxBlock { region, colors } => json!({
+            "cmd": "pxget",
+            "x": region.x,
+            "y": region.y,
+            "width": region.width,
+            "height": region.height,
+            "colors": colors.iter().map(|color| format!("{:X}", color)).collect::<Vec<_>>(),
+        }),
+        Response::Info(_) | Response::Stats(_) | Response::Limits(_) => key_values(&response.to_string()),
+        Response::NoReply(enabled) => json!({ "cmd": "noreply", "enabled": enabled }),
+        Response::Executed(count) => json!({ "cmd": "exec", "count": count }),
+        // the remaining responses are confirmations like `AUTH OK` or `COMPRESS gzip`
+        _ => {
+            let text = response.to_string();
+            let (cmd, value) = text.split_once(' ').unwrap_or((&text, "OK"));
+            match value {
+                "OK" => json!({ "cmd": cmd.to_ascii_lowercase(), "ok": true }),
+                value => json!({ "cmd": cmd.to_ascii_lowercase(), "value": value }),
+            }
+        }
+    })
+}
+
+/// Encode a response which consists of `key=value` pairs like INFO as an object with the same keys
+///
+/// Values which are integers are encoded as numbers.
+fn key_values(text: &str) -> Value {
+    let mut parts = text.split(' ');
+    let mut object = Map::new();
+    object.insert(
+        "cmd".to_string(),
+        Value::from(parts.next().unwrap_or_default().to_ascii_lowercase()),
+    );
+    for (key, value) in parts.filter_map(|part| part.split_once('=')) {
+        let value = match value.parse::<u64>() {
+            Ok(number) => Value::from(number),
+            Err(_) => Value::from(value),
+        };
+        object.insert(key.to_string(), value);
+    }
+    Value::Object(object)
+}
+
+fn object(value: Value) -> Map<String, Value> {
+    match value {
+        Value::Object(object) => object,
+        _ => unreachable!("responses are always encoded as objects"),
+    }
+}
+
+/// Encode a request as the JSON object which a server expects from clients in this mode
+///
+/// This is the inverse of [`parse_request`]. Requests without a JSON representation like image uploads are sent
+/// with only their command so that the server rejects them.
+pub(crate) fn encode_request(request: &Request) -> String {
+    let mut line = Vec::new();
+    request.write(&mut line).expect("writing into a Vec cannot fail");
+    let line = String::from_utf8_lossy(&line);
+    let mut words = line.split_whitespace();
+    let cmd = words.next().unwrap_or_default().to_ascii_lowercase();
+    let words = words.collect::<Vec<_>>();
+    let arguments = COMMANDS
+        .iter()
+        .find(|(name, _)| *name == cmd)
+        .map_or(&[][..], |(_, arguments)| *arguments);
+
+    let mut object = Map::new();
+    object.insert("cmd".to_string(), Value::from(cmd));
+    for (i, (argument, word)) in arguments.iter().zip(&words).enumerate() {
+        // the last argument takes all remaining words like the features of HELLO
+        let value = match i + 1 == arguments.len() && words.len() > arguments.len() {
+            true => Value::Array(words[i..].iter().map(|word| encode_argument(word)).collect()),
+            false => encode_argument(word),
+        };
+        object.insert(argument.to_string(), value);
+    }
+    Value::Object(object).to_string()
+}
+
+/// Encode a single argument of a request line as the JSON value which is translated back into the same word
+fn encode_argument(word: &str) -> Value {
+    match word {
+        "on" => Value::Bool(true),
+        "off" => Value::Bool(false),
+        // colors like 000080 must stay strings to keep their leading zeros
+        word => match word.parse::<u64>() {
+            Ok(number) if number.to_string() == word => Value::from(number),
+            _ => Value::from(word),
+        },
+    }
+}
+
+/// Decode a response which a server sent as a JSON object in this mode
+///
+/// This is the inverse of [`encode_result`]. If the server sent an error instead, it is returned as a
+/// [`ResponseError`].
+pub(crate) fn decode_result(msg: &str) -> anyhow::Result<Response> {
+    let Value::Object(object) = serde_json::from_str::<Value>(msg)? else {
+        return Err(anyhow!("server did not send a JSON object"));
+    };
+    if let Some(code) = object.get("error").and_then(Value::as_str) {
+        let message = object.get("message").and_then(Value::as_str).unwrap_or_default();
+        return match ResponseError::from_code(code, message.to_string()) {
+            Some(error) => Err(error.into()),
+            None => Err(anyhow!("server sent an unknown error {}: {}", code, message)),
+        };
+    }
+    let Some(cmd) = object.get("cmd").and_then(Value::as_str) else {
+        return Err(anyhow!("server sent a response without cmd"));
+    };
+
+    // translate the response back into the line of the text protocol which has the same meaning
+    let fields: &[&str] = match cmd {
+        "help" => match object.get("description") {
+            Some(description) => return Ok(Response::HelpJson(description.to_string())),
+            None => &["text"],
+        },
+        "hello" => &["version", "features"],
+        "size" => &["width", "height"],
+        "px" => &["x", "y", "color"],
+        "pxget" => &["x", "y", "width", "height", "colors"],
+        "noreply" => &["enabled"],
+        "exec" => &["count"],
+        _ => &[],
+    };
+    let mut line = match cmd {
+        "help" => String::new(),
+        cmd => cmd.to_ascii_uppercase(),
+    };
+    for field in fields {
+        let value = object
+            .get(*field)
+            .ok_or_else(|| anyhow!("{} response lacks {}", cmd, field))?;
+        match (*field, value) {
+            ("colors", Value::Array(colors)) => {
+                let rgb = colors
+                    .iter()
+                    .map(|color| {
+                        let color = color.as_str().unwrap_or_default().parse::<Color>()?;
+                        Ok(<[u8; 3]>::from(color))
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                push_word(&mut line, &BASE64_STANDARD.encode(rgb.concat()));
+            }
+            (_, Value::Array(items)) => items.iter().for_each(|item| push_value(&mut line, item)),
+            (_, value) => push_value(&mut line, value),
+        }
+    }
+    if fields.is_empty() {
+        match (object.get("ok"), object.get("value")) {
+            (Some(Value::Bool(true)), _) => push_word(&mut line, "OK"),
+            (_, Some(value)) => push_value(&mut line, value),
+            // the remaining responses consist of key=value pairs like INFO
+            _ => {
+                for (key, value) in object.iter().filter(|(key, _)| *key != "cmd" && *key != "id") {
+                    push_value(&mut line, &Value::from(format!("{}={}", key, value_word(value))));
+                }
+            }
+        }
+    }
+    Ok(parse_response_str(&line)?)
+}
+
+/// Append a single JSON value to a response line as the word which it was encoded from
+fn push_value(line: &mut String, value: &Value) {
+    push_word(line, &value_word(value));
+}
+
+fn push_word(line: &mut String, word: &str) {
+    if !line.is_empty() {
+        line.push(' ');
+    }
+    line.push_str(word);
+}
+
+fn value_word(value: &Value) -> String {
+    match value {
+        Value::Bool(true) => "on".to_string(),
+        Value::Bool(false) => "off".to_string(),
+        Value::String(string) => string.clone(),
+        value => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::protocol::{Features, Region, StateAlgorithm};
+
+    #[test]
+    fn test_parse_request() {
+        let (id, request) = parse_request(r#"{"cmd":"px","x":1,"y":2,"color":"FF0080","id":7}"#);
+        assert_eq!(id, Some(json!(7)));
+        assert_eq!(
+            request,
+            Ok(Request::SetPixel {
+                x: 1,
+                y: 2,
+                color: Color::from((0xFF, 0x00, 0x80))
+            })
+        );
+        assert_eq!(
+            parse_request(r#"{"cmd":"PX","x":1,"y":2}"#).1,
+            Ok(Request::GetPixel { x: 1, y: 2 })
+        );
+        assert_eq!(
+            parse_request(r#"{"cmd":"stream","algorithm":"px","fps":10}"#).1,
+            Ok(Request::StreamState {
+                algorithm: StateAlgorithm::Px,
+                fps: 10,
+                region: None
+            })
+        );
+        assert_eq!(
+            parse_request(r#"{"cmd":"hello","version":2,"features":["STREAM","PXB"]}"#).1,
+            Ok(Request::Hello {
+                version: 2,
+                features: Features::STREAM | Features::PXB
+            })
+        );
+        assert_eq!(
+            parse_request(r#"{"cmd":"noreply","enabled":true}"#).1,
+            Ok(Request::SetNoReply(true))
+        );
+
+        assert!(parse_request("PX 1 2").1.is_err());
+        assert!(parse_request("[1, 2]").1.is_err());
+        assert!(parse_request(r#"{"x":1}"#).1.is_err());
+        assert!(parse_request(r#"{"cmd":"flood"}"#).1.is_err());
+        assert!(parse_request(r#"{"cmd":"nick","name":"a\nPX 1 1 FFFFFF"}"#)
+            .1
+            .is_err());
+    }
+
+    #[test]
+    fn test_encode_result() {
+        let response = Response::PxData {
+            x: 1,
+            y: 2,
+            color: Color::from((0xFF, 0x00, 0x80)),
+        };
+        assert_eq!(
+            encode_result(Some(json!("a")), &Ok(Some(response))).unwrap(),
+            r#"{"cmd":"px","color":"FF0080","id":"a","x":1,"y":2}"#
+        );
+        assert_eq!(encode_result(None, &Ok(None)), None);
+
+        let response = Response::PxBlock {
+            region: Region {
+                x: 0,
+                y: 0,
+                width: 2,
+                height: 1,
+            },
+            colors: vec![Color::from((0, 0, 0)), Color::from((0xFF, 0xFF, 0xFF))],
+        };
+        assert_eq!(
+            encode_result(None, &Ok(Some(response))).unwrap(),
+            r#"{"cmd":"pxget","colors":["000000","FFFFFF"],"height":1,"width":2,"x":0,"y":0}"#
+        );
+        assert_eq!(
+            encode_result(None, &Ok(Some(Response::Claimed))).unwrap(),
+            r#"{"cmd":"claim","ok":true}"#
+        );
+
+        let error = ResponseError::OutOfBounds("Pixel 900,20 is outside the canvas".to_string());
+        assert_eq!(
+            encode_result(None, &Err(error)).unwrap(),
+            r#"{"error":"OUT_OF_BOUNDS","message":"Pixel 900,20 is outside the canvas"}"#
+        );
+    }
+
+    #[test]
+    fn test_client_round_trip() {
+        let requests = [
+            Request::SetPixel {
+                x: 1,
+                y: 2,
+                color: Color::from((0x00, 0x00, 0x80)),
+            },
+            Request::GetPixel { x: 0, y: 0 },
+            Request::GetSize,
+            Request::Hello {
+                version: 2,
+                features: Features::STREAM | Features::PXB,
+            },
+            Request::SetNoReply(true),
+        ];
+        for request in requests {
+            assert_eq!(parse_request(&encode_request(&request)).1, Ok(request));
+        }
+
+        let responses = [
+            Response::PxData {
+                x: 1,
+                y: 2,
+                color: Color::from((0x00, 0x00, 0x80)),
+            },
+            Response::Size {
+                width: 800,
+                height: 600,
+            },
+            Response::PxBlock {
+                region: Region {
+                    x: 0,
+                    y: 0,
+                    width: 2,
+                    height: 1,
+                },
+                colors: vec![Color::from((0, 0, 0)), Color::from((0xFF, 0xFF, 0xFF))],
+            },
+            Response::Hello {
+                version: 2,
+                features: Features::STREAM | Features::PXB,
+            },
+            Response::NoReply(false),
+            Response::Executed(3),
+            Response::Claimed,
+        ];
+        for response in responses {
+            let encoded = encode_result(Some(json!(1)), &Ok(Some(response.clone()))).unwrap();
+            assert_eq!(decode_result(&encoded).unwrap(), response);
+        }
+
+        let error = ResponseError::OutOfBounds("Pixel 900,20 is outside the canvas".to_string());
+        let encoded = encode_result(None, &Err(error.clone())).unwrap();
+        assert_eq!(
+            decode_result(&encoded)
+                .unwrap_err()
+                .downcast::<ResponseError>()
+                .unwrap(),
+            error
+        );
+    }
+
+    #[test]
+    fn test_encode_key_values() {
+        let stats = Response::Stats(Default::default());
+        let encoded: Value = serde_json::from_str(&encode_result(None, &Ok(Some(stats))).unwrap()).unwrap();
+        assert_eq!(encoded["cmd"], "stats");
+        assert_eq!(encoded["pixels-per-sec"], 0);
+    }
+}
@@ -1,69 +1,512 @@
-use crate::net::servers::GenServer;
+use crate::net::protocol::{
+    parse_request_bin, parse_request_binary, split_tag, write_error_binary, write_response_binary,
+    Compression, Features, ParseErr, ProtocolVariant, Request, Response, ResponseError,
+};
+use crate::net::servers::compression::ResponseEncoder;
+use crate::net::servers::connection_limit::{self, ConnectionLimiter, Gatekeeper};
+use crate::net::servers::http_api;
+use crate::net::servers::proxy_protocol;
+use crate::net::servers::rate_limit::{RateLimiter, Usage};
+use crate::net::servers::sockets;
+use crate::net::servers::state_stream::StateStream;
+use crate::net::servers::statistics::{self, ConnectionGuard, Event};
+use crate::net::servers::storm_guard::StormGuard;
+use crate::net::servers::ws_deflate::{self, DeflateStream, MessageDeflater};
+use crate::net::servers::ws_json;
+use crate::net::servers::{
+    AccessControlOptions, BufferOptions, ClaimMode, ConnectionPreferences, GenServer, ListenerCapabilities,
+    RateLimitOptions, Reloadable, SocketOptions, StormProtectionOptions, WriteProtectionOptions,
+};
 use crate::pixmap::SharedPixmap;
+use crate::shutdown::{self, Phase};
+use crate::texts;
 use crate::DaemonResult;
 use anyhow::anyhow;
 use async_trait::async_trait;
 use futures_util::{SinkExt, StreamExt};
+use std::future;
 use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::task::{AbortHandle, JoinSet};
+use tokio::time::{Instant, MissedTickBehavior};
+use tokio_tungstenite::tungstenite::protocol::{Role, WebSocketConfig};
 use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+use url::form_urlencoded;
 
 /// Options with which the `WsServer` is configured
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct WsServerOptions {
     /// The address to which the server binds
     pub bind_addr: SocketAddr,
+    /// Tuning of the listening socket and of the sockets of connections
+    pub socket: SocketOptions,
+    /// The networks from which connections are accepted
+    ///
+    /// Proxied connections are checked against the address of the client from the PROXY protocol header.
+    pub access_control: Reloadable<AccessControlOptions>,
+    /// Whether and how connection storms from single IP addresses should be throttled
+    pub storm_protection: Option<StormProtectionOptions>,
+    /// How many connections a single IP address may have open at the same time, or `None` for no limit
+    pub max_connections_per_ip: Option<usize>,
+    /// How many requests and pixels a single connection may send and draw per second
+    pub rate_limit: Reloadable<RateLimitOptions>,
+    /// After which duration without requests connections are closed, or `None` to keep them open forever
+    pub idle_timeout: Option<Duration>,
+    /// Whether connections start with a PROXY protocol header which carries the actual address of the client
+    ///
+    /// This must only be enabled when the listener is exclusively reachable via a proxy which sends the header
+    /// since clients could otherwise claim arbitrary addresses.
+    pub proxy_protocol: bool,
+    /// Whether connections must authenticate before they may draw on the canvas
+    pub write_protection: Option<WriteProtectionOptions>,
+    /// How drawing into regions which other connections claimed is treated, if regions can be claimed via CLAIM
+    pub claims: Option<ClaimMode>,
+    /// The optional protocol features which clients may use, or `None` to enable all which the server supports
+    pub features: Option<Features>,
+    /// From which size on messages to clients which negotiated permessage-deflate are compressed, or `None` to not
+    /// offer the extension
+    pub deflate_threshold: Option<usize>,
+    /// How often connections are pinged, or `None` to never ping them
+    ///
+    /// Connections which did not answer a ping by the time the next one is due are considered dead and closed.
+    pub ping_interval: Option<Duration>,
 }
 
 /// A server implementation using WebSocket to transport pixelflut messages
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub struct WsServer {
     options: WsServerOptions,
 }
 
 impl WsServer {
     #[tracing::instrument(skip_all)]
-    async fn handle_listener(listener: TcpListener, pixmap: SharedPixmap) -> anyhow::Result<!> {
+    async fn handle_listener(
+        listener: TcpListener,
+        pixmap: SharedPixmap,
+        options: WsServerOptions,
+    ) -> anyhow::Result<!> {
+        let capabilities = ListenerCapabilities {
+            binary_protocol: true,
+            image_upload: false,
+            state_streaming: true,
+            compression: true,
+            max_line_len: None,
+            buffers: BufferOptions::default(),
+            storm_protection: options.storm_protection.clone().map(Arc::new),
+            max_connections_per_ip: options.max_connections_per_ip,
+            rate_limit: options.rate_limit,
+            idle_timeout: options.idle_timeout,
+            write_protection: options.write_protection.map(Arc::new),
+            claims: options.claims,
+            enabled_features: options.features,
+        };
+        let gatekeeper = Gatekeeper {
+            access_control: options.access_control,
+            storm_guard: options
+                .storm_protection
+                .map(|options| Arc::new(Mutex::new(StormGuard::new(options)))),
+            connection_limiter: options.max_connections_per_ip.map(ConnectionLimiter::new),
+        };
         loop {
-            let (stream, remote_addr) = listener.accept().await?;
+            let (mut stream, remote_addr) = listener.accept().await?;
+            let mut remote_addr = sockets::peer_addr(remote_addr);
+            // without the PROXY protocol, connections are admitted right away so that rejected ones cost nothing
+            let slot = match options.proxy_protocol {
+                true => None,
+                false => match gatekeeper.admit(remote_addr) {
+                    Ok(slot) => slot,
+                    Err(error) => {
+                        connection_limit::reject(stream, http_api::rejection(error).as_bytes());
+                        continue;
+                    }
+                },
+            };
             let pixmap = pixmap.clone();
+            let capabilities = capabilities.clone();
+            let gatekeeper = gatekeeper.clone();
+            let proxy_protocol = options.proxy_protocol;
+            let socket = options.socket;
+            let deflate_threshold = options.deflate_threshold;
+            let ping_interval = options.ping_interval;
             tokio::spawn(async move {
-                if let Err(e) = WsServer::handle_connection(stream, remote_addr, pixmap).await {
+                let mut slot = slot;
+                if let Err(e) = socket.apply_to_stream(&stream) {
+                    tracing::warn!("Could not configure WebSocket connection: {e}");
+                    return;
+                }
+                if proxy_protocol {
+                    match proxy_protocol::read_header(&mut stream).await {
+                        Ok(client_addr) => remote_addr = client_addr.map_or(remote_addr, sockets::peer_addr),
+                        Err(e) => {
+                            tracing::warn!("Got error while handling WebSocket connection: {e}");
+                            return;
+                        }
+                    }
+                    slot = match gatekeeper.admit(remote_addr) {
+                        Ok(slot) => slot,
+                        Err(error) => {
+                            connection_limit::reject(stream, http_api::rejection(error).as_bytes());
+                            return;
+                        }
+                    };
+                }
+                let _slot = slot;
+                if let Err(e) = WsServer::handle_connection(
+                    stream,
+                    remote_addr,
+                    pixmap,
+                    capabilities,
+                    deflate_threshold,
+                    ping_interval,
+                )
+                .await
+                {
                     tracing::error!("Got error while handling WebSocket connection: {e}");
                 }
             });
         }
     }
 
-    #[tracing::instrument(skip_all, fields(remote = _remote_addr.to_string()))]
+    #[tracing::instrument(skip_all, fields(remote = remote_addr.to_string(), nick = tracing::field::Empty))]
     async fn handle_connection(
         stream: TcpStream,
-        _remote_addr: SocketAddr,
+        remote_addr: SocketAddr,
         pixmap: SharedPixmap,
+        capabilities: ListenerCapabilities,
+        deflate_threshold: Option<usize>,
+        ping_interval: Option<Duration>,
     ) -> anyhow::Result<()> {
+        let mut stream = stream;
+        let (head, rest) = http_api::read_head(&mut stream).await?;
+        let Some(websocket_key) = &head.websocket_key else {
+            tracing::debug!("Client sent HTTP API request {} {}", head.method, head.path);
+            return http_api::serve(stream, head, rest, &pixmap, &capabilities).await;
+        };
+        // clients which request it exchange requests and responses as JSON objects instead of protocol lines
+        let json = form_urlencoded::parse(head.query.as_bytes())
+            .any(|(key, value)| key == "format" && value == "json");
+        let deflate = deflate_threshold.and_then(|_| ws_deflate::negotiate(&head.websocket_extensions));
         tracing::debug!("Client connected; performing WebSocket handshake");
-        let mut stream = tokio_tungstenite::accept_async(stream).await?;
+        let extensions = deflate.map(|params| params.header_value());
+        stream
+            .write_all(http_api::upgrade_response(websocket_key, extensions.as_deref()).as_bytes())
+            .await?;
+        let stream = DeflateStream::new(stream, deflate);
+        // the limits of tungstenite match those with which compressed messages are inflated
+        let config = WebSocketConfig {
+            max_message_size: Some(ws_deflate::MAX_MESSAGE_LEN),
+            max_frame_size: Some(ws_deflate::MAX_FRAME_LEN),
+            ..Default::default()
+        };
+        let mut stream = WebSocketStream::from_partially_read(stream, rest, Role::Server, Some(config)).await;
+        let mut deflater = deflate
+            .zip(deflate_threshold)
+            .map(|(params, threshold)| MessageDeflater::new(params, threshold));
+        let connection = ConnectionGuard::new(Some(remote_addr));
+        let shutdown = shutdown::Guard::new(Phase::Draining);
+        let mut response_encoder = ResponseEncoder::default();
+        let mut preferences = ConnectionPreferences {
+            client: Some(connection.id()),
+            remote: Some(remote_addr.ip()),
+            ..Default::default()
+        };
+        let mut state_stream = StateStream::default();
+        let mut rate_limiter = RateLimiter::new(capabilities.rate_limit.clone())
+            .with_storm_protection(capabilities.storm_protection.as_deref(), remote_addr.ip());
+        let mut pings = ping_interval.map(|ping_interval| {
+            let mut pings = tokio::time::interval_at(Instant::now() + ping_interval, ping_interval);
+            pings.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            pings
+        });
+        // when the most recent ping was sent, as long as it was not answered
+        let mut unanswered_ping: Option<Instant> = None;
 
         loop {
-            let request = stream.next().await;
-            let request = match &request {
+            // receive the next message or send the next frame of a requested state stream
+            let msg = tokio::select! {
+                msg = stream.next() => msg,
+                settings = state_stream.tick(preferences.stream) => {
+                    let mut frame = Vec::new();
+                    state_stream.write_binary_frame(&pixmap, settings, &mut frame)?;
+                    if !frame.is_empty() {
+                        Self::send_binary(&mut stream, &connection, &mut response_encoder, &mut deflater, frame).await?;
+                    }
+                    continue;
+                }
+                _ = async {
+                    match &mut pings {
+                        Some(pings) => pings.tick().await,
+                        None => future::pending().await,
+                    }
+                } => {
+                    if unanswered_ping.is_some() {
+                        tracing::debug!("Closing connection which did not answer a ping within {:?}", ping_interval);
+                        // a dead peer would never complete a closing handshake
+                        return Ok(());
+                    }
+                    unanswered_ping = Some(Instant::now());
+                    let ping = Message::Ping(Vec::new());
+                    connection.transferred(0, ping.len());
+                    stream.send(ping).await?;
+                    continue;
+                }
+                _ = super::idle(capabilities.idle_timeout, preferences.stream.is_some()) => {
+                    tracing::debug!("Closing connection which did not send anything for {:?}", capabilities.idle_timeout);
+                    statistics::record(Event::IdleTimeout);
+                    stream.close(None).await?;
+                    return Ok(());
+                }
+                _ = shutdown.requested() => {
+                    tracing::debug!("Closing connection since the server shuts down");
+                    let error = ResponseError::ShuttingDown("The server is shutting down".to_string());
+                    let text = match json {
+                        true => ws_json::encode_result(None, &Err(error)).unwrap_or_default(),
+                        false => texts::error_text(&error),
+                    };
+                    Self::send_text(&mut stream, &connection, &mut response_encoder, &mut deflater, text).await?;
+                    stream.close(None).await?;
+                    return Ok(());
+                }
+            };
+            let msg = match msg {
                 None => return Err(anyhow!("stream is closed")),
                 Some(Err(e)) => return Err(anyhow!("{}", e)),
-                Some(Ok(msg)) => match msg {
-                    Message::Text(msg) => msg.as_bytes(),
-                    Message::Binary(msg) => &msg,
-                    Message::Close(_) => return Err(anyhow!("WebSocket connection was closed")),
-                    _ => return Err(anyhow!("Got unexpected websocket message: {msg:?}")),
-                },
+                Some(Ok(msg)) => msg,
             };
-            let result = super::handle_request(request, &pixmap);
-            match result {
-                Err(e) => stream.send(Message::Text(e)).await?,
-                Ok(Some(response)) => stream.send(Message::Text(format!("{}", response))).await?,
+            connection.transferred(msg.len(), 0);
+            match msg {
+                Message::Text(msg) if json => {
+                    let (id, request) = ws_json::parse_request(&msg);
+                    tracing::trace!("Handling single JSON request {:?}", request);
+                    let usage = Usage::of(&request);
+                    let result = Self::handle_request(request, &pixmap, &capabilities, &mut preferences);
+                    if let Some(text) = ws_json::encode_result(id, &result) {
+                        Self::send_text(
+                            &mut stream,
+                            &connection,
+                            &mut response_encoder,
+                            &mut deflater,
+                            text,
+                        )
+                        .await?;
+                    }
+                    if let Ok(Some(Response::Compression(compression))) = result {
+                        let rest = response_encoder.switch(compression)?;
+                        if !rest.is_empty() {
+                            connection.transferred(0, rest.len());
+                            stream.send(Message::Binary(rest)).await?;
+                        }
+                    }
+                    rate_limiter.throttle(usage).await;
+                }
+                Message::Text(msg) => {
+                    let (tag, request) = split_tag(msg.as_bytes());
+                    tracing::trace!("Handling single request {:?}", request);
+                    let request = parse_request_bin(request).map_err(ResponseError::from);
+                    let usage = Usage::of(&request);
+                    let result = Self::handle_request(request, &pixmap, &capabilities, &mut preferences);
+                    let text = match &result {
+                        Err(e) => Some(texts::error_text(e)),
+                        Ok(Some(response)) => Some(format!("{}", response)),
+                        Ok(None) => None,
+                    };
+                    let text = match tag {
+                        Some(tag) => text.map(|text| format!("#{} {}", tag, text)),
+                        None => text,
+                    };
+
+                    if let Some(text) = text {
+                        Self::send_text(
+                            &mut stream,
+                            &connection,
+                            &mut response_encoder,
+                            &mut deflater,
+                            text,
+                        )
+                        .await?;
+                    }
+                    if let Ok(Some(Response::Compression(compression))) = result {
+                        let rest = response_encoder.switch(compression)?;
+                        if !rest.is_empty() {
+                            connection.transferred(0, rest.len());
+                            stream.send(Message::Binary(rest)).await?;
+                        }
+                    }
+                    rate_limiter.throttle(usage).await;
+                }
+                Message::Binary(msg) => {
+                    let (responses, usage) =
+                        Self::handle_binary_message(&msg, &pixmap, &capabilities, &mut preferences)?;
+                    if !responses.is_empty() {
+                        Self::send_binary(
+                            &mut stream,
+                            &connection,
+                            &mut response_encoder,
+                            &mut deflater,
+                            responses,
+                        )
+                        .await?;
+                    }
+                    rate_limiter.throttle(usage).await;
+                }
+                Message::Pong(_) => {
+                    if let Some(sent) = unanswered_ping.take() {
+                        connection.pong(sent.elapsed());
+                    }
+                }
+                // tungstenite answers pings of the client on its own
+                Message::Ping(_) => {}
+                Message::Close(_) => return Err(anyhow!("WebSocket connection was closed")),
+                msg => return Err(anyhow!("Got unexpected websocket message: {msg:?}")),
+            }
+        }
+    }
+
+    /// Handle all binary protocol requests of a binary message and return their encoded responses as well as
+    /// how much of the rate limits of the connection they used up
+    ///
+    /// A message which ends with an incomplete request or contains an unknown opcode is answered with an error and
+    /// the rest of it is discarded.
+    fn handle_binary_message(
+        msg: &[u8],
+        pixmap: &SharedPixmap,
+        capabilities: &ListenerCapabilities,
+        preferences: &mut ConnectionPreferences,
+    ) -> std::io::Result<(Vec<u8>, Usage)> {
+        let mut responses = Vec::new();
+        let mut usage = Usage::default();
+        if let Err(e) = super::check_features(
+            &Request::SetProtocol(ProtocolVariant::Binary),
+            capabilities,
+            preferences,
+        ) {
+            write_error_binary(&texts::error_text(&e), &mut responses)?;
+            return Ok((responses, usage));
+        }
+
+        let mut buf = msg;
+        while !buf.is_empty() {
+            let request = match parse_request_binary(buf) {
+                Ok(Some((request, len))) => {
+                    buf = &buf[len..];
+                    Ok(request)
+                }
+                Ok(None) => {
+                    buf = &[];
+                    Err(ResponseError::from(ParseErr::InvalidCommand))
+                }
+                Err(e) => {
+                    buf = &[];
+                    Err(ResponseError::from(e))
+                }
+            };
+            tracing::trace!("Handling single binary request {:?}", request);
+            usage += Usage::of(&request);
+            match Self::handle_request(request, pixmap, capabilities, preferences) {
+                Ok(Some(response)) => write_response_binary(&response, &mut responses)?,
+                Err(e) => write_error_binary(&texts::error_text(&e), &mut responses)?,
                 Ok(None) => {}
             }
         }
+        Ok((responses, usage))
+    }
+
+    /// Handle a single parsed request and return the response which should be sent to the client, if any
+    fn handle_request(
+        request: Result<Request, ResponseError>,
+        pixmap: &SharedPixmap,
+        capabilities: &ListenerCapabilities,
+        preferences: &mut ConnectionPreferences,
+    ) -> Result<Option<Response>, ResponseError> {
+        let request = request
+            .and_then(|request| super::check_features(&request, capabilities, preferences).map(|_| request));
+        let quiet = preferences.no_reply && request.as_ref().is_ok_and(Request::is_write);
+        let result = match request {
+            Ok(Request::SetProtocol(_)) => Err(ResponseError::Unsupported(
+                "WebSocket connections send binary protocol requests as binary messages instead".to_string(),
+            )),
+            Ok(Request::SetCompression(compression)) => Ok(Some(Response::Compression(compression))),
+            Ok(Request::Authenticate(token)) => super::authenticate(&token, capabilities, preferences),
+            Ok(Request::Hello { version, features }) => {
+                super::hello(version, features, capabilities, preferences)
+            }
+            Ok(Request::SetNoReply(enabled)) => {
+                preferences.no_reply = enabled;
+                Ok(Some(Response::NoReply(enabled)))
+            }
+            Ok(Request::Claim { region, secs }) => {
+                super::claim(region, secs, pixmap, capabilities, preferences)
+            }
+            Ok(Request::Release) => super::release(capabilities, preferences),
+            Ok(Request::Multi) => super::multi(preferences),
+            Ok(Request::Exec) => super::exec(pixmap, capabilities, preferences),
+            Ok(request) if request.is_write() && preferences.transaction.is_some() => {
+                super::queue(request, preferences)
+            }
+            Ok(Request::StreamState {
+                algorithm,
+                fps,
+                region,
+            }) => super::stream_state(algorithm, fps, region, pixmap, preferences),
+            Ok(request) => {
+                let pixels = statistics::pixels_drawn(&request);
+                let result = super::handle_parsed_request(request, pixmap, capabilities, preferences);
+                if super::pixels_were_drawn(&result, capabilities) && pixels > 0 {
+                    statistics::record(Event::PixelsSet(preferences.client, pixels));
+                }
+                result
+            }
+            Err(e) => Err(e),
+        };
+        if quiet {
+            Ok(None)
+        } else {
+            result
+        }
+    }
+
+    /// Send a text message to the client
+    ///
+    /// Compressed texts are sent as binary messages.
+    async fn send_text(
+        stream: &mut WebSocketStream<DeflateStream<TcpStream>>,
+        connection: &ConnectionGuard,
+        response_encoder: &mut ResponseEncoder,
+        deflater: &mut Option<MessageDeflater>,
+        text: String,
+    ) -> anyhow::Result<()> {
+        let message = match (response_encoder.compression(), deflater) {
+            (Compression::None, Some(deflater)) => deflater.deflate(Message::Text(text))?,
+            (Compression::None, None) => Message::Text(text),
+            _ => Message::Binary(response_encoder.encode(text.as_bytes())?.into_owned()),
+        };
+        connection.transferred(0, message.len());
+        stream.send(message).await?;
+        Ok(())
+    }
+
+    /// Send a binary message to the client, compressing it if requested
+    ///
+    /// Messages which are compressed via COMPRESS are not deflated again.
+    async fn send_binary(
+        stream: &mut WebSocketStream<DeflateStream<TcpStream>>,
+        connection: &ConnectionGuard,
+        response_encoder: &mut ResponseEncoder,
+        deflater: &mut Option<MessageDeflater>,
+        data: Vec<u8>,
+    ) -> anyhow::Result<()> {
+        let message = match (response_encoder.compression(), deflater) {
+            (Compression::None, Some(deflater)) => deflater.deflate(Message::Binary(data))?,
+            (Compression::None, None) => Message::Binary(data),
+            _ => Message::Binary(response_encoder.encode(&data)?.into_owned()),
+        };
+        connection.transferred(0, message.len());
+        stream.send(message).await?;
+        Ok(())
     }
 }
 
@@ -80,13 +523,110 @@ impl GenServer for WsServer {
         pixmap: SharedPixmap,
         join_set: &mut JoinSet<DaemonResult>,
     ) -> anyhow::Result<AbortHandle> {
-        let listener = TcpListener::bind(self.options.bind_addr).await?;
+        let listener = sockets::tcp_listener(self.options.bind_addr, &self.options.socket)?;
+        statistics::start();
         tracing::info!("Started WebSocket Server on {}", self.options.bind_addr);
 
         let handle = join_set
             .build_task()
             .name("ws_server")
-            .spawn(async move { WsServer::handle_listener(listener, pixmap).await })?;
+            .spawn(async move { WsServer::handle_listener(listener, pixmap, self.options).await })?;
         Ok(handle)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pixmap::{Color, Pixmap};
+    use tokio::task::JoinHandle;
+
+    /// Open a WebSocket connection which is handled by the server
+    async fn connect(
+        pixmap: SharedPixmap,
+        capabilities: ListenerCapabilities,
+        ping_interval: Option<Duration>,
+    ) -> (
+        WebSocketStream<TcpStream>,
+        SocketAddr,
+        JoinHandle<anyhow::Result<()>>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (server_stream, remote) = listener.accept().await.unwrap();
+        let server = tokio::spawn(WsServer::handle_connection(
+            server_stream,
+            remote,
+            pixmap,
+            capabilities,
+            None,
+            ping_interval,
+        ));
+        let (ws, _) = tokio_tungstenite::client_async(format!("ws://{}/", addr), stream)
+            .await
+            .unwrap();
+        (ws, remote, server)
+    }
+
+    /// Receive the next state frame and return its data
+    async fn next_frame(ws: &mut WebSocketStream<TcpStream>) -> Vec<u8> {
+        match ws.next().await {
+            // the data follows the opcode, algorithm, token, base and length
+            Some(Ok(Message::Binary(frame))) => frame[22..].to_vec(),
+            msg => panic!("expected a state frame but got {:?}", msg),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pings_are_answered() {
+        let pixmap = Arc::new(Pixmap::new(4, 2).unwrap());
+        let capabilities = ListenerCapabilities::default();
+        let (mut ws, remote, server) = connect(pixmap, capabilities, Some(Duration::from_millis(50))).await;
+        let client = tokio::spawn(async move {
+            // tungstenite answers pings while waiting for the next message
+            while let Some(msg) = ws.next().await {
+                assert!(matches!(msg, Ok(Message::Ping(_))));
+            }
+        });
+
+        // the accounting of clients is only published once per second
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let rtt = loop {
+            let connection = statistics::clients()
+                .connections
+                .into_iter()
+                .find(|connection| connection.remote == Some(remote));
+            match connection.and_then(|connection| connection.rtt) {
+                Some(rtt) => break rtt,
+                None if Instant::now() < deadline => tokio::time::sleep(Duration::from_millis(50)).await,
+                None => panic!("the pong of the client was not recorded"),
+            }
+        };
+        assert!(rtt < Duration::from_secs(1));
+        server.abort();
+        client.abort();
+    }
+
+    #[tokio::test]
+    async fn test_streamed_updates_are_coalesced() {
+        let pixmap = Arc::new(Pixmap::new(4, 2).unwrap());
+        let capabilities = ListenerCapabilities {
+            state_streaming: true,
+            ..Default::default()
+        };
+        let (mut ws, _, server) = connect(pixmap.clone(), capabilities, None).await;
+        ws.send(Message::Text("STREAM delta 30".to_string()))
+            .await
+            .unwrap();
+        // the first frame contains all pixels
+        assert_eq!(next_frame(&mut ws).await.len(), 8 * 11);
+
+        // a pixel which is drawn repeatedly is only sent once with its latest color
+        pixmap.set_pixel(1, 0, Color::from((0xFF, 0, 0))).unwrap();
+        pixmap.set_pixel(1, 0, Color::from((0, 0xFF, 0))).unwrap();
+        pixmap.set_pixel(1, 0, Color::from((0, 0, 0xFF))).unwrap();
+        assert_eq!(next_frame(&mut ws).await, [0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0xFF]);
+        server.abort();
+    }
+}
@@ -1,4 +1,6 @@
-use std::fmt::{Formatter, LowerHex, UpperHex};
+use std::fmt::{Display, Formatter, LowerHex, UpperHex};
+use std::str::FromStr;
+use thiserror::Error;
 
 #[cfg(test)]
 use quickcheck::{Arbitrary, Gen};
@@ -7,9 +9,92 @@ use quickcheck::{Arbitrary, Gen};
 ///
 /// The internal format is 0RGB stored as one u32.
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Default, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(C)]
 pub struct Color(u32);
 
+impl Color {
+    /// Blend the color `over` onto this one using `alpha` as the opacity of `over`
+    ///
+    /// An alpha of 0 keeps this color while an alpha of 255 results in `over`.
+    pub fn blend(self, over: Color, alpha: u8) -> Color {
+        let [_, r1, g1, b1] = self.0.to_be_bytes();
+        let [_, r2, g2, b2] = over.0.to_be_bytes();
+        let mix = |below: u8, above: u8| {
+            ((above as u32 * alpha as u32 + below as u32 * (255 - alpha as u32) + 127) / 255) as u8
+        };
+        Color::from((mix(r1, r2), mix(g1, g2), mix(b1, b2)))
+    }
+
+    /// Combine the color `over` with this one according to `mode`
+    pub fn compose(self, over: Color, mode: BlendMode) -> Color {
+        let [_, r1, g1, b1] = self.0.to_be_bytes();
+        let [_, r2, g2, b2] = over.0.to_be_bytes();
+        let mix = |below: u8, above: u8| match mode {
+            BlendMode::Normal => above,
+            BlendMode::Add => below.saturating_add(above),
+            BlendMode::Multiply => ((below as u32 * above as u32 + 127) / 255) as u8,
+            BlendMode::Screen => 255 - (((255 - below) as u32 * (255 - above) as u32 + 127) / 255) as u8,
+        };
+        Color::from((mix(r1, r2), mix(g1, g2), mix(b1, b2)))
+    }
+}
+
+/// How a color is combined with the color of the pixel onto which it is drawn
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BlendMode {
+    /// The pixel is replaced by the new color
+    #[default]
+    Normal,
+    /// The channels of both colors are added, which brightens the pixel
+    Add,
+    /// The channels of both colors are multiplied, which darkens the pixel
+    Multiply,
+    /// The inverted channels of both colors are multiplied, which brightens the pixel without saturating it
+    Screen,
+}
+
+impl BlendMode {
+    /// The name of the mode on the wire
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BlendMode::Normal => "normal",
+            BlendMode::Add => "add",
+            BlendMode::Multiply => "multiply",
+            BlendMode::Screen => "screen",
+        }
+    }
+}
+
+impl Display for BlendMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// An error which indicates that a string could not be parsed into a [`BlendMode`]
+#[derive(Debug, Error, Clone, Eq, PartialEq)]
+#[error("Invalid blend mode {0:?}; expected normal, add, multiply or screen")]
+pub struct InvalidBlendModeError(String);
+
+impl FromStr for BlendMode {
+    type Err = InvalidBlendModeError;
+
+    /// Parse the name of a blend mode, ignoring the case
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [
+            BlendMode::Normal,
+            BlendMode::Add,
+            BlendMode::Multiply,
+            BlendMode::Screen,
+        ]
+        .into_iter()
+        .find(|mode| mode.as_str().eq_ignore_ascii_case(s))
+        .ok_or_else(|| InvalidBlendModeError(s.to_string()))
+    }
+}
+
 impl From<[u8; 3]> for Color {
     fn from(data: [u8; 3]) -> Self {
         Self(u32::from_be_bytes([0, data[0], data[1], data[2]]))
@@ -55,10 +140,27 @@ impl From<Color> for Vec<u8> {
     }
 }
 
-impl ToString for Color {
-    fn to_string(&self) -> String {
-        let channels: [u8; 3] = (*self).into();
-        format!("#{:02X}{:02X}{:02X}", channels[0], channels[1], channels[2])
+/// An error which indicates that a string is not a hex encoded rgb color
+#[derive(Debug, Error, Clone, Eq, PartialEq)]
+#[error("Invalid color {0:?}; expected a hex encoded rgb color like #FF0080")]
+pub struct InvalidColorError(String);
+
+impl Display for Color {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("#{:X}", self))
+    }
+}
+
+impl FromStr for Color {
+    type Err = InvalidColorError;
+
+    /// Parse a hex encoded rgb color with an optional leading `#`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s.strip_prefix('#').unwrap_or(s);
+        match hex.len() == 6 && hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+            true => Ok(Color(u32::from_str_radix(hex, 16).unwrap())),
+            false => Err(InvalidColorError(s.to_string())),
+        }
     }
 }
 
@@ -109,3 +211,43 @@ fn test_conversion() {
     run_test([0xAA, 0xBB, 0xCC], Color(0x00AABBCC));
     run_test(0x00AABBCC, Color(0x00AABBCC));
 }
+
+#[cfg(test)]
+quickcheck! {
+    fn test_string_conversion_inversion(color: Color) -> bool {
+        let color = Color::from(<[u8; 3]>::from(color));
+        color.to_string().parse() == Ok(color) && format!("{:X}", color).parse() == Ok(color)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_blend() {
+    let below = Color::from((0x00, 0x80, 0xFF));
+    let above = Color::from((0xFF, 0x80, 0x00));
+    assert_eq!(below.blend(above, 0), below);
+    assert_eq!(below.blend(above, 255), above);
+    assert_eq!(below.blend(above, 128), Color::from((0x80, 0x80, 0x7F)));
+}
+
+#[cfg(test)]
+#[test]
+fn test_compose() {
+    let below = Color::from((0x00, 0x80, 0xFF));
+    let above = Color::from((0xFF, 0x80, 0x40));
+    assert_eq!(below.compose(above, BlendMode::Normal), above);
+    assert_eq!(
+        below.compose(above, BlendMode::Add),
+        Color::from((0xFF, 0xFF, 0xFF))
+    );
+    assert_eq!(
+        below.compose(above, BlendMode::Multiply),
+        Color::from((0x00, 0x40, 0x40))
+    );
+    assert_eq!(
+        below.compose(above, BlendMode::Screen),
+        Color::from((0xFF, 0xC0, 0xFF))
+    );
+    assert_eq!("Screen".parse(), Ok(BlendMode::Screen));
+    assert!("overlay".parse::<BlendMode>().is_err());
+}
@@ -8,8 +8,10 @@ pub use color::*;
 
 mod color;
 mod storage;
+mod transform;
 
 pub use storage::{InvalidCoordinatesError, Pixmap};
+pub use transform::{InvalidRotationError, InvalidTransformError, Rotation, Transform};
 
 /// A [`Pixmap`] which can be used throughout multiple threads
 ///
@@ -1,13 +1,18 @@
-use crate::pixmap::Color;
+use crate::pixmap::{BlendMode, Color, Transform};
 use std::cell::SyncUnsafeCell;
 use thiserror::Error;
 
 /// A fast pixel storage implementation
+///
+/// A pixmap can optionally be configured with a [`Transform`] which is applied when pixels are stored.
+/// All accessors work with untransformed coordinates while the raw color data (which is what sinks render) contains
+/// the transformed image.
 #[derive(Debug)]
 pub struct Pixmap {
     data: SyncUnsafeCell<Vec<Color>>,
     width: usize,
     height: usize,
+    transform: Transform,
 }
 
 /// An error which indicates that invalid coordinates could not be accessed
@@ -36,6 +41,15 @@ pub struct InvalidDataShapeError {
 impl Pixmap {
     /// Create a new Pixmap with the specified dimensions
     pub fn new(width: usize, height: usize) -> Result<Self, InvalidSizeError> {
+        Self::new_transformed(width, height, Transform::default())
+    }
+
+    /// Create a new Pixmap with the specified dimensions whose stored data is transformed by `transform`
+    pub fn new_transformed(
+        width: usize,
+        height: usize,
+        transform: Transform,
+    ) -> Result<Self, InvalidSizeError> {
         if width == 0 || height == 0 {
             return Err(InvalidSizeError {
                 size: (width, height),
@@ -47,17 +61,63 @@ impl Pixmap {
             data: SyncUnsafeCell::new(vec![Color::default(); width * height]),
             width,
             height,
+            transform,
         })
     }
 
+    /// Create a copy of this pixmap which has the same content but stores it with a different transform
+    pub fn with_transform(self, transform: Transform) -> Self {
+        if transform == self.transform {
+            return self;
+        }
+
+        let result = Self::new_transformed(self.width, self.height, transform)
+            .expect("pixmap size has already been validated");
+        for y in 0..self.height {
+            for x in 0..self.width {
+                result.set_pixel(x, y, self.get_pixel(x, y).unwrap()).unwrap();
+            }
+        }
+        result
+    }
+
     /// Get the size of this pixmap as `(width, height)` tuple
     pub fn get_size(&self) -> (usize, usize) {
         (self.width, self.height)
     }
 
+    /// Get the size of the raw color data as `(width, height)` tuple
+    ///
+    /// This differs from [`get_size()`](Self::get_size) if the pixmap is configured with a transform that rotates
+    /// by 90 or 270 degrees.
+    pub fn get_data_size(&self) -> (usize, usize) {
+        self.transform.transformed_size(self.width, self.height)
+    }
+
+    /// Get the transform which is applied to the stored data
+    pub fn get_transform(&self) -> Transform {
+        self.transform
+    }
+
+    /// Calculate the index into the raw color data at which the pixel (x,y) is stored
+    ///
+    /// If the coordinates are out of bounds, the returned index is also out of bounds.
+    #[inline(always)]
+    fn data_index(&self, x: usize, y: usize) -> usize {
+        if self.transform.is_identity() {
+            y.saturating_mul(self.width).saturating_add(x)
+        } else if x >= self.width || y >= self.height {
+            usize::MAX
+        } else {
+            let (data_width, _) = self.get_data_size();
+            let (x, y) = self.transform.apply(x, y, self.width, self.height);
+            y * data_width + x
+        }
+    }
+
     /// Get the color value of the pixel at position (x,y)
     pub fn get_pixel(&self, x: usize, y: usize) -> Result<Color, InvalidCoordinatesError> {
-        let i = y.saturating_mul(self.width).saturating_add(x);
+        let i = self.data_index(x, y);
         match unsafe { self.get_color_data() }.get(i) {
             None => Err(InvalidCoordinatesError {
                 target: (x, y),
@@ -69,7 +129,7 @@ impl Pixmap {
 
     /// Set the pixel value at position (x,y) to the specified color
     pub fn set_pixel(&self, x: usize, y: usize, color: Color) -> Result<(), InvalidCoordinatesError> {
-        let i = y.saturating_mul(self.width).saturating_add(x);
+        let i = self.data_index(x, y);
         match unsafe { self.get_color_data() }.get_mut(i) {
             None => Err(InvalidCoordinatesError {
                 target: (x, y),
@@ -82,8 +142,154 @@ impl Pixmap {
         }
     }
 
+    /// Set the colors of multiple pixels given as `(x, y, color)`
+    ///
+    /// Pixels are set in order and setting stops at the first pixel which lies outside the pixmap.
+    pub fn set_pixels(
+        &self,
+        pixels: impl IntoIterator<Item = (usize, usize, Color)>,
+    ) -> Result<(), InvalidCoordinatesError> {
+        let data = unsafe { self.get_color_data() };
+        for (x, y, color) in pixels {
+            let i = self.data_index(x, y);
+            match data.get_mut(i) {
+                None => {
+                    return Err(InvalidCoordinatesError {
+                        target: (x, y),
+                        pixmap_size: self.get_size(),
+                    })
+                }
+                Some(stored_color) => *stored_color = color,
+            }
+        }
+        Ok(())
+    }
+
+    /// Fill the rectangle whose top-left corner is at (x,y) and which has the given size with one color
+    ///
+    /// The rectangle must lie completely inside the pixmap, otherwise nothing is filled.
+    pub fn fill_rect(
+        &self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        color: Color,
+    ) -> Result<(), InvalidCoordinatesError> {
+        if width == 0 || height == 0 {
+            return Ok(());
+        }
+        let (x_end, y_end) = (x.saturating_add(width), y.saturating_add(height));
+        if x_end > self.width || y_end > self.height {
+            return Err(InvalidCoordinatesError {
+                target: (x_end - 1, y_end - 1),
+                pixmap_size: self.get_size(),
+            });
+        }
+
+        let data = unsafe { self.get_color_data() };
+        if self.transform.is_identity() {
+            for row in y..y_end {
+                data[row * self.width + x..row * self.width + x_end].fill(color);
+            }
+        } else {
+            for row in y..y_end {
+                for column in x..x_end {
+                    data[self.data_index(column, row)] = color;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Draw a straight line from (x1,y1) to (x2,y2) with one color using Bresenham's algorithm
+    ///
+    /// Both end points are included in the line and must lie inside the pixmap, otherwise nothing is drawn.
+    pub fn draw_line(
+        &self,
+        (x1, y1): (usize, usize),
+        (x2, y2): (usize, usize),
+        color: Color,
+    ) -> Result<(), InvalidCoordinatesError> {
+        for (x, y) in [(x1, y1), (x2, y2)] {
+            if x >= self.width || y >= self.height {
+                return Err(InvalidCoordinatesError {
+                    target: (x, y),
+                    pixmap_size: self.get_size(),
+                });
+            }
+        }
+
+        let data = unsafe { self.get_color_data() };
+        let (x2, y2) = (x2 as isize, y2 as isize);
+        let (mut x, mut y) = (x1 as isize, y1 as isize);
+        let (dx, dy) = ((x2 - x).abs(), -(y2 - y).abs());
+        let (step_x, step_y) = (if x < x2 { 1 } else { -1 }, if y < y2 { 1 } else { -1 });
+        let mut error = dx + dy;
+        loop {
+            data[self.data_index(x as usize, y as usize)] = color;
+            if x == x2 && y == y2 {
+                return Ok(());
+            }
+            let error2 = 2 * error;
+            if error2 >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if error2 <= dx {
+                error += dx;
+                y += step_y;
+            }
+        }
+    }
+
+    /// Blend the specified color onto the pixel at position (x,y) using `alpha` as the colors opacity
+    pub fn blend_pixel(
+        &self,
+        x: usize,
+        y: usize,
+        color: Color,
+        alpha: u8,
+    ) -> Result<(), InvalidCoordinatesError> {
+        let i = self.data_index(x, y);
+        match unsafe { self.get_color_data() }.get_mut(i) {
+            None => Err(InvalidCoordinatesError {
+                target: (x, y),
+                pixmap_size: self.get_size(),
+            }),
+            Some(stored_color) => {
+                *stored_color = stored_color.blend(color, alpha);
+                Ok(())
+            }
+        }
+    }
+
+    /// Combine the specified color with the pixel at position (x,y) according to `mode`
+    pub fn compose_pixel(
+        &self,
+        x: usize,
+        y: usize,
+        color: Color,
+        mode: BlendMode,
+    ) -> Result<(), InvalidCoordinatesError> {
+        let i = self.data_index(x, y);
+        match unsafe { self.get_color_data() }.get_mut(i) {
+            None => Err(InvalidCoordinatesError {
+                target: (x, y),
+                pixmap_size: self.get_size(),
+            }),
+            Some(stored_color) => {
+                *stored_color = stored_color.compose(color, mode);
+                Ok(())
+            }
+        }
+    }
+
     /// Get a (usable) handle to the raw data that is contained in the pixmap
     ///
+    /// The data is laid out row by row according to [`get_data_size()`](Self::get_data_size) and already has the
+    /// pixmaps transform applied.
+    ///
     /// # Safety
     /// No memory safety rules are ensured for this data.
     /// The handed out mutable reference is not checked to be the only one and the underlying data may change at any time.
@@ -117,5 +323,42 @@ mod test {
                 }
             }
         }
+
+        fn test_set_and_get_pixel_transformed(x: usize, y: usize, transform: Transform) -> TestResult {
+            let color = Color::from((0xAB, 0xAB, 0xAB));
+            let pixmap = Pixmap::new_transformed(80, 60, transform).unwrap();
+            match pixmap.set_pixel(x, y, color) {
+                Err(_) => TestResult::discard(),
+                Ok(_) => {
+                    let got_color = pixmap.get_pixel(x, y).unwrap();
+                    let stored_colors = unsafe { pixmap.get_color_data() }.iter().filter(|c| **c == color).count();
+                    TestResult::from_bool(color == got_color && stored_colors == 1)
+                }
+            }
+        }
+
+        fn test_fill_rect(transform: Transform) -> bool {
+            let color = Color::from((0xAB, 0xAB, 0xAB));
+            let pixmap = Pixmap::new_transformed(8, 6, transform).unwrap();
+            pixmap.fill_rect(2, 1, 3, 4, color).unwrap();
+            let stored_colors = unsafe { pixmap.get_color_data() }.iter().filter(|c| **c == color).count();
+            stored_colors == 12
+                && pixmap.get_pixel(2, 1).unwrap() == color
+                && pixmap.get_pixel(4, 4).unwrap() == color
+                && pixmap.get_pixel(5, 4).unwrap() != color
+                && pixmap.fill_rect(6, 0, 3, 1, color).is_err()
+        }
+
+        fn test_draw_line(transform: Transform) -> bool {
+            let color = Color::from((0xAB, 0xAB, 0xAB));
+            let pixmap = Pixmap::new_transformed(8, 6, transform).unwrap();
+            pixmap.draw_line((7, 5), (1, 2), color).unwrap();
+            let stored_colors = unsafe { pixmap.get_color_data() }.iter().filter(|c| **c == color).count();
+            stored_colors == 7
+                && pixmap.get_pixel(1, 2).unwrap() == color
+                && pixmap.get_pixel(7, 5).unwrap() == color
+                && pixmap.get_pixel(4, 3).unwrap() == color
+                && pixmap.draw_line((0, 0), (8, 0), color).is_err()
+        }
     }
 }
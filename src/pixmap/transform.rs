@@ -0,0 +1,195 @@
+use std::str::FromStr;
+use thiserror::Error;
+
+#[cfg(test)]
+use quickcheck::{Arbitrary, Gen};
+
+/// A clockwise rotation in steps of 90 degrees
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default, Hash)]
+pub enum Rotation {
+    /// The image is not rotated
+    #[default]
+    None,
+    /// The image is rotated by 90 degrees clockwise
+    Deg90,
+    /// The image is rotated by 180 degrees
+    Deg180,
+    /// The image is rotated by 270 degrees clockwise
+    Deg270,
+}
+
+/// A transformation consisting of optional mirroring followed by a rotation.
+///
+/// Mirroring is applied in the coordinate space of the untransformed image before it is rotated.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default, Hash)]
+pub struct Transform {
+    /// The rotation which is applied after mirroring
+    pub rotation: Rotation,
+    /// Whether the image is mirrored along its vertical axis (left becomes right)
+    pub flip_horizontal: bool,
+    /// Whether the image is mirrored along its horizontal axis (top becomes bottom)
+    pub flip_vertical: bool,
+}
+
+/// An error which indicates that a string could not be parsed into a [`Rotation`]
+#[derive(Debug, Error, Clone, Eq, PartialEq)]
+#[error("Invalid rotation {0:?}; expected one of 0, 90, 180 or 270")]
+pub struct InvalidRotationError(String);
+
+impl FromStr for Rotation {
+    type Err = InvalidRotationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "0" => Ok(Rotation::None),
+            "90" => Ok(Rotation::Deg90),
+            "180" => Ok(Rotation::Deg180),
+            "270" => Ok(Rotation::Deg270),
+            _ => Err(InvalidRotationError(s.to_string())),
+        }
+    }
+}
+
+/// An error which indicates that a string could not be parsed into a [`Transform`]
+#[derive(Debug, Error, Clone, Eq, PartialEq)]
+#[error("Invalid transform component {0:?}; expected a comma separated list of 0, 90, 180, 270, flip-horizontal or flip-vertical")]
+pub struct InvalidTransformError(String);
+
+impl FromStr for Transform {
+    type Err = InvalidTransformError;
+
+    /// Parse a transform from a comma separated list of components, e.g. `90,flip-horizontal`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut result = Transform::default();
+        for component in s.split(',').map(str::trim).filter(|c| !c.is_empty()) {
+            match component {
+                "flip-horizontal" => result.flip_horizontal = true,
+                "flip-vertical" => result.flip_vertical = true,
+                rotation => {
+                    result.rotation = Rotation::from_str(rotation)
+                        .map_err(|_| InvalidTransformError(rotation.to_string()))?
+                }
+            }
+        }
+        Ok(result)
+    }
+}
+
+impl Transform {
+    /// Whether this transform leaves all coordinates unchanged
+    #[inline(always)]
+    pub fn is_identity(&self) -> bool {
+        self.rotation == Rotation::None && !self.flip_horizontal && !self.flip_vertical
+    }
+
+    /// Get the size of an image with the given `width` and `height` after it has been transformed
+    #[inline(always)]
+    pub fn transformed_size(&self, width: usize, height: usize) -> (usize, usize) {
+        match self.rotation {
+            Rotation::None | Rotation::Deg180 => (width, height),
+            Rotation::Deg90 | Rotation::Deg270 => (height, width),
+        }
+    }
+
+    /// Map the coordinates `(x, y)` of an image with the given `width` and `height` to their position in the
+    /// transformed image.
+    ///
+    /// The coordinates must lie inside the image.
+    #[inline(always)]
+    pub fn apply(&self, x: usize, y: usize, width: usize, height: usize) -> (usize, usize) {
+        debug_assert!(x < width && y < height);
+        let x = if self.flip_horizontal { width - 1 - x } else { x };
+        let y = if self.flip_vertical { height - 1 - y } else { y };
+        match self.rotation {
+            Rotation::None => (x, y),
+            Rotation::Deg90 => (height - 1 - y, x),
+            Rotation::Deg180 => (width - 1 - x, height - 1 - y),
+            Rotation::Deg270 => (y, width - 1 - x),
+        }
+    }
+
+    /// Compute for every pixel of the transformed image, at which index its data is located in the untransformed
+    /// image of the given `width` and `height`.
+    ///
+    /// The result is indexed row by row according to [`transformed_size()`](Self::transformed_size) and can be
+    /// used to efficiently transform many images of the same size.
+    pub fn source_indices(&self, width: usize, height: usize) -> Vec<usize> {
+        let (t_width, _) = self.transformed_size(width, height);
+        let mut result = vec![0; width * height];
+        for y in 0..height {
+            for x in 0..width {
+                let (tx, ty) = self.apply(x, y, width, height);
+                result[ty * t_width + tx] = y * width + x;
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+impl Arbitrary for Transform {
+    fn arbitrary(g: &mut Gen) -> Self {
+        Self {
+            rotation: *g
+                .choose(&[
+                    Rotation::None,
+                    Rotation::Deg90,
+                    Rotation::Deg180,
+                    Rotation::Deg270,
+                ])
+                .unwrap(),
+            flip_horizontal: bool::arbitrary(g),
+            flip_vertical: bool::arbitrary(g),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rotate_corners() {
+        let rotate = |rotation| Transform {
+            rotation,
+            ..Default::default()
+        };
+        // top-left corner of a 4x2 image
+        assert_eq!(rotate(Rotation::None).apply(0, 0, 4, 2), (0, 0));
+        assert_eq!(rotate(Rotation::Deg90).apply(0, 0, 4, 2), (1, 0));
+        assert_eq!(rotate(Rotation::Deg180).apply(0, 0, 4, 2), (3, 1));
+        assert_eq!(rotate(Rotation::Deg270).apply(0, 0, 4, 2), (0, 3));
+    }
+
+    #[test]
+    fn test_parse_transform() {
+        assert_eq!(Transform::from_str("0"), Ok(Transform::default()));
+        assert_eq!(
+            Transform::from_str("270,flip-vertical"),
+            Ok(Transform {
+                rotation: Rotation::Deg270,
+                flip_horizontal: false,
+                flip_vertical: true,
+            })
+        );
+        assert!(Transform::from_str("45").is_err());
+    }
+
+    quickcheck! {
+        fn test_apply_is_bijective(transform: Transform) -> bool {
+            let (width, height) = (5, 3);
+            let (t_width, t_height) = transform.transformed_size(width, height);
+            let mut seen = vec![false; width * height];
+            for y in 0..height {
+                for x in 0..width {
+                    let (tx, ty) = transform.apply(x, y, width, height);
+                    if tx >= t_width || ty >= t_height || seen[ty * t_width + tx] {
+                        return false;
+                    }
+                    seen[ty * t_width + tx] = true;
+                }
+            }
+            true
+        }
+    }
+}
@@ -0,0 +1,132 @@
+//! Coordination of a graceful shutdown of all servers and sinks
+//!
+//! A shutdown proceeds in phases.
+//! While [draining](drain), all open connections are told that the server shuts down and are closed once they
+//! handled the requests which they already received.
+//! Afterwards, while [flushing](flush), sinks complete their pending work like writing a final snapshot.
+//! Every phase only ends once all work which was registered for it is done or a timeout elapsed.
+
+use std::sync::LazyLock;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// The phases through which a shutdown proceeds
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Phase {
+    /// No shutdown was initiated
+    #[default]
+    Running,
+    /// Connections are closed
+    Draining,
+    /// Sinks complete their pending work
+    Flushing,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    phase: Phase,
+    /// How many guards are registered for every phase
+    pending: [usize; 3],
+}
+
+static STATE: LazyLock<watch::Sender<State>> = LazyLock::new(|| watch::Sender::new(State::default()));
+
+/// Registration of work which must be done in a phase of the shutdown before it can proceed
+///
+/// The work counts as done once the guard is dropped.
+#[derive(Debug)]
+pub(crate) struct Guard {
+    state: &'static watch::Sender<State>,
+    phase: Phase,
+}
+
+impl Guard {
+    pub fn new(phase: Phase) -> Self {
+        Self::register(&STATE, phase)
+    }
+
+    fn register(state: &'static watch::Sender<State>, phase: Phase) -> Self {
+        state.send_modify(|state| state.pending[phase as usize] += 1);
+        Self { state, phase }
+    }
+
+    /// Wait until the shutdown reached the phase in which the work of this guard must be done
+    pub async fn requested(&self) {
+        wait_for(self.state, self.phase).await
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        self.state
+            .send_modify(|state| state.pending[self.phase as usize] -= 1);
+    }
+}
+
+/// Wait until the shutdown reached `phase`
+pub async fn reached(phase: Phase) {
+    wait_for(&STATE, phase).await
+}
+
+async fn wait_for(state: &watch::Sender<State>, phase: Phase) {
+    let _ = state.subscribe().wait_for(|state| state.phase >= phase).await;
+}
+
+/// Tell all connections that the server shuts down and wait until they are closed
+///
+/// Returns whether all connections were closed before `timeout` elapsed.
+pub async fn drain(timeout: Duration) -> bool {
+    advance(&STATE, Phase::Draining, timeout).await
+}
+
+/// Tell all sinks to complete their pending work and wait until they are done
+///
+/// Returns whether all sinks were done before `timeout` elapsed.
+pub async fn flush(timeout: Duration) -> bool {
+    advance(&STATE, Phase::Flushing, timeout).await
+}
+
+async fn advance(state: &watch::Sender<State>, phase: Phase, timeout: Duration) -> bool {
+    state.send_modify(|state| state.phase = state.phase.max(phase));
+    let mut state = state.subscribe();
+    let done = tokio::time::timeout(
+        timeout,
+        state.wait_for(|state| state.pending[phase as usize] == 0),
+    )
+    .await;
+    done.is_ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::time::{sleep, Instant};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_phases() {
+        // the global state is shared with the connections of other tests
+        let state: &'static _ = Box::leak(Box::new(watch::Sender::new(State::default())));
+        let connection = Guard::register(state, Phase::Draining);
+        let sink = Guard::register(state, Phase::Flushing);
+        let closed = tokio::spawn(async move {
+            connection.requested().await;
+            sleep(Duration::from_secs(1)).await;
+        });
+        let start = Instant::now();
+
+        // draining waits until the connection is closed but does not affect the sink
+        assert!(advance(state, Phase::Draining, Duration::from_secs(5)).await);
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+        closed.await.unwrap();
+        assert!(tokio::time::timeout(Duration::from_secs(1), sink.requested())
+            .await
+            .is_err());
+
+        // flushing gives up once the timeout elapsed
+        assert!(!advance(state, Phase::Flushing, Duration::from_secs(5)).await);
+        assert_eq!(start.elapsed(), Duration::from_secs(7));
+        sink.requested().await;
+        drop(sink);
+        assert!(advance(state, Phase::Flushing, Duration::from_secs(5)).await);
+    }
+}
@@ -1,6 +1,7 @@
 //! A sink which pipes the canvas into ffmpeg for video encoding or streaming
 
-use crate::pixmap::SharedPixmap;
+use crate::pixmap::{SharedPixmap, Transform};
+use crate::shutdown::{self, Phase};
 use crate::DaemonResult;
 use anyhow::anyhow;
 use std::process::Stdio;
@@ -28,6 +29,7 @@ use tokio::task::{AbortHandle, JoinSet};
 ///     framerate: FPS,
 ///     synthesize_audio: true,
 ///     log_level: "warning".to_string(),
+///     transform: Default::default(),
 ///     output_spec: FfmpegOptions::make_rtsp_out_spec("rtsp://localhost:8554/pixelflut", FPS)
 /// };
 /// ```
@@ -42,6 +44,7 @@ use tokio::task::{AbortHandle, JoinSet};
 ///     framerate: FPS,
 ///     synthesize_audio: true,
 ///     log_level: "warning".to_string(),
+///     transform: Default::default(),
 ///     output_spec: [
 ///         FfmpegOptions::make_rtsp_out_spec("rtsp://localhost:8554/pixelflut", FPS),
 ///         FfmpegOptions::make_rtmp_out_spec("rtmp://localhost:1935/pixelflut2", FPS),
@@ -67,6 +70,9 @@ pub struct FfmpegOptions {
     /// some viewers won't display the video data if there is no audio component present.
     pub synthesize_audio: bool,
 
+    /// A transform which is applied to the pixmap data before it is passed to ffmpeg
+    pub transform: Transform,
+
     /// Additional ffmpeg arguments that should be placed in the output part of the generated command.
     pub output_spec: Vec<String>,
 }
@@ -158,10 +164,11 @@ impl FfmpegSink {
     /// Spawn the ffmpeg child process and start sinking data into it
     pub async fn start(mut self, join_set: &mut JoinSet<DaemonResult>) -> anyhow::Result<AbortHandle> {
         self.start_ffmpeg()?;
+        let shutdown = shutdown::Guard::new(Phase::Flushing);
         let handle = join_set
             .build_task()
             .name("ffmpeg")
-            .spawn(async move { self.run().await })?;
+            .spawn(async move { self.run(shutdown).await })?;
         Ok(handle)
     }
 
@@ -171,7 +178,8 @@ impl FfmpegSink {
             return Err(anyhow!("ffmpeg is already running"));
         }
 
-        let (width, height) = self.pixmap.get_size();
+        let (width, height) = self.pixmap.get_data_size();
+        let (width, height) = self.options.transform.transformed_size(width, height);
 
         let mut cmd = Command::new("ffmpeg");
         cmd.stdin(Stdio::piped()).kill_on_drop(true).env_clear();
@@ -217,7 +225,9 @@ impl FfmpegSink {
     }
 
     /// Execute the main loop which periodically sinks data into ffmpeg
-    async fn run(self) -> anyhow::Result<!> {
+    ///
+    /// When the server shuts down, ffmpeg's input is closed so that it can finish encoding and exit cleanly.
+    async fn run(self, shutdown: shutdown::Guard) -> anyhow::Result<!> {
         let mut ffmpeg = self.ffmpeg_proc.ok_or(anyhow!("ffmpeg is not running"))?;
         let Some(channel) = &mut ffmpeg.stdin else {
             return Err(anyhow!("ffmpegs stdin is not attached"));
@@ -226,17 +236,33 @@ impl FfmpegSink {
         let mut interval =
             tokio::time::interval(Duration::from_secs_f64(1.0 / self.options.framerate as f64));
 
+        let (width, height) = self.pixmap.get_data_size();
+        let transform = self.options.transform;
+        let source_indices = (!transform.is_identity()).then(|| transform.source_indices(width, height));
+
         loop {
-            let data = unsafe {
-                self.pixmap
-                    .get_color_data()
+            let color_data = unsafe { self.pixmap.get_color_data() };
+            let data = match &source_indices {
+                None => color_data
                     .iter()
                     .flat_map(|c| Into::<[u8; 3]>::into(*c))
-                    .collect::<Vec<_>>()
+                    .collect::<Vec<_>>(),
+                Some(source_indices) => source_indices
+                    .iter()
+                    .flat_map(|i| Into::<[u8; 3]>::into(color_data[*i]))
+                    .collect::<Vec<_>>(),
             };
             channel.write_all(&data).await.expect("Could not write to ffmpeg");
 
-            interval.tick().await;
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown.requested() => break,
+            }
         }
+        drop(ffmpeg.stdin.take());
+        let status = ffmpeg.wait().await?;
+        tracing::info!("ffmpeg exited with {}", status);
+        drop(shutdown);
+        std::future::pending().await
     }
 }
@@ -1,6 +1,6 @@
 //! A sink implementation for drawing on a linux framebuffer
 
-use crate::pixmap::{Color, SharedPixmap};
+use crate::pixmap::{Color, SharedPixmap, Transform};
 use crate::DaemonResult;
 use anyhow::Context;
 use framebuffer::{Bitfield, Framebuffer};
@@ -19,20 +19,30 @@ struct Sampler {
 }
 
 impl Sampler {
-    pub fn new(src_width: usize, src_height: usize, out_width: usize, out_height: usize) -> Self {
-        if src_width == out_width && src_height == out_height {
+    pub fn new(
+        src_width: usize,
+        src_height: usize,
+        transform: Transform,
+        out_width: usize,
+        out_height: usize,
+    ) -> Self {
+        let (transformed_width, transformed_height) = transform.transformed_size(src_width, src_height);
+        if transform.is_identity() && src_width == out_width && src_height == out_height {
             Self { mapping: None }
         } else {
-            tracing::warn!("Framebuffer has size {}x{} while pixmap has size {}x{}. This requires an additional sampling step which slows down rendering", out_width, out_height, src_width, src_height);
+            if transformed_width != out_width || transformed_height != out_height {
+                tracing::warn!("Framebuffer has size {}x{} while pixmap has size {}x{}. This requires an additional sampling step which slows down rendering", out_width, out_height, transformed_width, transformed_height);
+            }
+            let source_indices = transform.source_indices(src_width, src_height);
             Self {
                 mapping: Some(
                     (0..out_width * out_height)
                         .map(|i_screen_px| {
                             let screen_x = i_screen_px % out_width;
                             let screen_y = i_screen_px / out_width;
-                            let px_x = (screen_x * src_width) / out_width;
-                            let px_y = (screen_y * src_height) / out_height;
-                            (px_y * src_width + px_x) as u32
+                            let px_x = (screen_x * transformed_width) / out_width;
+                            let px_y = (screen_y * transformed_height) / out_height;
+                            source_indices[px_y * transformed_width + px_x] as u32
                         })
                         .collect(),
                 ),
@@ -67,6 +77,10 @@ pub struct FramebufferSinkOptions {
     pub path: PathBuf,
     /// How many frames per second should be rendered
     pub framerate: usize,
+    /// A transform which is applied to the pixmap data before it is rendered
+    ///
+    /// This is useful for physically rotated displays.
+    pub transform: Transform,
 }
 
 /// A sink that periodically renders pixmap data onto a framebuffer device
@@ -116,10 +130,16 @@ impl FramebufferSink {
         let mut interval = interval(Duration::from_secs_f64(1.0 / self.options.framerate as f64));
         interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
 
-        let (pixmap_width, pixmap_height) = self.pixmap.get_size();
+        let (pixmap_width, pixmap_height) = self.pixmap.get_data_size();
         let screen_width = fb.var_screen_info.xres as usize;
         let screen_height = fb.var_screen_info.yres as usize;
-        let sampler = Sampler::new(pixmap_width, pixmap_height, screen_width, screen_height);
+        let sampler = Sampler::new(
+            pixmap_width,
+            pixmap_height,
+            self.options.transform,
+            screen_width,
+            screen_height,
+        );
 
         let fb_pixels = screen_width * screen_height;
         let encoder = Encoder {
@@ -4,6 +4,7 @@
 
 pub mod ffmpeg;
 pub mod framebuffer;
+pub mod multicast;
 pub mod pixmap_file;
 #[cfg(feature = "windowing")]
 pub mod window;
@@ -0,0 +1,185 @@
+//! A sink which announces the server on a multicast group and optionally distributes the canvas state there
+
+use crate::net::protocol::{Region, Response, PROTOCOL_VERSION};
+use crate::pixmap::{Pixmap, SharedPixmap};
+use crate::DaemonResult;
+use anyhow::anyhow;
+use std::future;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::task::{AbortHandle, JoinSet};
+use tokio::time::{interval, MissedTickBehavior};
+
+/// The largest datagram which is sent, chosen so that datagrams are not fragmented on common networks
+const MAX_DATAGRAM_LEN: usize = 1400;
+
+/// How many pixels are sent in one state datagram
+///
+/// Every pixel takes four bytes of base64 and the rest of the datagram is left for the `PXGET` header.
+const BLOCK_PIXELS: usize = (MAX_DATAGRAM_LEN - 64) / 4;
+
+/// Options for configuring a [`MulticastSink`]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MulticastSinkOptions {
+    /// The multicast group and port to which datagrams are sent, e.g. `239.255.80.70:1234`
+    pub group: SocketAddr,
+    /// The interval between announcements
+    pub interval: Duration,
+    /// The urls of the listeners which are announced
+    pub listeners: Vec<String>,
+    /// How long sending the whole canvas state takes, or `None` to not distribute the state at all
+    ///
+    /// The state is sent in small blocks which are spread evenly across this duration so that receivers are not
+    /// flooded.
+    pub state_interval: Option<Duration>,
+    /// The time-to-live of IPv4 datagrams which limits how many routers they may cross
+    pub ttl: u32,
+}
+
+/// A sink that periodically multicasts an announcement of the server and optionally the canvas state
+///
+/// Announcements are sent as a single line `ANNOUNCE <protocol version> <width> <height> <listener url>...`.
+/// Receivers should substitute the address from which they received the announcement for unspecified hosts like
+/// `0.0.0.0` in the listener urls.
+///
+/// The state is sent as `PXGET <x> <y> <width> <height> <base64 rgb>` lines like the responses to PXGET, one block
+/// of pixels per datagram, and cycles through the canvas row by row.
+#[derive(Debug)]
+pub struct MulticastSink {
+    options: MulticastSinkOptions,
+    pixmap: SharedPixmap,
+}
+
+impl MulticastSink {
+    /// Create a new `MulticastSink`
+    pub fn new(options: MulticastSinkOptions, pixmap: SharedPixmap) -> Self {
+        Self { options, pixmap }
+    }
+
+    /// Open a socket for sending to the multicast group and start the background task which sends datagrams
+    pub async fn start(self, join_set: &mut JoinSet<DaemonResult>) -> anyhow::Result<AbortHandle> {
+        if !self.options.group.ip().is_multicast() {
+            return Err(anyhow!("{} is not a multicast address", self.options.group.ip()));
+        }
+        let socket = match self.options.group {
+            SocketAddr::V4(_) => {
+                let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+                socket.set_multicast_ttl_v4(self.options.ttl)?;
+                socket
+            }
+            SocketAddr::V6(_) => UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await?,
+        };
+        tracing::info!("Announcing the server on multicast group {}", self.options.group);
+        let handle = join_set
+            .build_task()
+            .name("multicast")
+            .spawn(async move { self.run(socket).await })?;
+        Ok(handle)
+    }
+
+    async fn run(self, socket: UdpSocket) -> anyhow::Result<!> {
+        let mut announcements = interval(self.options.interval);
+        announcements.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        let (width, height) = self.pixmap.get_size();
+        let blocks = state_blocks(width, height);
+        let mut state_blocks = self.options.state_interval.map(|state_interval| {
+            let mut interval = interval((state_interval / blocks.len() as u32).max(Duration::from_millis(1)));
+            interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+            interval
+        });
+        let mut next_block = blocks.iter().cycle();
+
+        loop {
+            let datagram = tokio::select! {
+                _ = announcements.tick() => announcement(width, height, &self.options.listeners),
+                _ = async {
+                    match &mut state_blocks {
+                        Some(state_blocks) => state_blocks.tick().await,
+                        None => future::pending().await,
+                    }
+                } => {
+                    let region = *next_block.next().expect("The canvas has at least one block");
+                    state_block(&self.pixmap, region).to_string() + "\n"
+                }
+            };
+            if let Err(e) = socket.send_to(datagram.as_bytes(), self.options.group).await {
+                tracing::warn!(
+                    "Could not send datagram to multicast group {}: {}",
+                    self.options.group,
+                    e
+                );
+            }
+        }
+    }
+}
+
+/// The line with which the server announces itself
+fn announcement(width: usize, height: usize, listeners: &[String]) -> String {
+    let mut announcement = format!("ANNOUNCE {} {} {}", PROTOCOL_VERSION, width, height);
+    for listener in listeners {
+        announcement.push(' ');
+        announcement.push_str(listener);
+    }
+    announcement.push('\n');
+    announcement
+}
+
+/// Split a canvas into the regions which are sent in one state datagram each, row by row
+fn state_blocks(width: usize, height: usize) -> Vec<Region> {
+    (0..height)
+        .flat_map(|y| {
+            (0..width).step_by(BLOCK_PIXELS).map(move |x| Region {
+                x,
+                y,
+                width: BLOCK_PIXELS.min(width - x),
+                height: 1,
+            })
+        })
+        .collect()
+}
+
+/// The colors of all pixels in a region of the canvas
+fn state_block(pixmap: &Pixmap, region: Region) -> Response {
+    let colors = (region.y..region.y + region.height)
+        .flat_map(|y| (region.x..region.x + region.width).map(move |x| (x, y)))
+        .map(|(x, y)| pixmap.get_pixel(x, y).unwrap_or_default())
+        .collect();
+    Response::PxBlock { region, colors }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pixmap::Color;
+
+    #[test]
+    fn test_announcement() {
+        let listeners = ["tcp://0.0.0.0:1234".to_string(), "ws://0.0.0.0:1235".to_string()];
+        assert_eq!(
+            announcement(800, 600, &listeners),
+            format!(
+                "ANNOUNCE {} 800 600 tcp://0.0.0.0:1234 ws://0.0.0.0:1235\n",
+                PROTOCOL_VERSION
+            )
+        );
+    }
+
+    #[test]
+    fn test_state_blocks() {
+        let blocks = state_blocks(BLOCK_PIXELS + 1, 2);
+        assert_eq!(blocks.len(), 4);
+        assert_eq!(blocks[1].x, BLOCK_PIXELS);
+        assert_eq!(blocks[1].width, 1);
+        assert_eq!(
+            blocks.iter().map(|block| block.width).sum::<usize>(),
+            2 * (BLOCK_PIXELS + 1)
+        );
+
+        let pixmap = Pixmap::new(BLOCK_PIXELS, 1).unwrap();
+        pixmap.set_pixel(0, 0, Color::from((0xFF, 0x00, 0x80))).unwrap();
+        let datagram = state_block(&pixmap, blocks[0]).to_string() + "\n";
+        assert!(datagram.starts_with("PXGET 0 0 "));
+        assert!(datagram.len() <= MAX_DATAGRAM_LEN);
+    }
+}
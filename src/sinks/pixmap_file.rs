@@ -1,24 +1,60 @@
 //! A sink for periodically snapshotting the canvas into a pixmap file
 
 use crate::pixmap::{Pixmap, SharedPixmap};
+use crate::shutdown::{self, Phase};
 use crate::DaemonResult;
 use anyhow::anyhow;
 use itertools::Itertools;
 use std::io::SeekFrom;
 use std::mem;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+use std::time::Duration;
 use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::watch;
 use tokio::task::{AbortHandle, JoinSet};
-use tokio::time::Interval;
+use tokio::time::{interval_at, Instant, Interval};
 
 const FILE_MAGIC: &[u8] = b"PIXELFLUT";
 const HEADER_SIZE: usize = mem::size_of::<u64>() * 2; // enough space for width and height
 
+/// How many rows of pixels are covered by one checksum
+///
+/// Checksums are stored after the pixel data so that a damaged snapshot can be partially restored.
+const CHUNK_ROWS: usize = 16;
+const CHECKSUM_SIZE: usize = size_of::<u32>();
+
 const SEEK_MAGIC: SeekFrom = SeekFrom::Start(0);
 const SEEK_HEADER: SeekFrom = SeekFrom::Start(FILE_MAGIC.len() as u64);
 const SEEK_DATA: SeekFrom = SeekFrom::Start((FILE_MAGIC.len() + HEADER_SIZE) as u64);
 
+/// How many snapshots were requested via [`request_snapshot`], which every running sink watches
+static SNAPSHOT_REQUESTS: LazyLock<watch::Sender<u64>> = LazyLock::new(|| watch::Sender::new(0));
+
+/// Ask all running file sinks to take a snapshot right away instead of waiting for their interval
+///
+/// Returns whether any file sink is running.
+pub fn request_snapshot() -> bool {
+    SNAPSHOT_REQUESTS.send_modify(|requests| *requests += 1);
+    SNAPSHOT_REQUESTS.receiver_count() > 0
+}
+
+/// The interval which was last set via [`set_snapshot_interval`], which every running sink watches
+static SNAPSHOT_INTERVAL: LazyLock<watch::Sender<Duration>> =
+    LazyLock::new(|| watch::Sender::new(Duration::ZERO));
+
+/// Change the interval in which all running file sinks take snapshots, starting with a full interval from now
+///
+/// # Panics
+///
+/// This panics if `interval` is zero.
+pub fn set_snapshot_interval(interval: Duration) {
+    assert!(!interval.is_zero(), "the snapshot interval must be non-zero");
+    SNAPSHOT_INTERVAL.send_replace(interval);
+}
+
 /// Configuration options for the [`FileSink`]
 #[derive(Debug)]
 pub struct FileSinkOptions {
@@ -47,11 +83,14 @@ impl FileSink {
     /// Open the target file and start the background tasks for periodic snapshotting
     pub async fn start(self, join_set: &mut JoinSet<DaemonResult>) -> anyhow::Result<AbortHandle> {
         let mut file = self.open_file().await?;
-        self.write_header(&mut file).await?;
+        write_header(&self.pixmap, &mut file).await?;
+        let shutdown = shutdown::Guard::new(Phase::Flushing);
+        let requests = SNAPSHOT_REQUESTS.subscribe();
+        let intervals = SNAPSHOT_INTERVAL.subscribe();
         let handle = join_set
             .build_task()
             .name("file_sink")
-            .spawn(async move { self.run(file).await })?;
+            .spawn(async move { self.run(file, requests, intervals, shutdown).await })?;
         Ok(handle)
     }
 
@@ -64,63 +103,165 @@ impl FileSink {
             .await?)
     }
 
-    /// Write appropriate header information into the file so that later operations only have to write data
-    async fn write_header(&self, file: &mut File) -> anyhow::Result<()> {
-        // set file length to exact content size
-        let (width, height) = self.pixmap.get_size();
-        file.set_len((FILE_MAGIC.len() + HEADER_SIZE + width * height * 3) as u64)
-            .await?;
-
-        // write magic bytes
-        file.seek(SEEK_MAGIC).await?;
-        file.write_all(FILE_MAGIC).await?;
-
-        // write actual header
-        file.seek(SEEK_HEADER).await?;
-        file.write_u64(width as u64).await?;
-        file.write_u64(height as u64).await?;
-
-        // sync data to disk
-        file.flush().await?;
-        file.sync_all().await?;
-        Ok(())
+    /// Execute the main loop which periodically snapshots data into the file
+    ///
+    /// Snapshots which are requested in between are taken right away and a changed interval applies from then on.
+    /// A final snapshot is taken when the server shuts down so that no pixels drawn since the previous one are lost.
+    async fn run(
+        mut self,
+        mut file: File,
+        mut requests: watch::Receiver<u64>,
+        mut intervals: watch::Receiver<Duration>,
+        shutdown: shutdown::Guard,
+    ) -> anyhow::Result<!> {
+        'snapshots: loop {
+            write_data(&self.pixmap, &mut file).await?;
+            loop {
+                tokio::select! {
+                    _ = self.options.interval.tick() => break,
+                    _ = requests.changed() => {
+                        tracing::info!("Taking requested snapshot into {}", self.options.path.display());
+                        break;
+                    }
+                    _ = intervals.changed() => {
+                        let period = *intervals.borrow_and_update();
+                        self.options.interval = interval_at(Instant::now() + period, period);
+                        tracing::info!("Taking snapshots into {} every {:?}", self.options.path.display(), period);
+                    }
+                    _ = shutdown.requested() => break 'snapshots,
+                }
+            }
+        }
+        write_data(&self.pixmap, &mut file).await?;
+        tracing::info!("Wrote final snapshot to {}", self.options.path.display());
+        drop(shutdown);
+        std::future::pending().await
     }
+}
+
+/// Write a complete snapshot of the pixmap into a file which can later be restored via [`load_pixmap_file`]
+pub async fn write_pixmap_file(pixmap: &Pixmap, file: &mut File) -> anyhow::Result<()> {
+    write_header(pixmap, file).await?;
+    write_data(pixmap, file).await
+}
+
+/// Write appropriate header information into the file so that later operations only have to write data
+async fn write_header(pixmap: &Pixmap, file: &mut File) -> anyhow::Result<()> {
+    // set file length to exact content size
+    let (width, height) = pixmap.get_size();
+    let checksums_len = height.div_ceil(CHUNK_ROWS) * CHECKSUM_SIZE;
+    file.set_len((FILE_MAGIC.len() + HEADER_SIZE + width * height * 3 + checksums_len) as u64)
+        .await?;
 
-    /// Write pixmap data into the data section of the file
-    async fn write_data(&self, file: &mut File) -> anyhow::Result<()> {
-        file.seek(SEEK_DATA).await?;
+    // write magic bytes
+    file.seek(SEEK_MAGIC).await?;
+    file.write_all(FILE_MAGIC).await?;
 
-        let data = unsafe { self.pixmap.get_color_data() };
-        let data = data
+    // write actual header
+    file.seek(SEEK_HEADER).await?;
+    file.write_u64(width as u64).await?;
+    file.write_u64(height as u64).await?;
+
+    // sync data to disk
+    file.flush().await?;
+    file.sync_all().await?;
+    Ok(())
+}
+
+/// Write pixmap data into the data section of the file followed by a checksum of every chunk
+///
+/// Data is always written untransformed so that snapshots can be loaded regardless of the transform with which
+/// a pixmap is configured.
+async fn write_data(pixmap: &Pixmap, file: &mut File) -> anyhow::Result<()> {
+    file.seek(SEEK_DATA).await?;
+
+    let data = if pixmap.get_transform().is_identity() {
+        unsafe { pixmap.get_color_data() }
             .iter()
             .flat_map(|c| Into::<[u8; 3]>::into(*c))
-            .collect::<Vec<_>>();
-        file.write_all(&data).await?;
+            .collect::<Vec<_>>()
+    } else {
+        let (width, height) = pixmap.get_size();
+        (0..height)
+            .cartesian_product(0..width)
+            .flat_map(|(y, x)| Into::<[u8; 3]>::into(pixmap.get_pixel(x, y).unwrap()))
+            .collect::<Vec<_>>()
+    };
+    file.write_all(&data).await?;
 
-        file.flush().await?;
-        file.sync_all().await?;
+    let (width, _) = pixmap.get_size();
+    let checksums = data
+        .chunks(width * 3 * CHUNK_ROWS)
+        .flat_map(|chunk| crc32fast::hash(chunk).to_be_bytes())
+        .collect::<Vec<_>>();
+    file.write_all(&checksums).await?;
 
-        Ok(())
-    }
+    file.flush().await?;
+    file.sync_all().await?;
 
-    /// Execute the main loop which periodically snapshots data into the file
-    async fn run(mut self, mut file: File) -> anyhow::Result<!> {
-        loop {
-            self.write_data(&mut file).await?;
-            self.options.interval.tick().await;
-        }
+    Ok(())
+}
+
+/// Problems which were found and repaired while loading a snapshot
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct SnapshotReport {
+    /// Whether the snapshot contained checksums against which its data was verified
+    ///
+    /// Snapshots written by older versions don't contain checksums.
+    pub verified: bool,
+    /// How many bytes of pixel data were missing from the end of the file and have been zeroed
+    pub missing_bytes: usize,
+    /// The row ranges whose checksum did not match and which have been zeroed
+    pub corrupted_rows: Vec<Range<usize>>,
+}
+
+impl SnapshotReport {
+    /// Whether the snapshot was loaded without any repairs
+    pub fn is_clean(&self) -> bool {
+        self.missing_bytes == 0 && self.corrupted_rows.is_empty()
     }
 }
 
 /// Restore a previously saved pixmap snapshot
+///
+/// Damaged parts of the snapshot are zeroed and logged.
+/// Use [`load_and_repair_pixmap_file`] to retrieve a report about them instead.
 pub async fn load_pixmap_file(path: &Path) -> anyhow::Result<Pixmap> {
-    let mut file = File::open(path).await?;
+    let (pixmap, report) = load_and_repair_pixmap_file(path).await?;
+    if !report.verified {
+        tracing::info!(
+            "Snapshot {} contains no checksums so its data could not be verified",
+            path.display()
+        );
+    }
+    if report.missing_bytes > 0 {
+        tracing::warn!(
+            "Snapshot {} is truncated, {}B of pixel data were missing and have been zeroed",
+            path.display(),
+            report.missing_bytes
+        );
+    }
+    for rows in &report.corrupted_rows {
+        tracing::warn!(
+            "Rows {}..{} of snapshot {} are corrupted and have been zeroed",
+            rows.start,
+            rows.end,
+            path.display()
+        );
+    }
+    Ok(pixmap)
+}
+
+/// Restore a previously saved pixmap snapshot and verify its data against the stored checksums
+///
+/// Instead of failing, chunks whose checksum does not match as well as data missing from the end of the file are
+/// zeroed and described in the returned report.
+/// Only snapshots whose header is damaged cannot be loaded.
+pub async fn load_and_repair_pixmap_file(path: &Path) -> anyhow::Result<(Pixmap, SnapshotReport)> {
+    let content = tokio::fs::read(path).await?;
 
     // verify magic bytes
-    let mut file_magic = [0u8; FILE_MAGIC.len()];
-    file.seek(SEEK_MAGIC).await?;
-    file.read_exact(&mut file_magic).await?;
-    if file_magic != FILE_MAGIC {
+    if !content.starts_with(FILE_MAGIC) || content.len() < FILE_MAGIC.len() + HEADER_SIZE {
         return Err(anyhow!(
             "File at {} does not contain valid pixmap data",
             path.display()
@@ -128,23 +269,50 @@ pub async fn load_pixmap_file(path: &Path) -> anyhow::Result<Pixmap> {
     }
 
     // load size information from header
-    file.seek(SEEK_HEADER).await?;
-    let width = file.read_u64().await? as usize;
-    let height = file.read_u64().await? as usize;
+    let header = &content[FILE_MAGIC.len()..FILE_MAGIC.len() + HEADER_SIZE];
+    let width = u64::from_be_bytes(header[..8].try_into().unwrap()) as usize;
+    let height = u64::from_be_bytes(header[8..].try_into().unwrap()) as usize;
+    let data_len = width
+        .checked_mul(height)
+        .and_then(|n| n.checked_mul(3))
+        .filter(|&n| n <= isize::MAX as usize)
+        .ok_or_else(|| anyhow!("Snapshot {} has a corrupted header", path.display()))?;
 
-    // load the file data into memory
-    let mut buf = vec![0u8; width * height * 3];
-    file.seek(SEEK_DATA).await?;
-    file.read_exact(&mut buf).await?;
+    // copy the available data and zero everything that is missing
+    let mut report = SnapshotReport::default();
+    let content = &content[FILE_MAGIC.len() + HEADER_SIZE..];
+    let mut data = vec![0u8; data_len];
+    let available = content.len().min(data_len);
+    data[..available].copy_from_slice(&content[..available]);
+    report.missing_bytes = data_len - available;
+
+    // verify every chunk against its checksum if they are present
+    let checksums = &content[available..];
+    let chunk_len = width * 3 * CHUNK_ROWS;
+    if chunk_len > 0 && checksums.len() >= height.div_ceil(CHUNK_ROWS) * CHECKSUM_SIZE {
+        report.verified = true;
+        for (i, (chunk, checksum)) in data
+            .chunks_mut(chunk_len)
+            .zip(checksums.chunks_exact(CHECKSUM_SIZE))
+            .enumerate()
+        {
+            if crc32fast::hash(chunk).to_be_bytes() != checksum {
+                chunk.fill(0);
+                report
+                    .corrupted_rows
+                    .push(i * CHUNK_ROWS..(i * CHUNK_ROWS + CHUNK_ROWS).min(height));
+            }
+        }
+    }
 
     // construct a pixmap with the loaded data
     let pixmap = Pixmap::new(width, height)?;
     let pixmap_data = unsafe { pixmap.get_color_data() };
-    for (i, i_color) in buf.into_iter().tuples::<(_, _, _)>().enumerate() {
+    for (i, i_color) in data.into_iter().tuples::<(_, _, _)>().enumerate() {
         pixmap_data[i] = i_color.into()
     }
 
-    Ok(pixmap)
+    Ok((pixmap, report))
 }
 
 #[cfg(test)]
@@ -180,8 +348,7 @@ mod test {
                 original_pixmap.clone(),
             );
             let mut file = sink.open_file().await.unwrap();
-            sink.write_header(&mut file).await.unwrap();
-            sink.write_data(&mut file).await.unwrap();
+            write_pixmap_file(&sink.pixmap, &mut file).await.unwrap();
         }
 
         // restore data from the file
@@ -192,4 +359,47 @@ mod test {
         let restored_data = unsafe { restored_pixmap.get_color_data() };
         assert_eq!(original_data, restored_data);
     }
+
+    #[tokio::test]
+    async fn test_repair_corrupted_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("test.pixmap");
+        let original_pixmap = Arc::new(Pixmap::new(4, 40).unwrap());
+        for y in 0..40 {
+            original_pixmap
+                .set_pixel(1, y, Color::from((0xab, 0xab, 0xab)))
+                .unwrap();
+        }
+        {
+            let sink = FileSink::new(
+                FileSinkOptions {
+                    path: file_path.clone(),
+                    interval: interval(Duration::from_secs(1)),
+                },
+                original_pixmap.clone(),
+            );
+            let mut file = sink.open_file().await.unwrap();
+            write_pixmap_file(&sink.pixmap, &mut file).await.unwrap();
+        }
+
+        // corrupt a pixel in the second chunk
+        let mut content = std::fs::read(&file_path).unwrap();
+        content[FILE_MAGIC.len() + HEADER_SIZE + 20 * 4 * 3 + 3] = 0x42;
+        std::fs::write(&file_path, content).unwrap();
+
+        let (restored_pixmap, report) = load_and_repair_pixmap_file(&file_path).await.unwrap();
+        assert!(report.verified);
+        assert_eq!(report.missing_bytes, 0);
+        assert_eq!(report.corrupted_rows.len(), 1);
+        assert_eq!(report.corrupted_rows[0], 16..32);
+        assert_eq!(
+            restored_pixmap.get_pixel(1, 15).unwrap(),
+            Color::from((0xab, 0xab, 0xab))
+        );
+        assert_eq!(restored_pixmap.get_pixel(1, 16).unwrap(), Color::from((0, 0, 0)));
+        assert_eq!(
+            restored_pixmap.get_pixel(1, 32).unwrap(),
+            Color::from((0xab, 0xab, 0xab))
+        );
+    }
 }
@@ -1,6 +1,6 @@
 //! A sink for drawing on an X or Wayland window
 
-use crate::pixmap::SharedPixmap;
+use crate::pixmap::{SharedPixmap, Transform};
 use crate::DaemonResult;
 use anyhow::anyhow;
 use minifb::{Window, WindowOptions};
@@ -11,10 +11,17 @@ use tokio::time::MissedTickBehavior;
 
 /// Start the window in the background.
 ///
+/// The given `transform` is applied to the pixmap data before it is displayed.
+///
 /// Note that handles to X/Wayland windows are not Send so the background task must always be scheduled on the same thread.
 /// This is achieved by passing an existing `LocalSet` in which the background task will execute.
-pub fn start(join_set: &mut JoinSet<DaemonResult>, pixmap: SharedPixmap) -> anyhow::Result<AbortHandle> {
-    let (width, height) = pixmap.get_size();
+pub fn start(
+    join_set: &mut JoinSet<DaemonResult>,
+    pixmap: SharedPixmap,
+    transform: Transform,
+) -> anyhow::Result<AbortHandle> {
+    let (width, height) = pixmap.get_data_size();
+    let (width, height) = transform.transformed_size(width, height);
     let mut window = Window::new("pixelflut", width, height, WindowOptions::default())?;
 
     window.set_title("Pixelflut Server");
@@ -22,12 +29,14 @@ pub fn start(join_set: &mut JoinSet<DaemonResult>, pixmap: SharedPixmap) -> anyh
     let handle = join_set
         .build_task()
         .name("window_renderer")
-        .spawn_local(async move { render(pixmap, window).await })?;
+        .spawn_local(async move { render(pixmap, window, transform).await })?;
     Ok(handle)
 }
 
-async fn render(pixmap: SharedPixmap, mut window: Window) -> anyhow::Result<!> {
-    let (width, height) = pixmap.get_size();
+async fn render(pixmap: SharedPixmap, mut window: Window, transform: Transform) -> anyhow::Result<!> {
+    let (width, height) = pixmap.get_data_size();
+    let source_indices = (!transform.is_identity()).then(|| transform.source_indices(width, height));
+    let (width, height) = transform.transformed_size(width, height);
     let mut interval = tokio::time::interval(Duration::from_millis(1000 / 60));
     interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
     loop {
@@ -38,9 +47,14 @@ async fn render(pixmap: SharedPixmap, mut window: Window) -> anyhow::Result<!> {
         }
 
         let buffer = unsafe { mem::transmute::<_, &[u32]>(pixmap.get_color_data()) };
-        window
-            .update_with_buffer(buffer, width, height)
-            .expect("Could not update window data");
+        match &source_indices {
+            None => window.update_with_buffer(buffer, width, height),
+            Some(source_indices) => {
+                let transformed = source_indices.iter().map(|i| buffer[*i]).collect::<Vec<_>>();
+                window.update_with_buffer(&transformed, width, height)
+            }
+        }
+        .expect("Could not update window data");
 
         interval.tick().await;
     }
@@ -1,32 +1,352 @@
-pub static HELP_GENERAL: &str = "HELP GENERAL\n\
-pixelflut - a pixel drawing game for programmers inspired by reddits r/place.\n\
-\n\
-Available subcommands are:\n\
-HELP\t- This help message\n\
-SIZE\t- Get the current canvas size\n\
-PX\t- Get or set one specific pixels color\n\
-\n\
-More detailed descriptions about these subcommands is available by sending 'HELP <subcommand>'\n\
-\n\
-All commands end with a newline character (\\n) and need to be sent as ASCII encoded strings.\n\
-Responses are also always newline terminated.\n";
-
-pub static HELP_SIZE: &str = "HELP SIZE\n\
-Syntax:\t\tSIZE\n\
-Response:\tSIZE <width> <height>\n\
-\n\
-Returns the current canvas size.\n\
-This server does not support changing the canvas size at runtime so the result can safely be cached\n";
-
-pub static HELP_PX: &str = "HELP PX\n\
-Syntax:\t\tPX <x> <y> [<rgb>]\n\
-Response:\t[PX <x> <y> <rgb>]\n\
-\n\
-Gets or sets the pixel color addressed by the coordinates <x> and <y>.\n\
-The mode of operation is determined by the third argument (<rgb>) being present or not.\n\
-If it is present, the pixel will be set to that color and no response will be sent.\n\
-It it is not present, the current color will be returned.\n\
-\n\
-<x>\t- X position on the canvas counted from the left side\n\
-<y>\t- Y position on the canvas counted from the top\n\
-<rgb>\t- HEX encoded rgb color (000000 - FFFFFF)\n";
+//! The messages which are sent to clients and a templating layer for customizing them
+
+use crate::net::protocol::{
+    CommandDescription, Features, HelpTopic, ResponseError, COMMANDS, PROTOCOL_VERSION,
+};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::{LazyLock, RwLock};
+
+/// The built-in HELP responses which are generated from the [`COMMANDS`] table
+static DEFAULT_HELP: LazyLock<HashMap<HelpTopic, String>> = LazyLock::new(|| {
+    HelpTopic::all()
+        .map(|topic| {
+            let text = match topic.command() {
+                Some(command) => command_help(command),
+                None => general_help(),
+            };
+            (topic, text)
+        })
+        .collect()
+});
+
+/// Generate the general help which lists all commands
+fn general_help() -> String {
+    let mut text = String::from(
+        "HELP GENERAL\n\
+        pixelflut - a pixel drawing game for programmers inspired by reddits r/place.\n\
+        \n\
+        Available subcommands are:\n",
+    );
+    let commands = std::iter::once(("HELP", "This help message"))
+        .chain(COMMANDS.iter().map(|command| (command.name, command.summary)));
+    push_aligned(&mut text, commands);
+    text.push_str(
+        "\n\
+        More detailed descriptions about these subcommands is available by sending 'HELP <subcommand>'\n\
+        A machine-readable description of all subcommands is available as JSON by sending 'HELP JSON'.\n\
+        \n\
+        All commands end with a newline character (\\n) and need to be sent as ASCII encoded strings.\n\
+        Responses are also always newline terminated.\n\
+        Commands can be prefixed with '#<tag> ' where <tag> is a decimal number, in which case their response is\n\
+        prefixed with the same tag so that responses can be matched to pipelined commands.\n\
+        Failed commands are answered with 'ERR <code> <message>' where <code> is one of OUT_OF_BOUNDS, PARSE_ERROR,\n\
+        UNAUTHORIZED, RATE_LIMITED, UNSUPPORTED or CLAIMED.\n\
+        Before the server shuts down, it sends 'ERR SHUTTING_DOWN <message>' to all clients and closes their\n\
+        connections.\n",
+    );
+    text
+}
+
+/// Generate the help which explains a single command
+fn command_help(command: &CommandDescription) -> String {
+    let mut text = format!(
+        "HELP {}\nSyntax:   {}\nResponse: {}\n",
+        command.name,
+        command.syntax,
+        command.response.unwrap_or("None")
+    );
+    push_lines(&mut text, command.description);
+    if !command.arguments.is_empty() {
+        text.push('\n');
+        push_aligned(
+            &mut text,
+            command
+                .arguments
+                .iter()
+                .map(|(name, _, description)| (*name, *description)),
+        );
+    }
+    push_lines(&mut text, command.notes);
+    text.push_str("\nExamples:\n");
+    command
+        .examples
+        .iter()
+        .for_each(|example| writeln!(text, "{}", example).unwrap());
+    text
+}
+
+/// Append a paragraph of lines, separated from the previous text by an empty line
+fn push_lines(text: &mut String, lines: &[&str]) {
+    if !lines.is_empty() {
+        text.push('\n');
+        lines.iter().for_each(|line| writeln!(text, "{}", line).unwrap());
+    }
+}
+
+/// Append one `<name> - <description>` line per entry with all descriptions starting in the same column
+///
+/// Further lines of a description are indented to the same column.
+fn push_aligned<'a>(text: &mut String, entries: impl Iterator<Item = (&'a str, &'a str)> + Clone) {
+    let width = entries.clone().map(|(name, _)| name.len()).max().unwrap_or(0);
+    for (name, description) in entries {
+        let mut lines = description.lines();
+        writeln!(text, "{:width$} - {}", name, lines.next().unwrap_or_default()).unwrap();
+        lines.for_each(|line| writeln!(text, "{:width$}   {}", "", line).unwrap());
+    }
+}
+
+/// Describe all commands and the optional features which are enabled on a listener as one line of JSON
+///
+/// Message templates are not applied so that clients like web frontends can generate their user interface from it.
+pub(crate) fn help_json(features: Features) -> String {
+    let mut json = format!("{{\"version\":{},\"extensions\":", PROTOCOL_VERSION);
+    push_json_strings(&mut json, features.names());
+    json.push_str(",\"commands\":[");
+    for (i, command) in COMMANDS.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        json.push_str("{\"name\":");
+        push_json_string(&mut json, command.name);
+        json.push_str(",\"summary\":");
+        push_json_string(&mut json, command.summary);
+        json.push_str(",\"syntax\":");
+        push_json_string(&mut json, command.syntax);
+        json.push_str(",\"response\":");
+        match command.response {
+            Some(response) => push_json_string(&mut json, response),
+            None => json.push_str("null"),
+        }
+        json.push_str(",\"description\":");
+        push_json_strings(&mut json, command.description.iter().copied());
+        json.push_str(",\"arguments\":[");
+        for (j, (name, kind, description)) in command.arguments.iter().enumerate() {
+            if j > 0 {
+                json.push(',');
+            }
+            json.push_str("{\"name\":");
+            push_json_string(&mut json, name);
+            json.push_str(",\"type\":");
+            push_json_string(&mut json, kind.as_str());
+            json.push_str(",\"description\":");
+            push_json_string(&mut json, description);
+            json.push('}');
+        }
+        json.push_str("],\"notes\":");
+        push_json_strings(&mut json, command.notes.iter().copied());
+        json.push_str(",\"examples\":");
+        push_json_strings(&mut json, command.examples.iter().copied());
+        json.push('}');
+    }
+    json.push_str("]}");
+    json
+}
+
+/// Append a JSON array of strings
+fn push_json_strings<'a>(json: &mut String, strings: impl Iterator<Item = &'a str>) {
+    json.push('[');
+    for (i, string) in strings.enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        push_json_string(json, string);
+    }
+    json.push(']');
+}
+
+/// Append a quoted and escaped JSON string
+fn push_json_string(json: &mut String, string: &str) {
+    json.push('"');
+    for c in string.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if c.is_control() => write!(json, "\\u{:04x}", c as u32).unwrap(),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+/// Customized versions of the messages which are sent to clients
+///
+/// Templates are loaded from a directory in which all of the following files are optional:
+///
+/// - `help_<topic>.txt` (e.g. `help_general.txt` or `help_px.txt`) replaces the body of the HELP response for
+///   the respective [`HelpTopic`]. The built-in text is available as `{default}`, e.g. to append event rules or
+///   contact information.
+/// - `error.txt` is used to render the messages of all errors, which are sent as `ERR <code> <message>`. The
+///   original message is available as `{message}`.
+/// - `variables.txt` defines additional `name = value` pairs (one per line) which are available as `{name}` in all
+///   other templates.
+///
+/// Localized messages can be served by providing translations of all files.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct MessageTemplates {
+    help: HashMap<HelpTopic, String>,
+    error: Option<String>,
+}
+
+/// The templates which are currently used to render messages
+static TEMPLATES: RwLock<Option<MessageTemplates>> = RwLock::new(None);
+
+impl MessageTemplates {
+    /// Load all templates that exist in the given directory
+    pub fn load(dir: &Path) -> std::io::Result<Self> {
+        let read = |name: &str| match std::fs::read_to_string(dir.join(name)) {
+            Ok(content) => Ok(Some(content)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        };
+
+        let mut variables = read("variables.txt")?
+            .map(|content| parse_variables(&content))
+            .unwrap_or_default();
+        let mut help = HashMap::new();
+        for topic in HelpTopic::all() {
+            let header = format!("HELP {}\n", topic.name());
+            variables.insert(
+                "default".to_string(),
+                DEFAULT_HELP[&topic].trim_start_matches(&header).to_string(),
+            );
+            if let Some(template) = read(&format!("help_{}.txt", topic.name().to_lowercase()))? {
+                let body = render(&template, &variables);
+                match body.ends_with('\n') {
+                    true => help.insert(topic, format!("{}{}", header, body)),
+                    false => help.insert(topic, format!("{}{}\n", header, body)),
+                };
+            }
+        }
+
+        Ok(Self {
+            help,
+            // variables are substituted now while {message} is kept for later
+            error: read("error.txt")?.map(|template| {
+                variables.insert("message".to_string(), "{message}".to_string());
+                render(template.trim_end(), &variables).replace('\n', " ")
+            }),
+        })
+    }
+}
+
+/// Parse `name = value` pairs from the lines of a variables file, ignoring empty lines and `#` comments
+fn parse_variables(content: &str) -> HashMap<String, String> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect()
+}
+
+/// Replace all `{name}` placeholders in `template` with the value of the respective variable
+///
+/// Placeholders of unknown variables are kept as they are.
+fn render(template: &str, variables: &HashMap<String, String>) -> String {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        match rest
+            .find('}')
+            .and_then(|end| variables.get(&rest[1..end]).map(|value| (end, value)))
+        {
+            Some((end, value)) => {
+                result.push_str(value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                result.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Replace the templates with which messages are rendered
+///
+/// This can be called at any time, e.g. to reload templates after they have been edited.
+pub fn set_message_templates(templates: MessageTemplates) {
+    *TEMPLATES.write().unwrap() = Some(templates);
+}
+
+/// Get the text that is sent in response to a HELP request
+pub(crate) fn help_text(topic: HelpTopic) -> Cow<'static, str> {
+    let templates = TEMPLATES.read().unwrap();
+    match templates
+        .as_ref()
+        .and_then(|templates| templates.help.get(&topic))
+    {
+        Some(text) => Cow::Owned(text.clone()),
+        None => Cow::Borrowed(&DEFAULT_HELP[&topic]),
+    }
+}
+
+/// Render an error that is sent to a client as `ERR <code> <message>` (without trailing newline)
+pub(crate) fn error_text(error: &ResponseError) -> String {
+    let templates = TEMPLATES.read().unwrap();
+    match templates.as_ref().and_then(|templates| templates.error.as_ref()) {
+        Some(template) => format!(
+            "ERR {} {}",
+            error.code(),
+            template.replace("{message}", error.message())
+        ),
+        None => error.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_load_templates() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("variables.txt"), "# event info\nevent = GPN\n").unwrap();
+        std::fs::write(dir.path().join("help_size.txt"), "Welcome to {event}!\n{default}").unwrap();
+        std::fs::write(dir.path().join("error.txt"), "{event}: {message}\n").unwrap();
+
+        let templates = MessageTemplates::load(dir.path()).unwrap();
+        assert_eq!(
+            templates.help[&HelpTopic::Size],
+            DEFAULT_HELP[&HelpTopic::Size].replacen("HELP SIZE\n", "HELP SIZE\nWelcome to GPN!\n", 1)
+        );
+        assert_eq!(templates.help.get(&HelpTopic::Px), None);
+        assert_eq!(templates.error.unwrap(), "GPN: {message}");
+    }
+
+    #[test]
+    fn test_generated_help() {
+        let general = &DEFAULT_HELP[&HelpTopic::General];
+        for command in COMMANDS {
+            assert!(general.contains(&format!("\n{} ", command.name)));
+            let text = &DEFAULT_HELP[&command.topic];
+            assert!(text.starts_with(&format!("HELP {}\nSyntax:   {}\n", command.name, command.syntax)));
+            assert!(text.ends_with(&format!("{}\n", command.examples.last().unwrap())));
+        }
+        assert!(DEFAULT_HELP[&HelpTopic::Stream].contains("\n              rgb64: "));
+    }
+
+    #[test]
+    fn test_help_json() {
+        let json = help_json(Features::RGBA | Features::PXB);
+        assert!(json.starts_with(r#"{"version":2,"extensions":["RGBA","PXB"],"commands":[{"name":"SIZE","#));
+        assert!(json.ends_with(r#""examples":["EXEC"]}]}"#));
+        assert!(json.contains(
+            r#"{"name":"<rgb>","type":"color","description":"HEX encoded rgb color (000000 - FFFFFF)"}"#
+        ));
+        assert!(json.contains(r#""response":null"#));
+        assert!(json.contains(r#""syntax":"IMG <x> <y> <len>\\n<data>""#));
+        assert!(json.contains(r#""description":"How the canvas state is encoded:\nrgb64: "#));
+        assert!(!json.contains('\n'));
+    }
+}
@@ -0,0 +1,64 @@
+// A viewer which shows the canvas of the server that serves this page
+//
+// The canvas is received as Server-Sent Events from `GET /events`: `state` events carry the whole canvas as a
+// `STATE rgb64 <base64>` line and `px` events carry one `PX <x> <y> <rrggbb>` line for every pixel which changed.
+
+const canvas = document.getElementById("canvas");
+const statusLine = document.getElementById("status");
+const context = canvas.getContext("2d");
+let image = null;
+
+function showStatus(text) {
+    statusLine.textContent = text;
+}
+
+function decodeState(line) {
+    const [command, algorithm, data] = line.split(" ");
+    if (command !== "STATE" || algorithm !== "rgb64") {
+        return;
+    }
+    const rgb = atob(data);
+    const pixels = image.data;
+    for (let i = 0, j = 0; i + 2 < rgb.length && j < pixels.length; i += 3, j += 4) {
+        pixels[j] = rgb.charCodeAt(i);
+        pixels[j + 1] = rgb.charCodeAt(i + 1);
+        pixels[j + 2] = rgb.charCodeAt(i + 2);
+        pixels[j + 3] = 255;
+    }
+}
+
+function decodePixel(line) {
+    const [command, x, y, color] = line.split(" ");
+    if (command !== "PX" || color === undefined) {
+        return;
+    }
+    const offset = (Number(y) * image.width + Number(x)) * 4;
+    const value = parseInt(color.slice(0, 6), 16);
+    image.data[offset] = (value >> 16) & 0xff;
+    image.data[offset + 1] = (value >> 8) & 0xff;
+    image.data[offset + 2] = value & 0xff;
+    image.data[offset + 3] = 255;
+}
+
+function handle(event, decode) {
+    for (const line of event.data.split("\n")) {
+        decode(line);
+    }
+    context.putImageData(image, 0, 0);
+}
+
+async function connect() {
+    const size = await fetch("/size").then((response) => response.json());
+    canvas.width = size.width;
+    canvas.height = size.height;
+    image = context.createImageData(size.width, size.height);
+
+    const events = new EventSource("/events");
+    events.addEventListener("state", (event) => handle(event, decodeState));
+    events.addEventListener("px", (event) => handle(event, decodePixel));
+    events.onopen = () => showStatus(`${location.host} · ${size.width}×${size.height}`);
+    // the browser reconnects on its own
+    events.onerror = () => showStatus("Connection lost, reconnecting…");
+}
+
+connect().catch((error) => showStatus(`Could not connect: ${error}`));